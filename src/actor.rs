@@ -0,0 +1,152 @@
+use log::debug;
+use serde_json::Value;
+
+use std::io::{Error, ErrorKind};
+use std::sync::mpsc;
+use std::vec::Vec;
+
+use crate::cmd::Command;
+use crate::lamp::{CallError, Lamp, RetryPolicy};
+use crate::response::Notification;
+
+/*
+ * Please follow this order:
+ * - structs/enums, ordered s.t. dependencies are above dependents
+ * - impls (e.g. impl LampHandle)
+ * - impl _ for _ (like Display, From<T>,...)
+ */
+
+/// A request to the actor thread, with the channel its result is sent back on.
+#[derive(Debug)]
+enum Message {
+    /// Send a command without waiting for its reply, see [Lamp::send_cmd].
+    Send(Command, mpsc::Sender<std::io::Result<u32>>),
+    /// Send a command and wait for its reply, see [Lamp::call].
+    Call(Command, mpsc::Sender<Result<Vec<Value>, CallError>>),
+    /// Create a channel receiving the notifications, see [Lamp::notifications].
+    Subscribe(mpsc::Sender<mpsc::Receiver<Notification>>),
+}
+
+/// A handle to a [Lamp] owned by a background thread (an actor), which is driven through a channel.
+///
+/// The handle can be cloned cheaply and moved between threads.
+/// The actor carries out the requests of all handles in order,
+/// matches the replies to them, and re-establishes the connection when it's lost.
+/// Once all handles are dropped, the actor closes the connection and stops.
+/// ```no_run
+/// # use yeerugina_lib::{actor::LampHandle, cmd::{Action, Command, Effect, Power}, lamp::Lamp};
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let lamp = LampHandle::spawn(Lamp::connect("192.168.1.20:55443")?)?;
+/// let notifications = lamp.subscribe_notifications()?;
+/// lamp.call(&Command::new(Action::new_power(Power::On), Effect::Sudden))?;
+/// for notification in notifications {
+///     println!("{:?}", notification.props);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct LampHandle {
+    messages: mpsc::Sender<Message>,
+}
+
+impl LampHandle {
+    /// Move a lamp to a new actor thread, returning a handle to it.
+    ///
+    /// If the lamp has no reconnect policy (see [Lamp::set_reconnect_policy]), the default [RetryPolicy] is used.
+    pub fn spawn(mut lamp: Lamp) -> std::io::Result<Self> {
+        if lamp.reconnect_policy().is_none() {
+            lamp.set_reconnect_policy(Some(RetryPolicy::default()));
+        }
+        let (messages, inbox) = mpsc::channel();
+        let _handle = std::thread::Builder::new()
+            .name("yeelight-actor".into())
+            .spawn(move || run(lamp, &inbox))?;
+        Ok(Self { messages })
+    }
+
+    /// Send a command to the lamp without waiting for its reply, returning its id (see [Lamp::send_cmd]).
+    pub fn send(&self, cmd: &Command) -> std::io::Result<u32> {
+        let (tx, rx) = mpsc::channel();
+        self.request(Message::Send(cmd.clone(), tx))?;
+        rx.recv().map_err(|_| stopped())?
+    }
+
+    /// Send a command to the lamp and wait for its reply (see [Lamp::call]).
+    ///
+    /// The actor carries out one request at a time, so requests of other handles wait until the reply arrived.
+    pub fn call(&self, cmd: &Command) -> Result<Vec<Value>, CallError> {
+        let (tx, rx) = mpsc::channel();
+        self.request(Message::Call(cmd.clone(), tx))
+            .map_err(CallError::Io)?;
+        rx.recv().map_err(|_| CallError::Io(stopped()))?
+    }
+
+    /// Get a channel receiving the notifications sent by the lamp from now on (see [Lamp::notifications]).
+    ///
+    /// The channel ends when the actor stops.
+    pub fn subscribe_notifications(&self) -> std::io::Result<mpsc::Receiver<Notification>> {
+        let (tx, rx) = mpsc::channel();
+        self.request(Message::Subscribe(tx))?;
+        rx.recv().map_err(|_| stopped())
+    }
+
+    /// Pass a request to the actor thread.
+    fn request(&self, message: Message) -> std::io::Result<()> {
+        self.messages.send(message).map_err(|_| stopped())
+    }
+}
+
+/// Carry out the requests until all handles are dropped, then close the connection.
+fn run(mut lamp: Lamp, messages: &mpsc::Receiver<Message>) {
+    for message in messages {
+        // the result is discarded if the requesting handle is gone
+        let _sent = match message {
+            Message::Send(cmd, reply) => reply.send(lamp.send_cmd(&cmd)).is_ok(),
+            Message::Call(cmd, reply) => reply.send(lamp.call(&cmd)).is_ok(),
+            Message::Subscribe(reply) => reply.send(lamp.notifications()).is_ok(),
+        };
+    }
+    debug!("Lamp | Actor stopped");
+    if let Err(err) = lamp.close() {
+        debug!("Lamp | Closing the connection failed: {err}");
+    }
+}
+
+/// The error returned when the actor thread stopped, e.g. because it panicked.
+fn stopped() -> Error {
+    Error::new(ErrorKind::NotConnected, "the lamp actor stopped")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cmd::{Action, Effect};
+    use crate::lamp::testing::{connected_pair, reply_to, respond};
+    use pretty_assertions::assert_eq;
+    use std::{format, vec};
+
+    #[test]
+    fn actor() {
+        let (lamp, peer) = connected_pair();
+        let handle = LampHandle::spawn(lamp).unwrap();
+        let notifications = handle.subscribe_notifications().unwrap();
+        let responder = respond(peer, |line| {
+            let notification = "{\"method\":\"props\",\"params\":{\"ct\":3200}}\r\n";
+            format!("{notification}{}", reply_to(line, r#""result":["ok"]"#))
+        });
+        let cmd = Command::new(Action::new_ct(3200), Effect::Sudden);
+        let other = handle.clone();
+        let thread = std::thread::spawn(move || other.call(&cmd).unwrap());
+        let cmd = Command::new(Action::new_ct(3200), Effect::Sudden);
+        assert_eq!(handle.call(&cmd).unwrap(), vec![Value::from("ok")]);
+        assert_eq!(thread.join().unwrap(), vec![Value::from("ok")]);
+        assert_eq!(
+            notifications.recv().unwrap().get("ct"),
+            Some(&Value::from(3200))
+        );
+        // dropping the last handle closes the connection
+        drop(handle);
+        responder.join().unwrap();
+    }
+}
@@ -1,7 +1,19 @@
+use alloc::{
+    borrow::ToOwned,
+    boxed::Box,
+    string::{String, ToString},
+    vec::Vec,
+};
 use color::{ColorSpace, OpaqueColor, Rgba8};
+use core::{fmt::Display, str::FromStr, time::Duration};
 use derive_more::{Debug, Display};
 use log::info;
-use std::{fmt::Display, time::Duration};
+
+use crate::{
+    colors::{self, HexError, Rgb},
+    limits::{self, LimitPolicy},
+    state::{ColorMode, LampState, Property},
+};
 
 /*
  * Please follow this order:
@@ -11,41 +23,496 @@ use std::{fmt::Display, time::Duration};
  * - impl _ for _ (like Display, From<T>,...)
  */
 
-#[derive(strum_macros::EnumDiscriminants)]
-#[strum(serialize_all = "snake_case")]
-#[strum_discriminants(derive(Display))]
-#[strum_discriminants(name(CommandKind))] // don't use default name
-#[strum_discriminants(vis(pub))]
-#[strum_discriminants(doc = "The different kinds of commands that can be given to the lamp.")]
-#[strum_discriminants(
-    doc = "They are derived from the [InnerAction] enum, but do not contain any values, and are publically available."
+/// Define a newtype around an integer that only accepts values in some inclusive range.
+///
+/// The generated type has a checked constructor `new`, a clamping constructor `new_clamped`,
+/// a constructor `new_with_policy` choosing between the two, and a `get` method returning the inner value. Display prints the inner value.
+macro_rules! bounded_newtype {
+    ($(#[$meta:meta])* $name:ident($inner:ty), $field:literal, $min:expr, $max:expr) => {
+        $(#[$meta])*
+        #[derive(Clone, Copy, Debug, Display, PartialEq, Eq, PartialOrd, Ord, Hash)]
+        #[debug("{_0}")]
+        #[display("{_0}")]
+        pub struct $name($inner);
+
+        impl $name {
+            /// The smallest accepted value.
+            pub const MIN: Self = Self($min);
+            /// The largest accepted value.
+            pub const MAX: Self = Self($max);
+
+            /// Create a new value, returning a [ValidationError] if it's outside of the accepted range.
+            #[allow(unused_comparisons)] // the minimum may be zero for unsigned types
+            pub const fn new(value: $inner) -> Result<Self, ValidationError> {
+                if value >= $min && value <= $max {
+                    Ok(Self(value))
+                } else {
+                    Err(ValidationError::OutOfRange {
+                        field: $field,
+                        min: $min as i64,
+                        max: $max as i64,
+                        got: value as i64,
+                    })
+                }
+            }
+
+            /// Create a new value from a wider integer, used by [Action::new].
+            fn from_wide(value: i64) -> Result<Self, ValidationError> {
+                match <$inner>::try_from(value) {
+                    Ok(value) => Self::new(value),
+                    Err(_) => Err(ValidationError::OutOfRange {
+                        field: $field,
+                        min: $min as i64,
+                        max: $max as i64,
+                        got: value,
+                    }),
+                }
+            }
+
+            /// Create a new value, clamping it to the accepted range.
+            pub fn new_clamped(value: $inner) -> Self {
+                Self(limits::clamp($field, value, $min, $max))
+            }
+
+            /// Create a new value, clamping or rejecting it if it's outside of the accepted range.
+            pub fn new_with_policy(value: $inner, policy: LimitPolicy) -> Result<Self, ValidationError> {
+                policy.apply($field, value, $min, $max).map(Self)
+            }
+
+            /// Get the inner value.
+            pub const fn get(self) -> $inner {
+                self.0
+            }
+        }
+
+        #[cfg(feature = "fuzzing")]
+        impl<'a> arbitrary::Arbitrary<'a> for $name {
+            fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+                u.int_in_range($min..=$max).map(Self)
+            }
+        }
+
+        #[cfg(feature = "serde")]
+        impl serde::Serialize for $name {
+            fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serde::Serialize::serialize(&self.0, serializer)
+            }
+        }
+
+        #[cfg(feature = "serde")]
+        impl<'de> serde::Deserialize<'de> for $name {
+            fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                let value = <$inner as serde::Deserialize>::deserialize(deserializer)?;
+                Self::new(value).map_err(serde::de::Error::custom)
+            }
+        }
+    };
+}
+
+/// Define the built-in methods of the crate from one table, see [InnerAction].
+///
+/// Every entry generates a variant, its method names, its [KindInfo] and optionally a constructor on [Action].
+/// The entries contain `effect` if the method takes an [Effect] or `duration` if it only takes its duration.
+macro_rules! builtin_commands {
+    (
+        @builtins
+        $(#[$enum_meta:meta])*
+        enum $inner:ident {
+            $(
+                $(#[$meta:meta])*
+                $variant:ident($($(#[$field_meta:meta])* $field:ident: $ty:ident $(in [$min:expr, $max:expr])?),+)
+                    => $method:literal, $background:literal, $takes:ident
+                    $(, $(#[$new_meta:meta])* fn $new:ident)?;
+            )*
+            @custom
+            $(#[$custom_meta:meta])*
+            Custom($custom_ty:ty),
+        }
+    ) => {
+        $(#[$enum_meta])*
+        enum $inner {
+            $(
+                $(#[$meta])*
+                #[cfg_attr(feature = "serde", serde(rename = $method))]
+                $variant($($(#[$field_meta])* $ty),+),
+            )*
+            $(#[$custom_meta])*
+            #[cfg_attr(feature = "serde", serde(rename = "custom"))]
+            Custom($custom_ty),
+        }
+
+        impl Action {
+            $(
+                builtin_commands!(
+                    @constructor $inner::$variant($($field: $ty $([$min, $max])?),+) $($(#[$new_meta])* fn $new)?
+                );
+            )*
+        }
+
+        impl CommandKind {
+            /// All kinds of commands, e.g. for iterating over their [KindInfo].
+            pub const ALL: [Self; [$(stringify!($variant),)* "Custom"].len()] = [$(Self::$variant,)* Self::Custom];
+
+            /// Get static information about this kind of command.
+            /// ```
+            /// # use yeerugina_lib::cmd::CommandKind;
+            /// let info = CommandKind::SetRgb.info();
+            /// assert_eq!(info.method, Some("set_rgb"));
+            /// assert_eq!(info.background_method, Some("bg_set_rgb"));
+            /// ```
+            pub const fn info(self) -> KindInfo {
+                match self {
+                    $(
+                        Self::$variant => KindInfo {
+                            method: Some($method),
+                            background_method: Some($background),
+                            takes_effect: builtin_commands!(@takes $takes effect),
+                            takes_duration: builtin_commands!(@takes $takes duration),
+                        },
+                    )*
+                    Self::Custom => KindInfo {
+                        method: None,
+                        background_method: None,
+                        takes_effect: false,
+                        takes_duration: false,
+                    },
+                }
+            }
+
+            /// The static part of a request between the id and the parameters, such as `,"method":"set_rgb","params":[`.
+            ///
+            /// These are precomputed so that hot paths (such as music mode) only need to write the numbers.
+            /// Custom methods have no static method name, so None is returned for them.
+            const fn request_prefix(self) -> Option<&'static str> {
+                match self {
+                    $(Self::$variant => Some(concat!(r#","method":""#, $method, r#"","params":["#)),)*
+                    Self::Custom => None,
+                }
+            }
+        }
+    };
+    (@takes effect effect) => {
+        true
+    };
+    (@takes duration duration) => {
+        true
+    };
+    (@takes $takes:ident $other:ident) => {
+        false
+    };
+    (@constructor $inner:ident::$variant:ident($($field:ident: $ty:ty $([$min:expr, $max:expr])?),+)) => {};
+    (
+        @constructor $inner:ident::$variant:ident($($field:ident: $ty:ty $([$min:expr, $max:expr])?),+)
+        $(#[$meta:meta])* fn $new:ident
+    ) => {
+        $(#[$meta])*
+        pub fn $new($($field: $ty),+) -> Self {
+            $($(let $field = limits::clamp(stringify!($field), $field, $min, $max);)?)+
+            Self($inner::$variant($($field),+))
+        }
+    };
+}
+
+bounded_newtype!(
+    /// The brightness of a lamp in percent, between 1 and 100.
+    Brightness(u8),
+    "brightness",
+    limits::BRIGHT_MIN,
+    limits::BRIGHT_MAX
+);
+bounded_newtype!(
+    /// The hue of a color in degrees, between 0 and 359.
+    Hue(u16),
+    "hue",
+    limits::HUE_MIN,
+    limits::HUE_MAX
+);
+bounded_newtype!(
+    /// The saturation of a color in percent, between 0 and 100.
+    Saturation(u8),
+    "saturation",
+    limits::SAT_MIN,
+    limits::SAT_MAX
+);
+bounded_newtype!(
+    /// A signed percentage used for relative adjustments, between -100 and 100.
+    Percentage(i8),
+    "percentage",
+    limits::PERCENTAGE_MIN,
+    limits::PERCENTAGE_MAX
+);
+
+builtin_commands! {
+    @builtins
+    #[derive(strum_macros::EnumDiscriminants)]
+    #[strum_discriminants(derive(Display))]
+    #[strum_discriminants(name(CommandKind))] // don't use default name
+    #[strum_discriminants(vis(pub))]
+    #[strum_discriminants(doc = "The different kinds of commands that can be given to the lamp.")]
+    #[strum_discriminants(
+        doc = "They are derived from the [InnerAction] enum, but do not contain any values, and are publically available."
+    )]
+    /// A change done to a lamp.
+    ///
+    /// This is the inner enum of [Action]. The commands that can be given to the lamp are defined here,
+    /// each with its method names, whether it takes an [Effect] or only its duration, its [KindInfo] metadata, and optionally a constructor.
+    /// The enum variants also contain data needed to accomplish these actions, and Display writes the parameters of the method.
+    #[derive(Clone, Debug, Display, PartialEq, Eq, Hash)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    enum InnerAction {
+        /// Set the color temperature of the lamp to some number of kelvins.
+        #[display("{_0}")]
+        SetCtAbx(#[debug("{_0}K")] ct: u16 in [limits::CT_MIN, limits::CT_MAX]) // add kelvin unit
+            => "set_ct_abx", "bg_set_ct_abx", effect,
+            /// Create a new Action for changing the color temperature of the lamp to some value.
+            ///
+            /// This method enforces the constraint 1700K <= ct <= 6500K.
+            fn new_ct;
+        /// Set the lamp to display a color by passing a u32.
+        /// The eight smallest bits denote the blue value, then the following bytes denote green and red.
+        /// For example, in order to set the lamp to display a purple color (RGB 165,26,234), you can pass 0xa61aeau32.
+        /// Generally, for a hex color #RRGGBB, you pass the integer 0x00{RR}{GG}{BB}.
+        #[display("{_0}")]
+        SetRgb(#[debug("{_0:x}")] rgb: u32) // print as hex
+            => "set_rgb", "bg_set_rgb", effect;
+        /// Set the lamp to display a color by passing its hue and saturation.
+        #[display("{_0},{_1}")]
+        SetHsv(hue: Hue, sat: Saturation) => "set_hsv", "bg_set_hsv", effect,
+            /// Create a new Action for changing the color of the lamp to some hue and saturation.
+            fn new_hsv;
+        /// Set the brightness of the lamp.
+        #[display("{_0}")]
+        SetBright(bright: Brightness) => "set_bright", "bg_set_bright", effect,
+            /// Create a new Action for changing the brightness of the lamp.
+            fn new_bright;
+        /// Turn the lamp on or off.
+        #[display("\"{_0}\"")]
+        SetPower(power: Power) => "set_power", "bg_set_power", effect,
+            /// Create a new Action for turning the lamp on or off.
+            fn new_power;
+        /// Change the brightness of the lamp by some percentage.
+        #[display("{_0}")]
+        AdjustBright(pct: Percentage) => "adjust_bright", "bg_adjust_bright", duration,
+            /// Create a new Action for changing the brightness of the lamp relative to the current brightness.
+            ///
+            /// Note that adjustments only take a duration, so a [Effect::Sudden] is sent as the shortest allowed duration.
+            fn new_adjust_bright;
+        /// Change the color temperature of the lamp by some percentage.
+        #[display("{_0}")]
+        AdjustCt(pct: Percentage) => "adjust_ct", "bg_adjust_ct", duration,
+            /// Create a new Action for changing the color temperature of the lamp relative to the current one.
+            ///
+            /// Note that adjustments only take a duration, so a [Effect::Sudden] is sent as the shortest allowed duration.
+            fn new_adjust_ct;
+        /// Change the color of the lamp by some percentage.
+        #[display("{_0}")]
+        AdjustColor(pct: Percentage) => "adjust_color", "bg_adjust_color", duration,
+            /// Create a new Action for changing the color of the lamp relative to the current one.
+            ///
+            /// Note that adjustments only take a duration, so a [Effect::Sudden] is sent as the shortest allowed duration.
+            fn new_adjust_color;
+        @custom
+        /// Call a method that is not modelled by this crate.
+        /// The method name and parameters are passed to the lamp as-is (apart from string escaping).
+        #[display("{}", ParamList(&_0.params))]
+        Custom(Box<CustomAction>), // boxed to keep the enum small
+    }
+}
+
+/// The data of a custom method call, see [Command::custom].
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+struct CustomAction {
+    method: String,
+    params: Vec<Param>,
+}
+
+/// A parameter passed to a custom method.
+///
+/// See [Command::custom] for more information.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(untagged)
 )]
-/// A change done to a lamp.
+pub enum Param {
+    /// An integer parameter, written as a JSON number.
+    Int(i64),
+    /// A string parameter, written as a quoted JSON string.
+    Str(String),
+}
+
+/// The parameters of an [Action], used by [Action::new].
 ///
-/// This is the inner enum of [Action]. The commands that can be given to the lamp are defined here.
-/// The enum variants also contain data needed to accomplish these actions.
+/// The parameters are grouped by their shape rather than by [CommandKind],
+/// so the same bundle can be used for several kinds of actions.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ActionParams {
+    /// A single integer, such as a color temperature or an RGB value.
+    Int(u32),
+    /// A pair of integers, such as a hue and a saturation.
+    Pair(u32, u32),
+    /// A signed integer, such as a percentage used for adjustments.
+    Signed(i32),
+    /// A method name and its parameters, used by [CommandKind::Custom].
+    Custom(String, Vec<Param>),
+}
+
+/// The reason a value was rejected when constructing an [Action] or a [Command].
 #[derive(Clone, Copy, Debug, Display, PartialEq, Eq)]
-//#[display("\"method\":{_variant}")]
-enum InnerAction {
-    /// Set the color temperature of the lamp to some number of kelvins.
-    #[display("\"set_ct_abx\",\"params\":[{_0}")]
-    SetCtAbx(#[debug("{_0}K")] u16), // add kelvin unit
-    /// Set the lamp to display a color by passing a u32.
-    /// The eight smallest bits denote the blue value, then the following bytes denote green and red.
-    /// For example, in order to set the lamp to display a purple color (RGB 165,26,234), you can pass 0xa61aeau32.
-    /// Generally, for a hex color #RRGGBB, you pass the integer 0x00{RR}{GG}{BB}.
-    #[display("\"set_rgb\",\"params\":[{_0}")]
-    SetRgb(#[debug("{_0:x}")] u32), // print as hex
+#[allow(variant_size_differences)] // errors are rare, so boxing OutOfRange isn't worth it
+pub enum ValidationError {
+    /// A value was outside of its accepted (inclusive) range.
+    #[display("{field} must be between {min} and {max}, got {got}")]
+    OutOfRange {
+        /// The name of the value, such as `brightness`.
+        field: &'static str,
+        /// The smallest accepted value.
+        min: i64,
+        /// The largest accepted value.
+        max: i64,
+        /// The rejected value.
+        got: i64,
+    },
+    /// The [ActionParams] passed to [Action::new] don't fit the given kind.
+    #[display("the parameters don't fit the action kind {_0}")]
+    ParamsMismatch(CommandKind),
+    /// A duration was shorter than the protocol allows.
+    #[display("{field} must be at least {}ms, got {}ms", min.as_millis(), got.as_millis())]
+    TooShort {
+        /// The name of the value, such as `duration`.
+        field: &'static str,
+        /// The shortest accepted duration.
+        min: Duration,
+        /// The rejected duration.
+        got: Duration,
+    },
+    /// [CommandBuilder::build] was called without setting an action.
+    #[display("no action was set")]
+    MissingAction,
+    /// The model of the lamp lacks a feature the action needs, such as `color`, see [Capabilities::check](crate::model::Capabilities::check).
+    #[display("the lamp doesn't support {_0}")]
+    Unsupported(&'static str),
 }
 
+/// The power state of a lamp.
+#[derive(Clone, Copy, Debug, Display, PartialEq, Eq, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum Power {
+    /// The lamp is on.
+    #[display("on")]
+    On,
+    /// The lamp is off.
+    #[display("off")]
+    Off,
+}
+
+/// Static information about a [CommandKind], see [CommandKind::info].
+///
+/// This lets higher layers (such as capability checks or generated documentation) be data-driven.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub struct KindInfo {
+    /// The method name on the wire, or None for [CommandKind::Custom].
+    pub method: Option<&'static str>,
+    /// The method name of the variant controlling the background light, if there is one.
+    pub background_method: Option<&'static str>,
+    /// Whether the method takes an [Effect], i.e. both its kind and its duration.
+    pub takes_effect: bool,
+    /// Whether the method only takes the duration of an [Effect], like the `adjust_*` methods.
+    pub takes_duration: bool,
+}
+
+/// The reason a string could not be parsed into an [Action].
+///
+/// See the [FromStr] implementation of [Action] for the accepted format.
+#[derive(Clone, Debug, Display, PartialEq, Eq)]
+pub enum ParseActionError {
+    /// The string didn't contain a colon separating the kind from the value.
+    #[display("expected <kind>:<value>, got {_0:?}")]
+    MissingSeparator(String),
+    /// The kind of the action was not recognized.
+    #[display(
+        "unknown action kind {_0:?} (expected ct, rgb, hsv, bright, power, adjust_bright, adjust_ct, or adjust_color)"
+    )]
+    UnknownKind(String),
+    /// The value could not be parsed for the given kind of action.
+    #[display("invalid value {value:?} for {kind}: {reason}")]
+    InvalidValue {
+        /// The kind of the action, such as `ct`.
+        kind: &'static str,
+        /// The value that could not be parsed.
+        value: String,
+        /// Why the value was rejected.
+        reason: String,
+    },
+}
+
+/// The reason a string could not be parsed into an [Effect].
+///
+/// See the [FromStr] implementation of [Effect] for the accepted format.
+#[derive(Clone, Debug, Display, PartialEq, Eq)]
+pub enum ParseEffectError {
+    /// The kind of the effect was not recognized.
+    #[display("unknown effect {_0:?} (expected sudden or smooth:<duration>)")]
+    UnknownKind(String),
+    /// The duration of a smooth effect could not be parsed.
+    #[display("invalid duration {_0:?} (expected e.g. 500ms, 2s, or 1s 500ms)")]
+    InvalidDuration(String),
+}
+
+/// Commonly used color temperatures.
+///
+/// A preset can be converted into an [Action] using [Action]::from() or into().
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum CtPreset {
+    /// Candlelight, 1800K.
+    Candle,
+    /// Warm white, 2700K.
+    Warm,
+    /// Neutral white, 4000K.
+    Neutral,
+    /// Daylight, 5500K.
+    Daylight,
+    /// Cool white, 6500K.
+    Cool,
+}
+
+/// Helper for writing a string as a quoted JSON string.
+///
+/// See [json_string] for the escaping rules.
+struct JsonStr<'a>(&'a str);
+
+/// Helper for writing a list of [Param]s separated by commas.
+struct ParamList<'a>(&'a [Param]);
+
+/// Helper for writing formatted text into a byte buffer, without depending on std::io.
+struct ByteWriter<'a>(&'a mut Vec<u8>);
+
+/// Helper for writing a [Command] as a JSON-RPC request with some id, without changing the command.
+struct Request<'a>(&'a Command, u32);
+
 /// The change that is done by a [Command].
 ///
 /// This is a newtype struct enclosing an enum so that restrictions on values can be enforced.
-#[derive(Clone, Copy, Display, Debug, PartialEq, Eq)]
+/// Display prints the method and its parameters, such as `set_rgb(10885690)`.
+/// With the `serde` feature, actions are (de)serialized as e.g. `{"set_rgb": 10885690}`,
+/// and the constraints on the values are checked when deserializing.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(try_from = "InnerAction", into = "InnerAction")
+)]
 pub struct Action(#[debug("{_0:?}")] InnerAction);
 // remove prefix SmoothDuration() from Debug output
 
-#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
 /// A newtype enclosing a [Duration].
 ///
 /// This is used to enforce the requirement that smooth transitions must last at least 30 milliseconds.
@@ -53,17 +520,23 @@ pub struct Action(#[debug("{_0:?}")] InnerAction);
 /// or alternatively, to call the into() method on a Duration.
 pub struct SmoothDuration(Duration);
 
-#[derive(Clone, Copy, Display, Debug, Default, PartialEq, Eq)]
+#[derive(Clone, Copy, Display, Debug, Default, PartialEq, Eq, Hash)]
 /// The transition between the current and new state of the lamp.
 ///
 /// In addition to constructing instances manually, Durations can be converted to [Effect](Effects)
 /// using [Effect]::from() or the into() method on a Duration.
+/// With the `serde` feature, effects are (de)serialized as `"sudden"` or `{"smooth": 500}` (in milliseconds).
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
 pub enum Effect {
     #[default]
-    #[display("\"sudden\", 0")]
+    #[display("\"sudden\",0")]
     /// Change the lamp to the new state immediately.
     Sudden,
-    #[display("\"smooth\", {_0}")]
+    #[display("\"smooth\",{_0}")]
     /// Smoothly fade into the new state over some [SmoothDuration].
     Smooth(#[debug("{}ms",_0.0.as_millis())] SmoothDuration), // print as millis
 }
@@ -72,54 +545,195 @@ pub enum Effect {
 ///
 /// Assuming you have a valid [Action] and [Effect], you can construct the [Command] struct yourself.
 /// What the command does is stored in the data field of [Command].
-#[derive(Clone, Copy, Display, Debug)]
-#[display(r#"{{"id":{id},"method":{action}, {eff}]}}"#)]
+///
+/// Display prints the JSON request without the `\r\n` terminator,
+/// so writing a command manually is equivalent to [Lamp::send_cmd](crate::lamp::Lamp::send_cmd):
+/// ```
+/// # use std::io::Write;
+/// # use yeerugina_lib::cmd::{Action, Command, Effect};
+/// let cmd = Command { action: Action::new_ct(3200), eff: Effect::Sudden, id: Some(1) };
+/// let mut buf = Vec::new();
+/// write!(&mut buf, "{}\r\n", cmd).unwrap();
+/// assert_eq!(buf, b"{\"id\":1,\"method\":\"set_ct_abx\",\"params\":[3200,\"sudden\",0]}\r\n");
+/// ```
+///
+/// With the `serde` feature, commands can be (de)serialized, e.g. for storing scenes in config files.
+/// The eff and id fields are optional when deserializing.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Command {
     /// This field denotes the change done by [Command], along with other data, such as color temperature or RGB value.
     pub action: Action,
     /// The transition (sudden or smooth) is represented by [Effect].
+    #[cfg_attr(feature = "serde", serde(default))]
     pub eff: Effect,
     /// A integer used to distinguish between requests.
-    pub id: u8,
+    ///
+    /// If this is None, [Lamp::send_cmd](crate::lamp::Lamp::send_cmd) assigns an id automatically.
+    /// Commands without an id are displayed with the id 0.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub id: Option<u32>,
 }
+/// A builder for [Command]s.
+///
+/// Create a builder using [Command::builder], set an action and optionally an effect and an id,
+/// and call [CommandBuilder::build]:
+/// ```
+/// # use yeerugina_lib::cmd::Command;
+/// let cmd = Command::builder().rgb(0xFF0000).smooth_ms(400).build().unwrap();
+/// assert_eq!(cmd.to_request(), r#"{"id":0,"method":"set_rgb","params":[16711680,"smooth",400]}"#);
+/// ```
+/// If no effect is set, the effect is sudden (or the default effect of a lamp,
+/// see [CommandBuilder::build_with_default_effect]).
+/// If no id is set, the id is assigned when the command is sent.
+#[derive(Clone, Debug, Default)]
+pub struct CommandBuilder {
+    action: Option<Action>,
+    eff: Option<Effect>,
+    id: Option<u32>,
+    /// The error of an invalid value passed to the builder, returned by [CommandBuilder::build].
+    invalid: Option<ValidationError>,
+}
+
 /* Explanation for the display string:
- * Here, we do {"id":32,"method":
- * then action's Display does "set_ct_abx","params":[3200
- * then we add a comma and space ,
- * then effect's Display does "smooth", 3200
+ * Here, we do {"id":32,"method":"set_ct_abx","params":[
+ * (the method name comes from Action::method)
+ * then the Display of the InnerAction does 3200
+ * then we add a comma ,
+ * then effect's Display does "smooth",500
  * and we finish off with ]}
  * and we add \r\n in the lamp send_cmd
+ * Custom methods don't take an effect, so the effect part is skipped for them.
+ * Adjustments only take a duration, so only the milliseconds are written for them.
  */
 
+/// Escape a string as a quoted JSON string, as used for string parameters on the wire.
+///
+/// Quotes, backslashes and all control characters are escaped,
+/// so the result never contains a raw line break that could end the command early.
+///
+/// ```
+/// use yeerugina_lib::cmd::json_string;
+///
+/// assert_eq!(json_string("say \"hi\"\n"), r#""say \"hi\"\n""#);
+/// ```
+pub fn json_string(s: &str) -> String {
+    JsonStr(s).to_string()
+}
+
 impl Action {
-    /// Create a new Action for changing the color temperature of the lamp to some value.
-    ///
-    /// This method enforces the constraint 1700K <= ct <= 6500K.
-    pub fn new_ct(ct: u16) -> Self {
-        if ct < 1700 {
-            info!("Action | Clamping ct to 1700K");
-            Self(InnerAction::SetCtAbx(1700))
-        } else if ct > 6500 {
-            info!("Action | Clamping ct to 6500K");
-            Self(InnerAction::SetCtAbx(6500))
-        } else {
-            Self(InnerAction::SetCtAbx(ct))
+    /// Create a new Action of some kind from a bundle of parameters.
+    ///
+    /// This is useful for data-driven callers (such as config files), which only know the kind of action at runtime.
+    /// The values are validated as by the strict constructors (such as [Action::new_ct_strict]).
+    /// If the parameters don't fit the given kind or a value is invalid, a [ValidationError] is returned.
+    pub fn new(kind: CommandKind, params: ActionParams) -> Result<Self, ValidationError> {
+        let wide = i64::from;
+        match (kind, params) {
+            (CommandKind::SetCtAbx, ActionParams::Int(ct)) => {
+                let ct = u16::try_from(ct).map_err(|_| ValidationError::OutOfRange {
+                    field: "ct",
+                    min: i64::from(limits::CT_MIN),
+                    max: i64::from(limits::CT_MAX),
+                    got: wide(ct),
+                })?;
+                Self::new_ct_strict(ct)
+            }
+            (CommandKind::SetRgb, ActionParams::Int(rgb)) => Self::new_rgb_strict(rgb),
+            (CommandKind::SetHsv, ActionParams::Pair(hue, sat)) => Ok(Self::new_hsv(
+                Hue::from_wide(wide(hue))?,
+                Saturation::from_wide(wide(sat))?,
+            )),
+            (CommandKind::SetBright, ActionParams::Int(bright)) => {
+                Ok(Self::new_bright(Brightness::from_wide(wide(bright))?))
+            }
+            (CommandKind::AdjustBright, ActionParams::Signed(pct)) => Ok(Self::new_adjust_bright(
+                Percentage::from_wide(i64::from(pct))?,
+            )),
+            (CommandKind::AdjustCt, ActionParams::Signed(pct)) => {
+                Ok(Self::new_adjust_ct(Percentage::from_wide(i64::from(pct))?))
+            }
+            (CommandKind::AdjustColor, ActionParams::Signed(pct)) => Ok(Self::new_adjust_color(
+                Percentage::from_wide(i64::from(pct))?,
+            )),
+            (CommandKind::SetPower, ActionParams::Int(power)) => match power {
+                0 => Ok(Self::new_power(Power::Off)),
+                1 => Ok(Self::new_power(Power::On)),
+                _ => Err(ValidationError::OutOfRange {
+                    field: "power",
+                    min: 0,
+                    max: 1,
+                    got: wide(power),
+                }),
+            },
+            (CommandKind::Custom, ActionParams::Custom(method, params)) => {
+                Ok(Self::new_custom(&method, params))
+            }
+            (kind, _) => Err(ValidationError::ParamsMismatch(kind)),
         }
     }
 
-    /// Create a new Action for changing the color of the lamp to some RGB color.
+    /// Create a new Action for changing the color temperature of the lamp to some value in mireds.
+    ///
+    /// Mireds (reciprocal megakelvins) are used by many smart home ecosystems, such as Home Assistant and Hue.
+    /// The value is converted to kelvins (see [mired_to_kelvin](colors::mired_to_kelvin)),
+    /// so as with [Action::new_ct], the color temperature is clamped to 154-588 mireds (6500K-1700K).
+    pub fn new_ct_mired(mired: u16) -> Self {
+        Self::new_ct(colors::mired_to_kelvin(mired))
+    }
+
+    /// Create a new Action for changing the color temperature of the lamp, rejecting invalid values.
     ///
-    /// The largest byte of the u32 will be ignored.
-    pub fn new_rgb_from_int(rgb: u32) -> Self {
-        Self(InnerAction::SetRgb(rgb & 0x00FFFFFFu32))
+    /// Unlike [Action::new_ct], this returns a [ValidationError] if the constraint 1700K <= ct <= 6500K doesn't hold.
+    pub fn new_ct_strict(ct: u16) -> Result<Self, ValidationError> {
+        Self::new_ct_with_policy(ct, LimitPolicy::Reject)
+    }
+
+    /// Create a new Action for changing the color temperature of the lamp,
+    /// clamping or rejecting values outside of 1700K-6500K depending on the policy.
+    pub fn new_ct_with_policy(ct: u16, policy: LimitPolicy) -> Result<Self, ValidationError> {
+        policy
+            .apply("ct", ct, limits::CT_MIN, limits::CT_MAX)
+            .map(|ct| Self(InnerAction::SetCtAbx(ct)))
+    }
+
+    /// Create a new Action for changing the color of the lamp to some RGB color of the form 0x00RRGGBB.
+    ///
+    /// This is the same as [Action::new_rgb_strict], so zero and values that don't fit in 24 bits
+    /// return a [ValidationError]. Use [Action::new_rgb_lenient] for masking them instead.
+    pub fn new_rgb_from_int(rgb: u32) -> Result<Self, ValidationError> {
+        Self::new_rgb_strict(rgb)
+    }
+
+    /// Create a new Action for changing the color of the lamp to some RGB color, rejecting invalid values.
+    ///
+    /// The protocol requires 1 <= rgb <= 0xFFFFFF, so zero and values that don't fit in 24 bits
+    /// return a [ValidationError].
+    pub fn new_rgb_strict(rgb: u32) -> Result<Self, ValidationError> {
+        LimitPolicy::Reject
+            .apply("rgb", rgb, limits::RGB_MIN, limits::RGB_MAX)
+            .map(|rgb| Self(InnerAction::SetRgb(rgb)))
+    }
+
+    /// Create a new Action for changing the color of the lamp to some RGB color, masking invalid values.
+    ///
+    /// The largest byte of the u32 will be ignored, so e.g. 0x12A61A3A becomes 0xA61A3A.
+    /// Note that the result may still be zero, which the lamp will reject.
+    pub fn new_rgb_lenient(rgb: u32) -> Self {
+        if rgb > limits::RGB_MAX {
+            info!("Action | Masking out the largest byte of rgb");
+        }
+        Self(InnerAction::SetRgb(rgb & limits::RGB_MAX))
     }
 
     /// Create a new Action for changing the color of the lamp to some RGB color.
     ///
     /// This function takes three u8 values representing the red, green, and blue channels.
+    /// The lamp rejects black (zero), so it is sent as 0x000001, the closest color the lamp accepts.
     pub fn new_rgb_from_parts(r: u8, g: u8, b: u8) -> Self {
-        // We don't need to verify since we know that the largest byte is zero
-        Self(InnerAction::SetRgb(u32::from_be_bytes([0x0, r, g, b])))
+        // The largest byte is zero, so only the lower bound needs checking
+        let rgb = u32::from_be_bytes([0x0, r, g, b]).max(limits::RGB_MIN);
+        Self(InnerAction::SetRgb(rgb))
     }
 
     /// Create a new Action for changing the color of the lamp to some RGB color.
@@ -130,25 +744,727 @@ impl Action {
         Self::new_rgb_from_parts(r, g, b)
     }
 
+    /// Create a new Action for changing the color of the lamp to some RGB color.
+    ///
+    /// This function takes a hex string of the form `#RRGGBB`, `RRGGBB`, `#RGB`, or `RGB`.
+    /// See [Rgb::from_hex] for more information.
+    pub fn new_rgb_from_hex(hex: &str) -> Result<Self, HexError> {
+        Rgb::from_hex(hex).map(Self::from)
+    }
+
+    /// Create a new Action calling some method that is not modelled by this crate.
+    ///
+    /// See [Command::custom] for more information.
+    pub fn new_custom(method: &str, params: Vec<Param>) -> Self {
+        Self(InnerAction::Custom(Box::new(CustomAction {
+            method: method.to_owned(),
+            params,
+        })))
+    }
+
+    /// Approximate a color temperature action as an RGB action.
+    ///
+    /// This is useful for lamps that don't support the set_ct_abx method.
+    /// Returns None if the action doesn't set the color temperature.
+    /// See [kelvin_to_rgb](crate::colors::kelvin_to_rgb) for more information.
+    pub fn ct_as_rgb(&self) -> Option<Self> {
+        match self.0 {
+            InnerAction::SetCtAbx(ct) => Some(colors::kelvin_to_rgb(ct).into()),
+            _ => None,
+        }
+    }
+
+    /// Get the color temperature (in kelvins) set by this action, if it sets one.
+    pub fn ct(&self) -> Option<u16> {
+        match self.0 {
+            InnerAction::SetCtAbx(ct) => Some(ct),
+            _ => None,
+        }
+    }
+
     // TODO research color::gradient() function, which returns a GradientIter.
+
+    /// The name of the method called by this action, such as `set_rgb`.
+    pub fn method(&self) -> &str {
+        match &self.0 {
+            InnerAction::Custom(custom) => &custom.method,
+            inner => CommandKind::from(inner).info().method.unwrap_or_default(),
+        }
+    }
+
+    /// The kind of this action.
+    pub fn kind(&self) -> CommandKind {
+        CommandKind::from(&self.0)
+    }
+
+    /// Get a key identifying which state of the lamp this action overwrites, see [Command::coalesce_key].
+    ///
+    /// Relative (`adjust_*`) and custom actions return None, since collapsing them would change the outcome.
+    pub fn coalesce_key(&self) -> Option<&str> {
+        match self.0 {
+            InnerAction::Custom(_)
+            | InnerAction::AdjustBright(_)
+            | InnerAction::AdjustCt(_)
+            | InnerAction::AdjustColor(_) => None,
+            _ => Some(self.method()),
+        }
+    }
+
+    /// Update a cached state after the lamp confirmed this action, see [LampState::apply_action].
+    pub(crate) fn apply_to(&self, state: &mut LampState) {
+        match &self.0 {
+            InnerAction::SetCtAbx(ct) => {
+                state.ct = Some(*ct);
+                state.color_mode = Some(ColorMode::ColorTemperature);
+            }
+            InnerAction::SetRgb(rgb) => {
+                state.rgb = Some(Rgb::from_int(*rgb));
+                state.color_mode = Some(ColorMode::Rgb);
+            }
+            InnerAction::SetHsv(hue, sat) => {
+                state.hue = Some(*hue);
+                state.sat = Some(*sat);
+                state.color_mode = Some(ColorMode::Hsv);
+            }
+            InnerAction::SetBright(bright) => state.bright = Some(*bright),
+            InnerAction::SetPower(power) => state.power = Some(*power),
+            InnerAction::AdjustBright(_) => state.bright = None,
+            InnerAction::AdjustCt(_) => state.ct = None,
+            InnerAction::AdjustColor(_) => {
+                state.rgb = None;
+                state.hue = None;
+                state.sat = None;
+            }
+            InnerAction::Custom(_) => {}
+        }
+    }
+
+    /// Whether the [Effect] of a [Command] should be sent along with this action.
+    fn takes_effect(&self) -> bool {
+        self.kind().info().takes_effect
+    }
+
+    /// Whether only the duration of the [Effect] of a [Command] should be sent along with this action.
+    fn takes_duration(&self) -> bool {
+        self.kind().info().takes_duration
+    }
+}
+
+impl CtPreset {
+    /// The color temperature of the preset in kelvins.
+    pub fn kelvin(self) -> u16 {
+        match self {
+            Self::Candle => 1800,
+            Self::Warm => 2700,
+            Self::Neutral => 4000,
+            Self::Daylight => 5500,
+            Self::Cool => 6500,
+        }
+    }
+}
+
+impl SmoothDuration {
+    /// Create a new SmoothDuration, clamping or rejecting durations shorter than 30ms depending on the policy.
+    ///
+    /// Converting from a [Duration] always clamps.
+    pub fn new(duration: Duration, policy: LimitPolicy) -> Result<Self, ValidationError> {
+        policy
+            .apply_min_duration("duration", duration, limits::SMOOTH_MIN)
+            .map(Self)
+    }
+}
+
+impl Effect {
+    /// Create a sudden effect, the same as [Effect::Sudden].
+    pub const fn sudden() -> Self {
+        Self::Sudden
+    }
+
+    /// Create a smooth effect lasting some number of milliseconds.
+    ///
+    /// As with [Effect]::from(), a zero duration results in a sudden effect,
+    /// and other durations are clamped to at least 30 milliseconds.
+    pub fn smooth_ms(millis: u64) -> Self {
+        Duration::from_millis(millis).into()
+    }
+
+    /// Create a smooth effect lasting some number of seconds.
+    ///
+    /// As with [Effect]::from(), a zero duration results in a sudden effect.
+    pub fn smooth_secs(secs: u64) -> Self {
+        Duration::from_secs(secs).into()
+    }
+
+    /// The duration of the effect in milliseconds, for methods that only take a duration.
+    ///
+    /// A sudden effect is represented by the shortest allowed duration (30 milliseconds).
+    fn duration_ms(&self) -> u128 {
+        match self {
+            Self::Sudden => limits::SMOOTH_MIN.as_millis(),
+            Self::Smooth(dur) => dur.0.as_millis(),
+        }
+    }
+}
+
+impl Command {
+    /// Create a new [CommandBuilder].
+    pub fn builder() -> CommandBuilder {
+        CommandBuilder::default()
+    }
+
+    /// Create a new Command without an id.
+    ///
+    /// The id is assigned by [Lamp::send_cmd](crate::lamp::Lamp::send_cmd) when the command is sent.
+    pub fn new(action: Action, eff: Effect) -> Self {
+        Self {
+            action,
+            eff,
+            id: None,
+        }
+    }
+
+    /// Get a key for collapsing bursts of redundant commands, such as the updates of a brightness slider.
+    ///
+    /// Commands with the same key overwrite the same state of the lamp, so only the latest one needs to be sent.
+    /// The key is the method name, which identifies both the kind of command and the light it targets.
+    /// Commands that can't be collapsed (relative adjustments and custom methods) return None.
+    /// ```
+    /// # use yeerugina_lib::cmd::{Action, Command, Effect};
+    /// let first = Command::new(Action::new_ct(2700), Effect::Sudden);
+    /// let second = Command::new(Action::new_ct(4000), Effect::smooth_ms(500));
+    /// assert_eq!(first.coalesce_key(), Some("set_ct_abx"));
+    /// assert_eq!(first.coalesce_key(), second.coalesce_key());
+    /// ```
+    pub fn coalesce_key(&self) -> Option<&str> {
+        self.action.coalesce_key()
+    }
+
+    /// Encode the command as a JSON-RPC request, such as `{"id":1,"method":"set_bright","params":[50,"sudden",0]}`.
+    ///
+    /// The request doesn't contain the `\r\n` terminator required by the lamp.
+    /// This is the same as the Display output of the command.
+    pub fn to_request(&self) -> String {
+        self.to_string()
+    }
+
+    /// Write the command as a JSON-RPC request, including the `\r\n` terminator, into some writer.
+    ///
+    /// The request is written directly into the writer without constructing an intermediate String.
+    /// Note that this may result in several small writes, so unbuffered writers should be wrapped in a BufWriter.
+    #[cfg(feature = "std")]
+    pub fn write_request(&self, w: &mut impl std::io::Write) -> std::io::Result<()> {
+        write!(w, "{self}\r\n")
+    }
+
+    /// Encode the command as a JSON-RPC request, including the `\r\n` terminator, into a reusable buffer.
+    ///
+    /// The buffer is cleared first, so that hot paths can reuse its allocation and send the request in one write.
+    pub fn encode_into(&self, buf: &mut Vec<u8>) {
+        self.encode_with_id(self.id.unwrap_or(0), buf);
+    }
+
+    /// Encode the command as in [Command::encode_into], but with the given id instead of its own.
+    pub(crate) fn encode_with_id(&self, id: u32, buf: &mut Vec<u8>) {
+        use core::fmt::Write;
+        buf.clear();
+        // writing into a Vec can't fail
+        let _res = write!(ByteWriter(buf), "{}\r\n", Request(self, id));
+    }
+
+    /// Create a new Command calling some method that is not modelled by this crate.
+    ///
+    /// This is an escape hatch for firmware features or vendor quirks that don't have a constructor yet.
+    /// The parameters are sent exactly as given, so no [Effect] is appended to them.
+    /// The returned command has no id, so one is assigned when it's sent.
+    pub fn custom(method: &str, params: Vec<Param>) -> Self {
+        Self {
+            action: Action::new_custom(method, params),
+            eff: Effect::default(),
+            id: None,
+        }
+    }
+
+    /// Create a new Command querying some properties of the lamp with `get_prop`.
+    ///
+    /// The lamp replies with the values in the same order, see [LampState::from_props].
+    pub fn get_prop(props: &[Property]) -> Self {
+        Self::custom(
+            "get_prop",
+            props.iter().map(|prop| prop.name().into()).collect(),
+        )
+    }
+}
+
+impl CommandBuilder {
+    /// Set the action of the command.
+    pub fn action(mut self, action: impl Into<Action>) -> Self {
+        self.action = Some(action.into());
+        self.invalid = None;
+        self
+    }
+
+    /// Set the action of the command to changing the color temperature, see [Action::new_ct].
+    pub fn ct(self, ct: u16) -> Self {
+        self.action(Action::new_ct(ct))
+    }
+
+    /// Set the action of the command to changing the RGB color, see [Action::new_rgb_from_int].
+    ///
+    /// If the color is invalid, [CommandBuilder::build] returns the [ValidationError].
+    pub fn rgb(mut self, rgb: u32) -> Self {
+        match Action::new_rgb_from_int(rgb) {
+            Ok(action) => self.action(action),
+            Err(err) => {
+                self.action = None;
+                self.invalid = Some(err);
+                self
+            }
+        }
+    }
+
+    /// Set the action of the command to changing the hue and saturation, see [Action::new_hsv].
+    pub fn hsv(self, hue: Hue, sat: Saturation) -> Self {
+        self.action(Action::new_hsv(hue, sat))
+    }
+
+    /// Set the action of the command to changing the brightness, see [Action::new_bright].
+    pub fn bright(self, bright: Brightness) -> Self {
+        self.action(Action::new_bright(bright))
+    }
+
+    /// Set the action of the command to turning the lamp on or off, see [Action::new_power].
+    pub fn power(self, power: Power) -> Self {
+        self.action(Action::new_power(power))
+    }
+
+    /// Set the effect of the command.
+    pub fn effect(mut self, eff: Effect) -> Self {
+        self.eff = Some(eff);
+        self
+    }
+
+    /// Set the effect of the command to [Effect::Sudden].
+    pub fn sudden(self) -> Self {
+        self.effect(Effect::sudden())
+    }
+
+    /// Set the effect of the command to a smooth transition lasting some duration.
+    ///
+    /// As with [Effect]::from(), a zero duration results in a sudden effect.
+    pub fn smooth(self, dur: Duration) -> Self {
+        self.effect(dur.into())
+    }
+
+    /// Set the effect of the command to a smooth transition lasting some number of milliseconds.
+    pub fn smooth_ms(self, millis: u64) -> Self {
+        self.effect(Effect::smooth_ms(millis))
+    }
+
+    /// Set the id of the command.
+    pub fn id(mut self, id: u32) -> Self {
+        self.id = Some(id);
+        self
+    }
+
+    /// Build the command, returning [ValidationError::MissingAction] if no action was set.
+    pub fn build(self) -> Result<Command, ValidationError> {
+        self.build_with_default_effect(Effect::default())
+    }
+
+    /// Build the command, using the given effect if no effect was set.
+    ///
+    /// This is useful for applying the default effect of a lamp,
+    /// see [Lamp::default_effect](crate::lamp::Lamp::default_effect).
+    pub fn build_with_default_effect(self, eff: Effect) -> Result<Command, ValidationError> {
+        if let Some(err) = self.invalid {
+            return Err(err);
+        }
+        Ok(Command {
+            action: self.action.ok_or(ValidationError::MissingAction)?,
+            eff: self.eff.unwrap_or(eff),
+            id: self.id,
+        })
+    }
+}
+
+impl Display for Command {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}", Request(self, self.id.unwrap_or(0)))
+    }
+}
+
+impl core::fmt::Write for ByteWriter<'_> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        self.0.extend_from_slice(s.as_bytes());
+        Ok(())
+    }
+}
+
+impl Display for Request<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let Self(cmd, id) = self;
+        write!(f, r#"{{"id":{id}"#)?;
+        match cmd.action.kind().request_prefix() {
+            Some(prefix) => f.write_str(prefix)?,
+            None => write!(
+                f,
+                r#","method":{},"params":["#,
+                JsonStr(cmd.action.method())
+            )?,
+        }
+        write!(f, "{}", cmd.action.0)?;
+        if cmd.action.takes_effect() {
+            write!(f, ",{}", cmd.eff)?;
+        } else if cmd.action.takes_duration() {
+            write!(f, ",{}", cmd.eff.duration_ms())?;
+        }
+        write!(f, "]}}")
+    }
+}
+
+impl Display for Action {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}({})", self.method(), self.0)
+    }
+}
+
+impl Display for Param {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Int(num) => write!(f, "{num}"),
+            Self::Str(s) => write!(f, "{}", JsonStr(s)),
+        }
+    }
+}
+
+impl Display for JsonStr<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "\"")?;
+        for c in self.0.chars() {
+            match c {
+                '"' => write!(f, "\\\"")?,
+                '\\' => write!(f, "\\\\")?,
+                '\n' => write!(f, "\\n")?,
+                '\r' => write!(f, "\\r")?,
+                '\t' => write!(f, "\\t")?,
+                '\u{8}' => write!(f, "\\b")?,
+                '\u{c}' => write!(f, "\\f")?,
+                // remaining control characters must be escaped, and the lamp ends commands on \r\n
+                c if c.is_control() => write!(f, "\\u{:04x}", u32::from(c))?,
+                _ => write!(f, "{c}")?,
+            }
+        }
+        write!(f, "\"")
+    }
+}
+
+impl Display for ParamList<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        for (idx, param) in self.0.iter().enumerate() {
+            if idx > 0 {
+                write!(f, ",")?;
+            }
+            write!(f, "{param}")?;
+        }
+        Ok(())
+    }
 }
 
 impl Display for SmoothDuration {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(f, "{}", self.0.as_millis())
     }
 }
 
 impl From<Duration> for SmoothDuration {
     fn from(value: Duration) -> Self {
-        if value.as_millis() < 30 {
-            Self(Duration::from_millis(30))
-        } else {
-            Self(value)
+        Self(value.max(limits::SMOOTH_MIN))
+    }
+}
+
+impl core::error::Error for ValidationError {}
+
+impl core::error::Error for ParseActionError {}
+
+impl core::error::Error for ParseEffectError {}
+
+/// Parse an [Action] from a string of the form `<kind>:<value>`.
+///
+/// The accepted kinds and values are:
+/// - `ct:4000` for a color temperature in kelvins (between 1700 and 6500),
+///   or one of the presets, such as `ct:warm` (see [CtPreset])
+/// - `rgb:#ff8800` for an RGB color as a hex string, or a named CSS color, such as `rgb:rebeccapurple`
+/// - `hsv:270,67` for a hue and a saturation
+/// - `bright:70` for a brightness in percent
+/// - `power:on` or `power:off`
+/// - `adjust_bright:-20`, `adjust_ct:20`, `adjust_color:20` for a relative change in percent
+///
+/// The kinds, preset names, and power states are case-insensitive.
+impl FromStr for Action {
+    type Err = ParseActionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, value) = s
+            .split_once(':')
+            .ok_or_else(|| ParseActionError::MissingSeparator(s.to_owned()))?;
+        let (kind, value) = (kind.trim(), value.trim());
+        let invalid = |kind: &'static str, reason: &dyn Display| ParseActionError::InvalidValue {
+            kind,
+            value: value.to_owned(),
+            reason: reason.to_string(),
+        };
+        let pct = |kind: &'static str| {
+            let pct: i8 = value.parse().map_err(|e| invalid(kind, &e))?;
+            Percentage::new(pct).map_err(|e| invalid(kind, &e))
+        };
+        match kind.to_ascii_lowercase().as_str() {
+            "ct" => {
+                let preset = match value.to_ascii_lowercase().as_str() {
+                    "candle" => Some(CtPreset::Candle),
+                    "warm" => Some(CtPreset::Warm),
+                    "neutral" => Some(CtPreset::Neutral),
+                    "daylight" => Some(CtPreset::Daylight),
+                    "cool" => Some(CtPreset::Cool),
+                    _ => None,
+                };
+                if let Some(preset) = preset {
+                    return Ok(preset.into());
+                }
+                let ct: u16 = value.parse().map_err(|e| invalid("ct", &e))?;
+                Self::new_ct_strict(ct).map_err(|e| invalid("ct", &e))
+            }
+            "rgb" => {
+                let rgb = match colors::by_name(value) {
+                    Some(rgb) => rgb,
+                    None => Rgb::from_hex(value).map_err(|e| invalid("rgb", &e))?,
+                };
+                Self::new_rgb_strict(rgb.to_int()).map_err(|e| invalid("rgb", &e))
+            }
+            "hsv" => {
+                let (hue, sat) = value
+                    .split_once(',')
+                    .ok_or_else(|| invalid("hsv", &"expected <hue>,<saturation>"))?;
+                let hue: u16 = hue.trim().parse().map_err(|e| invalid("hsv", &e))?;
+                let sat: u8 = sat.trim().parse().map_err(|e| invalid("hsv", &e))?;
+                let hue = Hue::new(hue).map_err(|e| invalid("hsv", &e))?;
+                let sat = Saturation::new(sat).map_err(|e| invalid("hsv", &e))?;
+                Ok(Self::new_hsv(hue, sat))
+            }
+            "bright" => {
+                let bright: u8 = value.parse().map_err(|e| invalid("bright", &e))?;
+                Brightness::new(bright)
+                    .map(Self::new_bright)
+                    .map_err(|e| invalid("bright", &e))
+            }
+            "power" => match value.to_ascii_lowercase().as_str() {
+                "on" => Ok(Self::new_power(Power::On)),
+                "off" => Ok(Self::new_power(Power::Off)),
+                _ => Err(invalid("power", &"expected on or off")),
+            },
+            "adjust_bright" => pct("adjust_bright").map(Self::new_adjust_bright),
+            "adjust_ct" => pct("adjust_ct").map(Self::new_adjust_ct),
+            "adjust_color" => pct("adjust_color").map(Self::new_adjust_color),
+            _ => Err(ParseActionError::UnknownKind(kind.to_owned())),
         }
     }
 }
 
+#[cfg(feature = "serde")]
+impl serde::Serialize for SmoothDuration {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let millis = u64::try_from(self.0.as_millis()).unwrap_or(u64::MAX);
+        serializer.serialize_u64(millis)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for SmoothDuration {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let millis = <u64 as serde::Deserialize>::deserialize(deserializer)?;
+        Ok(Duration::from_millis(millis).into())
+    }
+}
+
+// Used for deserializing actions, so that the constraints are enforced.
+#[cfg(feature = "serde")]
+impl TryFrom<InnerAction> for Action {
+    type Error = ValidationError;
+
+    fn try_from(value: InnerAction) -> Result<Self, Self::Error> {
+        match value {
+            InnerAction::SetCtAbx(ct) => Self::new_ct_strict(ct),
+            InnerAction::SetRgb(rgb) => Self::new_rgb_strict(rgb),
+            // The other values are validated by their own types
+            inner => Ok(Self(inner)),
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl From<Action> for InnerAction {
+    fn from(value: Action) -> Self {
+        value.0
+    }
+}
+
+/// Set the lamp to some color, see [Action::new_rgb_from_parts] for how black is handled.
+impl From<Rgb> for Action {
+    fn from(value: Rgb) -> Self {
+        Self::new_rgb_from_parts(value.r, value.g, value.b)
+    }
+}
+
+/// Set the lamp to some color from the palette crate, see [Rgb].
+#[cfg(feature = "palette")]
+impl From<palette::Srgb<u8>> for Action {
+    fn from(value: palette::Srgb<u8>) -> Self {
+        Rgb::from(value).into()
+    }
+}
+
+/// Set the lamp to some color from the palette crate, see [Rgb].
+#[cfg(feature = "palette")]
+impl From<palette::Srgb> for Action {
+    fn from(value: palette::Srgb) -> Self {
+        Rgb::from(value).into()
+    }
+}
+
+/// Set the lamp to the hue and saturation of some color from the palette crate.
+///
+/// The value of the color is ignored, as the brightness is set separately.
+#[cfg(feature = "palette")]
+impl From<palette::Hsv> for Action {
+    fn from(value: palette::Hsv) -> Self {
+        let hsv = colors::Hsv::from(value);
+        Self::new_hsv(hsv.hue, hsv.sat)
+    }
+}
+
+/// Set the lamp to some color from the rgb crate, see [Rgb].
+#[cfg(feature = "rgb")]
+impl From<rgb::RGB8> for Action {
+    fn from(value: rgb::RGB8) -> Self {
+        Rgb::from(value).into()
+    }
+}
+
+/// Set the lamp to some color given as red, green and blue, see [Action::new_rgb_from_parts].
+impl From<(u8, u8, u8)> for Action {
+    fn from((r, g, b): (u8, u8, u8)) -> Self {
+        Self::new_rgb_from_parts(r, g, b)
+    }
+}
+
+/// Convert an RGB value of the form 0x00RRGGBB into an [Action], see [Action::new_rgb_strict].
+impl TryFrom<u32> for Action {
+    type Error = ValidationError;
+
+    fn try_from(value: u32) -> Result<Self, Self::Error> {
+        Self::new_rgb_strict(value)
+    }
+}
+
+/// Convert a color temperature in kelvins into an [Action], see [Action::new_ct_strict].
+impl TryFrom<u16> for Action {
+    type Error = ValidationError;
+
+    fn try_from(value: u16) -> Result<Self, Self::Error> {
+        Self::new_ct_strict(value)
+    }
+}
+
+impl From<CtPreset> for Action {
+    fn from(value: CtPreset) -> Self {
+        Self::new_ct(value.kelvin())
+    }
+}
+
+/// Parse an [Effect] from a string such as `sudden`, `smooth:500ms`, or `smooth:2s`.
+///
+/// The duration of a smooth effect is a sequence of numbers with units, such as `1s 500ms` or `1m30s`.
+/// The supported units are `ms`, `s`, `m`, and `h`.
+/// As with [Effect]::from(), a zero duration results in a sudden effect,
+/// and durations shorter than 30 milliseconds are lengthened.
+impl FromStr for Effect {
+    type Err = ParseEffectError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.eq_ignore_ascii_case("sudden") {
+            return Ok(Self::Sudden);
+        }
+        match s.split_once(':') {
+            Some((kind, dur)) if kind.trim().eq_ignore_ascii_case("smooth") => parse_duration(dur)
+                .map(Self::from)
+                .ok_or_else(|| ParseEffectError::InvalidDuration(dur.trim().to_owned())),
+            _ => Err(ParseEffectError::UnknownKind(s.to_owned())),
+        }
+    }
+}
+
+/// Parse a humantime-style duration such as `1s 500ms`, returning None if it's invalid.
+fn parse_duration(s: &str) -> Option<Duration> {
+    let mut rest = s.trim();
+    if rest.is_empty() {
+        return None;
+    }
+    let mut total = Duration::ZERO;
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let num: u64 = rest[..digits].parse().ok()?;
+        rest = rest[digits..].trim_start();
+        let unit_len = rest
+            .find(|c: char| !c.is_ascii_alphabetic())
+            .unwrap_or(rest.len());
+        let part = match &rest[..unit_len] {
+            "ms" => Duration::from_millis(num),
+            "s" => Duration::from_secs(num),
+            "m" => Duration::from_secs(num.checked_mul(60)?),
+            "h" => Duration::from_secs(num.checked_mul(3600)?),
+            _ => return None,
+        };
+        total = total.checked_add(part)?;
+        rest = rest[unit_len..].trim_start();
+    }
+    Some(total)
+}
+
+impl From<i64> for Param {
+    fn from(value: i64) -> Self {
+        Self::Int(value)
+    }
+}
+
+impl From<&str> for Param {
+    fn from(value: &str) -> Self {
+        Self::Str(value.to_owned())
+    }
+}
+
+impl From<String> for Param {
+    fn from(value: String) -> Self {
+        Self::Str(value)
+    }
+}
+
+/// Implement From<T> for [Param] for integer types that fit in an i64.
+macro_rules! param_from_int {
+    ($($int:ty),*) => {
+        $(
+            impl From<$int> for Param {
+                fn from(value: $int) -> Self {
+                    Self::Int(i64::from(value))
+                }
+            }
+        )*
+    };
+}
+
+param_from_int!(u8, u16, u32, i8, i16, i32);
+
 impl From<Duration> for Effect {
     fn from(value: Duration) -> Self {
         if value.is_zero() {
@@ -159,10 +1475,109 @@ impl From<Duration> for Effect {
     }
 }
 
+// Only valid values are generated, so that the invariants of the constructors can be property-tested.
+#[cfg(feature = "fuzzing")]
+impl<'a> arbitrary::Arbitrary<'a> for Action {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        Ok(match u.int_in_range(0..=8u8)? {
+            0 => Self::new_ct(u.int_in_range(limits::CT_MIN..=limits::CT_MAX)?),
+            1 => Self::new_rgb_lenient(u.int_in_range(limits::RGB_MIN..=limits::RGB_MAX)?),
+            2 => Self::new_hsv(u.arbitrary()?, u.arbitrary()?),
+            3 => Self::new_bright(u.arbitrary()?),
+            4 => Self::new_power(u.arbitrary()?),
+            5 => Self::new_adjust_bright(u.arbitrary()?),
+            6 => Self::new_adjust_ct(u.arbitrary()?),
+            7 => Self::new_adjust_color(u.arbitrary()?),
+            _ => Self::new_custom(u.arbitrary()?, u.arbitrary()?),
+        })
+    }
+}
+
+#[cfg(feature = "fuzzing")]
+impl<'a> arbitrary::Arbitrary<'a> for Power {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        u.choose(&[Self::On, Self::Off]).copied()
+    }
+}
+
+#[cfg(feature = "fuzzing")]
+impl<'a> arbitrary::Arbitrary<'a> for Param {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        if u.arbitrary()? {
+            u.arbitrary().map(Self::Int)
+        } else {
+            u.arbitrary().map(Self::Str)
+        }
+    }
+}
+
+#[cfg(feature = "fuzzing")]
+impl<'a> arbitrary::Arbitrary<'a> for SmoothDuration {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        let millis: u32 = u.arbitrary()?;
+        Ok(Self::from(Duration::from_millis(u64::from(millis))))
+    }
+}
+
+#[cfg(feature = "fuzzing")]
+impl<'a> arbitrary::Arbitrary<'a> for Effect {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        if u.arbitrary()? {
+            Ok(Self::Sudden)
+        } else {
+            u.arbitrary().map(Self::Smooth)
+        }
+    }
+}
+
+#[cfg(feature = "fuzzing")]
+impl<'a> arbitrary::Arbitrary<'a> for Command {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        Ok(Self {
+            action: u.arbitrary()?,
+            eff: u.arbitrary()?,
+            id: u.arbitrary()?,
+        })
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use pretty_assertions::{assert_eq, assert_ne};
+    use std::{format, vec};
+
+    /// Advances a xorshift generator, used for deterministic pseudo-random test inputs.
+    fn xorshift(state: &mut u64) -> u64 {
+        *state ^= *state << 13;
+        *state ^= *state >> 7;
+        *state ^= *state << 17;
+        *state
+    }
+
+    /// Generates pseudo-random strings biased towards characters that need escaping.
+    fn fuzz_strings(count: usize) -> Vec<String> {
+        const SPECIAL: [char; 12] = [
+            '"', '\\', '\n', '\r', '\t', '\u{0}', '\u{8}', '\u{1f}', '\u{7f}', '\u{85}', 'é', '💡',
+        ];
+        let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+        let mut next = move || xorshift(&mut state);
+        (0..count)
+            .map(|_| {
+                let len = next() % 24;
+                (0..len)
+                    .map(|_| {
+                        let roll = next();
+                        if roll % 2 == 0 {
+                            SPECIAL[(roll / 2 % SPECIAL.len() as u64) as usize]
+                        } else {
+                            char::from_u32((roll / 2 % 0x3000) as u32).unwrap_or('?')
+                        }
+                    })
+                    .collect()
+            })
+            .collect()
+    }
 
     #[test]
     fn create_smooth_zero_secs() {
@@ -215,14 +1630,14 @@ mod tests {
 
     #[test]
     fn rgb_eq() {
-        let rgb_1 = Action::new_rgb_from_int(0xDEADFEu32);
+        let rgb_1 = Action::new_rgb_from_int(0xDEADFEu32).unwrap();
         let rgb_2 = Action::new_rgb_from_parts(222, 173, 254);
         assert_eq!(rgb_1, rgb_2);
     }
 
     #[test]
     fn rgb_eqne() {
-        let rgb_1 = Action::new_rgb_from_int(0xA61A3Au32);
+        let rgb_1 = Action::new_rgb_from_int(0xA61A3Au32).unwrap();
         let rgb_2_wrong = Action::new_rgb_from_parts(58, 26, 166);
         assert_ne!(rgb_1, rgb_2_wrong);
         let rgb_2_right = Action::new_rgb_from_parts(166, 26, 58);
@@ -232,9 +1647,770 @@ mod tests {
 
     #[test]
     fn rgb_opaque() {
-        let rgb_1 = Action::new_rgb_from_int(0xA61A3Au32);
+        let rgb_1 = Action::new_rgb_from_int(0xA61A3Au32).unwrap();
         let ocol = OpaqueColor::from_rgb8(0xA6, 0x1A, 0x3A);
         let rgb_2 = Action::new_rgb_from_opaque(ocol);
         assert_eq!(rgb_1, rgb_2);
     }
+
+    #[test]
+    fn rgb_strict() {
+        assert_eq!(
+            Action::new_rgb_strict(0xA61A3Au32),
+            Ok(Action::new_rgb_from_parts(166, 26, 58))
+        );
+        assert_eq!(
+            Action::new_rgb_strict(0xFFFFFFu32),
+            Ok(Action::new_rgb_lenient(0xFFFFFFu32))
+        );
+        let range_err = |got| ValidationError::OutOfRange {
+            field: "rgb",
+            min: 1,
+            max: 0xFFFFFF,
+            got,
+        };
+        assert_eq!(Action::new_rgb_strict(0), Err(range_err(0)));
+        assert_eq!(
+            Action::new_rgb_strict(0x1000000u32),
+            Err(range_err(0x1000000))
+        );
+    }
+
+    #[test]
+    fn rgb_lenient() {
+        let rgb_1 = Action::new_rgb_lenient(0x12A61A3Au32);
+        let rgb_2 = Action::new_rgb_from_parts(166, 26, 58);
+        assert_eq!(rgb_1, rgb_2);
+    }
+
+    #[test]
+    fn generic_new() {
+        let ct = Action::new(CommandKind::SetCtAbx, ActionParams::Int(4000));
+        assert_eq!(ct, Ok(Action::new_ct(4000)));
+        let rgb = Action::new(CommandKind::SetRgb, ActionParams::Int(0xA61A3Au32));
+        assert_eq!(rgb, Ok(Action::new_rgb_from_parts(166, 26, 58)));
+        let custom = Action::new(
+            CommandKind::Custom,
+            ActionParams::Custom("toggle".to_owned(), vec![]),
+        );
+        assert_eq!(custom, Ok(Action::new_custom("toggle", vec![])));
+    }
+
+    #[test]
+    fn generic_new_mismatch() {
+        let result = Action::new(
+            CommandKind::SetRgb,
+            ActionParams::Custom("toggle".to_owned(), vec![]),
+        );
+        assert_eq!(
+            result,
+            Err(ValidationError::ParamsMismatch(CommandKind::SetRgb))
+        );
+        let result = Action::new(CommandKind::Custom, ActionParams::Int(3));
+        assert_eq!(
+            result,
+            Err(ValidationError::ParamsMismatch(CommandKind::Custom))
+        );
+    }
+
+    #[test]
+    fn bounded_newtypes() {
+        assert!(Brightness::new(0).is_err());
+        assert_eq!(Brightness::new(1).map(Brightness::get), Ok(1));
+        assert!(Brightness::new(101).is_err());
+        assert_eq!(Brightness::new_clamped(0), Brightness::MIN);
+        assert!(Hue::new(360).is_err());
+        assert_eq!(Hue::new_clamped(400), Hue::MAX);
+        assert_eq!(Saturation::new(100).map(Saturation::get), Ok(100));
+        assert!(Percentage::new(-101).is_err());
+        assert_eq!(Percentage::new_clamped(-128), Percentage::MIN);
+        assert_eq!(Percentage::new(-100).map(Percentage::get), Ok(-100));
+    }
+
+    #[test]
+    fn generic_new_validated() {
+        let hsv = Action::new(CommandKind::SetHsv, ActionParams::Pair(255, 45));
+        assert_eq!(
+            hsv,
+            Ok(Action::new_hsv(
+                Hue::new(255).unwrap(),
+                Saturation::new(45).unwrap()
+            ))
+        );
+        let hsv = Action::new(CommandKind::SetHsv, ActionParams::Pair(70_000, 45));
+        assert_eq!(
+            hsv,
+            Err(ValidationError::OutOfRange {
+                field: "hue",
+                min: 0,
+                max: 359,
+                got: 70_000
+            })
+        );
+        let bright = Action::new(CommandKind::SetBright, ActionParams::Int(0));
+        assert_eq!(
+            bright.unwrap_err().to_string(),
+            "brightness must be between 1 and 100, got 0"
+        );
+        let ct = Action::new(CommandKind::SetCtAbx, ActionParams::Int(100_000));
+        assert_eq!(
+            ct.unwrap_err().to_string(),
+            "ct must be between 1700 and 6500, got 100000"
+        );
+        let rgb = Action::new(CommandKind::SetRgb, ActionParams::Int(0));
+        assert!(rgb.is_err());
+        let adjust = Action::new(CommandKind::AdjustCt, ActionParams::Signed(-20));
+        assert_eq!(
+            adjust,
+            Ok(Action::new_adjust_ct(Percentage::new(-20).unwrap()))
+        );
+    }
+
+    #[test]
+    fn display_hsv() {
+        let cmd = Command {
+            action: Action::new_hsv(Hue::new(255).unwrap(), Saturation::new(45).unwrap()),
+            eff: Effect::Sudden,
+            id: Some(1),
+        };
+        assert_eq!(
+            cmd.to_string(),
+            r#"{"id":1,"method":"set_hsv","params":[255,45,"sudden",0]}"#
+        );
+    }
+
+    #[test]
+    fn display_adjust() {
+        let cmd = Command {
+            action: Action::new_adjust_bright(Percentage::new(-20).unwrap()),
+            eff: Duration::from_millis(500).into(),
+            id: Some(2),
+        };
+        assert_eq!(
+            cmd.to_string(),
+            r#"{"id":2,"method":"adjust_bright","params":[-20,500]}"#
+        );
+        let cmd = Command {
+            action: Action::new_adjust_color(Percentage::new(20).unwrap()),
+            eff: Effect::Sudden,
+            id: Some(3),
+        };
+        assert_eq!(
+            cmd.to_string(),
+            r#"{"id":3,"method":"adjust_color","params":[20,30]}"#
+        );
+    }
+
+    #[test]
+    fn rgb_hex() {
+        let rgb_1 = Action::new_rgb_from_int(0xA61A3Au32).unwrap();
+        let rgb_2 = Action::new_rgb_from_hex("#a61a3a");
+        assert_eq!(Ok(rgb_1), rgb_2);
+        assert_eq!(
+            Action::new_rgb_from_hex("#zzz"),
+            Err(HexError::InvalidDigit('z'))
+        );
+    }
+
+    #[test]
+    fn ct_fallback() {
+        let result = Action::new_ct(2700).ct_as_rgb();
+        assert_eq!(result, Some(Action::new_rgb_from_parts(255, 167, 87)));
+        assert_eq!(Action::new_rgb_from_int(0xFF).unwrap().ct_as_rgb(), None);
+    }
+
+    #[test]
+    fn conversions() {
+        assert_eq!(
+            Action::from((166, 26, 58)),
+            Action::new_rgb_from_int(0xA61A3A).unwrap()
+        );
+        assert_eq!(
+            Action::try_from(0xA61A3Au32),
+            Ok(Action::new_rgb_from_int(0xA61A3A).unwrap())
+        );
+        assert!(Action::try_from(0x1000000u32).is_err());
+        assert_eq!(Action::try_from(4000u16), Ok(Action::new_ct(4000)));
+        assert!(Action::try_from(1000u16).is_err());
+        assert_eq!(Action::from((0, 0, 0)), Action::new_rgb_lenient(1));
+        assert_eq!(Action::from(colors::BLACK), Action::new_rgb_lenient(1));
+        let actions: Result<Vec<Action>, _> = [2700u16, 4000, 6500]
+            .into_iter()
+            .map(Action::try_from)
+            .collect();
+        assert_eq!(actions.unwrap().len(), 3);
+    }
+
+    #[cfg(feature = "palette")]
+    #[test]
+    fn palette_actions() {
+        let result = Action::from(palette::Srgb::new(0xA6u8, 0x1A, 0x3A));
+        assert_eq!(result, Action::new_rgb_from_int(0xA61A3A).unwrap());
+        let result = Action::from(palette::Hsv::new(120.0, 0.5, 0.1));
+        assert_eq!(
+            result,
+            Action::new_hsv(Hue::new(120).unwrap(), Saturation::new(50).unwrap())
+        );
+    }
+
+    #[cfg(feature = "rgb")]
+    #[test]
+    fn rgb_crate_action() {
+        let result = Action::from(rgb::RGB8::new(0xA6, 0x1A, 0x3A));
+        assert_eq!(result, Action::new_rgb_from_int(0xA61A3A).unwrap());
+    }
+
+    #[test]
+    fn ct_mired() {
+        assert_eq!(Action::new_ct_mired(250), Action::new_ct(4000));
+        assert_eq!(Action::new_ct_mired(100), Action::new_ct(6500));
+        assert_eq!(Action::new_ct_mired(1000), Action::new_ct(1700));
+        assert_eq!(Action::new_ct_mired(0), Action::new_ct(6500));
+    }
+
+    #[test]
+    fn named_color() {
+        let result: Action = colors::REBECCAPURPLE.into();
+        assert_eq!(result, Action::new_rgb_from_int(0x663399u32).unwrap());
+    }
+
+    #[test]
+    fn ct_presets() {
+        assert_eq!(Action::from(CtPreset::Candle), Action::new_ct(1800));
+        let warm: Action = CtPreset::Warm.into();
+        assert_eq!(warm, Action::new_ct(2700));
+        assert_eq!(Action::from(CtPreset::Cool), Action::new_ct(6500));
+    }
+
+    #[test]
+    fn display_ct() {
+        let cmd = Command {
+            action: Action::new_ct(3200),
+            eff: Duration::from_millis(500).into(),
+            id: Some(32),
+        };
+        assert_eq!(
+            cmd.to_string(),
+            r#"{"id":32,"method":"set_ct_abx","params":[3200,"smooth",500]}"#
+        );
+    }
+
+    #[test]
+    fn request_per_kind() {
+        let request = |action: Action, eff: Effect| {
+            Command {
+                action,
+                eff,
+                id: Some(7),
+            }
+            .to_request()
+        };
+        let smooth: Effect = Duration::from_millis(400).into();
+        assert_eq!(
+            request(Action::new_ct(4000), Effect::Sudden),
+            r#"{"id":7,"method":"set_ct_abx","params":[4000,"sudden",0]}"#
+        );
+        assert_eq!(
+            request(Action::new_rgb_from_int(0xFF8800).unwrap(), smooth),
+            r#"{"id":7,"method":"set_rgb","params":[16746496,"smooth",400]}"#
+        );
+        assert_eq!(
+            request(
+                Action::new_hsv(Hue::new(0).unwrap(), Saturation::new(100).unwrap()),
+                smooth
+            ),
+            r#"{"id":7,"method":"set_hsv","params":[0,100,"smooth",400]}"#
+        );
+        assert_eq!(
+            request(Action::new_bright(Brightness::MAX), Effect::Sudden),
+            r#"{"id":7,"method":"set_bright","params":[100,"sudden",0]}"#
+        );
+        assert_eq!(
+            request(Action::new_adjust_bright(Percentage::MIN), smooth),
+            r#"{"id":7,"method":"adjust_bright","params":[-100,400]}"#
+        );
+        assert_eq!(
+            request(Action::new_adjust_ct(Percentage::MAX), smooth),
+            r#"{"id":7,"method":"adjust_ct","params":[100,400]}"#
+        );
+        assert_eq!(
+            request(Action::new_adjust_color(Percentage::MAX), Effect::Sudden),
+            r#"{"id":7,"method":"adjust_color","params":[100,30]}"#
+        );
+        assert_eq!(
+            request(Action::new_custom("toggle", vec![]), smooth),
+            r#"{"id":7,"method":"toggle","params":[]}"#
+        );
+    }
+
+    #[test]
+    fn write_request() {
+        let cmd = Command {
+            action: Action::new_bright(Brightness::new(50).unwrap()),
+            eff: Effect::Sudden,
+            id: Some(9),
+        };
+        let mut buf = Vec::new();
+        cmd.write_request(&mut buf).unwrap();
+        assert_eq!(buf, format!("{}\r\n", cmd.to_request()).into_bytes());
+    }
+
+    #[test]
+    fn requests_are_valid_json() {
+        let cmds = [
+            Command::new(Action::new_ct(3200), Duration::from_secs(1).into()),
+            Command::new(Action::new_adjust_ct(Percentage::MIN), Effect::Sudden),
+            Command::custom("set_name", vec!["\\ \"quoted\" \\".into(), Param::Int(-3)]),
+        ];
+        for cmd in cmds {
+            let value: serde_json::Value = serde_json::from_str(&cmd.to_request()).unwrap();
+            assert_eq!(value["method"], cmd.action.method());
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_roundtrip() {
+        let cmds = [
+            Command {
+                action: Action::new_ct(3200),
+                eff: Duration::from_secs(1).into(),
+                id: Some(5),
+            },
+            Command::new(Action::new_hsv(Hue::MAX, Saturation::MIN), Effect::Sudden),
+            Command::new(colors::BLACK.into(), Effect::Sudden),
+            Command::custom("set_name", vec!["lamp".into(), Param::Int(-3)]),
+        ];
+        for cmd in cmds {
+            let json = serde_json::to_string(&cmd).unwrap();
+            let result: Command = serde_json::from_str(&json).unwrap();
+            assert_eq!(result.to_request(), cmd.to_request());
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_format() {
+        let cmd = Command::new(
+            Action::new_bright(Brightness::MAX),
+            Duration::from_millis(500).into(),
+        );
+        assert_eq!(
+            serde_json::to_string(&cmd).unwrap(),
+            r#"{"action":{"set_bright":100},"eff":{"smooth":500},"id":null}"#
+        );
+        let result: Command = serde_json::from_str(r#"{"action":{"set_ct_abx":3000}}"#).unwrap();
+        assert_eq!(result.action, Action::new_ct(3000));
+        assert_eq!(result.eff, Effect::Sudden);
+        let result: Effect = serde_json::from_str(r#"{"smooth":10}"#).unwrap();
+        assert_eq!(result, Duration::from_millis(30).into());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_validation() {
+        assert!(serde_json::from_str::<Action>(r#"{"set_rgb":0}"#).is_err());
+        assert!(serde_json::from_str::<Action>(r#"{"set_ct_abx":9000}"#).is_err());
+        assert!(serde_json::from_str::<Action>(r#"{"set_bright":0}"#).is_err());
+        assert!(serde_json::from_str::<Action>(r#"{"set_hsv":[360,50]}"#).is_err());
+        assert!(serde_json::from_str::<Action>(r#"{"adjust_ct":-100}"#).is_ok());
+    }
+
+    #[test]
+    fn parse_action() {
+        assert_eq!("ct:4000".parse(), Ok(Action::new_ct(4000)));
+        assert_eq!("CT:Warm".parse(), Ok(Action::new_ct(2700)));
+        assert_eq!(
+            "rgb:#ff8800".parse(),
+            Ok(Action::new_rgb_from_int(0xFF8800).unwrap())
+        );
+        assert_eq!(
+            "rgb:rebeccapurple".parse(),
+            Ok(Action::new_rgb_from_int(0x663399).unwrap())
+        );
+        assert_eq!(
+            "hsv: 270, 67".parse(),
+            Ok(Action::new_hsv(
+                Hue::new(270).unwrap(),
+                Saturation::new(67).unwrap()
+            ))
+        );
+        assert_eq!(
+            "bright:70".parse(),
+            Ok(Action::new_bright(Brightness::new(70).unwrap()))
+        );
+        assert_eq!("power:on".parse(), Ok(Action::new_power(Power::On)));
+        assert_eq!("power:OFF".parse(), Ok(Action::new_power(Power::Off)));
+        assert_eq!(
+            "adjust_bright:-20".parse(),
+            Ok(Action::new_adjust_bright(Percentage::new(-20).unwrap()))
+        );
+    }
+
+    #[test]
+    fn parse_action_errors() {
+        assert_eq!(
+            "ct4000".parse::<Action>(),
+            Err(ParseActionError::MissingSeparator("ct4000".to_owned()))
+        );
+        assert_eq!(
+            "temp:4000".parse::<Action>(),
+            Err(ParseActionError::UnknownKind("temp".to_owned()))
+        );
+        let err = "ct:9000".parse::<Action>().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            r#"invalid value "9000" for ct: ct must be between 1700 and 6500, got 9000"#
+        );
+        let err = "rgb:#000000".parse::<Action>().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            r##"invalid value "#000000" for rgb: rgb must be between 1 and 16777215, got 0"##
+        );
+        let err = "bright:lots".parse::<Action>().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            r#"invalid value "lots" for bright: invalid digit found in string"#
+        );
+        assert!("power:maybe".parse::<Action>().is_err());
+        assert!("hsv:360,50".parse::<Action>().is_err());
+        assert!("adjust_ct:101".parse::<Action>().is_err());
+    }
+
+    #[test]
+    fn parse_effect() {
+        assert_eq!("sudden".parse(), Ok(Effect::Sudden));
+        assert_eq!(
+            "smooth:500ms".parse(),
+            Ok(Effect::from(Duration::from_millis(500)))
+        );
+        assert_eq!(
+            "Smooth: 2s".parse(),
+            Ok(Effect::from(Duration::from_secs(2)))
+        );
+        assert_eq!(
+            "smooth:1s 500ms".parse(),
+            Ok(Effect::from(Duration::from_millis(1500)))
+        );
+        assert_eq!(
+            "smooth:1m30s".parse(),
+            Ok(Effect::from(Duration::from_secs(90)))
+        );
+        assert_eq!(
+            "smooth:10ms".parse(),
+            Ok(Effect::from(Duration::from_millis(30)))
+        );
+        assert_eq!("smooth:0s".parse(), Ok(Effect::Sudden));
+    }
+
+    #[test]
+    fn parse_effect_errors() {
+        assert_eq!(
+            "fade".parse::<Effect>(),
+            Err(ParseEffectError::UnknownKind("fade".to_owned()))
+        );
+        assert_eq!(
+            "smooth:500".parse::<Effect>(),
+            Err(ParseEffectError::InvalidDuration("500".to_owned()))
+        );
+        assert_eq!(
+            "smooth:".parse::<Effect>(),
+            Err(ParseEffectError::InvalidDuration(String::new()))
+        );
+        assert!("smooth:ms".parse::<Effect>().is_err());
+        assert!("smooth:2 days".parse::<Effect>().is_err());
+    }
+
+    #[test]
+    fn builder() {
+        let cmd = Command::builder()
+            .bright(Brightness::new(50).unwrap())
+            .smooth_ms(400)
+            .id(3)
+            .build()
+            .unwrap();
+        assert_eq!(cmd.action, Action::new_bright(Brightness::new(50).unwrap()));
+        assert_eq!(cmd.eff, Duration::from_millis(400).into());
+        assert_eq!(cmd.id, Some(3));
+        let cmd = Command::builder().action(CtPreset::Warm).build().unwrap();
+        assert_eq!(cmd.action, Action::new_ct(2700));
+        assert_eq!(cmd.eff, Effect::Sudden);
+        assert_eq!(cmd.id, None);
+        assert_eq!(
+            Command::builder().smooth_ms(400).build().unwrap_err(),
+            ValidationError::MissingAction
+        );
+        assert_eq!(
+            Command::builder().rgb(0x1000000).build().unwrap_err(),
+            ValidationError::OutOfRange {
+                field: "rgb",
+                min: 1,
+                max: 0xFFFFFF,
+                got: 0x1000000
+            }
+        );
+        assert!(Action::new_rgb_from_int(0).is_err());
+    }
+
+    #[test]
+    fn display_power() {
+        let cmd = Command {
+            action: Action::new_power(Power::On),
+            eff: Duration::from_millis(500).into(),
+            id: Some(1),
+        };
+        assert_eq!(
+            cmd.to_request(),
+            r#"{"id":1,"method":"set_power","params":["on","smooth",500]}"#
+        );
+    }
+
+    #[test]
+    fn action_method_and_kind() {
+        let action = Action::new_rgb_from_int(0xA61A3A).unwrap();
+        assert_eq!(action.method(), "set_rgb");
+        assert_eq!(action.kind(), CommandKind::SetRgb);
+        assert_eq!(action.to_string(), "set_rgb(10885690)");
+        let action = Action::new_custom("set_name", vec!["lamp".into()]);
+        assert_eq!(action.method(), "set_name");
+        assert_eq!(action.kind(), CommandKind::Custom);
+        assert_eq!(action.to_string(), r#"set_name("lamp")"#);
+    }
+
+    #[test]
+    fn display_custom() {
+        let mut cmd = Command::custom("set_name", vec!["my \"lamp\"".into()]);
+        cmd.id = Some(3);
+        assert_eq!(
+            cmd.to_string(),
+            r#"{"id":3,"method":"set_name","params":["my \"lamp\""]}"#
+        );
+    }
+
+    #[test]
+    fn display_custom_mixed_params() {
+        let cmd = Command::custom(
+            "start_cf",
+            vec![Param::Int(4), 2.into(), "1000, 2, 2700, 100".into()],
+        );
+        assert_eq!(
+            cmd.to_string(),
+            r#"{"id":0,"method":"start_cf","params":[4,2,"1000, 2, 2700, 100"]}"#
+        );
+    }
+
+    #[test]
+    fn json_string_escapes() {
+        assert_eq!(json_string("plain"), r#""plain""#);
+        assert_eq!(json_string(r#"a"b\c"#), r#""a\"b\\c""#);
+        assert_eq!(json_string("\r\n\t\u{8}\u{c}"), r#""\r\n\t\b\f""#);
+        assert_eq!(json_string("\u{0}\u{1f}\u{7f}"), r#""\u0000\u001f\u007f""#);
+        assert_eq!(json_string("💡 lamp"), "\"💡 lamp\"");
+    }
+
+    #[test]
+    fn json_string_fuzz() {
+        for s in fuzz_strings(2000) {
+            let escaped = json_string(&s);
+            assert!(!escaped.contains(['\r', '\n']), "{escaped}");
+            let parsed: String = serde_json::from_str(&escaped).unwrap();
+            assert_eq!(parsed, s);
+        }
+    }
+
+    #[test]
+    fn custom_params_fuzz() {
+        for s in fuzz_strings(500) {
+            let cmd = Command::custom("set_name", vec![s.as_str().into(), 1.into()]);
+            let value: serde_json::Value = serde_json::from_str(&cmd.to_string()).unwrap();
+            assert_eq!(value["params"][0], s.as_str());
+            assert_eq!(value["params"][1], 1);
+        }
+    }
+
+    #[test]
+    fn limit_policies() {
+        assert_eq!(
+            Action::new_ct_with_policy(9000, LimitPolicy::Clamp),
+            Ok(Action::new_ct(6500))
+        );
+        assert_eq!(
+            Action::new_ct_with_policy(9000, LimitPolicy::Reject),
+            Action::new_ct_strict(9000)
+        );
+        assert_eq!(
+            Brightness::new_with_policy(0, LimitPolicy::Clamp),
+            Ok(Brightness::MIN)
+        );
+        assert_eq!(
+            Brightness::new_with_policy(0, LimitPolicy::Reject),
+            Brightness::new(0)
+        );
+        let short = Duration::from_millis(5);
+        assert_eq!(
+            SmoothDuration::new(short, LimitPolicy::Clamp),
+            Ok(SmoothDuration::from(short))
+        );
+        assert_eq!(
+            SmoothDuration::new(short, LimitPolicy::Reject)
+                .unwrap_err()
+                .to_string(),
+            "duration must be at least 30ms, got 5ms"
+        );
+    }
+
+    #[test]
+    fn effect_constructors() {
+        assert_eq!(Effect::sudden(), Effect::Sudden);
+        assert_eq!(Effect::smooth_ms(0), Effect::Sudden);
+        assert_eq!(Effect::smooth_ms(10), Duration::from_millis(30).into());
+        assert_eq!(Effect::smooth_ms(500), Duration::from_millis(500).into());
+        assert_eq!(Effect::smooth_secs(2), Duration::from_millis(2000).into());
+        assert_eq!(Effect::smooth_secs(0), Effect::Sudden);
+    }
+
+    #[test]
+    fn coalesce_keys() {
+        let cmd = |action| Command::new(action, Effect::Sudden);
+        let bright = |val| Action::new_bright(Brightness::new(val).unwrap());
+        let burst: Vec<Command> = (1..=50).map(|val| cmd(bright(val))).collect();
+        let mut latest = std::collections::HashMap::new();
+        for cmd in &burst {
+            let _prev = latest.insert(cmd.coalesce_key().unwrap(), cmd);
+        }
+        assert_eq!(latest.len(), 1);
+        assert_eq!(latest["set_bright"], &cmd(bright(50)));
+        assert_ne!(
+            cmd(bright(1)).coalesce_key(),
+            cmd(Action::new_ct(4000)).coalesce_key()
+        );
+        let pct = Percentage::new(10).unwrap();
+        assert_eq!(cmd(Action::new_adjust_bright(pct)).coalesce_key(), None);
+        assert_eq!(Command::custom("toggle", vec![]).coalesce_key(), None);
+    }
+
+    #[test]
+    fn hash_commands() {
+        let mut set = std::collections::HashSet::new();
+        let cmd = Command::new(Action::new_ct(4000), Effect::smooth_ms(500));
+        assert!(set.insert(cmd.clone()));
+        assert!(!set.insert(cmd.clone()));
+        let mut other = cmd;
+        other.id = Some(1);
+        assert!(set.insert(other));
+        assert!(set.insert(Command::custom("set_name", vec!["lamp".into()])));
+    }
+
+    #[test]
+    fn request_prefixes() {
+        let pct = Percentage::new(10).unwrap();
+        let actions = [
+            Action::new_ct(4000),
+            Action::new_rgb_from_int(0xA61A3A).unwrap(),
+            Action::new_hsv(Hue::new(270).unwrap(), Saturation::new(67).unwrap()),
+            Action::new_bright(Brightness::new(50).unwrap()),
+            Action::new_power(Power::On),
+            Action::new_adjust_bright(pct),
+            Action::new_adjust_ct(pct),
+            Action::new_adjust_color(pct),
+        ];
+        for action in actions {
+            let generic = format!(r#","method":{},"params":["#, JsonStr(action.method()));
+            assert_eq!(action.kind().request_prefix(), Some(generic.as_str()));
+        }
+        assert_eq!(CommandKind::Custom.request_prefix(), None);
+    }
+
+    #[test]
+    fn encode_into() {
+        let mut buf = b"leftover".to_vec();
+        let mut cmd = Command::new(Action::new_ct(4000), Effect::smooth_ms(500));
+        cmd.encode_into(&mut buf);
+        assert_eq!(buf, format!("{cmd}\r\n").into_bytes());
+        cmd.encode_with_id(7, &mut buf);
+        cmd.id = Some(7);
+        assert_eq!(buf, format!("{cmd}\r\n").into_bytes());
+        let custom = Command::custom("set_name", vec!["lamp".into()]);
+        custom.encode_into(&mut buf);
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "{\"id\":0,\"method\":\"set_name\",\"params\":[\"lamp\"]}\r\n"
+        );
+    }
+
+    #[cfg(feature = "fuzzing")]
+    #[test]
+    fn arbitrary_commands() {
+        use arbitrary::{Arbitrary, Unstructured};
+        let mut state: u64 = 0x9e37_79b9_7f4a_7c15;
+        let bytes: Vec<u8> = (0..1 << 16)
+            .map(|_| xorshift(&mut state).to_le_bytes()[0])
+            .collect();
+        let mut u = Unstructured::new(&bytes);
+        let mut count = 0;
+        while let Ok(cmd) = Command::arbitrary(&mut u) {
+            if u.is_empty() {
+                break;
+            }
+            count += 1;
+            let value: serde_json::Value = serde_json::from_str(&cmd.to_string()).unwrap();
+            assert_eq!(value["id"], cmd.id.unwrap_or(0));
+            assert_eq!(value["method"], cmd.action.method());
+            let first = &value["params"][0];
+            let in_range = |min: i64, max: i64| (min..=max).contains(&first.as_i64().unwrap());
+            match cmd.action.kind() {
+                CommandKind::SetCtAbx => assert!(in_range(1700, 6500)),
+                CommandKind::SetRgb => assert!(in_range(1, 0xFFFFFF)),
+                CommandKind::SetHsv => assert!(in_range(0, 359)),
+                CommandKind::SetBright => assert!(in_range(1, 100)),
+                CommandKind::AdjustBright | CommandKind::AdjustCt | CommandKind::AdjustColor => {
+                    assert!(in_range(-100, 100))
+                }
+                CommandKind::SetPower | CommandKind::Custom => {}
+            }
+            if let Effect::Smooth(dur) = cmd.eff {
+                assert!(dur.0 >= limits::SMOOTH_MIN);
+            }
+            #[cfg(feature = "serde")]
+            {
+                let json = serde_json::to_string(&cmd).unwrap();
+                assert_eq!(serde_json::from_str::<Command>(&json).unwrap(), cmd);
+            }
+        }
+        assert!(count > 100, "only {count} commands were generated");
+    }
+
+    #[test]
+    fn kind_info() {
+        let pct = Percentage::new(10).unwrap();
+        let actions = [
+            Action::new_ct(4000),
+            Action::new_rgb_from_int(0xA61A3A).unwrap(),
+            Action::new_hsv(Hue::new(270).unwrap(), Saturation::new(67).unwrap()),
+            Action::new_bright(Brightness::new(50).unwrap()),
+            Action::new_power(Power::On),
+            Action::new_adjust_bright(pct),
+            Action::new_adjust_ct(pct),
+            Action::new_adjust_color(pct),
+            Action::new_custom("toggle", vec![]),
+        ];
+        assert_eq!(actions.len(), CommandKind::ALL.len());
+        for (action, kind) in actions.iter().zip(CommandKind::ALL) {
+            assert_eq!(action.kind(), kind);
+            let info = kind.info();
+            assert_eq!(info.method.is_some(), kind != CommandKind::Custom);
+            if let Some(method) = info.method {
+                assert_eq!(method, action.method());
+                assert_eq!(
+                    info.background_method,
+                    Some(format!("bg_{method}").as_str())
+                );
+            }
+            let cmd = Command::new(action.clone(), Effect::Sudden);
+            let value: serde_json::Value = serde_json::from_str(&cmd.to_string()).unwrap();
+            let params = value["params"].as_array().unwrap();
+            assert_eq!(info.takes_effect, params.contains(&"sudden".into()));
+            assert_eq!(info.takes_duration, !info.takes_effect && params.len() > 1);
+        }
+    }
 }
@@ -0,0 +1,895 @@
+use core::str::FromStr;
+use derive_more::{Debug, Display};
+
+use crate::cmd::{Hue, Saturation};
+
+/*
+ * Please follow this order:
+ * - structs/enums, ordered s.t. dependencies are above dependents
+ * - impls (e.g. impl Rgb)
+ * - impl _ for _ (like Display, From<T>,...)
+ */
+
+/// A color represented by its red, green, and blue channels.
+///
+/// This can be converted into an [Action](crate::cmd::Action) using [Action](crate::cmd::Action)::from() or into().
+/// Display prints the color as a hex string such as `#663399`.
+#[derive(Clone, Copy, Debug, Display, PartialEq, Eq, Hash)]
+#[debug("#{r:02x}{g:02x}{b:02x}")]
+#[display("#{r:02x}{g:02x}{b:02x}")]
+pub struct Rgb {
+    /// The red channel.
+    pub r: u8,
+    /// The green channel.
+    pub g: u8,
+    /// The blue channel.
+    pub b: u8,
+}
+
+/// A color represented by its hue, saturation, and value.
+///
+/// This is the representation used by the set_hsv method (which ignores the value, as that is set by the brightness).
+/// Conversions between [Rgb] and [Hsv] use integer arithmetic, so they may be off by one due to rounding.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Hsv {
+    /// The hue of the color in degrees.
+    pub hue: Hue,
+    /// The saturation of the color in percent.
+    pub sat: Saturation,
+    /// The value of the color in percent. Values above 100 are treated as 100.
+    pub val: u8,
+}
+
+/// The reason a hex color string could not be parsed.
+#[derive(Clone, Copy, Debug, Display, PartialEq, Eq)]
+pub enum HexError {
+    /// The string didn't contain three or six hex digits (after the optional `#`).
+    #[display("expected 3 or 6 hex digits, got {_0} characters")]
+    InvalidLength(usize),
+    /// The string contained a character that isn't a hex digit.
+    #[display("invalid hex digit {_0:?}")]
+    InvalidDigit(char),
+}
+
+impl Rgb {
+    /// Create a new color from its red, green, and blue channels.
+    pub const fn new(r: u8, g: u8, b: u8) -> Self {
+        Self { r, g, b }
+    }
+
+    /// Get the color as an integer of the form 0x00RRGGBB, as used by the set_rgb method.
+    pub fn to_int(self) -> u32 {
+        u32::from_be_bytes([0x0, self.r, self.g, self.b])
+    }
+
+    /// Create a color from an integer of the form 0x00RRGGBB, as reported by lamps.
+    ///
+    /// The largest byte of the u32 is ignored.
+    pub const fn from_int(rgb: u32) -> Self {
+        let [_, r, g, b] = rgb.to_be_bytes();
+        Self { r, g, b }
+    }
+
+    /// Parse a color from a hex string.
+    ///
+    /// The accepted forms are `#RRGGBB`, `RRGGBB`, `#RGB`, and `RGB`.
+    /// In the short form, each digit is repeated, so `#f80` is the same as `#ff8800`.
+    pub fn from_hex(hex: &str) -> Result<Self, HexError> {
+        let digits = hex.strip_prefix('#').unwrap_or(hex);
+        let mut nibbles = [0u8; 6];
+        let len = digits.chars().count();
+        if len != 3 && len != 6 {
+            return Err(HexError::InvalidLength(len));
+        }
+        for (idx, c) in digits.chars().enumerate() {
+            let nibble = c.to_digit(16).ok_or(HexError::InvalidDigit(c))?;
+            // to_digit(16) returns at most 15, so this doesn't truncate
+            nibbles[idx] = nibble as u8;
+        }
+        if len == 3 {
+            // Expand RGB to RRGGBB
+            let [r, g, b, ..] = nibbles;
+            Ok(Self::new(r * 0x11, g * 0x11, b * 0x11))
+        } else {
+            let [r1, r2, g1, g2, b1, b2] = nibbles;
+            Ok(Self::new(r1 << 4 | r2, g1 << 4 | g2, b1 << 4 | b2))
+        }
+    }
+
+    /// Convert the color to its hue, saturation, and value.
+    pub fn to_hsv(self) -> Hsv {
+        let (r, g, b) = (i32::from(self.r), i32::from(self.g), i32::from(self.b));
+        let max = r.max(g).max(b);
+        let min = r.min(g).min(b);
+        let delta = max - min;
+        let hue = if delta == 0 {
+            0
+        } else if max == r {
+            div_round(60 * (g - b), delta).rem_euclid(360)
+        } else if max == g {
+            div_round(60 * (b - r), delta) + 120
+        } else {
+            div_round(60 * (r - g), delta) + 240
+        };
+        let sat = if max == 0 {
+            0
+        } else {
+            div_round(100 * delta, max)
+        };
+        let val = div_round(100 * max, 255);
+        // All values are within range, so clamping does nothing apart from the conversion
+        Hsv {
+            hue: Hue::new_clamped(u16::try_from(hue).unwrap_or_default()),
+            sat: Saturation::new_clamped(u8::try_from(sat).unwrap_or_default()),
+            val: u8::try_from(val).unwrap_or_default(),
+        }
+    }
+}
+
+impl Hsv {
+    /// Convert the color to its red, green, and blue channels.
+    pub fn to_rgb(self) -> Rgb {
+        let hue = i32::from(self.hue.get());
+        let sat = i32::from(self.sat.get());
+        let val = i32::from(self.val.min(100));
+        let (region, rem) = (hue / 60, hue % 60);
+        // The channels are computed in 1/100ths (sat) * 1/100ths (val) * 1/60ths (rem) of 255
+        let v = div_round(val * 255, 100);
+        let p = div_round(val * (100 - sat) * 255, 100 * 100);
+        let q = div_round(val * (6000 - sat * rem) * 255, 100 * 100 * 60);
+        let t = div_round(val * (6000 - sat * (60 - rem)) * 255, 100 * 100 * 60);
+        let (r, g, b) = match region {
+            0 => (v, t, p),
+            1 => (q, v, p),
+            2 => (p, v, t),
+            3 => (p, q, v),
+            4 => (t, p, v),
+            _ => (v, p, q),
+        };
+        let channel = |c: i32| u8::try_from(c).unwrap_or(u8::MAX);
+        Rgb::new(channel(r), channel(g), channel(b))
+    }
+}
+
+/// Approximate a color temperature as an RGB color.
+///
+/// This is useful for lamps that don't support the set_ct_abx method.
+/// The approximation is based on Tanner Helland's algorithm, which fits curves to the blackbody color table.
+/// It is reasonably accurate between 1000K and 40000K.
+pub fn kelvin_to_rgb(kelvin: u16) -> Rgb {
+    let temp = f64::from(kelvin) / 100.0;
+    let red = if temp <= 66.0 {
+        255.0
+    } else {
+        329.698727446 * libm::pow(temp - 60.0, -0.1332047592)
+    };
+    let green = if temp <= 66.0 {
+        99.4708025861 * libm::log(temp) - 161.1195681661
+    } else {
+        288.1221695283 * libm::pow(temp - 60.0, -0.0755148492)
+    };
+    let blue = if temp >= 66.0 {
+        255.0
+    } else if temp <= 19.0 {
+        0.0
+    } else {
+        138.5177312231 * libm::log(temp - 10.0) - 305.0447927307
+    };
+    // The clamp makes the cast lossless (apart from the rounding)
+    // (libm is used instead of the float methods so that this works without std)
+    let channel = |c: f64| libm::round(c).clamp(0.0, 255.0) as u8;
+    Rgb::new(channel(red), channel(green), channel(blue))
+}
+
+/// Convert a color temperature from mireds (reciprocal megakelvins) to kelvins.
+///
+/// Many smart home ecosystems (such as Home Assistant and Hue) express color temperatures in mireds.
+/// The result is rounded to the nearest kelvin. Zero mireds would be infinitely hot, so it's converted to u16::MAX.
+pub fn mired_to_kelvin(mired: u16) -> u16 {
+    reciprocal_mega(mired)
+}
+
+/// Convert a color temperature from kelvins to mireds (reciprocal megakelvins).
+///
+/// The result is rounded to the nearest mired. Zero kelvins is converted to u16::MAX.
+pub fn kelvin_to_mired(kelvin: u16) -> u16 {
+    reciprocal_mega(kelvin)
+}
+
+/// Compute 1000000 / value, rounded to the nearest integer and saturating at u16::MAX.
+fn reciprocal_mega(value: u16) -> u16 {
+    if value == 0 {
+        return u16::MAX;
+    }
+    let value = u32::from(value);
+    let result = (2_000_000 + value) / (2 * value);
+    u16::try_from(result).unwrap_or(u16::MAX)
+}
+
+/// Divide two integers, rounding to the nearest integer (halves are rounded up).
+fn div_round(num: i32, den: i32) -> i32 {
+    (2 * num + den).div_euclid(2 * den)
+}
+
+/// Find a named CSS color by its name.
+///
+/// The lookup ignores ASCII case, so both `"RebeccaPurple"` and `"rebeccapurple"` return [REBECCAPURPLE].
+pub fn by_name(name: &str) -> Option<Rgb> {
+    NAMED
+        .iter()
+        .find(|(named, _)| named.eq_ignore_ascii_case(name))
+        .map(|&(_, color)| color)
+}
+
+impl core::error::Error for HexError {}
+
+impl From<(u8, u8, u8)> for Rgb {
+    fn from((r, g, b): (u8, u8, u8)) -> Self {
+        Self::new(r, g, b)
+    }
+}
+
+impl From<Rgb> for Hsv {
+    fn from(value: Rgb) -> Self {
+        value.to_hsv()
+    }
+}
+
+impl From<Hsv> for Rgb {
+    fn from(value: Hsv) -> Self {
+        value.to_rgb()
+    }
+}
+
+impl FromStr for Rgb {
+    type Err = HexError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::from_hex(s)
+    }
+}
+
+/// Convert an 8-bit sRGB color from the palette crate.
+#[cfg(feature = "palette")]
+impl From<palette::Srgb<u8>> for Rgb {
+    fn from(value: palette::Srgb<u8>) -> Self {
+        Self::new(value.red, value.green, value.blue)
+    }
+}
+
+/// Convert a floating-point sRGB color from the palette crate.
+#[cfg(feature = "palette")]
+impl From<palette::Srgb> for Rgb {
+    fn from(value: palette::Srgb) -> Self {
+        value.into_format::<u8>().into()
+    }
+}
+
+#[cfg(feature = "palette")]
+impl From<Rgb> for palette::Srgb<u8> {
+    fn from(value: Rgb) -> Self {
+        Self::new(value.r, value.g, value.b)
+    }
+}
+
+/// Convert an HSV color from the palette crate.
+///
+/// The saturation and value are rounded to whole percents, and the hue to whole degrees.
+#[cfg(feature = "palette")]
+impl From<palette::Hsv> for Hsv {
+    fn from(value: palette::Hsv) -> Self {
+        // The clamps make the casts lossless (apart from the rounding)
+        let hue = libm::roundf(value.hue.into_positive_degrees()).clamp(0.0, 360.0) as u16;
+        let percent = |v: f32| libm::roundf(v * 100.0).clamp(0.0, 100.0) as u8;
+        Self {
+            // 359.5 degrees and above are rounded to 360, which is the same as 0
+            hue: Hue::new_clamped(hue % 360),
+            sat: Saturation::new_clamped(percent(value.saturation)),
+            val: percent(value.value),
+        }
+    }
+}
+
+#[cfg(feature = "palette")]
+impl From<Hsv> for palette::Hsv {
+    fn from(value: Hsv) -> Self {
+        Self::new(
+            f32::from(value.hue.get()),
+            f32::from(value.sat.get()) / 100.0,
+            f32::from(value.val.min(100)) / 100.0,
+        )
+    }
+}
+
+#[cfg(feature = "rgb")]
+impl From<rgb::RGB8> for Rgb {
+    fn from(value: rgb::RGB8) -> Self {
+        Self::new(value.r, value.g, value.b)
+    }
+}
+
+#[cfg(feature = "rgb")]
+impl From<Rgb> for rgb::RGB8 {
+    fn from(value: Rgb) -> Self {
+        Self::new(value.r, value.g, value.b)
+    }
+}
+
+/// All named CSS colors, used by [by_name].
+const NAMED: [(&str, Rgb); 148] = [
+    ("aliceblue", ALICEBLUE),
+    ("antiquewhite", ANTIQUEWHITE),
+    ("aqua", AQUA),
+    ("aquamarine", AQUAMARINE),
+    ("azure", AZURE),
+    ("beige", BEIGE),
+    ("bisque", BISQUE),
+    ("black", BLACK),
+    ("blanchedalmond", BLANCHEDALMOND),
+    ("blue", BLUE),
+    ("blueviolet", BLUEVIOLET),
+    ("brown", BROWN),
+    ("burlywood", BURLYWOOD),
+    ("cadetblue", CADETBLUE),
+    ("chartreuse", CHARTREUSE),
+    ("chocolate", CHOCOLATE),
+    ("coral", CORAL),
+    ("cornflowerblue", CORNFLOWERBLUE),
+    ("cornsilk", CORNSILK),
+    ("crimson", CRIMSON),
+    ("cyan", CYAN),
+    ("darkblue", DARKBLUE),
+    ("darkcyan", DARKCYAN),
+    ("darkgoldenrod", DARKGOLDENROD),
+    ("darkgray", DARKGRAY),
+    ("darkgreen", DARKGREEN),
+    ("darkgrey", DARKGREY),
+    ("darkkhaki", DARKKHAKI),
+    ("darkmagenta", DARKMAGENTA),
+    ("darkolivegreen", DARKOLIVEGREEN),
+    ("darkorange", DARKORANGE),
+    ("darkorchid", DARKORCHID),
+    ("darkred", DARKRED),
+    ("darksalmon", DARKSALMON),
+    ("darkseagreen", DARKSEAGREEN),
+    ("darkslateblue", DARKSLATEBLUE),
+    ("darkslategray", DARKSLATEGRAY),
+    ("darkslategrey", DARKSLATEGREY),
+    ("darkturquoise", DARKTURQUOISE),
+    ("darkviolet", DARKVIOLET),
+    ("deeppink", DEEPPINK),
+    ("deepskyblue", DEEPSKYBLUE),
+    ("dimgray", DIMGRAY),
+    ("dimgrey", DIMGREY),
+    ("dodgerblue", DODGERBLUE),
+    ("firebrick", FIREBRICK),
+    ("floralwhite", FLORALWHITE),
+    ("forestgreen", FORESTGREEN),
+    ("fuchsia", FUCHSIA),
+    ("gainsboro", GAINSBORO),
+    ("ghostwhite", GHOSTWHITE),
+    ("gold", GOLD),
+    ("goldenrod", GOLDENROD),
+    ("gray", GRAY),
+    ("green", GREEN),
+    ("greenyellow", GREENYELLOW),
+    ("grey", GREY),
+    ("honeydew", HONEYDEW),
+    ("hotpink", HOTPINK),
+    ("indianred", INDIANRED),
+    ("indigo", INDIGO),
+    ("ivory", IVORY),
+    ("khaki", KHAKI),
+    ("lavender", LAVENDER),
+    ("lavenderblush", LAVENDERBLUSH),
+    ("lawngreen", LAWNGREEN),
+    ("lemonchiffon", LEMONCHIFFON),
+    ("lightblue", LIGHTBLUE),
+    ("lightcoral", LIGHTCORAL),
+    ("lightcyan", LIGHTCYAN),
+    ("lightgoldenrodyellow", LIGHTGOLDENRODYELLOW),
+    ("lightgray", LIGHTGRAY),
+    ("lightgreen", LIGHTGREEN),
+    ("lightgrey", LIGHTGREY),
+    ("lightpink", LIGHTPINK),
+    ("lightsalmon", LIGHTSALMON),
+    ("lightseagreen", LIGHTSEAGREEN),
+    ("lightskyblue", LIGHTSKYBLUE),
+    ("lightslategray", LIGHTSLATEGRAY),
+    ("lightslategrey", LIGHTSLATEGREY),
+    ("lightsteelblue", LIGHTSTEELBLUE),
+    ("lightyellow", LIGHTYELLOW),
+    ("lime", LIME),
+    ("limegreen", LIMEGREEN),
+    ("linen", LINEN),
+    ("magenta", MAGENTA),
+    ("maroon", MAROON),
+    ("mediumaquamarine", MEDIUMAQUAMARINE),
+    ("mediumblue", MEDIUMBLUE),
+    ("mediumorchid", MEDIUMORCHID),
+    ("mediumpurple", MEDIUMPURPLE),
+    ("mediumseagreen", MEDIUMSEAGREEN),
+    ("mediumslateblue", MEDIUMSLATEBLUE),
+    ("mediumspringgreen", MEDIUMSPRINGGREEN),
+    ("mediumturquoise", MEDIUMTURQUOISE),
+    ("mediumvioletred", MEDIUMVIOLETRED),
+    ("midnightblue", MIDNIGHTBLUE),
+    ("mintcream", MINTCREAM),
+    ("mistyrose", MISTYROSE),
+    ("moccasin", MOCCASIN),
+    ("navajowhite", NAVAJOWHITE),
+    ("navy", NAVY),
+    ("oldlace", OLDLACE),
+    ("olive", OLIVE),
+    ("olivedrab", OLIVEDRAB),
+    ("orange", ORANGE),
+    ("orangered", ORANGERED),
+    ("orchid", ORCHID),
+    ("palegoldenrod", PALEGOLDENROD),
+    ("palegreen", PALEGREEN),
+    ("paleturquoise", PALETURQUOISE),
+    ("palevioletred", PALEVIOLETRED),
+    ("papayawhip", PAPAYAWHIP),
+    ("peachpuff", PEACHPUFF),
+    ("peru", PERU),
+    ("pink", PINK),
+    ("plum", PLUM),
+    ("powderblue", POWDERBLUE),
+    ("purple", PURPLE),
+    ("rebeccapurple", REBECCAPURPLE),
+    ("red", RED),
+    ("rosybrown", ROSYBROWN),
+    ("royalblue", ROYALBLUE),
+    ("saddlebrown", SADDLEBROWN),
+    ("salmon", SALMON),
+    ("sandybrown", SANDYBROWN),
+    ("seagreen", SEAGREEN),
+    ("seashell", SEASHELL),
+    ("sienna", SIENNA),
+    ("silver", SILVER),
+    ("skyblue", SKYBLUE),
+    ("slateblue", SLATEBLUE),
+    ("slategray", SLATEGRAY),
+    ("slategrey", SLATEGREY),
+    ("snow", SNOW),
+    ("springgreen", SPRINGGREEN),
+    ("steelblue", STEELBLUE),
+    ("tan", TAN),
+    ("teal", TEAL),
+    ("thistle", THISTLE),
+    ("tomato", TOMATO),
+    ("turquoise", TURQUOISE),
+    ("violet", VIOLET),
+    ("wheat", WHEAT),
+    ("white", WHITE),
+    ("whitesmoke", WHITESMOKE),
+    ("yellow", YELLOW),
+    ("yellowgreen", YELLOWGREEN),
+];
+
+/// The CSS color aliceblue (#F0F8FF).
+pub const ALICEBLUE: Rgb = Rgb::new(0xF0, 0xF8, 0xFF);
+/// The CSS color antiquewhite (#FAEBD7).
+pub const ANTIQUEWHITE: Rgb = Rgb::new(0xFA, 0xEB, 0xD7);
+/// The CSS color aqua (#00FFFF).
+pub const AQUA: Rgb = Rgb::new(0x00, 0xFF, 0xFF);
+/// The CSS color aquamarine (#7FFFD4).
+pub const AQUAMARINE: Rgb = Rgb::new(0x7F, 0xFF, 0xD4);
+/// The CSS color azure (#F0FFFF).
+pub const AZURE: Rgb = Rgb::new(0xF0, 0xFF, 0xFF);
+/// The CSS color beige (#F5F5DC).
+pub const BEIGE: Rgb = Rgb::new(0xF5, 0xF5, 0xDC);
+/// The CSS color bisque (#FFE4C4).
+pub const BISQUE: Rgb = Rgb::new(0xFF, 0xE4, 0xC4);
+/// The CSS color black (#000000).
+pub const BLACK: Rgb = Rgb::new(0x00, 0x00, 0x00);
+/// The CSS color blanchedalmond (#FFEBCD).
+pub const BLANCHEDALMOND: Rgb = Rgb::new(0xFF, 0xEB, 0xCD);
+/// The CSS color blue (#0000FF).
+pub const BLUE: Rgb = Rgb::new(0x00, 0x00, 0xFF);
+/// The CSS color blueviolet (#8A2BE2).
+pub const BLUEVIOLET: Rgb = Rgb::new(0x8A, 0x2B, 0xE2);
+/// The CSS color brown (#A52A2A).
+pub const BROWN: Rgb = Rgb::new(0xA5, 0x2A, 0x2A);
+/// The CSS color burlywood (#DEB887).
+pub const BURLYWOOD: Rgb = Rgb::new(0xDE, 0xB8, 0x87);
+/// The CSS color cadetblue (#5F9EA0).
+pub const CADETBLUE: Rgb = Rgb::new(0x5F, 0x9E, 0xA0);
+/// The CSS color chartreuse (#7FFF00).
+pub const CHARTREUSE: Rgb = Rgb::new(0x7F, 0xFF, 0x00);
+/// The CSS color chocolate (#D2691E).
+pub const CHOCOLATE: Rgb = Rgb::new(0xD2, 0x69, 0x1E);
+/// The CSS color coral (#FF7F50).
+pub const CORAL: Rgb = Rgb::new(0xFF, 0x7F, 0x50);
+/// The CSS color cornflowerblue (#6495ED).
+pub const CORNFLOWERBLUE: Rgb = Rgb::new(0x64, 0x95, 0xED);
+/// The CSS color cornsilk (#FFF8DC).
+pub const CORNSILK: Rgb = Rgb::new(0xFF, 0xF8, 0xDC);
+/// The CSS color crimson (#DC143C).
+pub const CRIMSON: Rgb = Rgb::new(0xDC, 0x14, 0x3C);
+/// The CSS color cyan (#00FFFF).
+pub const CYAN: Rgb = Rgb::new(0x00, 0xFF, 0xFF);
+/// The CSS color darkblue (#00008B).
+pub const DARKBLUE: Rgb = Rgb::new(0x00, 0x00, 0x8B);
+/// The CSS color darkcyan (#008B8B).
+pub const DARKCYAN: Rgb = Rgb::new(0x00, 0x8B, 0x8B);
+/// The CSS color darkgoldenrod (#B8860B).
+pub const DARKGOLDENROD: Rgb = Rgb::new(0xB8, 0x86, 0x0B);
+/// The CSS color darkgray (#A9A9A9).
+pub const DARKGRAY: Rgb = Rgb::new(0xA9, 0xA9, 0xA9);
+/// The CSS color darkgreen (#006400).
+pub const DARKGREEN: Rgb = Rgb::new(0x00, 0x64, 0x00);
+/// The CSS color darkgrey (#A9A9A9).
+pub const DARKGREY: Rgb = Rgb::new(0xA9, 0xA9, 0xA9);
+/// The CSS color darkkhaki (#BDB76B).
+pub const DARKKHAKI: Rgb = Rgb::new(0xBD, 0xB7, 0x6B);
+/// The CSS color darkmagenta (#8B008B).
+pub const DARKMAGENTA: Rgb = Rgb::new(0x8B, 0x00, 0x8B);
+/// The CSS color darkolivegreen (#556B2F).
+pub const DARKOLIVEGREEN: Rgb = Rgb::new(0x55, 0x6B, 0x2F);
+/// The CSS color darkorange (#FF8C00).
+pub const DARKORANGE: Rgb = Rgb::new(0xFF, 0x8C, 0x00);
+/// The CSS color darkorchid (#9932CC).
+pub const DARKORCHID: Rgb = Rgb::new(0x99, 0x32, 0xCC);
+/// The CSS color darkred (#8B0000).
+pub const DARKRED: Rgb = Rgb::new(0x8B, 0x00, 0x00);
+/// The CSS color darksalmon (#E9967A).
+pub const DARKSALMON: Rgb = Rgb::new(0xE9, 0x96, 0x7A);
+/// The CSS color darkseagreen (#8FBC8F).
+pub const DARKSEAGREEN: Rgb = Rgb::new(0x8F, 0xBC, 0x8F);
+/// The CSS color darkslateblue (#483D8B).
+pub const DARKSLATEBLUE: Rgb = Rgb::new(0x48, 0x3D, 0x8B);
+/// The CSS color darkslategray (#2F4F4F).
+pub const DARKSLATEGRAY: Rgb = Rgb::new(0x2F, 0x4F, 0x4F);
+/// The CSS color darkslategrey (#2F4F4F).
+pub const DARKSLATEGREY: Rgb = Rgb::new(0x2F, 0x4F, 0x4F);
+/// The CSS color darkturquoise (#00CED1).
+pub const DARKTURQUOISE: Rgb = Rgb::new(0x00, 0xCE, 0xD1);
+/// The CSS color darkviolet (#9400D3).
+pub const DARKVIOLET: Rgb = Rgb::new(0x94, 0x00, 0xD3);
+/// The CSS color deeppink (#FF1493).
+pub const DEEPPINK: Rgb = Rgb::new(0xFF, 0x14, 0x93);
+/// The CSS color deepskyblue (#00BFFF).
+pub const DEEPSKYBLUE: Rgb = Rgb::new(0x00, 0xBF, 0xFF);
+/// The CSS color dimgray (#696969).
+pub const DIMGRAY: Rgb = Rgb::new(0x69, 0x69, 0x69);
+/// The CSS color dimgrey (#696969).
+pub const DIMGREY: Rgb = Rgb::new(0x69, 0x69, 0x69);
+/// The CSS color dodgerblue (#1E90FF).
+pub const DODGERBLUE: Rgb = Rgb::new(0x1E, 0x90, 0xFF);
+/// The CSS color firebrick (#B22222).
+pub const FIREBRICK: Rgb = Rgb::new(0xB2, 0x22, 0x22);
+/// The CSS color floralwhite (#FFFAF0).
+pub const FLORALWHITE: Rgb = Rgb::new(0xFF, 0xFA, 0xF0);
+/// The CSS color forestgreen (#228B22).
+pub const FORESTGREEN: Rgb = Rgb::new(0x22, 0x8B, 0x22);
+/// The CSS color fuchsia (#FF00FF).
+pub const FUCHSIA: Rgb = Rgb::new(0xFF, 0x00, 0xFF);
+/// The CSS color gainsboro (#DCDCDC).
+pub const GAINSBORO: Rgb = Rgb::new(0xDC, 0xDC, 0xDC);
+/// The CSS color ghostwhite (#F8F8FF).
+pub const GHOSTWHITE: Rgb = Rgb::new(0xF8, 0xF8, 0xFF);
+/// The CSS color gold (#FFD700).
+pub const GOLD: Rgb = Rgb::new(0xFF, 0xD7, 0x00);
+/// The CSS color goldenrod (#DAA520).
+pub const GOLDENROD: Rgb = Rgb::new(0xDA, 0xA5, 0x20);
+/// The CSS color gray (#808080).
+pub const GRAY: Rgb = Rgb::new(0x80, 0x80, 0x80);
+/// The CSS color green (#008000).
+pub const GREEN: Rgb = Rgb::new(0x00, 0x80, 0x00);
+/// The CSS color greenyellow (#ADFF2F).
+pub const GREENYELLOW: Rgb = Rgb::new(0xAD, 0xFF, 0x2F);
+/// The CSS color grey (#808080).
+pub const GREY: Rgb = Rgb::new(0x80, 0x80, 0x80);
+/// The CSS color honeydew (#F0FFF0).
+pub const HONEYDEW: Rgb = Rgb::new(0xF0, 0xFF, 0xF0);
+/// The CSS color hotpink (#FF69B4).
+pub const HOTPINK: Rgb = Rgb::new(0xFF, 0x69, 0xB4);
+/// The CSS color indianred (#CD5C5C).
+pub const INDIANRED: Rgb = Rgb::new(0xCD, 0x5C, 0x5C);
+/// The CSS color indigo (#4B0082).
+pub const INDIGO: Rgb = Rgb::new(0x4B, 0x00, 0x82);
+/// The CSS color ivory (#FFFFF0).
+pub const IVORY: Rgb = Rgb::new(0xFF, 0xFF, 0xF0);
+/// The CSS color khaki (#F0E68C).
+pub const KHAKI: Rgb = Rgb::new(0xF0, 0xE6, 0x8C);
+/// The CSS color lavender (#E6E6FA).
+pub const LAVENDER: Rgb = Rgb::new(0xE6, 0xE6, 0xFA);
+/// The CSS color lavenderblush (#FFF0F5).
+pub const LAVENDERBLUSH: Rgb = Rgb::new(0xFF, 0xF0, 0xF5);
+/// The CSS color lawngreen (#7CFC00).
+pub const LAWNGREEN: Rgb = Rgb::new(0x7C, 0xFC, 0x00);
+/// The CSS color lemonchiffon (#FFFACD).
+pub const LEMONCHIFFON: Rgb = Rgb::new(0xFF, 0xFA, 0xCD);
+/// The CSS color lightblue (#ADD8E6).
+pub const LIGHTBLUE: Rgb = Rgb::new(0xAD, 0xD8, 0xE6);
+/// The CSS color lightcoral (#F08080).
+pub const LIGHTCORAL: Rgb = Rgb::new(0xF0, 0x80, 0x80);
+/// The CSS color lightcyan (#E0FFFF).
+pub const LIGHTCYAN: Rgb = Rgb::new(0xE0, 0xFF, 0xFF);
+/// The CSS color lightgoldenrodyellow (#FAFAD2).
+pub const LIGHTGOLDENRODYELLOW: Rgb = Rgb::new(0xFA, 0xFA, 0xD2);
+/// The CSS color lightgray (#D3D3D3).
+pub const LIGHTGRAY: Rgb = Rgb::new(0xD3, 0xD3, 0xD3);
+/// The CSS color lightgreen (#90EE90).
+pub const LIGHTGREEN: Rgb = Rgb::new(0x90, 0xEE, 0x90);
+/// The CSS color lightgrey (#D3D3D3).
+pub const LIGHTGREY: Rgb = Rgb::new(0xD3, 0xD3, 0xD3);
+/// The CSS color lightpink (#FFB6C1).
+pub const LIGHTPINK: Rgb = Rgb::new(0xFF, 0xB6, 0xC1);
+/// The CSS color lightsalmon (#FFA07A).
+pub const LIGHTSALMON: Rgb = Rgb::new(0xFF, 0xA0, 0x7A);
+/// The CSS color lightseagreen (#20B2AA).
+pub const LIGHTSEAGREEN: Rgb = Rgb::new(0x20, 0xB2, 0xAA);
+/// The CSS color lightskyblue (#87CEFA).
+pub const LIGHTSKYBLUE: Rgb = Rgb::new(0x87, 0xCE, 0xFA);
+/// The CSS color lightslategray (#778899).
+pub const LIGHTSLATEGRAY: Rgb = Rgb::new(0x77, 0x88, 0x99);
+/// The CSS color lightslategrey (#778899).
+pub const LIGHTSLATEGREY: Rgb = Rgb::new(0x77, 0x88, 0x99);
+/// The CSS color lightsteelblue (#B0C4DE).
+pub const LIGHTSTEELBLUE: Rgb = Rgb::new(0xB0, 0xC4, 0xDE);
+/// The CSS color lightyellow (#FFFFE0).
+pub const LIGHTYELLOW: Rgb = Rgb::new(0xFF, 0xFF, 0xE0);
+/// The CSS color lime (#00FF00).
+pub const LIME: Rgb = Rgb::new(0x00, 0xFF, 0x00);
+/// The CSS color limegreen (#32CD32).
+pub const LIMEGREEN: Rgb = Rgb::new(0x32, 0xCD, 0x32);
+/// The CSS color linen (#FAF0E6).
+pub const LINEN: Rgb = Rgb::new(0xFA, 0xF0, 0xE6);
+/// The CSS color magenta (#FF00FF).
+pub const MAGENTA: Rgb = Rgb::new(0xFF, 0x00, 0xFF);
+/// The CSS color maroon (#800000).
+pub const MAROON: Rgb = Rgb::new(0x80, 0x00, 0x00);
+/// The CSS color mediumaquamarine (#66CDAA).
+pub const MEDIUMAQUAMARINE: Rgb = Rgb::new(0x66, 0xCD, 0xAA);
+/// The CSS color mediumblue (#0000CD).
+pub const MEDIUMBLUE: Rgb = Rgb::new(0x00, 0x00, 0xCD);
+/// The CSS color mediumorchid (#BA55D3).
+pub const MEDIUMORCHID: Rgb = Rgb::new(0xBA, 0x55, 0xD3);
+/// The CSS color mediumpurple (#9370DB).
+pub const MEDIUMPURPLE: Rgb = Rgb::new(0x93, 0x70, 0xDB);
+/// The CSS color mediumseagreen (#3CB371).
+pub const MEDIUMSEAGREEN: Rgb = Rgb::new(0x3C, 0xB3, 0x71);
+/// The CSS color mediumslateblue (#7B68EE).
+pub const MEDIUMSLATEBLUE: Rgb = Rgb::new(0x7B, 0x68, 0xEE);
+/// The CSS color mediumspringgreen (#00FA9A).
+pub const MEDIUMSPRINGGREEN: Rgb = Rgb::new(0x00, 0xFA, 0x9A);
+/// The CSS color mediumturquoise (#48D1CC).
+pub const MEDIUMTURQUOISE: Rgb = Rgb::new(0x48, 0xD1, 0xCC);
+/// The CSS color mediumvioletred (#C71585).
+pub const MEDIUMVIOLETRED: Rgb = Rgb::new(0xC7, 0x15, 0x85);
+/// The CSS color midnightblue (#191970).
+pub const MIDNIGHTBLUE: Rgb = Rgb::new(0x19, 0x19, 0x70);
+/// The CSS color mintcream (#F5FFFA).
+pub const MINTCREAM: Rgb = Rgb::new(0xF5, 0xFF, 0xFA);
+/// The CSS color mistyrose (#FFE4E1).
+pub const MISTYROSE: Rgb = Rgb::new(0xFF, 0xE4, 0xE1);
+/// The CSS color moccasin (#FFE4B5).
+pub const MOCCASIN: Rgb = Rgb::new(0xFF, 0xE4, 0xB5);
+/// The CSS color navajowhite (#FFDEAD).
+pub const NAVAJOWHITE: Rgb = Rgb::new(0xFF, 0xDE, 0xAD);
+/// The CSS color navy (#000080).
+pub const NAVY: Rgb = Rgb::new(0x00, 0x00, 0x80);
+/// The CSS color oldlace (#FDF5E6).
+pub const OLDLACE: Rgb = Rgb::new(0xFD, 0xF5, 0xE6);
+/// The CSS color olive (#808000).
+pub const OLIVE: Rgb = Rgb::new(0x80, 0x80, 0x00);
+/// The CSS color olivedrab (#6B8E23).
+pub const OLIVEDRAB: Rgb = Rgb::new(0x6B, 0x8E, 0x23);
+/// The CSS color orange (#FFA500).
+pub const ORANGE: Rgb = Rgb::new(0xFF, 0xA5, 0x00);
+/// The CSS color orangered (#FF4500).
+pub const ORANGERED: Rgb = Rgb::new(0xFF, 0x45, 0x00);
+/// The CSS color orchid (#DA70D6).
+pub const ORCHID: Rgb = Rgb::new(0xDA, 0x70, 0xD6);
+/// The CSS color palegoldenrod (#EEE8AA).
+pub const PALEGOLDENROD: Rgb = Rgb::new(0xEE, 0xE8, 0xAA);
+/// The CSS color palegreen (#98FB98).
+pub const PALEGREEN: Rgb = Rgb::new(0x98, 0xFB, 0x98);
+/// The CSS color paleturquoise (#AFEEEE).
+pub const PALETURQUOISE: Rgb = Rgb::new(0xAF, 0xEE, 0xEE);
+/// The CSS color palevioletred (#DB7093).
+pub const PALEVIOLETRED: Rgb = Rgb::new(0xDB, 0x70, 0x93);
+/// The CSS color papayawhip (#FFEFD5).
+pub const PAPAYAWHIP: Rgb = Rgb::new(0xFF, 0xEF, 0xD5);
+/// The CSS color peachpuff (#FFDAB9).
+pub const PEACHPUFF: Rgb = Rgb::new(0xFF, 0xDA, 0xB9);
+/// The CSS color peru (#CD853F).
+pub const PERU: Rgb = Rgb::new(0xCD, 0x85, 0x3F);
+/// The CSS color pink (#FFC0CB).
+pub const PINK: Rgb = Rgb::new(0xFF, 0xC0, 0xCB);
+/// The CSS color plum (#DDA0DD).
+pub const PLUM: Rgb = Rgb::new(0xDD, 0xA0, 0xDD);
+/// The CSS color powderblue (#B0E0E6).
+pub const POWDERBLUE: Rgb = Rgb::new(0xB0, 0xE0, 0xE6);
+/// The CSS color purple (#800080).
+pub const PURPLE: Rgb = Rgb::new(0x80, 0x00, 0x80);
+/// The CSS color rebeccapurple (#663399).
+pub const REBECCAPURPLE: Rgb = Rgb::new(0x66, 0x33, 0x99);
+/// The CSS color red (#FF0000).
+pub const RED: Rgb = Rgb::new(0xFF, 0x00, 0x00);
+/// The CSS color rosybrown (#BC8F8F).
+pub const ROSYBROWN: Rgb = Rgb::new(0xBC, 0x8F, 0x8F);
+/// The CSS color royalblue (#4169E1).
+pub const ROYALBLUE: Rgb = Rgb::new(0x41, 0x69, 0xE1);
+/// The CSS color saddlebrown (#8B4513).
+pub const SADDLEBROWN: Rgb = Rgb::new(0x8B, 0x45, 0x13);
+/// The CSS color salmon (#FA8072).
+pub const SALMON: Rgb = Rgb::new(0xFA, 0x80, 0x72);
+/// The CSS color sandybrown (#F4A460).
+pub const SANDYBROWN: Rgb = Rgb::new(0xF4, 0xA4, 0x60);
+/// The CSS color seagreen (#2E8B57).
+pub const SEAGREEN: Rgb = Rgb::new(0x2E, 0x8B, 0x57);
+/// The CSS color seashell (#FFF5EE).
+pub const SEASHELL: Rgb = Rgb::new(0xFF, 0xF5, 0xEE);
+/// The CSS color sienna (#A0522D).
+pub const SIENNA: Rgb = Rgb::new(0xA0, 0x52, 0x2D);
+/// The CSS color silver (#C0C0C0).
+pub const SILVER: Rgb = Rgb::new(0xC0, 0xC0, 0xC0);
+/// The CSS color skyblue (#87CEEB).
+pub const SKYBLUE: Rgb = Rgb::new(0x87, 0xCE, 0xEB);
+/// The CSS color slateblue (#6A5ACD).
+pub const SLATEBLUE: Rgb = Rgb::new(0x6A, 0x5A, 0xCD);
+/// The CSS color slategray (#708090).
+pub const SLATEGRAY: Rgb = Rgb::new(0x70, 0x80, 0x90);
+/// The CSS color slategrey (#708090).
+pub const SLATEGREY: Rgb = Rgb::new(0x70, 0x80, 0x90);
+/// The CSS color snow (#FFFAFA).
+pub const SNOW: Rgb = Rgb::new(0xFF, 0xFA, 0xFA);
+/// The CSS color springgreen (#00FF7F).
+pub const SPRINGGREEN: Rgb = Rgb::new(0x00, 0xFF, 0x7F);
+/// The CSS color steelblue (#4682B4).
+pub const STEELBLUE: Rgb = Rgb::new(0x46, 0x82, 0xB4);
+/// The CSS color tan (#D2B48C).
+pub const TAN: Rgb = Rgb::new(0xD2, 0xB4, 0x8C);
+/// The CSS color teal (#008080).
+pub const TEAL: Rgb = Rgb::new(0x00, 0x80, 0x80);
+/// The CSS color thistle (#D8BFD8).
+pub const THISTLE: Rgb = Rgb::new(0xD8, 0xBF, 0xD8);
+/// The CSS color tomato (#FF6347).
+pub const TOMATO: Rgb = Rgb::new(0xFF, 0x63, 0x47);
+/// The CSS color turquoise (#40E0D0).
+pub const TURQUOISE: Rgb = Rgb::new(0x40, 0xE0, 0xD0);
+/// The CSS color violet (#EE82EE).
+pub const VIOLET: Rgb = Rgb::new(0xEE, 0x82, 0xEE);
+/// The CSS color wheat (#F5DEB3).
+pub const WHEAT: Rgb = Rgb::new(0xF5, 0xDE, 0xB3);
+/// The CSS color white (#FFFFFF).
+pub const WHITE: Rgb = Rgb::new(0xFF, 0xFF, 0xFF);
+/// The CSS color whitesmoke (#F5F5F5).
+pub const WHITESMOKE: Rgb = Rgb::new(0xF5, 0xF5, 0xF5);
+/// The CSS color yellow (#FFFF00).
+pub const YELLOW: Rgb = Rgb::new(0xFF, 0xFF, 0x00);
+/// The CSS color yellowgreen (#9ACD32).
+pub const YELLOWGREEN: Rgb = Rgb::new(0x9A, 0xCD, 0x32);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::string::ToString;
+
+    #[test]
+    fn lookup() {
+        assert_eq!(by_name("rebeccapurple"), Some(REBECCAPURPLE));
+        assert_eq!(by_name("RebeccaPurple"), Some(Rgb::new(0x66, 0x33, 0x99)));
+        assert_eq!(by_name("grey"), by_name("gray"));
+        assert_eq!(by_name("notacolor"), None);
+    }
+
+    #[test]
+    fn parse_hex() {
+        assert_eq!(Rgb::from_hex("#663399"), Ok(REBECCAPURPLE));
+        assert_eq!(Rgb::from_hex("663399"), Ok(REBECCAPURPLE));
+        assert_eq!(Rgb::from_hex("#FF8800"), Ok(Rgb::new(0xff, 0x88, 0x00)));
+        assert_eq!(Rgb::from_hex("#f80"), Ok(Rgb::new(0xff, 0x88, 0x00)));
+        assert_eq!("fff".parse(), Ok(WHITE));
+    }
+
+    #[test]
+    fn parse_hex_invalid() {
+        assert_eq!(Rgb::from_hex("#12345"), Err(HexError::InvalidLength(5)));
+        assert_eq!(Rgb::from_hex(""), Err(HexError::InvalidLength(0)));
+        assert_eq!(Rgb::from_hex("##123"), Err(HexError::InvalidLength(4)));
+        assert_eq!(Rgb::from_hex("#12345g"), Err(HexError::InvalidDigit('g')));
+        assert_eq!(Rgb::from_hex("#ä12"), Err(HexError::InvalidDigit('ä')));
+    }
+
+    fn hsv(hue: u16, sat: u8, val: u8) -> Hsv {
+        Hsv {
+            hue: Hue::new(hue).unwrap(),
+            sat: Saturation::new(sat).unwrap(),
+            val,
+        }
+    }
+
+    #[test]
+    fn rgb_to_hsv() {
+        assert_eq!(RED.to_hsv(), hsv(0, 100, 100));
+        assert_eq!(LIME.to_hsv(), hsv(120, 100, 100));
+        assert_eq!(BLUE.to_hsv(), hsv(240, 100, 100));
+        assert_eq!(YELLOW.to_hsv(), hsv(60, 100, 100));
+        assert_eq!(FUCHSIA.to_hsv(), hsv(300, 100, 100));
+        assert_eq!(WHITE.to_hsv(), hsv(0, 0, 100));
+        assert_eq!(BLACK.to_hsv(), hsv(0, 0, 0));
+        assert_eq!(GRAY.to_hsv(), hsv(0, 0, 50));
+        assert_eq!(REBECCAPURPLE.to_hsv(), hsv(270, 67, 60));
+        assert_eq!(ORANGE.to_hsv(), hsv(39, 100, 100));
+    }
+
+    #[test]
+    fn hsv_to_rgb() {
+        assert_eq!(hsv(0, 100, 100).to_rgb(), RED);
+        assert_eq!(hsv(120, 100, 100).to_rgb(), LIME);
+        assert_eq!(hsv(180, 100, 100).to_rgb(), AQUA);
+        assert_eq!(hsv(240, 100, 100).to_rgb(), BLUE);
+        assert_eq!(hsv(300, 100, 100).to_rgb(), FUCHSIA);
+        assert_eq!(hsv(0, 0, 100).to_rgb(), WHITE);
+        assert_eq!(hsv(0, 0, 0).to_rgb(), BLACK);
+        assert_eq!(hsv(210, 50, 80).to_rgb(), Rgb::new(102, 153, 204));
+        // Values above 100 are treated as 100
+        assert_eq!(hsv(0, 100, 200).to_rgb(), RED);
+    }
+
+    #[test]
+    fn hsv_roundtrip() {
+        for color in [RED, LIME, BLUE, YELLOW, AQUA, FUCHSIA, WHITE, BLACK] {
+            assert_eq!(Rgb::from(Hsv::from(color)), color);
+        }
+    }
+
+    #[test]
+    fn kelvin_approximation() {
+        assert_eq!(kelvin_to_rgb(1000), Rgb::new(255, 68, 0));
+        assert_eq!(kelvin_to_rgb(1700), Rgb::new(255, 121, 0));
+        assert_eq!(kelvin_to_rgb(2700), Rgb::new(255, 167, 87));
+        assert_eq!(kelvin_to_rgb(6600), WHITE);
+        assert_eq!(kelvin_to_rgb(10000), Rgb::new(202, 218, 255));
+    }
+
+    #[cfg(feature = "palette")]
+    #[test]
+    fn palette_interop() {
+        let srgb = palette::Srgb::new(0x66u8, 0x33, 0x99);
+        assert_eq!(Rgb::from(srgb), REBECCAPURPLE);
+        assert_eq!(palette::Srgb::<u8>::from(REBECCAPURPLE), srgb);
+        assert_eq!(Rgb::from(palette::Srgb::new(1.0, 0.0, 0.0)), RED);
+        let phsv = palette::Hsv::new(270.0, 0.67, 0.6);
+        assert_eq!(Hsv::from(phsv), hsv(270, 67, 60));
+        assert_eq!(
+            Hsv::from(palette::Hsv::new(-90.0, 1.0, 1.0)),
+            hsv(270, 100, 100)
+        );
+        let back = palette::Hsv::from(hsv(270, 67, 60));
+        assert_eq!(Hsv::from(back), hsv(270, 67, 60));
+    }
+
+    #[cfg(feature = "rgb")]
+    #[test]
+    fn rgb_interop() {
+        let rgb8 = rgb::RGB8::new(0x66, 0x33, 0x99);
+        assert_eq!(Rgb::from(rgb8), REBECCAPURPLE);
+        assert_eq!(rgb::RGB8::from(REBECCAPURPLE), rgb8);
+    }
+
+    #[test]
+    fn mireds() {
+        assert_eq!(mired_to_kelvin(153), 6536);
+        assert_eq!(mired_to_kelvin(500), 2000);
+        assert_eq!(kelvin_to_mired(6500), 154);
+        assert_eq!(kelvin_to_mired(2700), 370);
+        assert_eq!(kelvin_to_mired(1700), 588);
+        assert_eq!(mired_to_kelvin(0), u16::MAX);
+        assert_eq!(mired_to_kelvin(1), u16::MAX);
+        assert_eq!(kelvin_to_mired(0), u16::MAX);
+    }
+
+    #[test]
+    fn display_and_int() {
+        assert_eq!(REBECCAPURPLE.to_string(), "#663399");
+        assert_eq!(Rgb::from_int(0xFF663399), REBECCAPURPLE);
+        assert_eq!(Rgb::from_int(REBECCAPURPLE.to_int()), REBECCAPURPLE);
+        assert_eq!(REBECCAPURPLE.to_int(), 0x663399u32);
+    }
+}
@@ -0,0 +1,2329 @@
+use derive_more::Display;
+use log::debug;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use socket2::{Domain, Protocol, Socket, Type};
+
+use std::collections::BTreeMap;
+use std::io::{Error, ErrorKind, Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, TcpStream, UdpSocket};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, mpsc};
+use std::thread::Thread;
+use std::time::{Duration, Instant, SystemTime};
+use std::{
+    borrow::ToOwned,
+    string::{String, ToString},
+    vec::Vec,
+};
+
+use crate::lamp::{ConnectError, LAMP_PORT, Lamp, Resolver};
+use crate::model::{Capabilities, Feature, Model};
+use crate::state::LampState;
+
+/*
+ * Please follow this order:
+ * - structs/enums, ordered s.t. dependencies are above dependents
+ * - impls (e.g. impl Discovery)
+ * - impl _ for _ (like Display, From<T>,...)
+ */
+
+/// The multicast address lamps listen on for discovery probes.
+pub const MULTICAST_ADDR: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(239, 255, 255, 250), 1982);
+
+/// The link-local IPv6 multicast group lamps are probed at, see [`Discovery::ipv6`].
+pub const MULTICAST_ADDR_V6: SocketAddrV6 =
+    SocketAddrV6::new(Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0xc), 1982, 0, 0);
+
+/// The probe asking all lamps on the network to reply with their address and properties.
+pub const SEARCH_REQUEST: &str = "M-SEARCH * HTTP/1.1\r\nHOST: 239.255.255.250:1982\r\nMAN: \"ssdp:discover\"\r\nST: wifi_bulb\r\n";
+
+/// A lamp that replied to a discovery probe or advertised itself, see [`Discovery::search`].
+///
+/// Discovery replies carry the state of the lamp as well, so it's known without querying the lamp.
+/// With serde, the state is stored as the advertised properties (see [LampState::to_props]).
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "StoredInfo", into = "StoredInfo")]
+pub struct LampInfo {
+    /// The address to connect to, from the `Location` header.
+    pub location: SocketAddr,
+    /// The unique id of the lamp, such as `0x000000000015243f`.
+    pub id: String,
+    /// The model of the lamp, such as a color bulb or a light strip (`model`).
+    pub model: Option<Model>,
+    /// The firmware version (`fw_ver`).
+    pub fw_ver: Option<u32>,
+    /// The methods the lamp supports, such as `set_ct_abx` (`support`).
+    pub support: Vec<String>,
+    /// The advertised state: `power`, `bright`, `color_mode`, `ct`, `rgb`, `hue`, `sat` and `name`.
+    pub state: LampState,
+}
+
+/// The reason a discovery reply could not be parsed into a [LampInfo].
+#[derive(Clone, Debug, Display, PartialEq, Eq)]
+pub enum ParseInfoError {
+    /// The message is neither a reply to a probe nor an advertisement.
+    #[display("not a discovery reply: {_0}")]
+    NotAReply(String),
+    /// A required header is missing.
+    #[display("missing header {_0}")]
+    MissingHeader(&'static str),
+    /// A header has an invalid value, such as a `Location` that isn't a `yeelight://` address.
+    #[display("invalid value {value:?} of header {header}")]
+    InvalidHeader {
+        /// The name of the header.
+        header: &'static str,
+        /// The invalid value.
+        value: String,
+    },
+}
+
+/// A condition the lamps found by a [Discovery] must meet, see [Discovery::filter].
+#[derive(Clone, derive_more::Debug)]
+pub enum LampFilter {
+    /// The lamp is of a model, such as [Model::Color].
+    Model(Model),
+    /// The lamp has a feature, according to its model and advertised methods (see [LampInfo::capabilities]).
+    ///
+    /// Lamps of unknown models don't pass.
+    Feature(Feature),
+    /// The name of the lamp (set with `set_name`) starts with a prefix, such as `living room`.
+    NamePrefix(String),
+    /// The lamp passes a check.
+    Predicate(#[debug(skip)] Arc<dyn Fn(&LampInfo) -> bool + Send + Sync>),
+}
+
+/// A search for the lamps on the local network, e.g. for connecting to them without knowing their addresses.
+///
+/// The search multicasts a probe, which every lamp with LAN Control enabled replies to,
+/// and collects the replies for a while.
+/// ```no_run
+/// # use std::time::Duration;
+/// # use yeerugina_lib::discovery::Discovery;
+/// # fn main() -> std::io::Result<()> {
+/// for lamp in Discovery::new().window(Duration::from_secs(2)).search()? {
+///     println!("{} at {}", lamp.id, lamp.location);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct Discovery {
+    /// How long replies are collected.
+    window: Duration,
+    /// Where the probe is sent, the multicast group of the lamps by default.
+    targets: Vec<SocketAddr>,
+    /// The IPv4 subnets whose hosts the probe is sent to, as network address and prefix length.
+    subnets: Vec<(Ipv4Addr, u8)>,
+    /// The local addresses of the interfaces the probe is sent from, or none for the interface of the default route.
+    interfaces: Vec<Ipv4Addr>,
+    /// The conditions the lamps must meet.
+    filters: Vec<LampFilter>,
+}
+
+/// The lamps replying to a probe, yielded as soon as they reply, see [`Discovery::stream`].
+///
+/// This is a blocking iterator, which ends once the window of the [Discovery] elapsed or if receiving fails.
+#[derive(Debug)]
+pub struct DiscoveryStream {
+    socket: UdpSocket,
+    /// When the window elapses.
+    deadline: Instant,
+    /// The ids of the lamps yielded already, lowercased as lamps are told apart ignoring the case of their id.
+    seen: Vec<String>,
+    /// The conditions the lamps must meet, see [Discovery::filter].
+    filters: Vec<LampFilter>,
+}
+
+/// A listener for the advertisements lamps multicast periodically (and when they join the network), see [`Advertisements::listen`].
+///
+/// This is a blocking iterator over the advertising lamps, which ends if receiving fails.
+/// Unlike [`Discovery::search`], nothing is sent, so long-running programs notice new lamps without probing again.
+/// Lamps advertise themselves about once an hour, so each lamp is usually yielded many times.
+/// ```no_run
+/// # use yeerugina_lib::discovery::Advertisements;
+/// # fn main() -> std::io::Result<()> {
+/// for lamp in Advertisements::listen()? {
+///     println!("{} is at {}", lamp.id, lamp.location);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct Advertisements {
+    socket: UdpSocket,
+}
+
+/// A [`Resolver`] finding lamps by probing the network, the default resolver of a [`Lamp`](crate::lamp::Lamp).
+///
+/// All lamps reply to the probe, so the address of the one with the device id is looked up in the replies.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct SsdpResolver {
+    /// How long to wait for the lamp to reply to the probe.
+    pub timeout: Duration,
+}
+
+/// A lamp in a [DiscoveryRegistry], with when it was last seen.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KnownLamp {
+    /// The last reply or advertisement of the lamp, with its current address and state.
+    pub info: LampInfo,
+    /// When the lamp last replied or advertised itself (or was registered).
+    pub last_seen: Instant,
+    /// Whether the lamp was registered manually (see [`DiscoveryRegistry::register`]), so it never expires.
+    pub registered: bool,
+}
+
+/// A change of a [DiscoveryRegistry], see [DiscoveryRegistry::events].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DiscoveryEvent {
+    /// A lamp was seen for the first time (or again after it expired).
+    Added(LampInfo),
+    /// A known lamp advertised a different address or state.
+    Updated(LampInfo),
+    /// A lamp wasn't seen for the expiry and was removed, with its last known info.
+    Expired(LampInfo),
+    /// A registered lamp was removed with [`DiscoveryRegistry::unregister`], with its last known info.
+    Unregistered(LampInfo),
+}
+
+/// The lamps seen by a [DiscoveryDaemon], keyed by id, which can be shared with other threads.
+///
+/// The registry is a [Resolver], so Lamps can follow lamps changing their address without probing the network
+/// (see [`Lamp::set_resolver`]).
+#[derive(Clone, Debug, Default)]
+pub struct DiscoveryRegistry {
+    lamps: Arc<Mutex<BTreeMap<String, KnownLamp>>>,
+    /// The channels receiving the changes of the registry.
+    subscribers: Arc<Mutex<Vec<mpsc::Sender<DiscoveryEvent>>>>,
+}
+
+/// A scan of an IPv4 subnet for hosts accepting connections on the port of the lamps, see [`SubnetScan::scan`].
+///
+/// This is a fallback for networks where multicast doesn't work at all (such as many mesh Wi-Fi setups),
+/// so that the probes of a [Discovery] never reach the lamps. Unlike a search, the scan only finds addresses,
+/// not ids or states, and other devices listening on the port are found as well.
+/// ```no_run
+/// # use std::net::Ipv4Addr;
+/// # use yeerugina_lib::{discovery::SubnetScan, lamp::Lamp};
+/// # fn main() -> std::io::Result<()> {
+/// for addr in SubnetScan::new(Ipv4Addr::new(192, 168, 1, 0), 24).scan()? {
+///     let _lamp = Lamp::connect(addr)?;
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct SubnetScan {
+    network: Ipv4Addr,
+    prefix_len: u8,
+    port: u16,
+    /// How long to wait for each host.
+    timeout: Duration,
+    /// How many hosts are connected to at once.
+    concurrency: usize,
+}
+
+/// The options of a [DiscoveryDaemon], see [`DiscoveryDaemon::builder`].
+#[derive(Clone, Debug)]
+pub struct DaemonBuilder {
+    discovery: Discovery,
+    interval: Duration,
+    expiry: Duration,
+    advertisements: bool,
+}
+
+/// A background thread keeping a [DiscoveryRegistry] of the lamps on the network up to date.
+///
+/// The daemon probes the network periodically and listens for advertisements in between.
+/// Lamps that weren't seen for a while are removed. The thread is stopped when the daemon is dropped.
+/// ```no_run
+/// # use yeerugina_lib::discovery::{DiscoveryDaemon, DiscoveryEvent};
+/// # fn main() -> std::io::Result<()> {
+/// let daemon = DiscoveryDaemon::spawn()?;
+/// for event in daemon.registry().events() {
+///     if let DiscoveryEvent::Added(lamp) = event {
+///         println!("{} appeared at {}", lamp.id, lamp.location);
+///     }
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct DiscoveryDaemon {
+    registry: DiscoveryRegistry,
+    /// Set to stop the thread.
+    stop: Arc<AtomicBool>,
+    /// The thread, unparked for stopping it without waiting for the next probe.
+    thread: Thread,
+}
+
+/// A fake lamp network for testing discovery without lamps: it replies to probes with the configured lamps.
+///
+/// The responder listens on a local port instead of the multicast group, so tests search it with
+/// [`FakeResponder::discovery`]. The thread is stopped when the responder is dropped.
+/// ```
+/// # use std::time::Duration;
+/// # use yeerugina_lib::discovery::{FakeResponder, LampInfo};
+/// # fn main() -> std::io::Result<()> {
+/// let reply = "HTTP/1.1 200 OK\r\nLocation: yeelight://192.168.1.20:55443\r\nid: 0x1\r\n";
+/// let responder = FakeResponder::start()?;
+/// responder.add(LampInfo::parse(reply).unwrap());
+/// responder.set_repeat(3);
+/// let lamps = responder.discovery().window(Duration::from_millis(200)).search()?;
+/// assert_eq!(lamps.len(), 1);
+/// assert_eq!(responder.probes(), 1);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct FakeResponder {
+    socket: UdpSocket,
+    /// The state shared with the thread.
+    network: Arc<FakeNetwork>,
+}
+
+/// The lamps of a [FakeResponder] and its counters.
+#[derive(Debug, Default)]
+struct FakeNetwork {
+    lamps: Mutex<Vec<LampInfo>>,
+    /// How often each reply is sent, for testing that duplicates are skipped.
+    repeat: AtomicUsize,
+    /// The number of probes received.
+    probes: AtomicUsize,
+    /// Set to stop the thread.
+    stop: AtomicBool,
+}
+
+/// The stored form of a [LampInfo], with the state as properties.
+#[derive(Serialize, Deserialize)]
+struct StoredInfo {
+    location: SocketAddr,
+    id: String,
+    #[serde(default)]
+    model: Option<String>,
+    #[serde(default)]
+    fw_ver: Option<u32>,
+    #[serde(default)]
+    support: Vec<String>,
+    #[serde(default)]
+    props: BTreeMap<String, Value>,
+}
+
+/// A lamp in a [DiscoveryCache], with when it was last seen and how long that's trusted.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CachedLamp {
+    /// The last reply or advertisement of the lamp.
+    pub info: LampInfo,
+    /// When the lamp was last seen or verified.
+    pub seen_at: SystemTime,
+    /// How long after it was seen the entry is used, see [CachedLamp::is_expired].
+    pub ttl: Duration,
+}
+
+/// The lamps found by earlier searches, which can be stored with serde (e.g. as JSON) for starting without a search.
+///
+/// Entries expire after their TTL, after which [DiscoveryCache::fresh] skips them.
+/// Lamps may still have moved while they're fresh, so [DiscoveryCache::verify] checks an entry before it's used,
+/// and a [DiscoveryDaemon] started with [DiscoveryCache::to_registry] refreshes the lamps in the background.
+/// ```no_run
+/// # use yeerugina_lib::discovery::{Discovery, DiscoveryCache};
+/// # fn main() -> std::io::Result<()> {
+/// let mut cache = match std::fs::File::open("lamps.json") {
+///     Ok(file) => DiscoveryCache::load(file)?,
+///     Err(_) => DiscoveryCache::default(),
+/// };
+/// if cache.fresh().next().is_none() {
+///     let _found = cache.refresh(&Discovery::new())?;
+/// }
+/// for lamp in cache.fresh() {
+///     println!("{} at {}", lamp.id, lamp.location);
+/// }
+/// cache.save(std::fs::File::create("lamps.json")?)?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DiscoveryCache {
+    /// The TTL of new entries.
+    ttl: Duration,
+    /// The lamps, keyed by their lowercase id (see [DiscoveryRegistry::key]).
+    lamps: BTreeMap<String, CachedLamp>,
+}
+
+/// Names given to lamps by the user, such as `bedroom`, which can be stored with serde (e.g. as JSON).
+///
+/// Names are mapped to device ids, which don't change when a lamp gets another address.
+/// The lamps are looked up in a [DiscoveryRegistry], which isn't stored; it's usually the one of a [DiscoveryDaemon]
+/// or one created by [DiscoveryCache::to_registry].
+/// ```no_run
+/// # use yeerugina_lib::discovery::{DiscoveryDaemon, NameRegistry};
+/// # fn main() -> std::io::Result<()> {
+/// let daemon = DiscoveryDaemon::spawn()?;
+/// let names = NameRegistry::load(std::fs::File::open("names.json")?, daemon.registry().clone())?;
+/// if let Some(lamp) = names.get("bedroom") {
+///     println!("the bedroom lamp is at {}", lamp.location);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct NameRegistry {
+    /// The device ids, keyed by name.
+    names: BTreeMap<String, String>,
+    /// The lamps the names are looked up in.
+    #[serde(skip)]
+    lamps: DiscoveryRegistry,
+}
+
+impl LampInfo {
+    /// The properties advertised along with the lamp, see [LampInfo::state].
+    const PROPS: [&str; 8] = [
+        "power",
+        "bright",
+        "color_mode",
+        "ct",
+        "rgb",
+        "hue",
+        "sat",
+        "name",
+    ];
+
+    /// Parse a reply to a discovery probe (`HTTP/1.1 200 OK`) or an advertisement (`NOTIFY`).
+    ///
+    /// The address and id are required. Lamps leave properties they don't have empty, which are left unknown.
+    /// ```
+    /// # use yeerugina_lib::{cmd::Power, discovery::LampInfo, model::Model};
+    /// let reply = "HTTP/1.1 200 OK\r\nLocation: yeelight://192.168.1.20:55443\r\nid: 0x15243f\r\n\
+    ///     model: color\r\nsupport: get_prop set_power\r\npower: on\r\nbright: 100\r\n";
+    /// let info = LampInfo::parse(reply).unwrap();
+    /// assert_eq!(info.model, Some(Model::Color));
+    /// assert_eq!(info.state.power, Some(Power::On));
+    /// ```
+    pub fn parse(reply: &str) -> Result<Self, ParseInfoError> {
+        let start = reply.lines().next().unwrap_or_default();
+        if !(start.starts_with("HTTP/1.1 200") || start.starts_with("NOTIFY")) {
+            return Err(ParseInfoError::NotAReply(start.to_owned()));
+        }
+        let required = |name| header(reply, name).ok_or(ParseInfoError::MissingHeader(name));
+        let invalid = |header, value: &str| ParseInfoError::InvalidHeader {
+            header,
+            value: value.to_owned(),
+        };
+        let location = required("Location")?;
+        let location = location
+            .strip_prefix("yeelight://")
+            .and_then(|addr| addr.parse().ok())
+            .ok_or_else(|| invalid("Location", location))?;
+        let id = required("id")?;
+        if id.is_empty() {
+            return Err(invalid("id", id));
+        }
+        let fw_ver = match header(reply, "fw_ver") {
+            Some(fw_ver) => Some(fw_ver.parse().map_err(|_| invalid("fw_ver", fw_ver))?),
+            None => None,
+        };
+        let mut state = LampState::default();
+        for prop in Self::PROPS {
+            if let Some(value) = header(reply, prop)
+                && !value.is_empty()
+                && !state.apply_prop(prop, &Value::from(value))
+            {
+                return Err(invalid(prop, value));
+            }
+        }
+        Ok(Self {
+            location,
+            id: id.to_owned(),
+            model: header(reply, "model")
+                .filter(|model| !model.is_empty())
+                .map(Model::from_name),
+            fw_ver,
+            support: header(reply, "support")
+                .unwrap_or_default()
+                .split_whitespace()
+                .map(ToString::to_string)
+                .collect(),
+            state,
+        })
+    }
+
+    /// Describe a lamp by its address and id only, e.g. for [`DiscoveryRegistry::register`].
+    ///
+    /// The other fields are unknown, but can be set as well (such as the model).
+    pub fn new(location: SocketAddr, id: impl Into<String>) -> Self {
+        Self {
+            location,
+            id: id.into(),
+            model: None,
+            fw_ver: None,
+            support: Vec::new(),
+            state: LampState::default(),
+        }
+    }
+
+    /// Fill in a registered lamp with what discovery found out about it.
+    ///
+    /// The address and model are the registered ones, the rest is taken from the discovery if it's known.
+    fn enrich(&mut self, discovered: Self) {
+        self.model = self.model.take().or(discovered.model);
+        self.fw_ver = discovered.fw_ver.or(self.fw_ver);
+        if !discovered.support.is_empty() {
+            self.support = discovered.support;
+        }
+        self.state = discovered.state;
+    }
+
+    /// Connect to the lamp, see [`Lamp::connect_from_info`].
+    pub fn connect(&self) -> Result<Lamp, ConnectError> {
+        Lamp::connect_from_info(self)
+    }
+
+    /// Get the features of the lamp from its model, adjusted to the methods it advertises, if the model is known.
+    pub fn capabilities(&self) -> Option<Capabilities> {
+        Some(
+            self.model
+                .as_ref()?
+                .capabilities()?
+                .with_methods(&self.support),
+        )
+    }
+
+    /// Format the lamp as a reply to a discovery probe, the inverse of [LampInfo::parse].
+    pub fn to_reply(&self) -> String {
+        std::format!("HTTP/1.1 200 OK\r\n{}", self.headers())
+    }
+
+    /// Format the lamp as an advertisement, as lamps send it to the multicast group.
+    pub fn to_advertisement(&self) -> String {
+        std::format!(
+            "NOTIFY * HTTP/1.1\r\nHost: {MULTICAST_ADDR}\r\nNTS: ssdp:alive\r\n{}",
+            self.headers()
+        )
+    }
+
+    /// Format the headers describing the lamp, shared by replies and advertisements.
+    fn headers(&self) -> String {
+        let mut headers = std::format!(
+            "Cache-Control: max-age=3600\r\nLocation: yeelight://{}\r\nid: {}\r\n",
+            self.location,
+            self.id
+        );
+        if let Some(model) = &self.model {
+            headers.push_str(&std::format!("model: {model}\r\n"));
+        }
+        if let Some(fw_ver) = self.fw_ver {
+            headers.push_str(&std::format!("fw_ver: {fw_ver}\r\n"));
+        }
+        headers.push_str(&std::format!("support: {}\r\n", self.support.join(" ")));
+        let props = self.state.to_props();
+        for prop in Self::PROPS {
+            if let Some(value) = props.get(prop).and_then(Value::as_str) {
+                headers.push_str(&std::format!("{prop}: {value}\r\n"));
+            }
+        }
+        headers
+    }
+
+    /// Parse a discovery reply, logging why it's skipped if it's invalid.
+    fn from_reply(reply: &str) -> Option<Self> {
+        Self::parse(reply)
+            .inspect_err(|err| debug!("Lamp | Skipping discovery reply: {err}"))
+            .ok()
+    }
+}
+
+impl LampFilter {
+    /// Whether a lamp meets the condition.
+    pub fn matches(&self, lamp: &LampInfo) -> bool {
+        match self {
+            Self::Model(model) => lamp.model.as_ref() == Some(model),
+            Self::Feature(feature) => lamp
+                .capabilities()
+                .is_some_and(|capabilities| capabilities.has(*feature)),
+            Self::NamePrefix(prefix) => lamp
+                .state
+                .name
+                .as_ref()
+                .is_some_and(|name| name.starts_with(prefix.as_str())),
+            Self::Predicate(predicate) => predicate(lamp),
+        }
+    }
+}
+
+impl Discovery {
+    /// Create a search collecting replies for 3 seconds.
+    pub fn new() -> Self {
+        Self {
+            window: Duration::from_secs(3),
+            targets: Vec::from([MULTICAST_ADDR.into()]),
+            subnets: Vec::new(),
+            interfaces: Vec::new(),
+            filters: Vec::new(),
+        }
+    }
+
+    /// Set how long replies are collected.
+    ///
+    /// Lamps usually reply within a second, but busy Wi-Fi networks may delay them.
+    pub fn window(mut self, window: Duration) -> Self {
+        self.window = window;
+        self
+    }
+
+    /// Send the probe from the interface with a local address, e.g. the one in the network of the lamps.
+    ///
+    /// On hosts with several interfaces, the probe is only sent through the interface of the default route,
+    /// which may not reach the lamps. This can be called repeatedly for probing from several interfaces;
+    /// the replies are collected from all of them.
+    pub fn interface(mut self, addr: Ipv4Addr) -> Self {
+        self.interfaces.push(addr);
+        self
+    }
+
+    /// Send the probe to another address as well, such as a lamp on a network multicast isn't forwarded to.
+    ///
+    /// Lamps reply to probes sent to port 1982 of their address. IPv6 addresses are supported as well.
+    pub fn target(mut self, addr: SocketAddr) -> Self {
+        self.targets.push(addr);
+        self
+    }
+
+    /// Send the probe to the link-local IPv6 multicast group as well, on the interface with the index.
+    ///
+    /// IPv6 multicast needs an interface, which is the scope id of the address (see [MULTICAST_ADDR_V6]).
+    pub fn ipv6(self, interface: u32) -> Self {
+        let group = *MULTICAST_ADDR_V6.ip();
+        self.target(SocketAddrV6::new(group, MULTICAST_ADDR_V6.port(), 0, interface).into())
+    }
+
+    /// Send the probe to all hosts of an IPv4 subnet as well, e.g. `192.168.2.0/24` on a VLAN multicast isn't forwarded to.
+    ///
+    /// Subnets larger than `/16` are rejected when searching, as that many probes would flood the network.
+    pub fn sweep(mut self, network: Ipv4Addr, prefix_len: u8) -> Self {
+        self.subnets.push((network, prefix_len));
+        self
+    }
+
+    /// Only find the lamps meeting a condition. This can be called repeatedly; the lamps must meet all conditions.
+    /// ```no_run
+    /// # use yeerugina_lib::{discovery::{Discovery, LampFilter}, model::{Feature, Model}};
+    /// # fn main() -> std::io::Result<()> {
+    /// // all color bulbs supporting music mode
+    /// let lamps = Discovery::new()
+    ///     .filter(LampFilter::Model(Model::Color))
+    ///     .filter(LampFilter::Feature(Feature::Music))
+    ///     .search()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn filter(mut self, filter: LampFilter) -> Self {
+        self.filters.push(filter);
+        self
+    }
+
+    /// Only find the lamps passing a check, see [LampFilter::Predicate].
+    pub fn matching<F>(self, predicate: F) -> Self
+    where
+        F: Fn(&LampInfo) -> bool + Send + Sync + 'static,
+    {
+        self.filter(LampFilter::Predicate(Arc::new(predicate)))
+    }
+
+    /// Whether a lamp meets all conditions of the search, e.g. for filtering advertisements in the same way.
+    pub fn accepts(&self, lamp: &LampInfo) -> bool {
+        self.filters.iter().all(|filter| filter.matches(lamp))
+    }
+
+    /// Send the probe and collect the lamps that replied within the window, in the order they replied.
+    ///
+    /// Lamps replying more than once are only listed once, with their last reply.
+    /// This returns once the window elapsed; see [`Discovery::stream`] for getting the lamps as they reply.
+    pub fn search(&self) -> std::io::Result<Vec<LampInfo>> {
+        let stream = self.stream()?;
+        let mut lamps = Vec::<LampInfo>::new();
+        while let Some(lamp) = stream.next_reply()? {
+            match lamps
+                .iter_mut()
+                .find(|known| known.id.eq_ignore_ascii_case(&lamp.id))
+            {
+                Some(known) => *known = lamp,
+                None => lamps.push(lamp),
+            }
+        }
+        debug!("Lamp | Discovered {} lamp(s)", lamps.len());
+        Ok(lamps)
+    }
+
+    /// Send the probe, returning an iterator yielding each lamp as soon as it replies, until the window elapsed.
+    ///
+    /// Lamps replying more than once are only yielded once, with their first reply.
+    /// ```no_run
+    /// # use yeerugina_lib::discovery::Discovery;
+    /// # fn main() -> std::io::Result<()> {
+    /// for lamp in Discovery::new().stream()? {
+    ///     println!("found {} at {}", lamp.id, lamp.location);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn stream(&self) -> std::io::Result<DiscoveryStream> {
+        let mut targets = self.targets.clone();
+        for &(network, prefix_len) in &self.subnets {
+            targets.extend(
+                hosts(network, prefix_len)?
+                    .map(|host| SocketAddr::from((host, MULTICAST_ADDR.port()))),
+            );
+        }
+        let ipv6 = targets.iter().any(SocketAddr::is_ipv6);
+        let socket = match ipv6 {
+            // a dual-stack socket reaches the IPv4 targets as well
+            true => {
+                let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
+                socket.set_only_v6(false)?;
+                socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)).into())?;
+                socket
+            }
+            false => {
+                let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+                // a single interface is bound to, so that replies from other networks are ignored
+                let local = match self.interfaces[..] {
+                    [interface] => interface,
+                    _ => Ipv4Addr::UNSPECIFIED,
+                };
+                socket.bind(&SocketAddr::from((local, 0)).into())?;
+                socket
+            }
+        };
+        let mut sent = 0;
+        let mut last_err = None;
+        for target in targets {
+            match probe(&socket, target, &self.interfaces, ipv6) {
+                Ok(()) => sent += 1,
+                Err(err) => {
+                    debug!("Lamp | Probing {target} failed: {err}");
+                    last_err = Some(err);
+                }
+            }
+        }
+        // unreachable targets are skipped, as long as the probe was sent somewhere
+        if let (0, Some(err)) = (sent, last_err) {
+            return Err(err);
+        }
+        Ok(DiscoveryStream {
+            socket: socket.into(),
+            deadline: Instant::now() + self.window,
+            seen: Vec::new(),
+            filters: self.filters.clone(),
+        })
+    }
+}
+
+impl SubnetScan {
+    /// Create a scan of a subnet such as `192.168.1.0/24`, connecting to 32 hosts at once for 500ms each.
+    pub fn new(network: Ipv4Addr, prefix_len: u8) -> Self {
+        Self {
+            network,
+            prefix_len,
+            port: LAMP_PORT,
+            timeout: Duration::from_millis(500),
+            concurrency: 32,
+        }
+    }
+
+    /// Set the port to scan ([LAMP_PORT] by default).
+    pub fn port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    /// Set how long to wait for each host to accept the connection.
+    ///
+    /// Lamps on the local network accept within a few milliseconds, but sleeping Wi-Fi clients may take longer.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Set how many hosts are connected to at once (at least one), i.e. how many threads scan.
+    pub fn concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    /// Connect to every host of the subnet, returning the addresses that accepted, in order.
+    ///
+    /// The connections are closed right away. Subnets larger than `/16` are rejected with [ErrorKind::InvalidInput].
+    pub fn scan(&self) -> std::io::Result<Vec<SocketAddr>> {
+        let hosts = hosts(self.network, self.prefix_len)?
+            .map(|host| SocketAddr::from((host, self.port)))
+            .collect::<Vec<_>>();
+        debug!(
+            "Lamp | Scanning {} hosts of {}/{}",
+            hosts.len(),
+            self.network,
+            self.prefix_len
+        );
+        let next = AtomicUsize::new(0);
+        let found = Mutex::new(Vec::new());
+        std::thread::scope(|scope| {
+            for _ in 0..self.concurrency.min(hosts.len()) {
+                let _worker = scope.spawn(|| {
+                    while let Some(addr) = hosts.get(next.fetch_add(1, Ordering::Relaxed)) {
+                        if TcpStream::connect_timeout(addr, self.timeout).is_ok() {
+                            found
+                                .lock()
+                                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                                .push(*addr);
+                        }
+                    }
+                });
+            }
+        });
+        let mut found = found
+            .into_inner()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        found.sort_unstable();
+        Ok(found)
+    }
+}
+
+impl DiscoveryStream {
+    /// Get how long the stream keeps waiting for replies.
+    pub fn remaining(&self) -> Duration {
+        self.deadline.saturating_duration_since(Instant::now())
+    }
+
+    /// Wait for the next valid reply (also of lamps that replied already), returning None once the window elapsed.
+    fn next_reply(&self) -> std::io::Result<Option<LampInfo>> {
+        let mut buf = [0; 2048];
+        loop {
+            let left = self.remaining();
+            if left.is_zero() {
+                return Ok(None);
+            }
+            self.socket.set_read_timeout(Some(left))?;
+            match self.socket.recv_from(&mut buf) {
+                Ok((len, from)) => match std::str::from_utf8(&buf[..len]) {
+                    Ok(reply) => {
+                        if let Some(lamp) = LampInfo::from_reply(reply)
+                            .filter(|lamp| self.filters.iter().all(|filter| filter.matches(lamp)))
+                        {
+                            return Ok(Some(lamp));
+                        }
+                    }
+                    Err(err) => {
+                        debug!("Lamp | Skipping invalid discovery reply from {from}: {err}")
+                    }
+                },
+                Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    return Ok(None);
+                }
+                Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+impl Advertisements {
+    /// Join the multicast group of the lamps on all interfaces, to receive their advertisements.
+    ///
+    /// The port is shared, so several listeners (also of other programs) can run at once.
+    pub fn listen() -> std::io::Result<Self> {
+        Self::listen_on(&[])
+    }
+
+    /// Join the multicast group of the lamps on the interfaces with the local addresses, see [`Discovery::interface`].
+    ///
+    /// Without addresses, the operating system picks the interface, as in [`Advertisements::listen`].
+    pub fn listen_on(interfaces: &[Ipv4Addr]) -> std::io::Result<Self> {
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+        socket.set_reuse_address(true)?;
+        socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, MULTICAST_ADDR.port())).into())?;
+        if interfaces.is_empty() {
+            socket.join_multicast_v4(MULTICAST_ADDR.ip(), &Ipv4Addr::UNSPECIFIED)?;
+        }
+        for interface in interfaces {
+            socket.join_multicast_v4(MULTICAST_ADDR.ip(), interface)?;
+        }
+        Ok(Self {
+            socket: socket.into(),
+        })
+    }
+
+    /// Receive advertisements sent to a local address directly rather than to the multicast group,
+    /// such as those of a [FakeResponder].
+    pub fn bind(addr: SocketAddr) -> std::io::Result<Self> {
+        Ok(Self {
+            socket: UdpSocket::bind(addr)?,
+        })
+    }
+
+    /// Wait for the next advertisement, skipping other messages (such as the probes of other clients).
+    pub fn recv(&self) -> std::io::Result<LampInfo> {
+        self.socket.set_read_timeout(None)?;
+        self.receive(None)?
+            .ok_or_else(|| Error::from(ErrorKind::TimedOut))
+    }
+
+    /// Wait for the next advertisement for a timeout at most, returning None if none arrived.
+    pub fn recv_timeout(&self, timeout: Duration) -> std::io::Result<Option<LampInfo>> {
+        self.receive(Some(Instant::now() + timeout))
+    }
+
+    /// Wait for the next advertisement until the deadline, if any.
+    fn receive(&self, deadline: Option<Instant>) -> std::io::Result<Option<LampInfo>> {
+        let mut buf = [0; 2048];
+        loop {
+            if let Some(deadline) = deadline {
+                let left = deadline.saturating_duration_since(Instant::now());
+                if left.is_zero() {
+                    return Ok(None);
+                }
+                self.socket.set_read_timeout(Some(left))?;
+            }
+            let (len, from) = match self.socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    return Ok(None);
+                }
+                Err(err) => return Err(err),
+            };
+            let advertisement = std::str::from_utf8(&buf[..len])
+                .ok()
+                .filter(|message| message.starts_with("NOTIFY"))
+                .and_then(LampInfo::from_reply);
+            match advertisement {
+                Some(lamp) => return Ok(Some(lamp)),
+                None => debug!("Lamp | Skipping a message from {from} that isn't an advertisement"),
+            }
+        }
+    }
+}
+
+impl SsdpResolver {
+    /// Create a resolver waiting for replies for a timeout.
+    pub fn new(timeout: Duration) -> Self {
+        Self { timeout }
+    }
+}
+
+impl DiscoveryRegistry {
+    /// Create an empty registry.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get the known lamps, ordered by id.
+    pub fn lamps(&self) -> Vec<KnownLamp> {
+        self.lock().values().cloned().collect()
+    }
+
+    /// Get a known lamp by id, ignoring case.
+    pub fn get(&self, id: &str) -> Option<KnownLamp> {
+        self.lock().get(&Self::key(id)).cloned()
+    }
+
+    /// Get a channel receiving the changes of the registry from now on.
+    ///
+    /// Once the receiver is dropped, the channel is removed on the next change.
+    pub fn events(&self) -> mpsc::Receiver<DiscoveryEvent> {
+        let (tx, rx) = mpsc::channel();
+        self.subscribers
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .push(tx);
+        rx
+    }
+
+    /// Record that a lamp replied or advertised itself, adding it or updating its info.
+    ///
+    /// Registered lamps keep their address and model, see [`DiscoveryRegistry::register`].
+    pub fn observe(&self, info: LampInfo) {
+        let event = {
+            let mut lamps = self.lock();
+            let last_seen = Instant::now();
+            match lamps.get_mut(&Self::key(&info.id)) {
+                Some(known) => {
+                    known.last_seen = last_seen;
+                    let updated = match known.registered {
+                        true => {
+                            let mut registered = known.info.clone();
+                            registered.enrich(info);
+                            registered
+                        }
+                        false => info,
+                    };
+                    (known.info != updated).then(|| {
+                        known.info = updated.clone();
+                        DiscoveryEvent::Updated(updated)
+                    })
+                }
+                None => {
+                    debug!("Lamp | Found {} at {}", info.id, info.location);
+                    let _new = lamps.insert(
+                        Self::key(&info.id),
+                        KnownLamp {
+                            info: info.clone(),
+                            last_seen,
+                            registered: false,
+                        },
+                    );
+                    Some(DiscoveryEvent::Added(info))
+                }
+            }
+        };
+        if let Some(event) = event {
+            self.publish(&event);
+        }
+    }
+
+    /// Add a lamp manually, such as one on a network blocking multicast, which discovery never finds.
+    ///
+    /// Registered lamps never expire. When discovery sees them, their state, firmware version and methods
+    /// are updated, but the registered address and model are kept (the model is filled in if it wasn't registered).
+    /// Registering a known lamp again replaces its address and model.
+    /// ```
+    /// # use yeerugina_lib::{discovery::{DiscoveryRegistry, LampInfo}, model::Model};
+    /// let registry = DiscoveryRegistry::new();
+    /// let mut lamp = LampInfo::new("192.168.1.20:55443".parse().unwrap(), "0x15243f");
+    /// lamp.model = Some(Model::Color);
+    /// registry.register(lamp);
+    /// assert!(registry.get("0x15243f").unwrap().registered);
+    /// ```
+    pub fn register(&self, info: LampInfo) {
+        let event = {
+            let mut lamps = self.lock();
+            let last_seen = Instant::now();
+            match lamps.get_mut(&Self::key(&info.id)) {
+                Some(known) => {
+                    known.registered = true;
+                    let mut updated = known.info.clone();
+                    updated.location = info.location;
+                    updated.model = info.model.or(updated.model);
+                    (known.info != updated).then(|| {
+                        known.info = updated.clone();
+                        DiscoveryEvent::Updated(updated)
+                    })
+                }
+                None => {
+                    debug!("Lamp | Registered {} at {}", info.id, info.location);
+                    let _new = lamps.insert(
+                        Self::key(&info.id),
+                        KnownLamp {
+                            info: info.clone(),
+                            last_seen,
+                            registered: true,
+                        },
+                    );
+                    Some(DiscoveryEvent::Added(info))
+                }
+            }
+        };
+        if let Some(event) = event {
+            self.publish(&event);
+        }
+    }
+
+    /// Remove a registered lamp by id, ignoring case, returning its last known info.
+    ///
+    /// Lamps found by discovery aren't removed, as they would be found again; they expire instead.
+    pub fn unregister(&self, id: &str) -> Option<LampInfo> {
+        let removed = {
+            let mut lamps = self.lock();
+            let key = Self::key(id);
+            if !lamps.get(&key)?.registered {
+                return None;
+            }
+            lamps.remove(&key)?.info
+        };
+        debug!("Lamp | Unregistered {}", removed.id);
+        self.publish(&DiscoveryEvent::Unregistered(removed.clone()));
+        Some(removed)
+    }
+
+    /// Remove the lamps that weren't seen for the expiry, except for the registered ones.
+    pub fn expire(&self, expiry: Duration) {
+        let expired = {
+            let mut lamps = self.lock();
+            let (expired, kept) = core::mem::take(&mut *lamps)
+                .into_iter()
+                .partition::<BTreeMap<_, _>, _>(|(_, known)| {
+                    !known.registered && known.last_seen.elapsed() >= expiry
+                });
+            *lamps = kept;
+            expired
+        };
+        for known in expired.into_values() {
+            debug!("Lamp | {} expired", known.info.id);
+            self.publish(&DiscoveryEvent::Expired(known.info));
+        }
+    }
+
+    /// Get the key of a lamp in the map, as lamps are looked up ignoring the case of their id.
+    fn key(id: &str) -> String {
+        id.to_ascii_lowercase()
+    }
+
+    /// Pass a change to the subscribers, removing the ones whose receiver was dropped.
+    fn publish(&self, event: &DiscoveryEvent) {
+        self.subscribers
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .retain(|tx| tx.send(event.clone()).is_ok());
+    }
+
+    /// Lock the lamps, ignoring poisoning.
+    fn lock(&self) -> MutexGuard<'_, BTreeMap<String, KnownLamp>> {
+        self.lamps
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl DaemonBuilder {
+    /// Set the search probing the network, e.g. for a shorter window or other interfaces.
+    ///
+    /// Advertisements are listened for on the interfaces of the search.
+    pub fn discovery(mut self, discovery: Discovery) -> Self {
+        self.discovery = discovery;
+        self
+    }
+
+    /// Set how often the network is probed (30 seconds by default).
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Set how long a lamp may go unseen before it's removed (3 minutes by default).
+    ///
+    /// This should span a few probes, as lamps on busy Wi-Fi networks don't reply to every probe.
+    pub fn expiry(mut self, expiry: Duration) -> Self {
+        self.expiry = expiry;
+        self
+    }
+
+    /// Set whether advertisements are listened for between probes (the default).
+    pub fn advertisements(mut self, enabled: bool) -> Self {
+        self.advertisements = enabled;
+        self
+    }
+
+    /// Start the daemon with an empty registry.
+    pub fn spawn(self) -> std::io::Result<DiscoveryDaemon> {
+        self.spawn_with_registry(DiscoveryRegistry::new())
+    }
+
+    /// Start the daemon, updating an existing registry.
+    pub fn spawn_with_registry(
+        self,
+        registry: DiscoveryRegistry,
+    ) -> std::io::Result<DiscoveryDaemon> {
+        let advertisements = match self.advertisements {
+            true => Advertisements::listen_on(&self.discovery.interfaces)
+                .inspect_err(|err| debug!("Lamp | Not listening for advertisements: {err}"))
+                .ok(),
+            false => None,
+        };
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = Arc::clone(&stop);
+        let shared = registry.clone();
+        let handle = std::thread::Builder::new()
+            .name("yeelight-discovery".into())
+            .spawn(move || {
+                while !stopped.load(Ordering::Relaxed) {
+                    let next = Instant::now() + self.interval;
+                    match self.discovery.stream() {
+                        Ok(stream) => {
+                            for lamp in stream.take_while(|_| !stopped.load(Ordering::Relaxed)) {
+                                shared.observe(lamp);
+                            }
+                        }
+                        Err(err) => debug!("Lamp | Probing for lamps failed: {err}"),
+                    }
+                    shared.expire(self.expiry);
+                    while !stopped.load(Ordering::Relaxed) && Instant::now() < next {
+                        let left = next.saturating_duration_since(Instant::now());
+                        match &advertisements {
+                            Some(advertisements) => match advertisements.recv_timeout(left) {
+                                Ok(Some(lamp)) if self.discovery.accepts(&lamp) => {
+                                    shared.observe(lamp);
+                                }
+                                Ok(Some(_)) => {}
+                                Ok(None) => {}
+                                Err(err) => {
+                                    debug!("Lamp | Listening for advertisements failed: {err}");
+                                    std::thread::park_timeout(left);
+                                }
+                            },
+                            None => std::thread::park_timeout(left),
+                        }
+                    }
+                }
+            })?;
+        Ok(DiscoveryDaemon {
+            registry,
+            stop,
+            thread: handle.thread().clone(),
+        })
+    }
+}
+
+impl DiscoveryDaemon {
+    /// Get the options of a daemon, to be started with [`DaemonBuilder::spawn`].
+    pub fn builder() -> DaemonBuilder {
+        DaemonBuilder::default()
+    }
+
+    /// Start a daemon with the default options, see [`DiscoveryDaemon::builder`].
+    pub fn spawn() -> std::io::Result<Self> {
+        Self::builder().spawn()
+    }
+
+    /// Get the registry of the lamps, which can be shared and outlives the daemon.
+    pub fn registry(&self) -> &DiscoveryRegistry {
+        &self.registry
+    }
+}
+
+impl FakeResponder {
+    /// Start replying to probes sent to a local port, without any lamps yet.
+    pub fn start() -> std::io::Result<Self> {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))?;
+        let receiver = socket.try_clone()?;
+        // the thread checks whether it's stopped between reads
+        receiver.set_read_timeout(Some(Duration::from_millis(20)))?;
+        let network = Arc::new(FakeNetwork {
+            repeat: AtomicUsize::new(1),
+            ..FakeNetwork::default()
+        });
+        let shared = Arc::clone(&network);
+        let _handle = std::thread::Builder::new()
+            .name("yeelight-fake-responder".into())
+            .spawn(move || {
+                let mut buf = [0; 512];
+                while !shared.stop.load(Ordering::Relaxed) {
+                    let (len, from) = match receiver.recv_from(&mut buf) {
+                        Ok(received) => received,
+                        Err(err)
+                            if matches!(
+                                err.kind(),
+                                ErrorKind::WouldBlock
+                                    | ErrorKind::TimedOut
+                                    | ErrorKind::Interrupted
+                            ) =>
+                        {
+                            continue;
+                        }
+                        Err(err) => {
+                            debug!("Lamp | The fake responder stopped: {err}");
+                            return;
+                        }
+                    };
+                    if !buf[..len].starts_with(b"M-SEARCH") {
+                        continue;
+                    }
+                    let _probes = shared.probes.fetch_add(1, Ordering::Relaxed);
+                    let replies: Vec<String> =
+                        shared.lock().iter().map(LampInfo::to_reply).collect();
+                    for _ in 0..shared.repeat.load(Ordering::Relaxed) {
+                        for reply in &replies {
+                            if let Err(err) = receiver.send_to(reply.as_bytes(), from) {
+                                debug!("Lamp | The fake responder couldn't reply to {from}: {err}");
+                            }
+                        }
+                    }
+                }
+            })?;
+        Ok(Self { socket, network })
+    }
+
+    /// Get the address the responder receives probes on.
+    pub fn addr(&self) -> std::io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Get a search probing only the responder, instead of the network.
+    pub fn discovery(&self) -> Discovery {
+        Discovery {
+            targets: self.addr().into_iter().collect(),
+            ..Discovery::new()
+        }
+    }
+
+    /// Add a lamp replying to the following probes, replacing a lamp with the same id.
+    pub fn add(&self, lamp: LampInfo) {
+        let mut lamps = self.network.lock();
+        lamps.retain(|known| !known.id.eq_ignore_ascii_case(&lamp.id));
+        lamps.push(lamp);
+    }
+
+    /// Remove a lamp, so that it no longer replies (such as after being unplugged).
+    pub fn remove(&self, id: &str) -> Option<LampInfo> {
+        let mut lamps = self.network.lock();
+        let index = lamps
+            .iter()
+            .position(|lamp| lamp.id.eq_ignore_ascii_case(id))?;
+        Some(lamps.remove(index))
+    }
+
+    /// Remove all lamps.
+    pub fn clear(&self) {
+        self.network.lock().clear();
+    }
+
+    /// Get the lamps replying to probes.
+    pub fn lamps(&self) -> Vec<LampInfo> {
+        self.network.lock().clone()
+    }
+
+    /// Send each reply several times (1 by default), as lamps do when they're reachable on several interfaces.
+    pub fn set_repeat(&self, times: usize) {
+        self.network.repeat.store(times, Ordering::Relaxed);
+    }
+
+    /// Get how many probes the responder received.
+    pub fn probes(&self) -> usize {
+        self.network.probes.load(Ordering::Relaxed)
+    }
+
+    /// Send an advertisement of every lamp to an address, such as that of [`Advertisements::bind`].
+    pub fn advertise(&self, to: SocketAddr) -> std::io::Result<()> {
+        for lamp in self.network.lock().iter() {
+            let _sent = self
+                .socket
+                .send_to(lamp.to_advertisement().as_bytes(), to)?;
+        }
+        Ok(())
+    }
+}
+
+impl FakeNetwork {
+    /// Lock the lamps, ignoring poisoning.
+    fn lock(&self) -> MutexGuard<'_, Vec<LampInfo>> {
+        self.lamps
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl CachedLamp {
+    /// Whether the TTL elapsed since the lamp was seen.
+    pub fn is_expired(&self) -> bool {
+        // an entry seen in the future (after the clock was set back) is kept
+        self.seen_at.elapsed().is_ok_and(|age| age >= self.ttl)
+    }
+}
+
+impl DiscoveryCache {
+    /// Create an empty cache whose entries expire after a TTL.
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            lamps: BTreeMap::new(),
+        }
+    }
+
+    /// Get the TTL of new entries.
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    /// Set the TTL of new entries. The entries in the cache keep their TTL.
+    pub fn set_ttl(&mut self, ttl: Duration) {
+        self.ttl = ttl;
+    }
+
+    /// Read a cache stored as JSON with [DiscoveryCache::save].
+    pub fn load<R: Read>(reader: R) -> std::io::Result<Self> {
+        let mut cache: Self = serde_json::from_reader(reader)?;
+        // the file may have been written by hand, or by a version keying the lamps by the id as advertised
+        cache.lamps = core::mem::take(&mut cache.lamps)
+            .into_values()
+            .map(|lamp| (DiscoveryRegistry::key(&lamp.info.id), lamp))
+            .collect();
+        Ok(cache)
+    }
+
+    /// Write the cache as JSON.
+    pub fn save<W: Write>(&self, writer: W) -> std::io::Result<()> {
+        Ok(serde_json::to_writer_pretty(writer, self)?)
+    }
+
+    /// Add a lamp seen just now with the TTL of the cache, replacing the entry of the lamp, if any.
+    pub fn insert(&mut self, info: LampInfo) -> Option<CachedLamp> {
+        self.insert_with_ttl(info, self.ttl)
+    }
+
+    /// Add a lamp seen just now with its own TTL, e.g. a shorter one for lamps that often change their address.
+    pub fn insert_with_ttl(&mut self, info: LampInfo, ttl: Duration) -> Option<CachedLamp> {
+        let lamp = CachedLamp {
+            info,
+            seen_at: SystemTime::now(),
+            ttl,
+        };
+        self.lamps
+            .insert(DiscoveryRegistry::key(&lamp.info.id), lamp)
+    }
+
+    /// Remove a lamp by id, ignoring its case.
+    pub fn remove(&mut self, id: &str) -> Option<CachedLamp> {
+        self.lamps.remove(&DiscoveryRegistry::key(id))
+    }
+
+    /// Get a lamp by id (ignoring its case), even if it expired.
+    pub fn get(&self, id: &str) -> Option<&CachedLamp> {
+        self.lamps.get(&DiscoveryRegistry::key(id))
+    }
+
+    /// Get all lamps, including the expired ones, ordered by id.
+    pub fn lamps(&self) -> impl Iterator<Item = &CachedLamp> {
+        self.lamps.values()
+    }
+
+    /// Get the lamps that didn't expire, ordered by id.
+    pub fn fresh(&self) -> impl Iterator<Item = &LampInfo> {
+        self.lamps
+            .values()
+            .filter(|lamp| !lamp.is_expired())
+            .map(|lamp| &lamp.info)
+    }
+
+    /// Remove the expired lamps, returning them.
+    pub fn remove_expired(&mut self) -> Vec<LampInfo> {
+        let (expired, kept) = core::mem::take(&mut self.lamps)
+            .into_iter()
+            .partition::<BTreeMap<_, _>, _>(|(_, lamp)| lamp.is_expired());
+        self.lamps = kept;
+        expired.into_values().map(|lamp| lamp.info).collect()
+    }
+
+    /// Check that a lamp still accepts connections at its address, connecting for a timeout at most.
+    ///
+    /// A lamp that does is seen again, renewing its entry; one that doesn't is removed, so it's found by the next search.
+    /// Returns false for unknown lamps as well.
+    pub fn verify(&mut self, id: &str, timeout: Duration) -> bool {
+        let key = DiscoveryRegistry::key(id);
+        let Some(lamp) = self.lamps.get_mut(&key) else {
+            return false;
+        };
+        match TcpStream::connect_timeout(&lamp.info.location, timeout) {
+            Ok(_stream) => {
+                lamp.seen_at = SystemTime::now();
+                true
+            }
+            Err(err) => {
+                debug!("Lamp | Forgetting {id} at {}: {err}", lamp.info.location);
+                let _removed = self.lamps.remove(&key);
+                false
+            }
+        }
+    }
+
+    /// Search for lamps and add the ones that replied, returning how many did.
+    pub fn refresh(&mut self, discovery: &Discovery) -> std::io::Result<usize> {
+        let found = discovery.search()?;
+        let count = found.len();
+        for info in found {
+            let _prev = self.insert(info);
+        }
+        Ok(count)
+    }
+
+    /// Create a registry of the lamps that didn't expire, e.g. for starting a [DiscoveryDaemon] refreshing them.
+    pub fn to_registry(&self) -> DiscoveryRegistry {
+        let registry = DiscoveryRegistry::new();
+        for info in self.fresh() {
+            registry.observe(info.clone());
+        }
+        registry
+    }
+
+    /// Add the lamps of a registry, e.g. the one of a [DiscoveryDaemon], as seen when the registry last saw them.
+    pub fn update(&mut self, registry: &DiscoveryRegistry) {
+        let (now, wall_now) = (Instant::now(), SystemTime::now());
+        for known in registry.lamps() {
+            let age = now.saturating_duration_since(known.last_seen);
+            let lamp = CachedLamp {
+                seen_at: wall_now.checked_sub(age).unwrap_or(wall_now),
+                info: known.info,
+                ttl: self.ttl,
+            };
+            let _prev = self
+                .lamps
+                .insert(DiscoveryRegistry::key(&lamp.info.id), lamp);
+        }
+    }
+}
+
+impl NameRegistry {
+    /// Create a registry without names, looking lamps up in a [DiscoveryRegistry].
+    pub fn new(lamps: DiscoveryRegistry) -> Self {
+        Self {
+            names: BTreeMap::new(),
+            lamps,
+        }
+    }
+
+    /// Read names stored as JSON with [NameRegistry::save], looking lamps up in a [DiscoveryRegistry].
+    pub fn load<R: Read>(reader: R, lamps: DiscoveryRegistry) -> std::io::Result<Self> {
+        Ok(Self {
+            lamps,
+            ..serde_json::from_reader(reader)?
+        })
+    }
+
+    /// Write the names as JSON.
+    pub fn save<W: Write>(&self, writer: W) -> std::io::Result<()> {
+        Ok(serde_json::to_writer_pretty(writer, self)?)
+    }
+
+    /// Get the registry the lamps are looked up in.
+    pub fn lamps(&self) -> &DiscoveryRegistry {
+        &self.lamps
+    }
+
+    /// Give a lamp a name, returning the device id the name was given to before, if any.
+    ///
+    /// A lamp may have several names, e.g. `desk` and `office`.
+    pub fn assign(
+        &mut self,
+        name: impl Into<String>,
+        device_id: impl Into<String>,
+    ) -> Option<String> {
+        self.names.insert(name.into(), device_id.into())
+    }
+
+    /// Remove a name, returning the device id it was given to.
+    pub fn unassign(&mut self, name: &str) -> Option<String> {
+        self.names.remove(name)
+    }
+
+    /// Get the device id with a name.
+    pub fn device_id(&self, name: &str) -> Option<&str> {
+        self.names.get(name).map(String::as_str)
+    }
+
+    /// Get the first name (in order) of a device id, ignoring case.
+    pub fn name_of(&self, device_id: &str) -> Option<&str> {
+        self.names
+            .iter()
+            .find(|(_, id)| id.eq_ignore_ascii_case(device_id))
+            .map(|(name, _)| name.as_str())
+    }
+
+    /// Get the names with their device ids, ordered by name.
+    pub fn names(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.names
+            .iter()
+            .map(|(name, id)| (name.as_str(), id.as_str()))
+    }
+
+    /// Get the lamp with a name, if the name is known and the lamp is in the registry of lamps.
+    pub fn get(&self, name: &str) -> Option<LampInfo> {
+        Some(self.lamps.get(self.device_id(name)?)?.info)
+    }
+
+    /// Connect to the lamp with a name, see [`Lamp::connect_from_info`].
+    ///
+    /// Fails with [ErrorKind::NotFound] if the name is unknown or the lamp wasn't found (yet).
+    pub fn connect(&self, name: &str) -> Result<Lamp, ConnectError> {
+        let info = self.get(name).ok_or_else(|| {
+            Error::new(
+                ErrorKind::NotFound,
+                std::format!("no lamp named {name} was found"),
+            )
+        })?;
+        Lamp::connect_from_info(&info)
+    }
+}
+
+/// Send the probe to a target, from each interface for IPv4 targets.
+fn probe(
+    socket: &Socket,
+    target: SocketAddr,
+    interfaces: &[Ipv4Addr],
+    ipv6: bool,
+) -> std::io::Result<()> {
+    let send = |target: SocketAddr| {
+        socket
+            .send_to(SEARCH_REQUEST.as_bytes(), &target.into())
+            .map(drop)
+    };
+    match target {
+        SocketAddr::V4(target) => {
+            // IPv4 targets are mapped for dual-stack sockets
+            let mapped = match ipv6 {
+                true => SocketAddrV6::new(target.ip().to_ipv6_mapped(), target.port(), 0, 0).into(),
+                false => target.into(),
+            };
+            if interfaces.is_empty() {
+                return send(mapped);
+            }
+            for interface in interfaces {
+                debug!("Lamp | Probing {target} from {interface}");
+                socket.set_multicast_if_v4(interface)?;
+                send(mapped)?;
+            }
+            Ok(())
+        }
+        SocketAddr::V6(target) => {
+            if target.ip().is_multicast() {
+                socket.set_multicast_if_v6(target.scope_id())?;
+            }
+            send(target.into())
+        }
+    }
+}
+
+/// Get the hosts of an IPv4 subnet, without the network and broadcast addresses of subnets with more than two addresses.
+fn hosts(network: Ipv4Addr, prefix_len: u8) -> std::io::Result<impl Iterator<Item = Ipv4Addr>> {
+    if !(16..=32).contains(&prefix_len) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            std::format!(
+                "{network}/{prefix_len} is too large, the prefix must be between 16 and 32"
+            ),
+        ));
+    }
+    let size = 1u32 << (32 - prefix_len);
+    let first = network.to_bits() & !(size - 1);
+    let hosts = match size {
+        1 | 2 => first..=first + (size - 1),
+        _ => first + 1..=first + (size - 2),
+    };
+    Ok(hosts.map(Ipv4Addr::from_bits))
+}
+
+/// Get the value of a header of a discovery reply, ignoring the case of its name.
+fn header<'a>(reply: &'a str, name: &str) -> Option<&'a str> {
+    reply.lines().find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        key.trim()
+            .eq_ignore_ascii_case(name)
+            .then_some(value.trim())
+    })
+}
+
+impl core::error::Error for ParseInfoError {}
+
+impl Default for Discovery {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Default for DiscoveryCache {
+    /// An empty cache whose entries expire after an hour, like the replies of the lamps.
+    fn default() -> Self {
+        Self::new(Duration::from_secs(3600))
+    }
+}
+
+impl Default for DaemonBuilder {
+    fn default() -> Self {
+        Self {
+            discovery: Discovery::new(),
+            interval: Duration::from_secs(30),
+            expiry: Duration::from_secs(180),
+            advertisements: true,
+        }
+    }
+}
+
+impl Default for SsdpResolver {
+    fn default() -> Self {
+        Self::new(Duration::from_secs(3))
+    }
+}
+
+impl Iterator for Advertisements {
+    type Item = LampInfo;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.recv()
+            .inspect_err(|err| debug!("Lamp | Listening for advertisements failed: {err}"))
+            .ok()
+    }
+}
+
+impl Iterator for DiscoveryStream {
+    type Item = LampInfo;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.next_reply() {
+                Ok(Some(lamp)) => {
+                    let key = DiscoveryRegistry::key(&lamp.id);
+                    if !self.seen.contains(&key) {
+                        self.seen.push(key);
+                        return Some(lamp);
+                    }
+                }
+                Ok(None) => return None,
+                Err(err) => {
+                    debug!("Lamp | Discovery failed: {err}");
+                    return None;
+                }
+            }
+        }
+    }
+}
+
+impl From<StoredInfo> for LampInfo {
+    fn from(stored: StoredInfo) -> Self {
+        let mut state = LampState::default();
+        for (prop, value) in &stored.props {
+            let _known = state.apply_prop(prop, value);
+        }
+        Self {
+            location: stored.location,
+            id: stored.id,
+            model: stored.model.as_deref().map(Model::from_name),
+            fw_ver: stored.fw_ver,
+            support: stored.support,
+            state,
+        }
+    }
+}
+
+impl From<LampInfo> for StoredInfo {
+    fn from(info: LampInfo) -> Self {
+        Self {
+            props: info.state.to_props(),
+            location: info.location,
+            id: info.id,
+            model: info.model.as_ref().map(|model| model.name().into()),
+            fw_ver: info.fw_ver,
+            support: info.support,
+        }
+    }
+}
+
+impl Drop for DiscoveryDaemon {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        self.thread.unpark();
+    }
+}
+
+impl Drop for FakeResponder {
+    fn drop(&mut self) {
+        self.network.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+impl Resolver for DiscoveryRegistry {
+    fn resolve(&self, device_id: &str) -> std::io::Result<Option<SocketAddr>> {
+        Ok(self.get(device_id).map(|known| known.info.location))
+    }
+}
+
+impl Resolver for SsdpResolver {
+    fn resolve(&self, device_id: &str) -> std::io::Result<Option<SocketAddr>> {
+        let stream = Discovery::new().window(self.timeout).stream()?;
+        while let Some(lamp) = stream.next_reply()? {
+            if lamp.id.eq_ignore_ascii_case(device_id) {
+                return Ok(Some(lamp.location));
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cmd::{Brightness, Hue, Power, Saturation};
+    use crate::colors::Rgb;
+    use crate::state::ColorMode;
+    use pretty_assertions::assert_eq;
+
+    /// A lamp with only an address and id, as sent by the fake lamps of the tests.
+    fn info(location: &str, id: &str) -> LampInfo {
+        LampInfo::new(location.parse().unwrap(), id)
+    }
+
+    #[test]
+    fn parses_reply() {
+        let reply = "HTTP/1.1 200 OK\r\nCache-Control: max-age=3600\r\nDate: \r\nExt: \r\n\
+            Location: yeelight://192.168.1.239:55443\r\nServer: POSIX UPnP/1.0 YGLC/1\r\n\
+            id: 0x000000000015243f\r\nmodel: color\r\nfw_ver: 18\r\n\
+            support: get_prop set_default set_power toggle set_bright start_cf stop_cf\r\n\
+            power: on\r\nbright: 100\r\ncolor_mode: 2\r\nct: 4000\r\nrgb: 16711680\r\n\
+            hue: 100\r\nsat: 35\r\nname: \r\n";
+        assert_eq!(header(reply, "ID"), Some("0x000000000015243f"));
+        assert_eq!(header(reply, "date"), Some(""));
+        let info = LampInfo::parse(reply).unwrap();
+        assert_eq!(info.location, "192.168.1.239:55443".parse().unwrap());
+        assert_eq!(info.id, "0x000000000015243f");
+        assert_eq!(info.model, Some(Model::Color));
+        assert_eq!(info.fw_ver, Some(18));
+        assert_eq!(info.support.len(), 7);
+        assert_eq!(
+            info.state,
+            LampState {
+                power: Some(Power::On),
+                bright: Some(Brightness::new(100).unwrap()),
+                color_mode: Some(ColorMode::ColorTemperature),
+                ct: Some(4000),
+                rgb: Some(Rgb::from_int(0xFF0000)),
+                hue: Some(Hue::new(100).unwrap()),
+                sat: Some(Saturation::new(35).unwrap()),
+                ..LampState::default()
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_reply() {
+        let reply = "HTTP/1.1 200 OK\r\nLocation: yeelight://192.168.1.239:55443\r\nid: 0x1\r\n";
+        assert!(LampInfo::parse(reply).is_ok());
+        assert_eq!(
+            LampInfo::parse("M-SEARCH * HTTP/1.1\r\n"),
+            Err(ParseInfoError::NotAReply("M-SEARCH * HTTP/1.1".into()))
+        );
+        assert_eq!(
+            LampInfo::parse("HTTP/1.1 200 OK\r\nid: 0x1\r\n"),
+            Err(ParseInfoError::MissingHeader("Location"))
+        );
+        assert_eq!(
+            LampInfo::parse("HTTP/1.1 200 OK\r\nLocation: http://192.168.1.239\r\nid: 0x1\r\n"),
+            Err(ParseInfoError::InvalidHeader {
+                header: "Location",
+                value: "http://192.168.1.239".into()
+            })
+        );
+        assert_eq!(
+            LampInfo::parse(&std::format!("{reply}bright: 200\r\n")),
+            Err(ParseInfoError::InvalidHeader {
+                header: "bright",
+                value: "200".into()
+            })
+        );
+        assert_eq!(
+            LampInfo::parse(&std::format!("{reply}fw_ver: beta\r\n")),
+            Err(ParseInfoError::InvalidHeader {
+                header: "fw_ver",
+                value: "beta".into()
+            })
+        );
+    }
+
+    #[test]
+    fn advertisements() {
+        let mut advertisements = Advertisements {
+            socket: UdpSocket::bind("127.0.0.1:0").unwrap(),
+        };
+        let addr = advertisements.socket.local_addr().unwrap();
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        for message in [
+            SEARCH_REQUEST,
+            "NOTIFY * HTTP/1.1\r\nHost: 239.255.255.250:1982\r\nCache-Control: max-age=3600\r\n\
+                Location: yeelight://192.168.1.20:55443\r\nNTS: ssdp:alive\r\nid: 0x1\r\n",
+        ] {
+            let _sent = sender.send_to(message.as_bytes(), addr).unwrap();
+        }
+        assert_eq!(
+            advertisements.next(),
+            Some(info("192.168.1.20:55443", "0x1"))
+        );
+        assert_eq!(
+            advertisements
+                .recv_timeout(Duration::from_millis(50))
+                .unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn search() {
+        let responder = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let discovery = Discovery {
+            targets: Vec::from([responder.local_addr().unwrap()]),
+            ..Discovery::new().window(Duration::from_millis(300))
+        };
+        let _responder = std::thread::spawn(move || {
+            let mut buf = [0; 512];
+            let (len, from) = responder.recv_from(&mut buf).unwrap();
+            assert_eq!(std::str::from_utf8(&buf[..len]), Ok(SEARCH_REQUEST));
+            for reply in [
+                "HTTP/1.1 200 OK\r\nLocation: yeelight://192.168.1.20:55443\r\nid: 0x1\r\n",
+                "HTTP/1.1 200 OK\r\nid: 0x3\r\n",
+                "HTTP/1.1 200 OK\r\nLocation: yeelight://192.168.1.21:55443\r\nid: 0x2\r\n",
+                "HTTP/1.1 200 OK\r\nLocation: yeelight://192.168.1.22:55443\r\nid: 0x1\r\n",
+                "HTTP/1.1 200 OK\r\nLocation: yeelight://192.168.1.23:55443\r\nid: 0X2\r\n",
+            ] {
+                let _sent = responder.send_to(reply.as_bytes(), from).unwrap();
+            }
+        });
+        let lamps = discovery.search().unwrap();
+        assert_eq!(
+            lamps,
+            [
+                info("192.168.1.22:55443", "0x1"),
+                info("192.168.1.23:55443", "0X2")
+            ]
+        );
+    }
+
+    #[test]
+    fn stream() {
+        let responder = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let discovery = Discovery {
+            targets: Vec::from([responder.local_addr().unwrap()]),
+            ..Discovery::new().window(Duration::from_millis(500))
+        };
+        let (next, replies) = mpsc::channel::<&str>();
+        let _responder = std::thread::spawn(move || {
+            let mut buf = [0; 512];
+            let (_len, from) = responder.recv_from(&mut buf).unwrap();
+            for reply in replies {
+                let _sent = responder.send_to(reply.as_bytes(), from).unwrap();
+            }
+        });
+        let mut stream = discovery.stream().unwrap();
+        // each lamp is yielded as soon as it replies, long before the window elapsed
+        next.send("HTTP/1.1 200 OK\r\nLocation: yeelight://192.168.1.20:55443\r\nid: 0x1\r\n")
+            .unwrap();
+        assert_eq!(stream.next(), Some(info("192.168.1.20:55443", "0x1")));
+        assert!(stream.remaining() > Duration::from_millis(100));
+        next.send("HTTP/1.1 200 OK\r\nLocation: yeelight://192.168.1.22:55443\r\nid: 0x1\r\n")
+            .unwrap();
+        next.send("HTTP/1.1 200 OK\r\nLocation: yeelight://192.168.1.21:55443\r\nid: 0x2\r\n")
+            .unwrap();
+        assert_eq!(stream.next(), Some(info("192.168.1.21:55443", "0x2")));
+        next.send("HTTP/1.1 200 OK\r\nLocation: yeelight://192.168.1.23:55443\r\nid: 0X2\r\n")
+            .unwrap();
+        assert_eq!(stream.next(), None);
+        assert!(stream.remaining().is_zero());
+    }
+
+    #[test]
+    fn daemon() {
+        let responder = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let discovery = Discovery {
+            targets: Vec::from([responder.local_addr().unwrap()]),
+            ..Discovery::new().window(Duration::from_millis(50))
+        };
+        let reply = Arc::new(Mutex::new(Some("192.168.1.20:55443")));
+        let replying = Arc::clone(&reply);
+        let _responder = std::thread::spawn(move || {
+            let mut buf = [0; 512];
+            while let Ok((_len, from)) = responder.recv_from(&mut buf) {
+                if let Some(location) = *replying.lock().unwrap() {
+                    let reply = std::format!(
+                        "HTTP/1.1 200 OK\r\nLocation: yeelight://{location}\r\nid: 0x1\r\n"
+                    );
+                    let _sent = responder.send_to(reply.as_bytes(), from);
+                }
+            }
+        });
+        let registry = DiscoveryRegistry::new();
+        let events = registry.events();
+        let daemon = DiscoveryDaemon::builder()
+            .discovery(discovery)
+            .interval(Duration::from_millis(100))
+            .expiry(Duration::from_millis(300))
+            .advertisements(false)
+            .spawn_with_registry(registry)
+            .unwrap();
+        let timeout = Duration::from_secs(5);
+        assert_eq!(
+            events.recv_timeout(timeout),
+            Ok(DiscoveryEvent::Added(info("192.168.1.20:55443", "0x1")))
+        );
+        assert_eq!(
+            daemon.registry().resolve("0X1").unwrap(),
+            Some("192.168.1.20:55443".parse().unwrap())
+        );
+
+        // the lamp moved
+        *reply.lock().unwrap() = Some("192.168.1.21:55443");
+        assert_eq!(
+            events.recv_timeout(timeout),
+            Ok(DiscoveryEvent::Updated(info("192.168.1.21:55443", "0x1")))
+        );
+
+        // the lamp left
+        *reply.lock().unwrap() = None;
+        assert_eq!(
+            events.recv_timeout(timeout),
+            Ok(DiscoveryEvent::Expired(info("192.168.1.21:55443", "0x1")))
+        );
+        assert_eq!(daemon.registry().lamps(), []);
+    }
+
+    #[test]
+    fn interfaces() {
+        let responder = UdpSocket::bind("127.0.0.1:0").unwrap();
+        responder
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut buf = [0; 512];
+        let discovery = Discovery {
+            targets: Vec::from([responder.local_addr().unwrap()]),
+            ..Discovery::new().window(Duration::ZERO)
+        };
+        // a single interface is bound to
+        let _stream = discovery
+            .clone()
+            .interface(Ipv4Addr::new(127, 0, 0, 2))
+            .stream()
+            .unwrap();
+        let (_len, from) = responder.recv_from(&mut buf).unwrap();
+        assert_eq!(from.ip(), Ipv4Addr::new(127, 0, 0, 2));
+        // the probe is sent from each interface
+        let _stream = discovery
+            .interface(Ipv4Addr::LOCALHOST)
+            .interface(Ipv4Addr::new(127, 0, 0, 2))
+            .stream()
+            .unwrap();
+        for _ in 0..2 {
+            let (len, _from) = responder.recv_from(&mut buf).unwrap();
+            assert_eq!(std::str::from_utf8(&buf[..len]), Ok(SEARCH_REQUEST));
+        }
+    }
+
+    #[test]
+    fn ipv6_and_unicast_targets() {
+        let v4 = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let v6 = UdpSocket::bind("[::1]:0").unwrap();
+        let discovery = Discovery {
+            targets: Vec::new(),
+            ..Discovery::new().window(Duration::from_millis(300))
+        }
+        .target(v4.local_addr().unwrap())
+        .target(v6.local_addr().unwrap());
+        let responders = [
+            (v4, "192.168.1.20:55443", "0x1"),
+            (v6, "[fd00::20]:55443", "0x2"),
+        ]
+        .map(|(responder, location, id)| {
+            std::thread::spawn(move || {
+                let mut buf = [0; 512];
+                let (len, from) = responder.recv_from(&mut buf).unwrap();
+                assert_eq!(std::str::from_utf8(&buf[..len]), Ok(SEARCH_REQUEST));
+                let reply = std::format!(
+                    "HTTP/1.1 200 OK\r\nLocation: yeelight://{location}\r\nid: {id}\r\n"
+                );
+                let _sent = responder.send_to(reply.as_bytes(), from).unwrap();
+            })
+        });
+        let mut lamps = discovery.search().unwrap();
+        lamps.sort_by(|a, b| a.id.cmp(&b.id));
+        assert_eq!(
+            lamps,
+            [
+                info("192.168.1.20:55443", "0x1"),
+                info("[fd00::20]:55443", "0x2")
+            ]
+        );
+        for responder in responders {
+            responder.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn sweep() {
+        let subnet = |network: &str, prefix_len| {
+            hosts(network.parse().unwrap(), prefix_len).map(|hosts| hosts.collect::<Vec<_>>())
+        };
+        assert_eq!(
+            subnet("192.168.2.7", 30).unwrap(),
+            [Ipv4Addr::new(192, 168, 2, 5), Ipv4Addr::new(192, 168, 2, 6)]
+        );
+        assert_eq!(subnet("192.168.2.0", 24).unwrap().len(), 254);
+        assert_eq!(subnet("10.0.0.0", 16).unwrap().len(), 65534);
+        assert_eq!(
+            subnet("192.168.2.7", 32).unwrap(),
+            [Ipv4Addr::new(192, 168, 2, 7)]
+        );
+        assert_eq!(
+            subnet("10.0.0.0", 8).unwrap_err().kind(),
+            ErrorKind::InvalidInput
+        );
+        let err = Discovery::new()
+            .sweep(Ipv4Addr::new(10, 0, 0, 0), 8)
+            .stream()
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn cache() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let reply = std::format!(
+            "HTTP/1.1 200 OK\r\nLocation: yeelight://{}\r\nid: 0x1\r\nmodel: color\r\n\
+            support: get_prop set_power\r\npower: on\r\nbright: 40\r\nname: desk\r\n",
+            listener.local_addr().unwrap()
+        );
+        let reachable = LampInfo::parse(&reply).unwrap();
+        let mut cache = DiscoveryCache::new(Duration::from_secs(60));
+        assert_eq!(cache.insert(reachable.clone()), None);
+        let _prev = cache.insert_with_ttl(info("127.0.0.1:1", "0x2"), Duration::ZERO);
+        assert_eq!(cache.fresh().collect::<Vec<_>>(), [&reachable]);
+
+        // the cache survives a round trip, including the advertised state
+        let mut json = Vec::new();
+        cache.save(&mut json).unwrap();
+        let mut loaded = DiscoveryCache::load(json.as_slice()).unwrap();
+        assert_eq!(loaded, cache);
+        assert!(DiscoveryCache::load(&b"{}"[..]).is_err());
+
+        assert_eq!(loaded.remove_expired(), [info("127.0.0.1:1", "0x2")]);
+        assert!(loaded.verify("0x1", Duration::from_secs(1)));
+        drop(listener);
+        assert!(!loaded.verify("0x1", Duration::from_secs(1)));
+        assert_eq!(loaded.lamps().count(), 0);
+
+        // lamps are handed to a registry and taken back from it
+        let registry = cache.to_registry();
+        assert_eq!(registry.lamps().len(), 1);
+        registry.observe(info("192.168.1.21:55443", "0x3"));
+        let mut updated = DiscoveryCache::default();
+        updated.update(&registry);
+        assert_eq!(
+            updated
+                .fresh()
+                .map(|lamp| lamp.id.as_str())
+                .collect::<Vec<_>>(),
+            ["0x1", "0x3"]
+        );
+    }
+
+    #[test]
+    fn cache_ignores_case() {
+        let mut cache = DiscoveryCache::new(Duration::from_secs(60));
+        assert_eq!(cache.insert(info("127.0.0.1:1", "0x00000000abc")), None);
+        let prev = cache.insert(info("127.0.0.1:2", "0x00000000ABC")).unwrap();
+        assert_eq!(prev.info, info("127.0.0.1:1", "0x00000000abc"));
+        assert_eq!(cache.lamps().count(), 1);
+        // the lamp is found by the id the registry knows it by
+        assert!(cache.to_registry().get("0x00000000abc").is_some());
+        let lamp = cache.get("0x00000000abc").unwrap();
+        assert_eq!(lamp.info, info("127.0.0.1:2", "0x00000000ABC"));
+
+        // caches keyed by the id as advertised are normalized when loading
+        let mut json = Vec::new();
+        cache.save(&mut json).unwrap();
+        let json = String::from_utf8(json).unwrap();
+        let advertised = json.replace(r#""0x00000000abc":"#, r#""0x00000000ABC":"#);
+        assert_ne!(advertised, json);
+        assert_eq!(DiscoveryCache::load(advertised.as_bytes()).unwrap(), cache);
+
+        assert!(cache.remove("0x00000000aBc").is_some());
+        assert_eq!(cache.lamps().count(), 0);
+    }
+
+    #[test]
+    fn names() {
+        let lamps = DiscoveryRegistry::new();
+        lamps.observe(info("192.168.1.20:55443", "0x1"));
+        let mut names = NameRegistry::new(lamps.clone());
+        assert_eq!(names.assign("bedroom", "0x1"), None);
+        assert_eq!(names.assign("hallway", "0x2"), None);
+        assert_eq!(names.assign("bed", "0x1"), None);
+        assert_eq!(
+            names.get("bedroom"),
+            Some(info("192.168.1.20:55443", "0x1"))
+        );
+        // the lamp wasn't found yet
+        assert_eq!(names.get("hallway"), None);
+        assert!(matches!(
+            names.connect("hallway"),
+            Err(ConnectError::Io(err)) if err.kind() == ErrorKind::NotFound
+        ));
+        assert_eq!(names.name_of("0X1"), Some("bed"));
+
+        let mut json = Vec::new();
+        names.save(&mut json).unwrap();
+        let mut loaded = NameRegistry::load(json.as_slice(), lamps).unwrap();
+        assert_eq!(
+            loaded.names().collect::<Vec<_>>(),
+            [("bed", "0x1"), ("bedroom", "0x1"), ("hallway", "0x2")]
+        );
+        assert_eq!(loaded.unassign("bed").as_deref(), Some("0x1"));
+        assert_eq!(loaded.device_id("bed"), None);
+        // the lamp moved
+        loaded.lamps().observe(info("192.168.1.21:55443", "0x1"));
+        assert_eq!(
+            loaded.get("bedroom"),
+            Some(info("192.168.1.21:55443", "0x1"))
+        );
+    }
+
+    #[test]
+    fn filters() {
+        let lamp = |reply: &str| {
+            LampInfo::parse(&std::format!(
+                "HTTP/1.1 200 OK\r\nLocation: yeelight://192.168.1.20:55443\r\n{reply}"
+            ))
+            .unwrap()
+        };
+        let color =
+            lamp("id: 0x1\r\nmodel: color\r\nsupport: set_rgb set_music\r\nname: living room\r\n");
+        let quiet = lamp("id: 0x2\r\nmodel: color4\r\nsupport: set_rgb\r\nname: bedroom\r\n");
+        let unknown = lamp("id: 0x3\r\nmodel: plug\r\n");
+        let music = LampFilter::Feature(Feature::Music);
+        assert_eq!(
+            [&color, &quiet, &unknown].map(|lamp| music.matches(lamp)),
+            [true, false, false]
+        );
+        let discovery = Discovery::new()
+            .filter(LampFilter::Model(Model::Color))
+            .filter(LampFilter::NamePrefix("living".into()));
+        assert_eq!(
+            [&color, &quiet, &unknown].map(|lamp| discovery.accepts(lamp)),
+            [true, false, false]
+        );
+        let discovery = discovery.matching(|lamp| lamp.id != "0x1");
+        assert!(!discovery.accepts(&color));
+
+        // the replies are filtered as they arrive
+        let responder = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let discovery = Discovery {
+            targets: Vec::from([responder.local_addr().unwrap()]),
+            ..Discovery::new().window(Duration::from_millis(300))
+        }
+        .filter(LampFilter::Feature(Feature::Color));
+        let _responder = std::thread::spawn(move || {
+            let mut buf = [0; 512];
+            let (_len, from) = responder.recv_from(&mut buf).unwrap();
+            for reply in [
+                "HTTP/1.1 200 OK\r\nLocation: yeelight://192.168.1.20:55443\r\nid: 0x1\r\nmodel: mono\r\n",
+                "HTTP/1.1 200 OK\r\nLocation: yeelight://192.168.1.21:55443\r\nid: 0x2\r\nmodel: stripe\r\n",
+            ] {
+                let _sent = responder.send_to(reply.as_bytes(), from).unwrap();
+            }
+        });
+        assert_eq!(
+            discovery
+                .search()
+                .unwrap()
+                .iter()
+                .map(|lamp| lamp.id.as_str())
+                .collect::<Vec<_>>(),
+            ["0x2"]
+        );
+    }
+
+    #[test]
+    fn subnet_scan() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let scan = SubnetScan::new(Ipv4Addr::new(127, 0, 0, 0), 30)
+            .port(port)
+            .concurrency(0)
+            .timeout(Duration::from_secs(1));
+        assert_eq!(scan.concurrency, 1);
+        assert_eq!(
+            scan.scan().unwrap(),
+            [SocketAddr::from(([127, 0, 0, 1], port))]
+        );
+        let err = SubnetScan::new(Ipv4Addr::new(10, 0, 0, 0), 8)
+            .scan()
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn fake_responder() {
+        let mut lamp = info("192.168.1.20:55443", "0x1");
+        lamp.model = Some(Model::Color);
+        lamp.fw_ver = Some(26);
+        lamp.support = Vec::from(["get_prop".into(), "set_power".into()]);
+        lamp.state.power = Some(Power::On);
+        lamp.state.bright = Some(Brightness::new(40).unwrap());
+        lamp.state.name = Some("desk".into());
+        assert_eq!(LampInfo::parse(&lamp.to_reply()), Ok(lamp.clone()));
+        assert_eq!(LampInfo::parse(&lamp.to_advertisement()), Ok(lamp.clone()));
+
+        let responder = FakeResponder::start().unwrap();
+        responder.add(lamp.clone());
+        responder.add(info("192.168.1.21:55443", "0x2"));
+        // duplicate replies are skipped
+        responder.set_repeat(3);
+        let discovery = responder.discovery().window(Duration::from_millis(200));
+        assert_eq!(
+            discovery.search().unwrap(),
+            [lamp.clone(), info("192.168.1.21:55443", "0x2")]
+        );
+        assert_eq!(
+            responder.remove("0X2"),
+            Some(info("192.168.1.21:55443", "0x2"))
+        );
+        assert_eq!(discovery.search().unwrap(), [lamp.clone()]);
+        assert_eq!(responder.probes(), 2);
+
+        let advertisements = Advertisements::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        responder
+            .advertise(advertisements.socket.local_addr().unwrap())
+            .unwrap();
+        assert_eq!(
+            advertisements.recv_timeout(Duration::from_secs(1)).unwrap(),
+            Some(lamp)
+        );
+        responder.clear();
+        assert_eq!(responder.lamps(), []);
+    }
+
+    #[test]
+    fn registration() {
+        let registry = DiscoveryRegistry::new();
+        let events = registry.events();
+        let mut registered = info("10.0.0.5:55443", "0x1");
+        registered.model = Some(Model::Ceiling);
+        registry.register(registered.clone());
+        assert_eq!(
+            events.try_recv(),
+            Ok(DiscoveryEvent::Added(registered.clone()))
+        );
+
+        // discovery enriches the registered lamp, but keeps its address and model (and the case of its id)
+        let mut discovered = info("192.168.1.20:55443", "0X1");
+        discovered.model = Some(Model::Other("ceiling4".into()));
+        discovered.support = Vec::from(["get_prop".into()]);
+        discovered.state.power = Some(Power::On);
+        registry.observe(discovered);
+        let enriched = LampInfo {
+            support: Vec::from(["get_prop".into()]),
+            state: LampState {
+                power: Some(Power::On),
+                ..LampState::default()
+            },
+            ..registered.clone()
+        };
+        assert_eq!(
+            events.try_recv(),
+            Ok(DiscoveryEvent::Updated(enriched.clone()))
+        );
+        registry.expire(Duration::ZERO);
+        assert_eq!(registry.lamps().len(), 1);
+        assert_eq!(registry.get("0x1").unwrap().info, enriched);
+
+        // registering a discovered lamp keeps it from expiring
+        registry.observe(info("192.168.1.21:55443", "0x2"));
+        registry.register(info("10.0.0.6:55443", "0x2"));
+        assert_eq!(registry.unregister("0x3"), None);
+        registry.expire(Duration::ZERO);
+        assert_eq!(registry.lamps().len(), 2);
+        assert_eq!(
+            registry.unregister("0X2"),
+            Some(info("10.0.0.6:55443", "0x2"))
+        );
+        let _added = events.try_recv();
+        let _updated = events.try_recv();
+        assert_eq!(
+            events.try_recv(),
+            Ok(DiscoveryEvent::Unregistered(info("10.0.0.6:55443", "0x2")))
+        );
+
+        // discovered lamps can't be unregistered
+        registry.observe(info("192.168.1.22:55443", "0x4"));
+        assert_eq!(registry.unregister("0x4"), None);
+    }
+}
@@ -0,0 +1,199 @@
+use log::debug;
+use mio::net::TcpStream;
+use mio::{Events, Interest, Poll, Token};
+
+use std::collections::HashMap;
+use std::io::{ErrorKind, Read};
+use std::net::ToSocketAddrs;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::framing::LineReader;
+use crate::lamp::{ConnectError, DisconnectReason, Lamp, LampBuilder};
+use crate::reader::Inbox;
+
+/*
+ * Please follow this order:
+ * - structs/enums, ordered s.t. dependencies are above dependents
+ * - impls (e.g. impl EventLoop)
+ * - impl _ for _ (like Display, From<T>,...)
+ */
+
+/// A connection read by an [EventLoop].
+#[derive(Debug)]
+struct Source {
+    /// The stream the lamp sends on.
+    stream: TcpStream,
+    /// The inbox of the lamp, receiving the responses.
+    inbox: Arc<Inbox>,
+    /// The lines received so far.
+    lines: LineReader,
+    /// The epoch of the connection, see [Inbox::close].
+    epoch: u64,
+}
+
+/// An event loop reading from many lamps on a single thread, instead of one reader thread per lamp.
+///
+/// Lamps connected through the event loop are used as usual, but their responses are only read
+/// (and their callbacks only run) while the loop runs, so it's usually run on its own thread.
+/// The loop switches the connections to nonblocking mode (see [Lamp::set_nonblocking]).
+/// When a lamp reconnects (see [Lamp::set_reconnect_policy]), the new connection is read by a reader thread instead.
+/// ```no_run
+/// # use yeerugina_lib::{event_loop::EventLoop, lamp::Lamp};
+/// # fn main() -> std::io::Result<()> {
+/// let mut event_loop = EventLoop::new()?;
+/// let mut lamps = ["192.168.1.20:55443", "192.168.1.21:55443"]
+///     .into_iter()
+///     .map(|addr| event_loop.connect(Lamp::builder(), addr))
+///     .collect::<Result<Vec<_>, _>>()?;
+/// for lamp in &mut lamps {
+///     lamp.on_notification(|notification| println!("{:?}", notification.props));
+/// }
+/// // runs until all lamps are dropped
+/// std::thread::spawn(move || event_loop.run());
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct EventLoop {
+    /// The poller waiting for the connections to become readable.
+    poll: Poll,
+    /// The connections, keyed by their token.
+    sources: HashMap<Token, Source>,
+    /// The token of the next connection.
+    next_token: usize,
+}
+
+impl EventLoop {
+    /// Create an event loop without lamps.
+    pub fn new() -> std::io::Result<Self> {
+        Ok(Self {
+            poll: Poll::new()?,
+            sources: HashMap::new(),
+            next_token: 0,
+        })
+    }
+
+    /// Connect to a lamp with the options of the builder (see [LampBuilder::connect]), reading it from this loop.
+    pub fn connect<A: ToSocketAddrs>(
+        &mut self,
+        builder: LampBuilder,
+        addr: A,
+    ) -> Result<Lamp, ConnectError> {
+        let stream = builder.connect_stream(addr)?;
+        let (lamp, inbox, stream, epoch) = Lamp::from_stream_polled(stream, builder)?;
+        let mut stream = TcpStream::from_std(stream);
+        let token = Token(self.next_token);
+        self.next_token += 1;
+        self.poll
+            .registry()
+            .register(&mut stream, token, Interest::READABLE)?;
+        let _prev = self.sources.insert(
+            token,
+            Source {
+                stream,
+                inbox,
+                lines: LineReader::default(),
+                epoch,
+            },
+        );
+        Ok(lamp)
+    }
+
+    /// Get the number of connections read by the loop.
+    ///
+    /// Connections are removed once they are closed, e.g. because their lamp was dropped.
+    pub fn len(&self) -> usize {
+        self.sources.len()
+    }
+
+    /// Whether no connections are read by the loop.
+    pub fn is_empty(&self) -> bool {
+        self.sources.is_empty()
+    }
+
+    /// Wait until some lamps sent data (for the given timeout at most), and route their responses.
+    pub fn run_once(&mut self, timeout: Option<Duration>) -> std::io::Result<()> {
+        let mut events = Events::with_capacity(self.sources.len().max(1));
+        match self.poll.poll(&mut events, timeout) {
+            Err(err) if err.kind() == ErrorKind::Interrupted => return Ok(()),
+            res => res?,
+        }
+        for event in &events {
+            let Some(source) = self.sources.get_mut(&event.token()) else {
+                continue;
+            };
+            if !source.read() {
+                self.poll.registry().deregister(&mut source.stream)?;
+                let _closed = self.sources.remove(&event.token());
+            }
+        }
+        Ok(())
+    }
+
+    /// Route the responses of the lamps until all their connections are closed.
+    pub fn run(&mut self) -> std::io::Result<()> {
+        while !self.is_empty() {
+            self.run_once(None)?;
+        }
+        Ok(())
+    }
+}
+
+impl Source {
+    /// Read everything available and route the responses, returning whether the connection is still open.
+    fn read(&mut self) -> bool {
+        let mut chunk = [0; 512];
+        let reason = loop {
+            match self.stream.read(&mut chunk) {
+                Ok(0) => break DisconnectReason::Closed,
+                Ok(len) => self.inbox.receive(&mut self.lines, &chunk[..len]),
+                Err(err) if err.kind() == ErrorKind::WouldBlock => return true,
+                Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                Err(err) => {
+                    debug!("Lamp | Reading failed: {err}");
+                    break err.kind().into();
+                }
+            }
+        };
+        self.inbox.close(self.epoch, reason);
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cmd::{Action, Command, Effect};
+    use crate::lamp::testing::{reply_to, respond};
+    use pretty_assertions::assert_eq;
+    use serde_json::Value;
+    use std::net::TcpListener;
+    use std::{vec, vec::Vec};
+
+    #[test]
+    fn serves_many_lamps() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut event_loop = EventLoop::new().unwrap();
+        let mut lamps = Vec::new();
+        for _ in 0..3 {
+            lamps.push(
+                event_loop
+                    .connect(Lamp::builder(), listener.local_addr().unwrap())
+                    .unwrap(),
+            );
+            let (peer, _) = listener.accept().unwrap();
+            let _responder = respond(peer, |line| reply_to(line, r#""result":["ok"]"#));
+        }
+        assert_eq!(event_loop.len(), 3);
+        let runner = std::thread::spawn(move || event_loop.run());
+        let cmd = Command::new(Action::new_ct(3200), Effect::Sudden);
+        for lamp in &mut lamps {
+            assert_eq!(lamp.call(&cmd).unwrap(), vec![Value::from("ok")]);
+            assert_eq!(lamp.state().ct, Some(3200));
+        }
+        // the loop stops once all lamps are dropped
+        drop(lamps);
+        runner.join().unwrap().unwrap();
+    }
+}
@@ -0,0 +1,229 @@
+use alloc::{string::String, vec::Vec};
+use derive_more::Display;
+
+/*
+ * Please follow this order:
+ * - structs/enums, ordered s.t. dependencies are above dependents
+ * - impls (e.g. impl LineReader)
+ * - impl _ for _ (like Display, From<T>,...)
+ */
+
+/// The reason a line could not be taken from a [LineReader].
+#[derive(Clone, Copy, Debug, Display, PartialEq, Eq)]
+pub enum FrameError {
+    /// A line was longer than the maximum length, so it was discarded.
+    #[display("line longer than {max} bytes")]
+    TooLong {
+        /// The maximum length of a line.
+        max: usize,
+    },
+    /// A line wasn't valid UTF-8, so it was discarded.
+    #[display("line is not valid UTF-8")]
+    InvalidUtf8,
+}
+
+/// A buffer splitting the bytes received from a lamp into lines.
+///
+/// Lamps terminate each message with `\r\n`, but TCP doesn't preserve message boundaries:
+/// a read may return half a message, or several messages at once.
+/// Push the received bytes into the reader, then take complete lines until [LineReader::next_line] returns None.
+/// Empty lines are skipped, and lines longer than the maximum length are discarded with a [FrameError].
+/// ```
+/// # use yeerugina_lib::framing::LineReader;
+/// let mut reader = LineReader::default();
+/// reader.push(b"{\"id\":1,\"result\":[\"ok\"]}\r\n{\"id\":2,");
+/// assert_eq!(reader.next_line(), Some(Ok(r#"{"id":1,"result":["ok"]}"#.to_owned())));
+/// assert_eq!(reader.next_line(), None);
+/// reader.push(b"\"result\":[\"ok\"]}\r\n");
+/// assert_eq!(reader.next_line(), Some(Ok(r#"{"id":2,"result":["ok"]}"#.to_owned())));
+/// ```
+#[derive(Clone, Debug)]
+pub struct LineReader {
+    /// The received bytes, of which the ones before `start` were already taken.
+    buf: Vec<u8>,
+    /// The start of the first line that wasn't taken yet.
+    start: usize,
+    /// How far `buf` was already searched for a line break, so that bytes aren't searched twice.
+    searched: usize,
+    /// The maximum length of a line, excluding the line break.
+    max_len: usize,
+    /// Whether the rest of an oversized line is being discarded.
+    discarding: bool,
+}
+
+impl LineReader {
+    /// The default maximum length of a line, which is much longer than any message sent by a lamp.
+    pub const DEFAULT_MAX_LEN: usize = 16 * 1024;
+
+    /// Create a new reader accepting lines of at most `max_len` bytes (excluding the line break).
+    pub fn new(max_len: usize) -> Self {
+        Self {
+            buf: Vec::new(),
+            start: 0,
+            searched: 0,
+            max_len,
+            discarding: false,
+        }
+    }
+
+    /// Add received bytes to the reader.
+    pub fn push(&mut self, bytes: &[u8]) {
+        // Reclaim the space of taken lines before growing the buffer
+        if self.start > 0 && self.start >= self.buf.len() / 2 {
+            let _taken = self.buf.drain(..self.start);
+            self.searched -= self.start;
+            self.start = 0;
+        }
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// Take the next complete line, without its line break (`\n` or `\r\n`).
+    ///
+    /// Returns None if there is no complete line yet; push more bytes and try again.
+    /// An oversized line results in one [FrameError::TooLong], after which its remaining bytes are skipped.
+    pub fn next_line(&mut self) -> Option<Result<String, FrameError>> {
+        loop {
+            let Some(offset) = self.buf[self.searched..].iter().position(|&b| b == b'\n') else {
+                self.searched = self.buf.len();
+                return self.check_partial();
+            };
+            let end = self.searched + offset;
+            let line_start = self.start;
+            self.start = end + 1;
+            self.searched = self.start;
+            if core::mem::take(&mut self.discarding) {
+                continue;
+            }
+            let mut line = &self.buf[line_start..end];
+            if let [rest @ .., b'\r'] = line {
+                line = rest;
+            }
+            if line.len() > self.max_len {
+                return Some(Err(FrameError::TooLong { max: self.max_len }));
+            }
+            if line.iter().all(u8::is_ascii_whitespace) {
+                continue;
+            }
+            return Some(
+                core::str::from_utf8(line)
+                    .map(String::from)
+                    .map_err(|_| FrameError::InvalidUtf8),
+            );
+        }
+    }
+
+    /// Whether there are buffered bytes that don't form a complete line yet.
+    pub fn has_partial(&self) -> bool {
+        self.start < self.buf.len() && !self.discarding
+    }
+
+    /// Check the incomplete line at the end of the buffer, so that it can't grow without bound.
+    fn check_partial(&mut self) -> Option<Result<String, FrameError>> {
+        // One more byte is allowed for the \r of the line break
+        if self.buf.len() - self.start <= self.max_len + 1 {
+            return None;
+        }
+        self.start = self.buf.len();
+        if core::mem::replace(&mut self.discarding, true) {
+            None
+        } else {
+            Some(Err(FrameError::TooLong { max: self.max_len }))
+        }
+    }
+}
+
+impl Default for LineReader {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_MAX_LEN)
+    }
+}
+
+impl core::error::Error for FrameError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::{borrow::ToOwned, vec};
+
+    /// Take all complete lines from the reader.
+    fn lines(reader: &mut LineReader) -> Vec<Result<String, FrameError>> {
+        core::iter::from_fn(|| reader.next_line()).collect()
+    }
+
+    #[test]
+    fn several_lines_in_one_push() {
+        let mut reader = LineReader::default();
+        reader.push(b"first\r\nsecond\nthird\r\n\r\nfourth");
+        assert_eq!(
+            lines(&mut reader),
+            vec![
+                Ok("first".to_owned()),
+                Ok("second".to_owned()),
+                Ok("third".to_owned())
+            ]
+        );
+        assert!(reader.has_partial());
+        reader.push(b"\r\n");
+        assert_eq!(lines(&mut reader), vec![Ok("fourth".to_owned())]);
+        assert!(!reader.has_partial());
+    }
+
+    #[test]
+    fn byte_by_byte() {
+        let mut reader = LineReader::default();
+        let mut got = Vec::new();
+        for byte in b"{\"id\":1}\r\n{\"id\":2}\r\n" {
+            reader.push(&[*byte]);
+            got.extend(lines(&mut reader));
+        }
+        assert_eq!(
+            got,
+            vec![Ok(r#"{"id":1}"#.to_owned()), Ok(r#"{"id":2}"#.to_owned())]
+        );
+    }
+
+    #[test]
+    fn oversized_lines() {
+        let mut reader = LineReader::new(4);
+        reader.push(b"1234\r\n12345\r\nok\r\n");
+        assert_eq!(
+            lines(&mut reader),
+            vec![
+                Ok("1234".to_owned()),
+                Err(FrameError::TooLong { max: 4 }),
+                Ok("ok".to_owned())
+            ]
+        );
+        // an oversized line without a line break is reported once, then skipped until the line break
+        reader.push(b"123456");
+        assert_eq!(
+            lines(&mut reader),
+            vec![Err(FrameError::TooLong { max: 4 })]
+        );
+        reader.push(b"789");
+        assert_eq!(lines(&mut reader), vec![]);
+        reader.push(b"0\r\nok\r\n");
+        assert_eq!(lines(&mut reader), vec![Ok("ok".to_owned())]);
+    }
+
+    #[test]
+    fn invalid_utf8() {
+        let mut reader = LineReader::default();
+        reader.push(b"\xff\xfe\r\nok\r\n");
+        assert_eq!(
+            lines(&mut reader),
+            vec![Err(FrameError::InvalidUtf8), Ok("ok".to_owned())]
+        );
+    }
+
+    #[test]
+    fn reuses_buffer() {
+        let mut reader = LineReader::default();
+        for _ in 0..1000 {
+            reader.push(b"{\"id\":1,\"result\":[\"ok\"]}\r\n");
+            assert_eq!(lines(&mut reader).len(), 1);
+        }
+        assert!(reader.buf.len() < 100);
+    }
+}
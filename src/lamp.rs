@@ -1,105 +1,4663 @@
+use derive_more::Display;
 use log::debug;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use socket2::{Domain, Protocol, Socket, TcpKeepalive, Type};
 
+use std::collections::VecDeque;
 use std::io::{Error, ErrorKind, Read, Write};
-use std::net::{TcpStream, ToSocketAddrs};
-use std::time::Duration;
+use std::net::{Shutdown, SocketAddr, TcpStream, ToSocketAddrs};
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, mpsc};
+use std::thread::Thread;
+use std::time::{Duration, Instant, SystemTime};
+use std::{
+    borrow::ToOwned,
+    boxed::Box,
+    string::{String, ToString},
+    vec::Vec,
+};
 
-use crate::cmd::Command;
+use crate::cmd::{Action, Command, CommandKind, Effect, Param};
+use crate::discovery::{LampInfo, SsdpResolver};
+use crate::model::{Capabilities, Model};
+use crate::music::{MusicConnection, MusicServer};
+use crate::reader::{EventCallback, Inbox, NotificationCallback};
+use crate::record::Recorder;
+use crate::response::{
+    LampError, LampErrorCode, Notification, ParseMode, ParseResponseError, Response,
+};
+use crate::state::{LampState, Property, StateChange};
+use crate::transport::Transport;
+
+/// A source of ids for requests sent by a [`Lamp`].
+///
+/// The default generator is [`IdCounter`]. Implement this trait if you need control over the ids,
+/// e.g. to inject deterministic ids in tests.
+pub trait IdGenerator: std::fmt::Debug + Send + Sync {
+    /// Get the id for the next request.
+    ///
+    /// The id 0 is used for commands without an id, so it should not be returned.
+    fn next_id(&self) -> u32;
+}
+
+/// A way of finding the current address of a lamp by its device id, see [`Lamp::set_device_id`].
+///
+/// The default resolver is [`SsdpResolver`], which probes the network.
+/// Implement this trait to look lamps up elsewhere, e.g. in a registry kept by the application.
+pub trait Resolver: std::fmt::Debug + Send + Sync {
+    /// Get the current address of the lamp with the device id, or None if it wasn't found.
+    fn resolve(&self, device_id: &str) -> std::io::Result<Option<SocketAddr>>;
+}
+
+#[derive(Debug)]
+/// An [`IdGenerator`] that counts upwards.
+///
+/// The ids start from 1 (unless created with [`IdCounter::starting_at`]) and wrap around, skipping 0.
+pub struct IdCounter(AtomicU32);
+
+/// How [`Lamp::call`] retries commands rejected with [`CallError::QuotaExceeded`].
+///
+/// Lamps accept 60 commands per minute, so by default a rejected command is retried once after a minute.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct QuotaBackoff {
+    /// How long to wait before retrying.
+    pub delay: Duration,
+    /// How often a command is retried at most.
+    pub max_retries: u32,
+}
+
+/// How [`Lamp::call`] retries commands that failed with a retryable error (see [`CallError::is_retryable`]).
+///
+/// The delay before each retry starts at `initial_backoff` and doubles with each attempt, up to `max_backoff`.
+/// Note that a command is resent if its reply timed out, so it may be carried out twice;
+/// this matters for relative commands such as `adjust_bright`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct RetryPolicy {
+    /// How often a command is sent at most, including the first attempt.
+    pub max_attempts: u32,
+    /// The delay before the first retry.
+    pub initial_backoff: Duration,
+    /// The longest delay between two attempts.
+    pub max_backoff: Duration,
+    /// Whether the delays are shortened randomly (by up to half), so that clients don't retry in lockstep.
+    pub jitter: bool,
+}
+
+/// How a [`Lamp`] checks that the lamp is still responsive, see [`Lamp::set_heartbeat`].
+///
+/// Every `interval`, a cheap `get_prop ["power"]` request is sent.
+/// The lamp is marked unhealthy once `max_missed` replies in a row didn't arrive within `timeout`,
+/// and healthy again as soon as a reply arrives.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Heartbeat {
+    /// How often the lamp is pinged.
+    pub interval: Duration,
+    /// How long to wait for each reply.
+    pub timeout: Duration,
+    /// How many replies in a row may be missed before the lamp is unhealthy.
+    pub max_missed: u32,
+}
+
+/// What a [`Lamp`] does with a command exceeding its [`RateLimit`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum RateLimitMode {
+    /// Wait until the command fits the limit, then send it.
+    #[default]
+    Block,
+    /// Send the command from a background thread once it fits the limit, without waiting.
+    ///
+    /// Later commands are queued behind it, so the order of the commands is kept.
+    /// The queue is bounded by [`RateLimit::queue`], and dropped along with the Lamp.
+    Queue,
+    /// Fail with an [`ErrorKind::QuotaExceeded`] error, without sending the command.
+    Error,
+}
+
+/// What a [`Lamp`] does with a command when its queue is full, see [`QueueBound`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum QueueOverflow {
+    /// Wait until the queue thread sent a command.
+    #[default]
+    Block,
+    /// Drop the oldest queued command.
+    DropOldest,
+    /// Drop the queued command overwriting the same state (see [`Command::coalesce_key`]),
+    /// or wait as with [`QueueOverflow::Block`] if there's none.
+    Coalesce,
+}
+
+/// The bound of the queue of a [`RateLimit`] in [`RateLimitMode::Queue`], so that the queue doesn't grow without limit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct QueueBound {
+    /// How many commands are queued at most (at least 1).
+    pub capacity: usize,
+    /// What to do with commands when the queue is full.
+    pub overflow: QueueOverflow,
+    /// Whether a queued command is replaced by a newer one overwriting the same state (see [`Command::coalesce_key`]),
+    /// even if the queue isn't full.
+    ///
+    /// This collapses bursts such as the updates of a brightness slider to their latest value,
+    /// which is moved to the end of the queue. Relative and custom commands are never collapsed.
+    pub coalesce: bool,
+}
+
+/// A token bucket limiting the commands a [`Lamp`] sends, see [`Lamp::set_rate_limit`].
+///
+/// The bucket holds up to `capacity` commands, and one command is added back every `refill_interval`.
+/// By default, this matches the quota of the lamps (60 commands per minute).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct RateLimit {
+    /// How many commands can be sent in a burst.
+    pub capacity: u32,
+    /// How long it takes until another command can be sent.
+    pub refill_interval: Duration,
+    /// What to do with commands exceeding the limit.
+    pub mode: RateLimitMode,
+    /// The bound of the queue in [`RateLimitMode::Queue`].
+    pub queue: QueueBound,
+}
+
+/// When a [`Lamp`] switches to music mode on its own, see [`Lamp::set_music_upgrade`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct MusicUpgrade {
+    /// Switch to music mode once at most this many commands are left in the rate limit.
+    pub reserve: u32,
+    /// Switch back once no command was sent for this long.
+    pub idle: Duration,
+    /// How long to wait for the lamp to connect in music mode (or to reply to `set_music`).
+    pub accept_timeout: Duration,
+}
+
+/// The port lamps listen on for LAN control.
+pub const LAMP_PORT: u16 = 55443;
+
+/// How long [`Lamp::call`] waits for a response by default.
+const DEFAULT_CALL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The reason connecting to a lamp failed.
+///
+/// This converts into an [`std::io::Error`], so `?` can be used in functions returning [`std::io::Result`].
+#[derive(Debug, Display)]
+pub enum ConnectError {
+    /// Resolving the address or connecting failed.
+    #[display("I/O error: {_0}")]
+    Io(Error),
+    /// The host refused (or reset) the connection to [`LAMP_PORT`], so it's reachable, but not listening.
+    ///
+    /// This almost always means that LAN Control is disabled, which is the default for new lamps.
+    #[display(
+        "{addr} refused the connection, so LAN Control is probably disabled (enable it in the Yeelight app)"
+    )]
+    LanControlDisabled {
+        /// The address of the lamp.
+        addr: SocketAddr,
+        /// The error returned when connecting.
+        source: Error,
+    },
+}
+
+/// The reason [`Lamp::call`] failed.
+#[derive(Debug, Display)]
+pub enum CallError {
+    /// Sending the request or receiving the response failed.
+    #[display("I/O error: {_0}")]
+    Io(Error),
+    /// No response with the id of the request arrived in time.
+    #[display("timed out waiting for a response")]
+    Timeout,
+    /// The lamp replied with an error (see [`LampError::kind`] for branching on it).
+    #[display("the lamp returned an error: {_0}")]
+    Lamp(LampError),
+    /// The lamp rejected the request because more than 60 commands were sent within a minute.
+    ///
+    /// See [`Lamp::set_quota_backoff`] for retrying automatically.
+    #[display("the command quota of the lamp was exceeded: {_0}")]
+    QuotaExceeded(LampError),
+    /// The lamp doesn't support the method of the command.
+    ///
+    /// If the methods supported by the lamp are known (see [`Lamp::set_supported_methods`]),
+    /// they are listed in the error message.
+    #[display("the lamp doesn't support {method}{}", SupportedHint(supported.as_deref()))]
+    Unsupported {
+        /// The kind of the rejected command.
+        kind: CommandKind,
+        /// The method of the rejected command, such as `set_hsv`.
+        method: String,
+        /// The methods the lamp supports, if they are known.
+        supported: Option<Arc<[String]>>,
+        /// The error sent by the lamp.
+        error: LampError,
+    },
+    /// The reply was rejected by the parse mode of the lamp (see [`Lamp::set_parse_mode`]).
+    #[display("invalid reply: {_0}")]
+    InvalidResponse(ParseResponseError),
+    /// The connection was lost, see [`ConnectionEvent::Disconnected`].
+    #[display("the connection was lost: {_0}")]
+    Disconnected(DisconnectReason),
+    /// The request was dropped from the queue of the rate limit without being sent,
+    /// because the queue was full or a newer request replaced it (see [`QueueBound`]).
+    #[display("the request was dropped from the rate limit queue")]
+    Dropped,
+}
 
 #[derive(Debug)]
 /// A struct that represents a Yeelight lamp.
 ///
+/// Everything the lamp sends is read by a background thread,
+/// which passes replies to [`Lamp::call`] and notifications to the callbacks registered with [`Lamp::on_notification`].
+/// The connection is shut down when the Lamp is dropped (or closed with [`Lamp::close`]), which stops the thread.
+///
 /// The struct implements Read and Write,
 /// so you can send commands by using the write! macro as follows:
-/// ```rust
-/// lamp.send_cmd(cmd)?;
+/// ```no_run
+/// # use std::io::Write;
+/// # use yeerugina_lib::{cmd::{Action, Command, Effect}, lamp::Lamp};
+/// # fn main() -> std::io::Result<()> {
+/// # let mut lamp = Lamp::connect("192.168.1.20:55443")?;
+/// # let cmd = Command { action: Action::new_ct(3200), eff: Effect::Sudden, id: Some(1) };
+/// let _id = lamp.send_cmd(&cmd)?;
 /// // calls inside itself:
 /// write!(&mut lamp, "{}\r\n", cmd)?;
+/// # Ok(())
+/// # }
 /// ```
-pub struct Lamp {
+///
+/// # Blocking
+///
+/// Connecting, [`Lamp::reconnect`] and the methods waiting for replies ([`Lamp::call`], [`Lamp::get_props`],
+/// [`Lamp::refresh_state`] and [`PendingReply::wait`]) block. Sending (e.g. [`Lamp::send_cmd`] and
+/// [`Lamp::send_cmd_no_wait`]) blocks until the request is written, unless the Lamp is in nonblocking mode
+/// (see [`Lamp::set_nonblocking`]), in which case the rest of the request is buffered.
+/// [`PendingReply::poll`], [`Lamp::poll_flush`] and [`Lamp::poll_read_response`] never block.
+///
+/// # Transports
+///
+/// Lamps usually talk TCP, but the protocol logic works over any [`Transport`], e.g. a unix socket in tests
+/// (see [`Lamp::with_transport`]). Connecting and the socket options are only available for TCP.
+pub struct Lamp<T: Transport = TcpStream> {
     /// The connection to the lamp.
     ///
-    /// For changing properties such as read and write timeouts, call the methods on this field directly.
-    pub stream: TcpStream,
+    /// Socket options are changed through [`Lamp::apply_connection_options`].
+    stream: T,
+    /// Whether color temperature commands are sent as approximate RGB commands.
+    ct_fallback: bool,
+    /// The generator of ids for commands without an id.
+    ids: Arc<dyn IdGenerator>,
+    /// The effect used for commands built without an explicit effect.
+    default_effect: Effect,
+    /// The buffer requests are encoded into, kept to reuse its allocation.
+    buf: Vec<u8>,
+    /// The replies and notifications received by the background reader thread.
+    inbox: Arc<Inbox>,
+    /// How long [`Lamp::call`] waits for a response.
+    call_timeout: Duration,
+    /// How [`Lamp::call`] retries commands exceeding the quota, if at all.
+    quota_backoff: Option<QuotaBackoff>,
+    /// How [`Lamp::call`] retries commands failing with retryable errors, if at all.
+    retry_policy: Option<RetryPolicy>,
+    /// The methods supported by the lamp, if they are known.
+    supported: Option<Arc<[String]>>,
+    /// The model of the lamp, if it's known.
+    model: Option<Model>,
+    /// Whether the stream is in nonblocking mode.
+    nonblocking: bool,
+    /// The part of the requests that couldn't be written yet in nonblocking mode.
+    pending: Vec<u8>,
+    /// The address of the lamp, if the transport has one, used for reconnecting.
+    peer: Option<SocketAddr>,
+    /// The epoch of the connection (see [`Inbox::attach`]), behind the one of the inbox after another Lamp sharing it reconnected.
+    epoch: u64,
+    /// The unique id of the lamp, used for finding it again after its address changed.
+    device_id: Option<String>,
+    /// How the address of the lamp is looked up by its device id.
+    resolver: Arc<dyn Resolver>,
+    /// The options the connection was established with, reused for reconnecting.
+    builder: LampBuilder,
+    /// How a lost connection is re-established, if at all.
+    reconnect_policy: Option<RetryPolicy>,
+    /// Whether requests that weren't replied to are resent after reconnecting.
+    resend_unacked: bool,
+    /// The thread checking the health of the lamp, if any.
+    heartbeat: Option<BackgroundThread>,
+    /// After how long without commands the connection is closed, if at all.
+    idle_timeout: Option<Duration>,
+    /// The thread closing the connection once it's idle, if any.
+    idle_watch: Option<BackgroundThread>,
+    /// The limit of the commands sent, if any.
+    rate_limiter: Option<RateLimiter>,
+    /// The automatic switching to music mode, if enabled.
+    music: Option<Music>,
+    /// Whether the connection was shut down already, so that dropping a closed Lamp does nothing.
+    closed: bool,
+    /// The number of Lamps sharing the connection (see [`Lamp::try_clone`]), which is shut down when the last one is dropped.
+    handles: Arc<AtomicUsize>,
 }
 // TcpStream will be dropped once we go out of scope
 
-impl Lamp {
-    /// Create a new Lamp from an IP address (or several addresses).
+/// A builder for connecting to a lamp with tuned socket options, see [`Lamp::builder`].
+///
+/// Options that aren't set keep the defaults of the operating system.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct LampBuilder {
+    connect_timeout: Option<Duration>,
+    total_connect_timeout: Option<Duration>,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    nodelay: Option<bool>,
+    keepalive: Option<Duration>,
+    recv_buffer_size: Option<usize>,
+    send_buffer_size: Option<usize>,
+    local_addr: Option<SocketAddr>,
+}
+
+/// The options of an open connection, which are read and applied as a unit.
+///
+/// See [`Lamp::connection_options`] and [`Lamp::apply_connection_options`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct ConnectionOptions {
+    /// The read timeout of the stream, or None for blocking reads.
     ///
-    /// The argument can be anything that implements [`ToSocketAddrs`], such as String, &str, or (&str, u16).
-    /// You can pass multiple addresses into the method, and the TcpStream will use the first successful connection.
-    /// If no address provides a connection, the most recent (i.e. last) error will be returned.
-    pub fn connect<A: ToSocketAddrs>(addr: A) -> std::io::Result<Self> {
-        debug!("Lamp | Attempt connect");
-        let stream = TcpStream::connect(addr)?;
-        debug!("Lamp | Connection Successful");
-        Ok(Self { stream })
+    /// Replies are read by a background thread, which isn't affected by the timeout.
+    pub read_timeout: Option<Duration>,
+    /// The write timeout of the stream, or None for blocking writes.
+    pub write_timeout: Option<Duration>,
+    /// Whether TCP_NODELAY is set, which sends small requests immediately instead of batching them.
+    pub nodelay: bool,
+    /// Whether the stream is in nonblocking mode, so that writes return [`ErrorKind::WouldBlock`] instead of blocking.
+    pub nonblocking: bool,
+}
+
+/// Whether a line seen by the wiretap of a [`Lamp`] was sent or received.
+#[derive(Clone, Copy, Debug, Display, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    /// The line was sent to the lamp.
+    #[display("TX")]
+    Sent,
+    /// The line was received from the lamp.
+    #[display("RX")]
+    Received,
+}
+
+/// An event of the connection to a lamp, see [`Lamp::on_connection_event`].
+#[derive(Clone, Copy, Debug, Display, PartialEq, Eq)]
+pub enum ConnectionEvent {
+    /// The connection was lost and has been re-established (see [`Lamp::set_reconnect_policy`]).
+    ///
+    /// Notifications sent while the connection was down are lost, so the cached state may be stale;
+    /// see [`Lamp::refresh_state`].
+    #[display("reconnected after {attempts} attempt(s)")]
+    Reconnected {
+        /// How many connection attempts were needed.
+        attempts: u32,
+    },
+    /// The lamp stopped replying to the pings of the heartbeat (see [`Lamp::set_heartbeat`]).
+    #[display("the lamp is unhealthy after {missed} missed ping(s)")]
+    Unhealthy {
+        /// How many replies in a row were missed.
+        missed: u32,
+    },
+    /// The lamp replies to the pings of the heartbeat again after it was unhealthy.
+    #[display("the lamp is healthy again")]
+    Healthy,
+    /// The connection was lost and couldn't be re-established.
+    #[display("reconnecting failed after {attempts} attempt(s): {kind}")]
+    ReconnectFailed {
+        /// How many connection attempts were made.
+        attempts: u32,
+        /// The kind of error of the last attempt.
+        kind: ErrorKind,
+    },
+    /// The connection was lost, e.g. because the lamp closed or reset it.
+    ///
+    /// Without a reconnect policy (see [`Lamp::set_reconnect_policy`]), commands fail with
+    /// [`CallError::Disconnected`] from now on, until the Lamp is reconnected with [`Lamp::reconnect`].
+    #[display("disconnected: {reason}")]
+    Disconnected {
+        /// Why the connection was lost.
+        reason: DisconnectReason,
+    },
+    /// The lamp was found at a new address after its old one became unreachable (see [`Lamp::set_device_id`]).
+    #[display("the lamp moved to {to}")]
+    Moved {
+        /// The new address of the lamp.
+        to: SocketAddr,
+    },
+}
+
+/// Why the connection to a lamp was lost, see [`ConnectionEvent::Disconnected`].
+///
+/// Timeouts don't end the connection, so they're never a reason.
+#[derive(Clone, Copy, Debug, Display, PartialEq, Eq, Hash)]
+pub enum DisconnectReason {
+    /// The lamp closed the connection, e.g. because it was idle or the lamp restarted.
+    #[display("the lamp closed the connection")]
+    Closed,
+    /// Writing failed because the lamp closed the connection already.
+    #[display("broken pipe")]
+    BrokenPipe,
+    /// The lamp reset the connection.
+    #[display("the lamp reset the connection")]
+    Reset,
+    /// The connection was aborted, e.g. by the local network stack.
+    #[display("the connection was aborted")]
+    Aborted,
+    /// The connection was closed after it was idle (see [`Lamp::set_idle_timeout`]); the next command reconnects.
+    #[display("the connection was idle")]
+    Idle,
+    /// Reading or writing failed with another error.
+    #[display("{_0}")]
+    Other(ErrorKind),
+}
+
+/// A raw line sent to or received from a lamp, see [`Lamp::set_wiretap_sink`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WireFrame<'a> {
+    /// Whether the line was sent or received.
+    pub direction: Direction,
+    /// The address of the lamp, if it's known.
+    pub peer: Option<SocketAddr>,
+    /// When the line was sent or received.
+    pub time: SystemTime,
+    /// The line, without the `\r\n` terminator.
+    pub line: &'a str,
+}
+
+/// The counters of a [`Lamp`] connection, see [`Lamp::stats`].
+///
+/// The counters are shared by the clones of a Lamp (see [`Lamp::try_clone`]) and kept across reconnects.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LampStats {
+    /// How many commands were written to the lamp, including those sent in music mode.
+    pub commands_sent: u64,
+    /// How many replies to commands were received, not counting notifications.
+    pub responses_received: u64,
+    /// How many times the Lamp reconnected successfully.
+    pub reconnects: u64,
+    /// The last error that occurred, if any.
+    pub last_error: Option<LastError>,
+}
+
+/// An error that occurred on a [`Lamp`] connection, see [`LampStats::last_error`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LastError {
+    /// The kind of the I/O error, or `None` for errors reported by the lamp.
+    pub kind: Option<ErrorKind>,
+    /// The description of the error.
+    pub message: String,
+    /// When the error occurred.
+    pub at: SystemTime,
+}
+
+/// The reading half of a [`Lamp`], receiving its notifications, see [`Lamp::split`].
+///
+/// The reader can be used as a blocking iterator, which ends when the connection is closed.
+#[derive(Debug)]
+pub struct LampReader {
+    /// The notifications passed on by the background reader thread.
+    notifications: mpsc::Receiver<Notification>,
+}
+
+/// The writing half of a [`Lamp`], sending commands, see [`Lamp::split`].
+///
+/// The writer dereferences to the Lamp, so all of its methods for sending commands are available.
+#[derive(Debug)]
+pub struct LampWriter<T: Transport = TcpStream> {
+    lamp: Lamp<T>,
+}
+
+/// A handle to a [`Lamp`] that can be cloned cheaply and shared between threads.
+///
+/// Access to the lamp is serialized internally, so requests sent from different threads never interleave.
+/// ```no_run
+/// # use yeerugina_lib::{cmd::{Action, Power}, lamp::{Lamp, SharedLamp}};
+/// # fn main() -> std::io::Result<()> {
+/// let lamp = SharedLamp::new(Lamp::connect("192.168.1.20:55443")?);
+/// let scheduler = lamp.clone();
+/// std::thread::spawn(move || scheduler.send_action(Action::new_power(Power::Off)));
+/// lamp.send_action(Action::new_power(Power::On))?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct SharedLamp<T: Transport = TcpStream>(Arc<Mutex<Lamp<T>>>);
+
+/// A background thread of a Lamp, such as the heartbeat, which is stopped when it's replaced or the lamp is dropped.
+#[derive(Debug)]
+struct BackgroundThread {
+    /// Set to stop the thread.
+    stop: Arc<AtomicBool>,
+    /// The thread, unparked for stopping it without waiting for its next check.
+    thread: Thread,
+}
+
+/// The commands left in a [`RateLimit`], shared by the Lamps sharing a connection and the queue thread.
+#[derive(Debug)]
+struct TokenBucket {
+    /// The limit.
+    limit: RateLimit,
+    /// The commands that can be sent right away.
+    tokens: u32,
+    /// When the last command was added back to the bucket.
+    refilled: Instant,
+}
+
+/// A request held back by the rate limit, see [`RateLimitMode::Queue`].
+#[derive(Debug)]
+struct Queued {
+    /// The id of the request, if it's known, whose reply fails with [`CallError::Dropped`] if the request is dropped.
+    id: Option<u32>,
+    /// The [`Command::coalesce_key`] of the request, if any.
+    key: Option<String>,
+    /// The encoded request.
+    request: Vec<u8>,
+}
+
+/// The state of a [`CommandQueue`].
+#[derive(Debug, Default)]
+struct QueueState {
+    /// The requests waiting to be sent, oldest first.
+    requests: VecDeque<Queued>,
+    /// Whether the queue thread is sending a request it took out of the queue.
+    sending: bool,
+    /// Whether the queue thread was started.
+    started: bool,
+    /// Whether all Lamps using the queue were dropped, so that the queue thread stops.
+    closed: bool,
+}
+
+/// The requests held back by a [`RateLimit`], sent by the queue thread.
+#[derive(Debug, Default)]
+struct CommandQueue {
+    /// The requests.
+    state: Mutex<QueueState>,
+    /// Notified when requests are added, taken out or the queue is closed.
+    changed: Condvar,
+}
+
+/// Closes its [`CommandQueue`] once the last Lamp sharing it is dropped, which stops the queue thread.
+#[derive(Debug)]
+struct QueueOwner(Arc<CommandQueue>);
+
+/// The rate limit of a [`Lamp`], with the thread sending the queued commands in [`RateLimitMode::Queue`].
+#[derive(Clone, Debug)]
+struct RateLimiter {
+    /// The commands left.
+    bucket: Arc<Mutex<TokenBucket>>,
+    /// The requests held back.
+    queue: Arc<QueueOwner>,
+}
+
+/// The connection the lamp opened in music mode, see [`Lamp::set_music_upgrade`].
+#[derive(Debug)]
+struct MusicSession {
+    /// The connection, which the lamp reads commands from without replying.
+    stream: TcpStream,
+    /// When the last command was sent over the connection.
+    last_sent: Instant,
+}
+
+/// The automatic switching to music mode of a [`Lamp`].
+#[derive(Debug)]
+struct Music {
+    /// When to switch.
+    upgrade: MusicUpgrade,
+    /// The music mode connection, while music mode is on.
+    session: Option<MusicSession>,
+    /// When switching may be tried again after it failed.
+    retry_at: Option<Instant>,
+}
+
+/// Helper for listing the supported methods in [`CallError::Unsupported`].
+struct SupportedHint<'a>(Option<&'a [String]>);
+
+/// A handle to the reply of a command sent with [`Lamp::send_cmd_no_wait`].
+///
+/// The reply can be polled without blocking, or waited on.
+/// If the handle is dropped before the reply arrived, the reply is discarded when it arrives.
+#[derive(Debug)]
+#[must_use = "the reply is discarded when the handle is dropped"]
+pub struct PendingReply {
+    /// The id of the sent request.
+    id: u32,
+    /// The action that was sent, applied to the cached state once the lamp confirms it.
+    action: Action,
+    /// The inbox receiving the reply.
+    inbox: Arc<Inbox>,
+    /// The methods supported by the lamp, if they are known.
+    supported: Option<Arc<[String]>>,
+    /// Whether the reply was taken, so that the id doesn't need to be forgotten on drop.
+    done: bool,
+}
+
+impl LampBuilder {
+    /// Give up connecting to an address after a (non-zero) timeout.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
     }
 
-    /// Create a new Lamp from an IP address (or several addresses), using a non-zero timeout period.
+    /// Give up connecting after a (non-zero) timeout in total, even if the address resolves to several addresses.
     ///
-    /// As previously, the addr argument can be anything implementing the [`ToSocketAddrs`] trait.
-    /// The first successful connection will be used.
-    /// If no address provides a connection, the most recent (i.e. last) error will be returned.
-    pub fn connect_timeout<A: ToSocketAddrs>(addr: A, timeout: Duration) -> std::io::Result<Self> {
+    /// The remaining time is split evenly between the addresses that weren't tried yet,
+    /// so an unreachable address can't use up the time of the others.
+    /// If [`LampBuilder::connect_timeout`] is set as well, each address gets the shorter of both timeouts.
+    pub fn total_connect_timeout(mut self, timeout: Duration) -> Self {
+        self.total_connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Set the read timeout of the stream.
+    ///
+    /// Replies are read by a background thread, which isn't affected by the timeout;
+    /// use [`Lamp::set_call_timeout`] for limiting how long calls wait.
+    pub fn read_timeout(mut self, timeout: Duration) -> Self {
+        self.read_timeout = Some(timeout);
+        self
+    }
+
+    /// Set the write timeout of the stream.
+    pub fn write_timeout(mut self, timeout: Duration) -> Self {
+        self.write_timeout = Some(timeout);
+        self
+    }
+
+    /// Enable or disable TCP_NODELAY, which sends small requests immediately instead of batching them.
+    pub fn nodelay(mut self, nodelay: bool) -> Self {
+        self.nodelay = Some(nodelay);
+        self
+    }
+
+    /// Enable TCP keepalive, probing the connection after it was idle for the given time.
+    pub fn keepalive(mut self, idle: Duration) -> Self {
+        self.keepalive = Some(idle);
+        self
+    }
+
+    /// Set the size of the receive buffer of the socket (SO_RCVBUF).
+    pub fn recv_buffer_size(mut self, size: usize) -> Self {
+        self.recv_buffer_size = Some(size);
+        self
+    }
+
+    /// Set the size of the send buffer of the socket (SO_SNDBUF).
+    pub fn send_buffer_size(mut self, size: usize) -> Self {
+        self.send_buffer_size = Some(size);
+        self
+    }
+
+    /// Bind the socket to a local address before connecting, e.g. to reach the lamp through a specific interface.
+    ///
+    /// On hosts with several interfaces, the operating system picks the interface of the default route,
+    /// which may not reach the network of the lamps. Use port 0 to pick any free port.
+    /// Only addresses of the same family (IPv4 or IPv6) as the local address are tried.
+    pub fn local_addr(mut self, addr: SocketAddr) -> Self {
+        self.local_addr = Some(addr);
+        self
+    }
+
+    /// Connect to a lamp with the options of the builder, see [`Lamp::connect`].
+    pub fn connect<A: ToSocketAddrs>(self, addr: A) -> Result<Lamp, ConnectError> {
+        let stream = self.connect_stream(addr)?;
+        Ok(Lamp::from_stream(stream, self)?)
+    }
+
+    /// Connect to a discovered lamp with the options of the builder, see [`Lamp::connect_from_info`].
+    pub fn connect_from_info(self, info: &LampInfo) -> Result<Lamp, ConnectError> {
+        let mut lamp = self.connect(info.location)?;
+        lamp.set_device_id(Some(info.id.clone()));
+        if !info.support.is_empty() {
+            lamp.set_supported_methods(Some(info.support.clone()));
+        }
+        lamp.set_model(info.model.clone());
+        lamp.inbox.update_state(|state| *state = info.state.clone());
+        Ok(lamp)
+    }
+
+    /// Connect to a lamp with the options of the builder, retrying failed attempts according to the policy.
+    ///
+    /// See [`Lamp::connect_with_retries`].
+    pub fn connect_with_retries<A: ToSocketAddrs>(
+        self,
+        addr: A,
+        policy: RetryPolicy,
+    ) -> Result<Lamp, ConnectError> {
+        let mut attempts = 1;
+        loop {
+            match self.connect(&addr) {
+                Ok(lamp) => return Ok(lamp),
+                // invalid arguments won't get better
+                Err(ConnectError::Io(err)) if err.kind() == ErrorKind::InvalidInput => {
+                    return Err(ConnectError::Io(err));
+                }
+                Err(err) if attempts < policy.max_attempts => {
+                    let delay = policy.delay(attempts);
+                    debug!(
+                        "Lamp | Connecting again in {}ms after: {err}",
+                        delay.as_millis()
+                    );
+                    std::thread::sleep(delay);
+                    attempts += 1;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Connect to the first address that works, applying the options to the socket.
+    pub(crate) fn connect_stream<A: ToSocketAddrs>(
+        &self,
+        addr: A,
+    ) -> Result<TcpStream, ConnectError> {
         // Check that timeout is non-zero
-        if timeout.is_zero() {
+        if [self.connect_timeout, self.total_connect_timeout]
+            .iter()
+            .flatten()
+            .any(Duration::is_zero)
+        {
             debug!("Lamp | Zero timeout passed to connect_timeout");
-            return Err(Error::new(
+            return Err(ConnectError::Io(Error::new(
                 ErrorKind::InvalidInput,
                 "Non-zero timeout Duration required",
-            ));
+            )));
         }
-        debug!("Lamp | Connecting with timeout");
         // Keep track of the most recent error
         // (inspired by std::sys::net::connection::each_addr function, which is used by TcpStream)
         // (see https://doc.rust-lang.org/src/std/sys/net/connection/mod.rs.html)
         let mut last_err = None;
-        // Get iterator of socket addresses
+        let deadline = self
+            .total_connect_timeout
+            .map(|timeout| Instant::now() + timeout);
+        // Get the socket addresses (collected for splitting the total timeout between them)
         // And try each of them to see what works
-        for sock_addr in addr.to_socket_addrs()? {
-            // Try to connect
-            debug!("Lamp | Attempt connect_timeout");
-            let mby_stream = TcpStream::connect_timeout(&sock_addr, timeout);
-            match mby_stream {
+        let addrs = addr.to_socket_addrs()?.collect::<Vec<_>>();
+        for (index, &sock_addr) in addrs.iter().enumerate() {
+            let timeout = match deadline {
+                Some(deadline) => {
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    if remaining.is_zero() {
+                        debug!("Lamp | Total connect timeout elapsed");
+                        last_err = Some((sock_addr, Error::from(ErrorKind::TimedOut)));
+                        break;
+                    }
+                    let share = remaining / u32::try_from(addrs.len() - index).unwrap_or(u32::MAX);
+                    Some(
+                        self.connect_timeout
+                            .map_or(share, |timeout| timeout.min(share)),
+                    )
+                }
+                None => self.connect_timeout,
+            };
+            debug!("Lamp | Attempt connect");
+            match self.connect_addr(sock_addr, timeout) {
                 Ok(stream) => {
-                    debug!("Lamp | Connection with timeout Successful");
-                    return Ok(Self { stream });
+                    debug!("Lamp | Connection Successful");
+                    return Ok(stream);
                 }
-                Err(e) => last_err = Some(e),
+                Err(err) => last_err = Some((sock_addr, err)),
             }
         }
-        debug!("Lamp | Connection with timeout Failed");
-        match last_err {
-            Some(err) => Err(err),
-            None => Err(Error::new(ErrorKind::InvalidInput, "No addresses provided")),
+        debug!("Lamp | Connection Failed");
+        Err(ConnectError::diagnose(last_err))
+    }
+
+    /// Connect to a single address within the timeout (if any), applying the options to the socket.
+    fn connect_addr(
+        &self,
+        addr: SocketAddr,
+        timeout: Option<Duration>,
+    ) -> std::io::Result<TcpStream> {
+        if let Some(local) = self.local_addr
+            && local.is_ipv4() != addr.is_ipv4()
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                std::format!("{addr} can't be reached from the local address {local}"),
+            ));
+        }
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+        if let Some(local) = self.local_addr {
+            socket.bind(&local.into())?;
+        }
+        // Buffer sizes must be set before connecting to affect the TCP window
+        if let Some(size) = self.recv_buffer_size {
+            socket.set_recv_buffer_size(size)?;
+        }
+        if let Some(size) = self.send_buffer_size {
+            socket.set_send_buffer_size(size)?;
         }
+        if let Some(nodelay) = self.nodelay {
+            socket.set_tcp_nodelay(nodelay)?;
+        }
+        if let Some(idle) = self.keepalive {
+            socket.set_tcp_keepalive(&TcpKeepalive::new().with_time(idle))?;
+        }
+        match timeout {
+            Some(timeout) => socket.connect_timeout(&addr.into(), timeout)?,
+            None => socket.connect(&addr.into())?,
+        }
+        let stream = TcpStream::from(socket);
+        stream.set_read_timeout(self.read_timeout)?;
+        stream.set_write_timeout(self.write_timeout)?;
+        Ok(stream)
     }
 
-    /// Send a command to the lamp.
+    /// Connect to the address of a lamp again, see [`Lamp::reconnect`].
+    pub(crate) fn reconnect_addr(&self, addr: SocketAddr) -> std::io::Result<TcpStream> {
+        self.connect_addr(addr, self.connect_timeout.or(self.total_connect_timeout))
+    }
+}
+
+impl Heartbeat {
+    /// Ping the lamp until stopped, reporting health transitions through the inbox.
     ///
-    /// This command takes a reference to a [`Command`], so it does not consume the command.
-    pub fn send_cmd(&mut self, cmd: &Command) -> std::io::Result<()> {
-        //self.stream.write
-        debug!("Lamp | Sending command {cmd:?}");
-        write!(self, "{}\r\n", cmd)
+    /// With a rate limit, each ping takes a command out of its bucket, and pings exceeding the limit are skipped.
+    fn run(
+        self,
+        inbox: &Inbox,
+        ids: &dyn IdGenerator,
+        bucket: Option<&Mutex<TokenBucket>>,
+        stop: &AtomicBool,
+    ) {
+        let ping = Command::get_prop(&[Property::Power]);
+        let mut buf = Vec::new();
+        let mut missed = 0;
+        loop {
+            let next = Instant::now() + self.interval;
+            while !stop.load(Ordering::Relaxed) && Instant::now() < next {
+                std::thread::park_timeout(next.saturating_duration_since(Instant::now()));
+            }
+            if stop.load(Ordering::Relaxed) {
+                break;
+            }
+            let limited = bucket.is_some_and(|bucket| {
+                bucket
+                    .lock()
+                    .unwrap_or_else(|poisoned| poisoned.into_inner())
+                    .take()
+                    .is_err()
+            });
+            if limited {
+                debug!("Lamp | Skipping a heartbeat exceeding the rate limit");
+                continue;
+            }
+            let id = ids.next_id();
+            ping.encode_with_id(id, &mut buf);
+            inbox.expect(id);
+            let replied = match inbox.send(&buf) {
+                Ok(()) => inbox.wait(id, Instant::now() + self.timeout).is_ok(),
+                Err(err) => {
+                    inbox.forget(id);
+                    debug!("Lamp | Sending heartbeat failed: {err}");
+                    false
+                }
+            };
+            if replied {
+                missed = 0;
+                if inbox.set_healthy(true) {
+                    inbox.emit(&ConnectionEvent::Healthy);
+                }
+            } else {
+                missed += 1;
+                if missed >= self.max_missed && inbox.set_healthy(false) {
+                    inbox.emit(&ConnectionEvent::Unhealthy { missed });
+                }
+            }
+        }
+        debug!("Lamp | Heartbeat stopped");
     }
 }
 
-// Delegate reading/writing to the internal stream.
-impl Read for Lamp {
-    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        self.stream.read(buf)
+impl LampReader {
+    /// Wait for the next notification, or None once the connection is closed.
+    pub fn recv(&self) -> Option<Notification> {
+        self.notifications.recv().ok()
+    }
+
+    /// Wait for the next notification, for the given timeout at most.
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<Notification, mpsc::RecvTimeoutError> {
+        self.notifications.recv_timeout(timeout)
+    }
+
+    /// Get the next notification if one arrived, without blocking.
+    pub fn try_recv(&self) -> Result<Notification, mpsc::TryRecvError> {
+        self.notifications.try_recv()
     }
 }
 
-impl Write for Lamp {
-    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        self.stream.write(buf)
+impl<T: Transport> LampWriter<T> {
+    /// Get the whole Lamp back, e.g. for closing it (the reader keeps receiving notifications until then).
+    pub fn into_inner(self) -> Lamp<T> {
+        self.lamp
+    }
+}
+
+impl<T: Transport> SharedLamp<T> {
+    /// Share a lamp.
+    pub fn new(lamp: Lamp<T>) -> Self {
+        Self(Arc::new(Mutex::new(lamp)))
     }
 
-    fn flush(&mut self) -> std::io::Result<()> {
-        self.stream.flush()
+    /// Lock the lamp for exclusive access, e.g. for changing its settings or sending several commands in a row.
+    ///
+    /// Other threads using the lamp wait until the guard is dropped.
+    pub fn lock(&self) -> MutexGuard<'_, Lamp<T>> {
+        self.0
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Send a command to the lamp, see [`Lamp::send_cmd`].
+    pub fn send_cmd(&self, cmd: &Command) -> std::io::Result<u32> {
+        self.lock().send_cmd(cmd)
+    }
+
+    /// Send an action to the lamp with its default effect, see [`Lamp::send_action`].
+    pub fn send_action(&self, action: impl Into<Action>) -> std::io::Result<u32> {
+        self.lock().send_action(action)
+    }
+
+    /// Send a command to the lamp and wait for its response, see [`Lamp::call`].
+    ///
+    /// The lamp stays locked until the response arrived (or the call failed).
+    /// For letting other threads send commands in the meantime, use [`SharedLamp::send_cmd_no_wait`] instead.
+    pub fn call(&self, cmd: &Command) -> Result<Vec<Value>, CallError> {
+        self.lock().call(cmd)
+    }
+
+    /// Send a command to the lamp without waiting for its response, see [`Lamp::send_cmd_no_wait`].
+    ///
+    /// The lamp is only locked while sending, so the reply can be awaited without blocking other threads.
+    pub fn send_cmd_no_wait(&self, cmd: &Command) -> std::io::Result<PendingReply> {
+        self.lock().send_cmd_no_wait(cmd)
+    }
+
+    /// Get the last known state of the lamp, see [`Lamp::state`].
+    pub fn state(&self) -> LampState {
+        self.lock().state()
+    }
+}
+
+impl TokenBucket {
+    /// Create a full bucket.
+    fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            tokens: limit.capacity,
+            refilled: Instant::now(),
+        }
+    }
+
+    /// Add back the commands whose refill interval passed.
+    fn refill(&mut self, now: Instant) {
+        let interval = self.limit.refill_interval;
+        if interval.is_zero() {
+            self.tokens = self.limit.capacity;
+            return;
+        }
+        let elapsed = now.saturating_duration_since(self.refilled);
+        let added = u32::try_from(elapsed.as_nanos() / interval.as_nanos()).unwrap_or(u32::MAX);
+        if added > 0 {
+            self.tokens = self.tokens.saturating_add(added).min(self.limit.capacity);
+            self.refilled = if self.tokens == self.limit.capacity {
+                now
+            } else {
+                self.refilled + interval * added
+            };
+        }
+    }
+
+    /// Take a command out of the bucket, or return how long to wait until one is added back.
+    fn take(&mut self) -> Result<(), Duration> {
+        let interval = self.limit.refill_interval;
+        if interval.is_zero() {
+            return Ok(());
+        }
+        let now = Instant::now();
+        self.refill(now);
+        if self.tokens == 0 {
+            return Err((self.refilled + interval).saturating_duration_since(now));
+        }
+        self.tokens -= 1;
+        Ok(())
+    }
+}
+
+impl RateLimiter {
+    /// Create a limiter with a full bucket.
+    fn new(limit: RateLimit) -> Self {
+        Self {
+            bucket: Arc::new(Mutex::new(TokenBucket::new(limit))),
+            queue: Arc::new(QueueOwner(Arc::default())),
+        }
+    }
+
+    /// Get the limit.
+    fn limit(&self) -> RateLimit {
+        self.lock().limit
+    }
+
+    /// Take a command out of the bucket, or return how long to wait until one is added back.
+    fn take(&self) -> Result<(), Duration> {
+        self.lock().take()
+    }
+
+    /// Get how many commands can be sent right away.
+    fn available(&self) -> u32 {
+        let mut bucket = self.lock();
+        bucket.refill(Instant::now());
+        bucket.tokens
+    }
+
+    /// Wait until a command can be sent.
+    fn wait(bucket: &Mutex<TokenBucket>) {
+        loop {
+            let res = bucket
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .take();
+            match res {
+                Ok(()) => return,
+                Err(delay) => std::thread::sleep(delay),
+            }
+        }
+    }
+
+    /// Queue a request for the queue thread if it can't be sent right away, returning whether it was queued.
+    ///
+    /// Requests queued earlier are sent first, so that the order is kept.
+    /// The key is the [`Command::coalesce_key`] of the request, if any.
+    /// When a queued request is dropped or replaced, the reply to its id fails (see [`Inbox::discard`]).
+    fn enqueue(
+        &self,
+        inbox: &Arc<Inbox>,
+        id: Option<u32>,
+        key: Option<&str>,
+        request: &[u8],
+    ) -> std::io::Result<bool> {
+        let queue = &self.queue.0;
+        let mut state = queue.lock();
+        if state.requests.is_empty() && !state.sending && self.take().is_ok() {
+            return Ok(false);
+        }
+        debug!("Lamp | Queueing a request exceeding the rate limit");
+        if !state.started {
+            let (queue, bucket) = (Arc::clone(queue), Arc::clone(&self.bucket));
+            let inbox = Arc::clone(inbox);
+            let _handle = std::thread::Builder::new()
+                .name("yeelight-queue".into())
+                .spawn(move || queue.run(&bucket, &inbox))?;
+            state.started = true;
+        }
+        let bound = self.limit().queue;
+        if bound.coalesce
+            && let Some(pos) = state
+                .requests
+                .iter()
+                .position(|queued| key.is_some() && queued.key.as_deref() == key)
+        {
+            debug!("Lamp | Coalescing a queued request");
+            let replaced = state.requests.remove(pos);
+            inbox.discard(replaced.and_then(|queued| queued.id));
+        }
+        while state.requests.len() >= bound.capacity.max(1) {
+            let same = state
+                .requests
+                .iter()
+                .position(|queued| key.is_some() && queued.key.as_deref() == key);
+            match (bound.overflow, same) {
+                (QueueOverflow::DropOldest, _) => {
+                    debug!("Lamp | Dropping the oldest queued request");
+                    let dropped = state.requests.pop_front();
+                    inbox.discard(dropped.and_then(|queued| queued.id));
+                }
+                (QueueOverflow::Coalesce, Some(pos)) => {
+                    debug!("Lamp | Replacing a queued request");
+                    let replaced = state.requests.remove(pos);
+                    inbox.discard(replaced.and_then(|queued| queued.id));
+                }
+                (QueueOverflow::Block | QueueOverflow::Coalesce, _) => {
+                    state = queue
+                        .changed
+                        .wait(state)
+                        .unwrap_or_else(|poisoned| poisoned.into_inner());
+                }
+            }
+        }
+        state.requests.push_back(Queued {
+            id,
+            key: key.map(ToOwned::to_owned),
+            request: request.to_vec(),
+        });
+        queue.changed.notify_all();
+        Ok(true)
+    }
+
+    /// Lock the bucket.
+    fn lock(&self) -> MutexGuard<'_, TokenBucket> {
+        self.bucket
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl CommandQueue {
+    /// Send the queued requests as the rate limit allows, until the queue is closed.
+    fn run(&self, bucket: &Mutex<TokenBucket>, inbox: &Inbox) {
+        loop {
+            let mut state = self.lock();
+            while state.requests.is_empty() && !state.closed {
+                state = self
+                    .changed
+                    .wait(state)
+                    .unwrap_or_else(|poisoned| poisoned.into_inner());
+            }
+            if state.closed {
+                break;
+            }
+            drop(state);
+            RateLimiter::wait(bucket);
+            let mut state = self.lock();
+            // the queue may have been closed (or the requests dropped) while waiting
+            let Some(queued) = state.requests.pop_front().filter(|_| !state.closed) else {
+                continue;
+            };
+            state.sending = true;
+            self.changed.notify_all();
+            drop(state);
+            if let Err(err) = inbox.send(&queued.request) {
+                debug!("Lamp | Sending a queued request failed: {err}");
+            }
+            self.lock().sending = false;
+        }
+        debug!("Lamp | Queue stopped");
+    }
+
+    /// Lock the state of the queue.
+    fn lock(&self) -> MutexGuard<'_, QueueState> {
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl BackgroundThread {
+    /// Stop the thread, waking it up if it's waiting for the next ping.
+    fn stop(&self) {
+        self.stop.store(true, Ordering::Relaxed);
+        self.thread.unpark();
+    }
+}
+
+impl ConnectError {
+    /// Explain the last error of a connection attempt, or that there was no address to connect to.
+    fn diagnose(last_err: Option<(SocketAddr, Error)>) -> Self {
+        match last_err {
+            Some((addr, source))
+                if addr.port() == LAMP_PORT
+                    && matches!(
+                        source.kind(),
+                        ErrorKind::ConnectionRefused | ErrorKind::ConnectionReset
+                    ) =>
+            {
+                Self::LanControlDisabled { addr, source }
+            }
+            Some((_, err)) => Self::Io(err),
+            None => Self::Io(Error::new(ErrorKind::InvalidInput, "No addresses provided")),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Get the delay before the given retry (starting at 1), see [`RetryPolicy`].
+    pub fn delay(&self, retry: u32) -> Duration {
+        let factor = 1u32
+            .checked_shl(retry.saturating_sub(1))
+            .unwrap_or(u32::MAX);
+        let delay = self
+            .initial_backoff
+            .saturating_mul(factor)
+            .min(self.max_backoff);
+        if !self.jitter {
+            return delay;
+        }
+        // The clock is a good enough source of randomness for spreading out retries
+        let nanos = SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |now| now.subsec_nanos());
+        delay.mul_f64(1.0 - f64::from(nanos % 1000) / 2000.0)
+    }
+}
+
+impl CallError {
+    /// Whether the call may succeed if it's retried.
+    ///
+    /// Timeouts, quota errors and transient socket errors (such as an interrupted or timed out read) are retryable.
+    /// Errors sent by the lamp (apart from the quota), invalid replies, closed connections and dropped requests are terminal.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::Timeout | Self::QuotaExceeded(_) => true,
+            Self::Io(err) => matches!(
+                err.kind(),
+                ErrorKind::TimedOut | ErrorKind::WouldBlock | ErrorKind::Interrupted
+            ),
+            Self::Lamp(_)
+            | Self::Unsupported { .. }
+            | Self::InvalidResponse(_)
+            | Self::Disconnected(_)
+            | Self::Dropped => false,
+        }
+    }
+}
+
+impl PendingReply {
+    /// Get the id of the sent request.
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// Check whether the reply arrived, without blocking.
+    ///
+    /// Returns None while the reply is pending, and the result of the call (as in [`Lamp::call`]) once it arrived.
+    /// The result is returned only once; afterwards, the handle returns None.
+    pub fn poll(&mut self) -> Option<Result<Vec<Value>, CallError>> {
+        if self.done {
+            return None;
+        }
+        let reply = self.inbox.try_take(self.id)?;
+        self.done = true;
+        Some(self.finish(reply))
+    }
+
+    /// Wait for the reply, for the given timeout at most.
+    pub fn wait(self, timeout: Duration) -> Result<Vec<Value>, CallError> {
+        self.wait_until(Instant::now() + timeout)
+    }
+
+    /// Wait for the reply, until the deadline at most.
+    fn wait_until(mut self, deadline: Instant) -> Result<Vec<Value>, CallError> {
+        if self.done {
+            return Err(CallError::Timeout);
+        }
+        self.done = true;
+        let reply = self.inbox.wait(self.id, deadline);
+        self.finish(reply)
+    }
+
+    /// Turn the reply into the result of the call, applying a confirmed action to the cached state.
+    fn finish(&self, reply: Result<Response, CallError>) -> Result<Vec<Value>, CallError> {
+        match reply? {
+            Response::Error { error, .. } if error.kind() == LampErrorCode::QuotaExceeded => {
+                Err(CallError::QuotaExceeded(error))
+            }
+            Response::Error { error, .. } if error.kind() == LampErrorCode::UnsupportedMethod => {
+                Err(CallError::Unsupported {
+                    kind: self.action.kind(),
+                    method: self.action.method().to_owned(),
+                    supported: self.supported.clone(),
+                    error,
+                })
+            }
+            Response::Error { error, .. } => Err(CallError::Lamp(error)),
+            Response::Result { result, .. } => {
+                self.inbox
+                    .update_state(|state| state.apply_action(&self.action));
+                Ok(result)
+            }
+            // only replies are routed to waiters
+            Response::Props(_) => Err(CallError::Timeout),
+        }
+    }
+}
+
+impl IdCounter {
+    /// Create a new counter whose first id is the given id.
+    pub fn starting_at(first: u32) -> Self {
+        Self(AtomicU32::new(first))
+    }
+}
+
+impl Lamp {
+    /// Create a new Lamp from an IP address (or several addresses).
+    ///
+    /// The argument can be anything that implements [`ToSocketAddrs`], such as String, &str, or (&str, u16).
+    /// You can pass multiple addresses into the method, and the TcpStream will use the first successful connection.
+    /// If no address provides a connection, the most recent (i.e. last) error will be returned.
+    /// If the lamp refused the connection, [`ConnectError::LanControlDisabled`] is returned.
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self, ConnectError> {
+        Self::builder().connect(addr)
+    }
+
+    /// Create a new Lamp from an IP address (or several addresses), using a non-zero timeout period.
+    ///
+    /// As previously, the addr argument can be anything implementing the [`ToSocketAddrs`] trait.
+    /// The first successful connection will be used.
+    /// If no address provides a connection, the most recent (i.e. last) error will be returned.
+    /// If the lamp refused the connection, [`ConnectError::LanControlDisabled`] is returned.
+    pub fn connect_timeout<A: ToSocketAddrs>(
+        addr: A,
+        timeout: Duration,
+    ) -> Result<Self, ConnectError> {
+        Self::builder().connect_timeout(timeout).connect(addr)
+    }
+
+    /// Create a new Lamp from an IP address (or several addresses), retrying failed attempts with backoff.
+    ///
+    /// Lamps coming out of deep power-save (or behind a rebooting router) frequently reject the first attempt.
+    /// Each attempt tries all addresses, as in [`Lamp::connect`]; the attempts are limited and spaced out by the policy.
+    /// The error of the last attempt is returned. Invalid arguments aren't retried.
+    pub fn connect_with_retries<A: ToSocketAddrs>(
+        addr: A,
+        policy: RetryPolicy,
+    ) -> Result<Self, ConnectError> {
+        Self::builder().connect_with_retries(addr, policy)
+    }
+
+    /// Create a new Lamp from a discovered lamp, see [`Discovery::search`](crate::discovery::Discovery::search).
+    ///
+    /// The Lamp connects to the advertised `Location` and follows the lamp by its id (see [`Lamp::set_device_id`]).
+    /// The advertised methods become the supported methods (see [`Lamp::set_supported_methods`]),
+    /// and the advertised properties seed the cached state (see [`Lamp::state`]), so no query is needed at startup.
+    pub fn connect_from_info(info: &LampInfo) -> Result<Self, ConnectError> {
+        Self::builder().connect_from_info(info)
+    }
+
+    /// Create a new Lamp from an IP address (or several addresses), giving up after a (non-zero) timeout in total.
+    ///
+    /// Unlike [`Lamp::connect_timeout`], whose timeout applies to each address,
+    /// this bounds the time spent on all addresses (see [`LampBuilder::total_connect_timeout`]).
+    pub fn connect_total_timeout<A: ToSocketAddrs>(
+        addr: A,
+        timeout: Duration,
+    ) -> Result<Self, ConnectError> {
+        Self::builder().total_connect_timeout(timeout).connect(addr)
+    }
+
+    /// Get the current options of the connection.
+    ///
+    /// The nonblocking mode can't be read from a stream, so it's the one last applied by this Lamp.
+    pub fn connection_options(&self) -> std::io::Result<ConnectionOptions> {
+        Ok(ConnectionOptions {
+            read_timeout: self.stream.read_timeout()?,
+            write_timeout: self.stream.write_timeout()?,
+            nodelay: self.stream.nodelay()?,
+            nonblocking: self.nonblocking,
+        })
+    }
+
+    /// Apply all options to the connection.
+    ///
+    /// If an option can't be applied (e.g. because a timeout is zero),
+    /// the previous options are restored before the error is returned, so the options are never half-applied.
+    pub fn apply_connection_options(&mut self, options: &ConnectionOptions) -> std::io::Result<()> {
+        let previous = self.connection_options()?;
+        if let Err(err) = self.set_options(options) {
+            debug!("Lamp | Restoring connection options after: {err}");
+            let _restored = self.set_options(&previous);
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    /// Set the options of the connection one by one.
+    fn set_options(&mut self, options: &ConnectionOptions) -> std::io::Result<()> {
+        self.stream.set_read_timeout(options.read_timeout)?;
+        self.stream.set_write_timeout(options.write_timeout)?;
+        self.stream.set_nodelay(options.nodelay)?;
+        self.switch_mode(options.nonblocking)
+    }
+
+    /// Create a builder for connecting with tuned socket options, such as TCP_NODELAY or keepalive.
+    /// ```no_run
+    /// # use std::time::Duration;
+    /// # use yeerugina_lib::lamp::Lamp;
+    /// # fn main() -> std::io::Result<()> {
+    /// let lamp = Lamp::builder()
+    ///     .connect_timeout(Duration::from_secs(2))
+    ///     .nodelay(true)
+    ///     .keepalive(Duration::from_secs(30))
+    ///     .connect("192.168.1.20:55443")?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn builder() -> LampBuilder {
+        LampBuilder::default()
+    }
+
+    /// Create a new Lamp from a connected stream, using the default settings, and start its reader thread.
+    fn from_stream(stream: TcpStream, builder: LampBuilder) -> std::io::Result<Self> {
+        let mut lamp = Self::unread(stream, builder);
+        lamp.epoch = lamp.inbox.spawn_reader(lamp.stream.try_clone()?)?;
+        Ok(lamp)
+    }
+
+    /// Create a new Lamp from a connected stream, whose connection is read by an event loop instead of a reader thread.
+    ///
+    /// The connection is switched to nonblocking mode. Returns the lamp, its inbox,
+    /// the stream to read from and the epoch of the connection (see [`Inbox::attach`]).
+    #[cfg(feature = "event-loop")]
+    pub(crate) fn from_stream_polled(
+        stream: TcpStream,
+        builder: LampBuilder,
+    ) -> std::io::Result<(Self, Arc<Inbox>, TcpStream, u64)> {
+        let mut lamp = Self::unread(stream, builder);
+        let reader = lamp.stream.try_clone()?;
+        // the clone shares the mode with the stream of the lamp
+        reader.set_nonblocking(true)?;
+        lamp.nonblocking = true;
+        let epoch = lamp.inbox.attach(&reader)?;
+        lamp.epoch = epoch;
+        let inbox = Arc::clone(&lamp.inbox);
+        Ok((lamp, inbox, reader, epoch))
+    }
+}
+
+impl<T: Transport> Lamp<T> {
+    /// Get the connection to the lamp, e.g. for reading its addresses.
+    pub fn stream(&self) -> &T {
+        &self.stream
+    }
+
+    /// Create a new Lamp communicating over a connected transport, using the default settings, and start its reader thread.
+    ///
+    /// Lamps created this way reconnect only if the transport supports it (see [`Transport::reconnect`]).
+    /// ```no_run
+    /// # use std::os::unix::net::UnixStream;
+    /// # use yeerugina_lib::lamp::Lamp;
+    /// # fn main() -> std::io::Result<()> {
+    /// let lamp = Lamp::with_transport(UnixStream::connect("/run/yeelight-bridge.sock")?)?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn with_transport(transport: T) -> std::io::Result<Self> {
+        let mut lamp = Self::unread(transport, LampBuilder::default());
+        lamp.epoch = lamp.inbox.spawn_reader(lamp.stream.try_clone()?)?;
+        Ok(lamp)
+    }
+
+    /// Switch the connection to nonblocking mode (or back), e.g. for integrating the lamp into an event loop.
+    ///
+    /// In nonblocking mode, requests that can't be written at once are buffered (see [`Lamp::poll_flush`]),
+    /// and the responses nobody waits for are queued for [`Lamp::poll_read_response`].
+    /// See [`Lamp`] for which methods still block.
+    pub fn set_nonblocking(&mut self, nonblocking: bool) -> std::io::Result<()> {
+        let previous = self.nonblocking;
+        if let Err(err) = self.switch_mode(nonblocking) {
+            debug!("Lamp | Restoring the blocking mode after: {err}");
+            let _restored = self.switch_mode(previous);
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    /// Switch the transport to nonblocking mode (or back), writing the buffered requests when switching back.
+    fn switch_mode(&mut self, nonblocking: bool) -> std::io::Result<()> {
+        self.stream.set_nonblocking(nonblocking)?;
+        self.nonblocking = nonblocking;
+        self.inbox.set_queueing(nonblocking);
+        if !self.nonblocking && !self.pending.is_empty() {
+            self.stream.write_all(&self.pending)?;
+            self.pending.clear();
+        }
+        Ok(())
+    }
+
+    /// Write as much of the buffered requests as possible without blocking, returning whether everything was written.
+    ///
+    /// Requests are only buffered in nonblocking mode (see [`Lamp::set_nonblocking`]),
+    /// so an event loop should call this when the socket becomes writable while [`Lamp::has_pending_writes`].
+    pub fn poll_flush(&mut self) -> std::io::Result<bool> {
+        self.follow()?;
+        while !self.pending.is_empty() {
+            match self.stream.write(&self.pending) {
+                Ok(0) => return Err(Error::from(ErrorKind::WriteZero)),
+                Ok(len) => {
+                    let _written = self.pending.drain(..len);
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => return Ok(false),
+                Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(true)
+    }
+
+    /// Whether some requests couldn't be written yet, see [`Lamp::poll_flush`].
+    pub fn has_pending_writes(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// Get the next response nobody waits for, without blocking.
+    ///
+    /// In nonblocking mode (see [`Lamp::set_nonblocking`]), notifications and the replies to commands sent with
+    /// [`Lamp::send_cmd`] are queued (up to 1024, dropping the oldest ones) instead of being discarded.
+    /// Returns `Ok(None)` if no response arrived, and an [`ErrorKind::UnexpectedEof`] error once the connection is closed
+    /// and the queue is empty. The buffered requests are flushed first, as with [`Lamp::poll_flush`].
+    pub fn poll_read_response(&mut self) -> std::io::Result<Option<Response>> {
+        let _flushed = self.poll_flush()?;
+        if let Some(response) = self.inbox.pop_response() {
+            return Ok(Some(response));
+        }
+        if self.inbox.is_closed() {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "connection closed by the lamp",
+            ));
+        }
+        Ok(None)
+    }
+
+    /// Write the encoded request in the buffer, buffering what can't be written yet in nonblocking mode.
+    ///
+    /// The request is held back first if it exceeds the rate limit (see [`Lamp::set_rate_limit`]).
+    /// The id and the [`Command::coalesce_key`] of the request are given if they're known, for coalescing queued requests
+    /// and failing the reply to a request dropped from the queue.
+    fn write_request(&mut self, id: Option<u32>, key: Option<&str>) -> std::io::Result<()> {
+        self.follow()?;
+        self.inbox.touch();
+        if let Some(limiter) = &self.rate_limiter {
+            match limiter.limit().mode {
+                RateLimitMode::Block => RateLimiter::wait(&limiter.bucket),
+                RateLimitMode::Queue => {
+                    if limiter.enqueue(&self.inbox, id, key, &self.buf)? {
+                        return Ok(());
+                    }
+                }
+                RateLimitMode::Error => {
+                    if let Err(delay) = limiter.take() {
+                        return Err(Error::new(
+                            ErrorKind::QuotaExceeded,
+                            std::format!(
+                                "the rate limit was exceeded, retry in {}ms",
+                                delay.as_millis()
+                            ),
+                        ));
+                    }
+                }
+            }
+        }
+        let res = if self.nonblocking {
+            self.pending.extend_from_slice(&self.buf);
+            self.poll_flush().map(|_flushed| ())
+        } else {
+            self.stream.write_all(&self.buf)
+        };
+        self.inbox.record_sent(&res, 1);
+        if let Err(err) = &res {
+            self.inbox.lose(self.epoch, err);
+        }
+        res
+    }
+
+    /// Move to the current connection if another Lamp sharing it reconnected in the meantime (see [`Lamp::try_clone`]).
+    ///
+    /// The requests that weren't written completely are lost with the previous connection.
+    fn follow(&mut self) -> std::io::Result<()> {
+        if self.inbox.epoch() == self.epoch {
+            return Ok(());
+        }
+        let Some(connection) = self.inbox.connection::<T>() else {
+            return Ok(());
+        };
+        let (epoch, stream) = connection?;
+        debug!("Lamp | Moving to the connection of a reconnected clone");
+        self.peer = stream.peer_addr().or(self.peer);
+        self.stream = stream;
+        self.epoch = epoch;
+        self.pending.clear();
+        // the mode is shared by all handles of a connection, as it was before reconnecting
+        if self.nonblocking {
+            self.stream.set_nonblocking(true)?;
+        }
+        Ok(())
+    }
+
+    /// Create a new Lamp from a connected stream, using the default settings, without reading from it.
+    fn unread(stream: T, builder: LampBuilder) -> Self {
+        Self {
+            peer: stream.peer_addr(),
+            epoch: 0,
+            device_id: None,
+            resolver: Arc::new(SsdpResolver::default()),
+            stream,
+            ct_fallback: false,
+            ids: Arc::new(IdCounter::default()),
+            default_effect: Effect::default(),
+            buf: Vec::new(),
+            inbox: Arc::new(Inbox::default()),
+            call_timeout: DEFAULT_CALL_TIMEOUT,
+            quota_backoff: None,
+            retry_policy: None,
+            supported: None,
+            model: None,
+            nonblocking: false,
+            pending: Vec::new(),
+            builder,
+            reconnect_policy: None,
+            resend_unacked: false,
+            heartbeat: None,
+            idle_timeout: None,
+            idle_watch: None,
+            rate_limiter: None,
+            music: None,
+            closed: false,
+            handles: Arc::new(AtomicUsize::new(1)),
+        }
+    }
+
+    /// Create another Lamp sharing the connection, e.g. for using it from several places in sync code.
+    ///
+    /// The clone shares the id generator, so the ids of both Lamps stay unique,
+    /// as well as the callbacks and the cached state. The other settings are copied.
+    /// The heartbeat (see [`Lamp::set_heartbeat`]) and the idle timeout (see [`Lamp::set_idle_timeout`])
+    /// keep running only once, for the original.
+    ///
+    /// The connection is shut down once all Lamps sharing it are dropped, or one of them is closed with [`Lamp::close`].
+    /// Reconnecting one of them (see [`Lamp::reconnect`]) moves all of them to the new connection;
+    /// the others move when they use it next, dropping the requests they couldn't write completely yet.
+    pub fn try_clone(&self) -> std::io::Result<Self> {
+        let stream = self.stream.try_clone()?;
+        let _prev = self.handles.fetch_add(1, Ordering::AcqRel);
+        Ok(Self {
+            stream,
+            ct_fallback: self.ct_fallback,
+            ids: Arc::clone(&self.ids),
+            default_effect: self.default_effect,
+            buf: Vec::new(),
+            inbox: Arc::clone(&self.inbox),
+            call_timeout: self.call_timeout,
+            quota_backoff: self.quota_backoff,
+            retry_policy: self.retry_policy,
+            supported: self.supported.clone(),
+            model: self.model.clone(),
+            nonblocking: self.nonblocking,
+            pending: Vec::new(),
+            peer: self.peer,
+            epoch: self.epoch,
+            device_id: self.device_id.clone(),
+            resolver: Arc::clone(&self.resolver),
+            builder: self.builder,
+            reconnect_policy: self.reconnect_policy,
+            resend_unacked: self.resend_unacked,
+            heartbeat: None,
+            idle_timeout: self.idle_timeout,
+            idle_watch: None,
+            rate_limiter: self.rate_limiter.clone(),
+            music: self.music.as_ref().map(|music| Music {
+                upgrade: music.upgrade,
+                session: None,
+                retry_at: None,
+            }),
+            closed: false,
+            handles: Arc::clone(&self.handles),
+        })
+    }
+
+    /// Start (or stop, with None) checking periodically that the lamp is still responsive.
+    ///
+    /// Transitions between healthy and unhealthy are reported as [`ConnectionEvent`]s (see [`Lamp::on_connection_event`]),
+    /// e.g. for reconnecting to an unhealthy lamp. The pings also keep lamps from dropping idle connections;
+    /// for detecting dead connections at the TCP level only, see [`LampBuilder::keepalive`] instead.
+    /// The pings count against the command quota of the lamp: they take part in the rate limit set when the heartbeat is started
+    /// (see [`Lamp::set_rate_limit`]), and are skipped while it's exceeded. They use the id generator set at that point as well.
+    /// ```no_run
+    /// # use std::time::Duration;
+    /// # use yeerugina_lib::lamp::{Heartbeat, Lamp};
+    /// # fn main() -> std::io::Result<()> {
+    /// let mut lamp = Lamp::connect("192.168.1.20:55443")?;
+    /// lamp.set_heartbeat(Some(Heartbeat {
+    ///     interval: Duration::from_secs(10),
+    ///     ..Heartbeat::default()
+    /// }))?;
+    /// for event in lamp.connection_events() {
+    ///     println!("{event}");
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn set_heartbeat(&mut self, heartbeat: Option<Heartbeat>) -> std::io::Result<()> {
+        if let Some(running) = self.heartbeat.take() {
+            running.stop();
+        }
+        let Some(heartbeat) = heartbeat else {
+            return Ok(());
+        };
+        let stop = Arc::new(AtomicBool::new(false));
+        let inbox = Arc::clone(&self.inbox);
+        let ids = Arc::clone(&self.ids);
+        let bucket = self
+            .rate_limiter
+            .as_ref()
+            .map(|limiter| Arc::clone(&limiter.bucket));
+        let stopped = Arc::clone(&stop);
+        let handle = std::thread::Builder::new()
+            .name("yeelight-heartbeat".into())
+            .spawn(move || heartbeat.run(&inbox, &*ids, bucket.as_deref(), &stopped))?;
+        self.heartbeat = Some(BackgroundThread {
+            stop,
+            thread: handle.thread().clone(),
+        });
+        Ok(())
+    }
+
+    /// Get after how long without commands the connection is closed, if at all.
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        self.idle_timeout
+    }
+
+    /// Close the connection once no commands were sent and no replies awaited for a timeout, or never (the default).
+    ///
+    /// Lamps only accept about 4 connections at once, so processes sharing a lamp can free their connection
+    /// while they don't use it. The next command reconnects transparently (see [`Lamp::reconnect`]),
+    /// even without a reconnect policy. Closing is reported as [`DisconnectReason::Idle`].
+    /// Notifications sent while the connection is closed are missed, and the pings of the heartbeat don't count as commands.
+    pub fn set_idle_timeout(&mut self, timeout: Option<Duration>) -> std::io::Result<()> {
+        if let Some(running) = self.idle_watch.take() {
+            running.stop();
+        }
+        self.idle_timeout = timeout;
+        let Some(timeout) = timeout else {
+            return Ok(());
+        };
+        let stop = Arc::new(AtomicBool::new(false));
+        let inbox = Arc::clone(&self.inbox);
+        let stopped = Arc::clone(&stop);
+        let handle = std::thread::Builder::new()
+            .name("yeelight-idle".into())
+            .spawn(move || {
+                while !stopped.load(Ordering::Relaxed) {
+                    let next = inbox.close_if_idle(timeout);
+                    while !stopped.load(Ordering::Relaxed) && Instant::now() < next {
+                        std::thread::park_timeout(next.saturating_duration_since(Instant::now()));
+                    }
+                }
+            })?;
+        self.idle_watch = Some(BackgroundThread {
+            stop,
+            thread: handle.thread().clone(),
+        });
+        Ok(())
+    }
+
+    /// Whether the connection is still open, i.e. the lamp didn't close it and it wasn't shut down.
+    pub fn is_connected(&self) -> bool {
+        !self.closed && !self.inbox.is_closed()
+    }
+
+    /// Get why the connection was lost, or None while it's open.
+    ///
+    /// The reason is also reported as [`ConnectionEvent::Disconnected`] when the connection is lost.
+    pub fn disconnect_reason(&self) -> Option<DisconnectReason> {
+        self.inbox.disconnect_reason()
+    }
+
+    /// Whether the lamp is responsive, according to the heartbeat (see [`Lamp::set_heartbeat`]).
+    ///
+    /// Without a heartbeat, the lamp is always considered healthy.
+    pub fn is_healthy(&self) -> bool {
+        self.inbox.is_healthy()
+    }
+
+    /// Get how a lost connection is re-established, if at all.
+    pub fn reconnect_policy(&self) -> Option<RetryPolicy> {
+        self.reconnect_policy
+    }
+
+    /// Set how a lost connection is re-established (by default, it isn't).
+    ///
+    /// Lamps regularly drop idle connections. With a policy, a command sent after the connection was lost
+    /// first reconnects to the lamp, using the options it was connected with (see [`Lamp::builder`])
+    /// and the current [`ConnectionOptions`]. The attempts are limited and spaced out by the policy.
+    /// [`Lamp::call`] also resends a command once if the connection was lost while waiting for its reply,
+    /// so the command may be carried out twice.
+    /// Replies pending on the lost connection (see [`Lamp::send_cmd_no_wait`]) fail or time out.
+    ///
+    /// Each reconnect is reported as a [`ConnectionEvent`] (see [`Lamp::on_connection_event`]).
+    pub fn set_reconnect_policy(&mut self, policy: Option<RetryPolicy>) {
+        self.reconnect_policy = policy;
+    }
+
+    /// Get the unique id of the lamp, if it's known.
+    pub fn device_id(&self) -> Option<&str> {
+        self.device_id.as_deref()
+    }
+
+    /// Set the unique id of the lamp, as reported by discovery (e.g. `0x000000000015243f`).
+    ///
+    /// Lamps usually get their address by DHCP, so it may change. With a device id, a reconnect
+    /// (see [`Lamp::set_reconnect_policy`]) that failed for good looks the lamp up by its id (see [`Lamp::set_resolver`])
+    /// and tries again at its new address, which is reported as [`ConnectionEvent::Moved`].
+    pub fn set_device_id(&mut self, device_id: Option<String>) {
+        self.device_id = device_id;
+    }
+
+    /// Replace how the address of the lamp is looked up by its device id (by default, [`SsdpResolver`]).
+    pub fn set_resolver<R: Resolver + 'static>(&mut self, resolver: R) {
+        self.resolver = Arc::new(resolver);
+    }
+
+    /// Look up the address of the lamp by its device id, if it's known and changed.
+    fn resolve_peer(&self) -> Option<SocketAddr> {
+        let device_id = self.device_id.as_deref()?;
+        match self.resolver.resolve(device_id) {
+            Ok(addr) => addr.filter(|&addr| Some(addr) != self.peer),
+            Err(err) => {
+                debug!("Lamp | Looking up {device_id} failed: {err}");
+                None
+            }
+        }
+    }
+
+    /// Whether commands that weren't replied to are resent after reconnecting.
+    pub fn resend_unacknowledged(&self) -> bool {
+        self.resend_unacked
+    }
+
+    /// Enable or disable resending the commands that weren't replied to after reconnecting (by default, they aren't).
+    ///
+    /// This gives at-least-once delivery for commands sent with [`Lamp::send_cmd`] and [`Lamp::send_cmd_no_wait`],
+    /// so state-setting commands sent during a connection blip aren't lost.
+    /// Commands are tracked by id until a reply with the id arrives, so each one is resent at most once per reconnect;
+    /// the latest 64 commands are tracked at most. Commands sent through [`Lamp::call`] aren't resent,
+    /// because the call resends them itself (see [`Lamp::set_reconnect_policy`]) or reports the error.
+    /// Resent commands count against the rate limit (see [`Lamp::set_rate_limit`]) like the others.
+    pub fn set_resend_unacknowledged(&mut self, enabled: bool) {
+        self.resend_unacked = enabled;
+    }
+
+    /// Re-establish the connection to the lamp now, as with [`Lamp::set_reconnect_policy`].
+    ///
+    /// Without a reconnect policy, a single attempt is made.
+    /// The callbacks and the cached state are kept; the previous connection is shut down once the new one is established,
+    /// so it's still used if reconnecting fails (e.g. because the transport can't reconnect).
+    /// The Lamps sharing the connection (see [`Lamp::try_clone`]) move to the new one as well.
+    pub fn reconnect(&mut self) -> std::io::Result<()> {
+        let policy = self.reconnect_policy.unwrap_or(RetryPolicy {
+            max_attempts: 1,
+            ..RetryPolicy::default()
+        });
+        let mut attempts = 1;
+        let mut resolved = false;
+        let stream = loop {
+            match T::reconnect(self.peer, &self.builder) {
+                Ok(stream) => break stream,
+                Err(err) if attempts < policy.max_attempts => {
+                    let delay = policy.delay(attempts);
+                    debug!(
+                        "Lamp | Reconnecting in {}ms after: {err}",
+                        delay.as_millis()
+                    );
+                    std::thread::sleep(delay);
+                    attempts += 1;
+                }
+                Err(err) => {
+                    // the lamp may have been given a new address, which is looked up once
+                    if !resolved {
+                        resolved = true;
+                        if let Some(addr) = self.resolve_peer() {
+                            debug!("Lamp | The lamp moved to {addr}");
+                            self.peer = Some(addr);
+                            self.inbox.emit(&ConnectionEvent::Moved { to: addr });
+                            attempts = 1;
+                            continue;
+                        }
+                    }
+                    self.inbox.record_error(Some(err.kind()), &err);
+                    self.inbox.emit(&ConnectionEvent::ReconnectFailed {
+                        attempts,
+                        kind: err.kind(),
+                    });
+                    return Err(err);
+                }
+            }
+        };
+        // the options changed since connecting are carried over
+        self.stream.carry_options_to(&stream)?;
+        // the reader of the previous connection stops without closing the inbox, which is attached to the new one
+        self.epoch = self.inbox.spawn_reader(stream.try_clone()?)?;
+        let _res = self.stream.shutdown(Shutdown::Both);
+        // requests that weren't written completely are lost with the connection
+        self.pending.clear();
+        self.stream = stream;
+        self.inbox.record_reconnect();
+        if self.nonblocking {
+            self.switch_mode(true)?;
+        }
+        let unacked = if self.resend_unacked {
+            self.inbox.unacknowledged()
+        } else {
+            Vec::new()
+        };
+        // the requests are written like the others, so they count against the rate limit;
+        // they stay tracked if writing fails, and the connection is reported as re-established either way
+        for (id, request) in unacked {
+            debug!("Lamp | Resending an unacknowledged request");
+            if let Ok(line) = std::str::from_utf8(&request) {
+                self.inbox.tap(Direction::Sent, line.trim_end());
+            }
+            self.buf = request;
+            if let Err(err) = self.write_request(Some(id), None) {
+                debug!("Lamp | Resending an unacknowledged request failed: {err}");
+            }
+        }
+        self.inbox.emit(&ConnectionEvent::Reconnected { attempts });
+        Ok(())
+    }
+
+    /// Register a callback invoked with each connection event, such as a reconnect.
+    ///
+    /// The callback runs on the thread that noticed the event, so it should return quickly.
+    pub fn on_connection_event<F>(&mut self, mut callback: F)
+    where
+        F: FnMut(&ConnectionEvent) + Send + 'static,
+    {
+        self.inbox.add_event_callback(Box::new(move |event| {
+            callback(event);
+            true
+        }));
+    }
+
+    /// Register a callback for connection events that is removed once it returns false.
+    pub(crate) fn add_event_callback(&self, callback: EventCallback) {
+        self.inbox.add_event_callback(callback);
+    }
+
+    /// Get a channel receiving the connection events from now on, see [`Lamp::on_connection_event`].
+    ///
+    /// Once the receiver is dropped, the channel is removed on the next event.
+    pub fn connection_events(&mut self) -> mpsc::Receiver<ConnectionEvent> {
+        let (tx, rx) = mpsc::channel();
+        self.inbox
+            .add_event_callback(Box::new(move |event| tx.send(*event).is_ok()));
+        rx
+    }
+
+    /// Re-establish the connection if it was lost and a reconnect policy is set (or it was idle),
+    /// or fail with the reason it was lost.
+    fn ensure_connected(&mut self) -> std::io::Result<()> {
+        self.follow()?;
+        let Some(reason) = self.inbox.disconnect_reason() else {
+            return Ok(());
+        };
+        if self.reconnect_policy.is_none() && reason != DisconnectReason::Idle {
+            return Err(Error::new(ErrorKind::NotConnected, reason.to_string()));
+        }
+        self.reconnect()
+    }
+
+    /// Send a command with the given id, re-establishing the connection first if it was lost and a reconnect policy is set.
+    ///
+    /// Writing to a connection the lamp just closed may fail as well, in which case the command is sent again after reconnecting.
+    fn send_reconnecting(&mut self, cmd: &Command, id: u32) -> std::io::Result<()> {
+        self.end_idle_music();
+        self.ensure_connected()?;
+        if self.reconnect_policy.is_none() {
+            return self.send_with_id(cmd, id);
+        }
+        match self.send_with_id(cmd, id) {
+            Err(err) if connection_lost(&err) => {
+                debug!("Lamp | Reconnecting after: {err}");
+                self.reconnect()?;
+                self.send_with_id(cmd, id)
+            }
+            res => res,
+        }
+    }
+
+    /// Send a command to the lamp.
+    ///
+    /// This command takes a reference to a [`Command`], so it does not consume the command.
+    /// If the command has no id, a unique id is assigned to it (without modifying the passed command).
+    /// The id of the sent command is returned, so that the reply of the lamp can be matched to it.
+    ///
+    /// If the color temperature fallback is enabled (see [`Lamp::set_ct_fallback`]),
+    /// color temperature commands are sent as approximate RGB commands instead.
+    /// If the model of the lamp is known (see [`Lamp::set_model`]), commands it can't carry out
+    /// fail with [`ErrorKind::InvalidInput`] without being sent.
+    pub fn send_cmd(&mut self, cmd: &Command) -> std::io::Result<u32> {
+        self.check_capabilities(cmd)?;
+        let id = cmd.id.unwrap_or_else(|| self.ids.next_id());
+        if !self.send_music(cmd, id) {
+            self.send_reconnecting(cmd, id)?;
+        }
+        Ok(id)
+    }
+
+    /// Send a command to the lamp with the given id, applying the color temperature fallback.
+    fn send_with_id(&mut self, cmd: &Command, id: u32) -> std::io::Result<()> {
+        self.encode(cmd, id);
+        if !self.resend_unacked {
+            return self.write_request(Some(id), cmd.coalesce_key());
+        }
+        // the request is tracked before it's written, so that a fast reply can't be missed
+        self.inbox.track(id, &self.buf);
+        let res = self.write_request(Some(id), cmd.coalesce_key());
+        if res.is_err() {
+            // the caller resends the command or reports the error
+            self.inbox.acknowledge(id);
+        }
+        res
+    }
+
+    /// Encode a command with the given id into the buffer, applying the color temperature fallback.
+    fn encode(&mut self, cmd: &Command, id: u32) {
+        let changed = match cmd.action.ct_as_rgb() {
+            Some(action) if self.ct_fallback => {
+                debug!("Lamp | Replacing color temperature with RGB");
+                Some(Command {
+                    action,
+                    ..cmd.clone()
+                })
+            }
+            _ => None,
+        };
+        let cmd = changed.as_ref().unwrap_or(cmd);
+        // the id is spliced into the request, so the command doesn't need to be cloned
+        debug!("Lamp | Sending command {cmd:?} with id {id}");
+        cmd.encode_with_id(id, &mut self.buf);
+        if let Ok(line) = std::str::from_utf8(&self.buf) {
+            self.inbox.tap(Direction::Sent, line.trim_end());
+        }
+    }
+
+    /// Get when the Lamp switches to music mode on its own, if it does.
+    pub fn music_upgrade(&self) -> Option<MusicUpgrade> {
+        self.music.as_ref().map(|music| music.upgrade)
+    }
+
+    /// Switch to music mode on its own under load (by default, it doesn't), routing the commands over it.
+    ///
+    /// Music mode bypasses the quota of the lamp: the lamp connects to a server opened by the Lamp,
+    /// and reads commands from that connection without replying to them.
+    /// Once the rate limit (see [`Lamp::set_rate_limit`]) runs low, i.e. when animations or sliders send
+    /// many commands, the Lamp sends `set_music` with its local address and accepts the connection of the lamp.
+    /// The commands sent with [`Lamp::send_cmd`] (and [`Lamp::send_action`]) are then sent over it,
+    /// while the commands waiting for replies (such as [`Lamp::call`]) keep using the regular connection.
+    /// Once no command was sent for the idle period, the next command switches music mode off again.
+    /// After switching off, or if switching fails (e.g. because a firewall blocks the connection of the lamp),
+    /// music mode is only switched to again after another idle period.
+    ///
+    /// Without a rate limit, the Lamp never switches to music mode. Only TCP connections support music mode.
+    pub fn set_music_upgrade(&mut self, upgrade: Option<MusicUpgrade>) {
+        match (upgrade, &mut self.music) {
+            (Some(upgrade), Some(music)) => music.upgrade = upgrade,
+            (Some(upgrade), None) => {
+                self.music = Some(Music {
+                    upgrade,
+                    session: None,
+                    retry_at: None,
+                });
+            }
+            (None, _) => {
+                self.end_music();
+                self.music = None;
+            }
+        }
+    }
+
+    /// Get the address of the lamp, if the transport has one.
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer
+    }
+
+    /// Get the local address of the connection, if the transport has one.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.stream.local_addr()
+    }
+
+    /// Get when the current connection was established, which is reset by [`Lamp::reconnect`].
+    pub fn connected_since(&self) -> SystemTime {
+        self.inbox.connected_since()
+    }
+
+    /// Get a snapshot of the counters of the connection, such as the commands sent and the last error.
+    pub fn stats(&self) -> LampStats {
+        self.inbox.stats()
+    }
+
+    /// Whether commands are currently sent over a music mode connection, see [`Lamp::set_music_upgrade`].
+    pub fn is_music_active(&self) -> bool {
+        self.music
+            .as_ref()
+            .is_some_and(|music| music.session.is_some())
+    }
+
+    /// Send a command over the music mode connection, switching to music mode first if the rate limit runs low.
+    ///
+    /// Returns whether the command was sent; if not, it has to be sent over the regular connection.
+    fn send_music(&mut self, cmd: &Command, id: u32) -> bool {
+        self.end_idle_music();
+        let Some(music) = &self.music else {
+            return false;
+        };
+        let low = self
+            .rate_limiter
+            .as_ref()
+            .is_some_and(|limiter| limiter.available() <= music.upgrade.reserve);
+        let retry = music
+            .retry_at
+            .is_none_or(|retry_at| Instant::now() >= retry_at);
+        if music.session.is_none() && low && retry {
+            let upgrade = music.upgrade;
+            let session = match self.start_music(upgrade.accept_timeout) {
+                Ok(connection) => Some(MusicSession {
+                    stream: connection.into_inner(),
+                    last_sent: Instant::now(),
+                }),
+                Err(err) => {
+                    debug!("Lamp | Switching to music mode failed: {err}");
+                    None
+                }
+            };
+            if let Some(music) = &mut self.music {
+                music.retry_at = session.is_none().then(|| Instant::now() + upgrade.idle);
+                music.session = session;
+            }
+        }
+        if !self.is_music_active() {
+            return false;
+        }
+        self.encode(cmd, id);
+        let Some(session) = self.music.as_mut().and_then(|music| music.session.as_mut()) else {
+            return false;
+        };
+        let res = session.stream.write_all(&self.buf);
+        self.inbox.record_sent(&res, 1);
+        match res {
+            Ok(()) => {
+                session.last_sent = Instant::now();
+                true
+            }
+            Err(err) => {
+                debug!("Lamp | Music mode connection failed: {err}");
+                self.end_music();
+                false
+            }
+        }
+    }
+
+    /// Switch the lamp to music mode, returning the connection the lamp opened to stream commands over.
+    ///
+    /// This opens a [MusicServer] on the local address of the connection, sends `set_music` with its address
+    /// (waiting for the reply for the timeout at most), and waits for the lamp to connect for the timeout again.
+    /// The commands sent over the returned connection aren't counted against the quota of the lamp,
+    /// while the Lamp keeps its connection for commands waiting for replies.
+    /// Music mode lasts until `set_music` is sent with `0`, or until the connection is closed.
+    ///
+    /// This is independent of the automatic switching of [`Lamp::set_music_upgrade`].
+    /// Only TCP connections support music mode, otherwise [`ErrorKind::Unsupported`] is returned.
+    pub fn start_music(&mut self, accept_timeout: Duration) -> std::io::Result<MusicConnection> {
+        let local = self.stream.local_addr().ok_or_else(|| {
+            Error::new(ErrorKind::Unsupported, "music mode needs a TCP connection")
+        })?;
+        let server = MusicServer::bind(local.ip())?;
+        debug!(
+            "Lamp | Switching to music mode on port {}",
+            server.local_addr()?.port()
+        );
+        let _result = self
+            .call_with_timeout(&server.command()?, accept_timeout)
+            .map_err(Error::other)?;
+        let connection = server.accept(accept_timeout)?;
+        self.inbox.update_state(|state| state.music_on = Some(true));
+        Ok(connection)
+    }
+
+    /// Switch music mode off by sending `set_music` with `0`, waiting for the reply.
+    ///
+    /// This also closes the connection of the automatic switching (see [`Lamp::set_music_upgrade`]), if it's active.
+    /// Connections returned by [`Lamp::start_music`] are closed by the lamp.
+    pub fn stop_music(&mut self) -> std::io::Result<()> {
+        if self.is_music_active() {
+            self.end_music();
+            return Ok(());
+        }
+        debug!("Lamp | Switching music mode off");
+        let _result = self
+            .call(&Command::custom("set_music", Vec::from([Param::Int(0)])))
+            .map_err(Error::other)?;
+        self.inbox
+            .update_state(|state| state.music_on = Some(false));
+        Ok(())
+    }
+
+    /// Switch music mode off if no command was sent over it for the idle period.
+    fn end_idle_music(&mut self) {
+        let idle = self.music.as_ref().is_some_and(|music| {
+            music
+                .session
+                .as_ref()
+                .is_some_and(|session| session.last_sent.elapsed() >= music.upgrade.idle)
+        });
+        if idle {
+            self.end_music();
+        }
+    }
+
+    /// Close the music mode connection (if any), and ask the lamp to switch music mode off if the connection is open.
+    fn end_music(&mut self) {
+        let Some(music) = &mut self.music else {
+            return;
+        };
+        let Some(session) = music.session.take() else {
+            return;
+        };
+        // the bucket is still low right after the burst, so switching back waits for another idle period
+        music.retry_at = Some(Instant::now() + music.upgrade.idle);
+        debug!("Lamp | Switching music mode off");
+        let _res = session.stream.shutdown(Shutdown::Both);
+        self.inbox
+            .update_state(|state| state.music_on = Some(false));
+        if self.inbox.disconnect_reason().is_some() {
+            // the lamp switched music mode off when the connection was closed, so don't reconnect just to tell it
+            return;
+        }
+        let id = self.ids.next_id();
+        if let Err(err) = self.send_reconnecting(
+            &Command::custom("set_music", Vec::from([Param::Int(0)])),
+            id,
+        ) {
+            debug!("Lamp | Switching music mode off failed: {err}");
+        }
+    }
+
+    /// Send a command to the lamp and wait for its response.
+    ///
+    /// Unlike [`Lamp::send_cmd`], this waits until the response whose id matches the command arrives,
+    /// skipping notifications and responses to other requests in the meantime.
+    /// The results of the response (such as `["ok"]`) are returned.
+    /// If the lamp replies with an error, [`CallError::Lamp`] is returned.
+    /// If no response arrives within the call timeout (see [`Lamp::set_call_timeout`]), [`CallError::Timeout`] is returned.
+    /// If the lamp rejects the command because of its quota, [`CallError::QuotaExceeded`] is returned,
+    /// unless the command is retried according to [`Lamp::set_quota_backoff`].
+    /// Other retryable errors are retried according to [`Lamp::set_retry_policy`],
+    /// and a lost connection is re-established according to [`Lamp::set_reconnect_policy`].
+    /// On success, the action of the command is applied to the cached state (see [`Lamp::state`]).
+    pub fn call(&mut self, cmd: &Command) -> Result<Vec<Value>, CallError> {
+        self.call_with_timeout(cmd, self.call_timeout)
+    }
+
+    /// Send a command to the lamp and wait for its response, as in [`Lamp::call`], but with its own timeout.
+    ///
+    /// The timeout only applies to waiting for the reply, and is independent of the timeouts of the stream.
+    /// If it elapses, [`CallError::Timeout`] is returned, the connection stays usable,
+    /// and the reply is discarded if it arrives later.
+    /// When a command is retried, each attempt has its own timeout.
+    pub fn call_with_timeout(
+        &mut self,
+        cmd: &Command,
+        timeout: Duration,
+    ) -> Result<Vec<Value>, CallError> {
+        let mut quota_retries = 0;
+        let mut attempts = 1;
+        let mut resent = false;
+        loop {
+            let deadline = Instant::now() + timeout;
+            let err = match self.send_cmd_no_wait(cmd) {
+                Ok(pending) => {
+                    let id = pending.id();
+                    match pending.wait_until(deadline) {
+                        Ok(result) => return Ok(result),
+                        Err(err) => {
+                            // the call resends the command itself, or reports the error
+                            self.inbox.acknowledge(id);
+                            err
+                        }
+                    }
+                }
+                Err(err) => match self.inbox.disconnect_reason() {
+                    Some(reason) if connection_lost(&err) => CallError::Disconnected(reason),
+                    _ => CallError::Io(err),
+                },
+            };
+            let (lost, idle) = match &err {
+                CallError::Io(io) => (connection_lost(io), false),
+                CallError::Disconnected(reason) => (true, *reason == DisconnectReason::Idle),
+                _ => (false, false),
+            };
+            if lost && (self.reconnect_policy.is_some() || idle) && !resent {
+                // the next attempt reconnects first
+                debug!("Lamp | Resending after: {err}");
+                resent = true;
+                continue;
+            }
+            let delay = match (&err, self.quota_backoff, self.retry_policy) {
+                (CallError::QuotaExceeded(_), Some(backoff), _)
+                    if quota_retries < backoff.max_retries =>
+                {
+                    quota_retries += 1;
+                    backoff.delay
+                }
+                (err, _, Some(policy)) if err.is_retryable() && attempts < policy.max_attempts => {
+                    attempts += 1;
+                    policy.delay(attempts - 1)
+                }
+                _ => {
+                    let kind = if let CallError::Io(io) = &err {
+                        Some(io.kind())
+                    } else {
+                        None
+                    };
+                    self.inbox.record_error(kind, &err);
+                    return Err(err);
+                }
+            };
+            debug!("Lamp | Retrying in {}ms after: {err}", delay.as_millis());
+            std::thread::sleep(delay);
+        }
+    }
+
+    /// Get how [`Lamp::call`] retries commands exceeding the quota, if at all.
+    pub fn quota_backoff(&self) -> Option<QuotaBackoff> {
+        self.quota_backoff
+    }
+
+    /// Set how [`Lamp::call`] retries commands exceeding the quota (by default, they aren't retried).
+    ///
+    /// Note that [`Lamp::call`] blocks while waiting to retry.
+    pub fn set_quota_backoff(&mut self, backoff: Option<QuotaBackoff>) {
+        self.quota_backoff = backoff;
+    }
+
+    /// Get the limit of the commands sent, if any.
+    pub fn rate_limit(&self) -> Option<RateLimit> {
+        self.rate_limiter.as_ref().map(RateLimiter::limit)
+    }
+
+    /// Limit the commands sent (by default, they aren't), so that bursts don't exceed the quota of the lamp.
+    ///
+    /// Lamps silently drop (or reject) the commands exceeding their quota of 60 commands per minute,
+    /// so the limit defaults to the quota, see [`RateLimit`]. Commands exceeding the limit block, are queued
+    /// or fail, depending on its [`RateLimitMode`]. Lamps sharing the connection (see [`Lamp::try_clone`])
+    /// share the limit, and setting a new limit starts with a full bucket.
+    /// Only the commands sent over the regular connection are limited, even while the lamp reports music mode:
+    /// the commands sent over a music mode connection (see [`Lamp::set_music_upgrade`]) bypass the quota.
+    /// The pings of a heartbeat started afterwards (see [`Lamp::set_heartbeat`]) are limited as well.
+    /// ```no_run
+    /// # use yeerugina_lib::{cmd::Action, lamp::{Lamp, RateLimit, RateLimitMode}};
+    /// # fn main() -> std::io::Result<()> {
+    /// let mut lamp = Lamp::connect("192.168.1.20:55443")?;
+    /// lamp.set_rate_limit(Some(RateLimit {
+    ///     mode: RateLimitMode::Queue,
+    ///     ..RateLimit::default()
+    /// }));
+    /// for ct in (2700..6500).step_by(38) {
+    ///     // the last 40 commands are sent over the next 40 seconds
+    ///     let _id = lamp.send_action(Action::new_ct(ct))?;
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn set_rate_limit(&mut self, limit: Option<RateLimit>) {
+        self.rate_limiter = limit.map(RateLimiter::new);
+    }
+
+    /// Get how [`Lamp::call`] retries commands failing with retryable errors, if at all.
+    pub fn retry_policy(&self) -> Option<RetryPolicy> {
+        self.retry_policy
+    }
+
+    /// Set how [`Lamp::call`] retries commands failing with retryable errors (by default, they aren't retried).
+    ///
+    /// Commands exceeding the quota are retried according to [`Lamp::set_quota_backoff`] if it's set,
+    /// and according to this policy otherwise.
+    pub fn set_retry_policy(&mut self, policy: Option<RetryPolicy>) {
+        self.retry_policy = policy;
+    }
+
+    /// Enable or disable the wiretap, which logs every line sent and received at trace level.
+    ///
+    /// The lines are logged with the address of the lamp, and passed to the sink set with [`Lamp::set_wiretap_sink`].
+    /// Bytes written directly through the Write impl of the Lamp aren't logged.
+    pub fn set_wiretap(&mut self, enabled: bool) {
+        self.inbox.lock_tap().enabled = enabled;
+    }
+
+    /// Pass every line sent and received to a sink (in addition to logging it), and enable the wiretap.
+    ///
+    /// The sink runs on the background reader thread for received lines, so it should return quickly.
+    /// ```no_run
+    /// # use yeerugina_lib::lamp::Lamp;
+    /// # fn main() -> std::io::Result<()> {
+    /// let mut lamp = Lamp::connect("192.168.1.20:55443")?;
+    /// lamp.set_wiretap_sink(|frame| eprintln!("{:?} {} {}", frame.time, frame.direction, frame.line));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn set_wiretap_sink<F>(&mut self, sink: F)
+    where
+        F: FnMut(&WireFrame<'_>) + Send + 'static,
+    {
+        let mut tap = self.inbox.lock_tap();
+        tap.sink = Some(Box::new(sink));
+        tap.enabled = true;
+    }
+
+    /// Start recording the lines sent and received, e.g. for replaying them in a test.
+    ///
+    /// The recording uses the wiretap, so it replaces the sink set with [`Lamp::set_wiretap_sink`].
+    /// See [`Recording`](crate::record::Recording) for replaying it.
+    pub fn start_recording(&mut self) -> Recorder {
+        let recorder = Recorder::new();
+        let sink = recorder.clone();
+        self.set_wiretap_sink(move |frame| sink.push(frame));
+        recorder
+    }
+
+    /// Get the methods supported by the lamp, if they are known.
+    pub fn supported_methods(&self) -> Option<&[String]> {
+        self.supported.as_deref()
+    }
+
+    /// Set the methods supported by the lamp, such as `set_ct_abx` (by default, they aren't known).
+    ///
+    /// Lamps advertise their methods in the `support` header of discovery responses.
+    /// The list is used for explaining [`CallError::Unsupported`] errors.
+    pub fn set_supported_methods(&mut self, methods: Option<Vec<String>>) {
+        self.supported = methods.map(Arc::from);
+    }
+
+    /// Get the model of the lamp, if it's known.
+    pub fn model(&self) -> Option<&Model> {
+        self.model.as_ref()
+    }
+
+    /// Set the model of the lamp (by default, it isn't known), as advertised in discovery responses.
+    ///
+    /// If the model is known to this crate, commands it can't carry out are rejected before they're sent,
+    /// see [`Lamp::capabilities`].
+    pub fn set_model(&mut self, model: Option<Model>) {
+        self.model = model;
+    }
+
+    /// Get the features of the lamp from its model, adjusted to its supported methods, if the model is known.
+    pub fn capabilities(&self) -> Option<Capabilities> {
+        let capabilities = self.model.as_ref()?.capabilities()?;
+        Some(match &self.supported {
+            Some(methods) => capabilities.with_methods(methods),
+            None => capabilities,
+        })
+    }
+
+    /// Check a command against the capabilities of the lamp, as it's sent (i.e. after the color temperature fallback).
+    fn check_capabilities(&self, cmd: &Command) -> std::io::Result<()> {
+        let Some(capabilities) = self.capabilities() else {
+            return Ok(());
+        };
+        let fallback = cmd.action.ct_as_rgb().filter(|_| self.ct_fallback);
+        capabilities
+            .check(fallback.as_ref().unwrap_or(&cmd.action))
+            .map_err(|err| Error::new(ErrorKind::InvalidInput, err))
+    }
+
+    /// Check a command for sending it in music mode (see [MusicLamp](crate::music::MusicLamp)), as in [`Lamp::send_cmd`].
+    ///
+    /// Returns the command to send instead if the color temperature fallback replaces it.
+    pub(crate) fn prepare_music(&self, cmd: &Command) -> std::io::Result<Option<Command>> {
+        self.check_capabilities(cmd)?;
+        Ok(cmd
+            .action
+            .ct_as_rgb()
+            .filter(|_| self.ct_fallback)
+            .map(|action| Command {
+                action,
+                ..cmd.clone()
+            }))
+    }
+
+    /// Send a command to the lamp without waiting for its response, returning a handle to the response.
+    ///
+    /// This lets latency-sensitive callers pipeline several commands before collecting their replies:
+    /// ```no_run
+    /// # use yeerugina_lib::{cmd::{Action, Command, Effect, Power}, lamp::Lamp};
+    /// # use std::time::Duration;
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let mut lamp = Lamp::connect("192.168.1.20:55443")?;
+    /// let power = lamp.send_cmd_no_wait(&Command::new(Action::new_power(Power::On), Effect::Sudden))?;
+    /// let ct = lamp.send_cmd_no_wait(&Command::new(Action::new_ct(3200), Effect::Sudden))?;
+    /// power.wait(Duration::from_secs(1))?;
+    /// ct.wait(Duration::from_secs(1))?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn send_cmd_no_wait(&mut self, cmd: &Command) -> std::io::Result<PendingReply> {
+        self.check_capabilities(cmd)?;
+        let id = cmd.id.unwrap_or_else(|| self.ids.next_id());
+        // The id is registered first, so that a fast reply can't be dropped
+        self.inbox.expect(id);
+        if let Err(err) = self.send_reconnecting(cmd, id) {
+            self.inbox.forget(id);
+            return Err(err);
+        }
+        Ok(self.pending_reply(cmd, id))
+    }
+
+    /// Create the handle to the reply of a command sent with the given id.
+    fn pending_reply(&self, cmd: &Command, id: u32) -> PendingReply {
+        let fallback = cmd.action.ct_as_rgb().filter(|_| self.ct_fallback);
+        PendingReply {
+            id,
+            action: fallback.unwrap_or_else(|| cmd.action.clone()),
+            inbox: Arc::clone(&self.inbox),
+            supported: self.supported.clone(),
+            done: false,
+        }
+    }
+
+    /// Send several commands at once and wait for their replies, e.g. for applying a scene.
+    ///
+    /// The commands are encoded into one buffer and written at once, instead of one write per command,
+    /// and then the replies are collected, for the call timeout in total (see [`Lamp::set_call_timeout`]).
+    /// The results are returned in the order of the commands; commands without a reply fail with [`CallError::Timeout`].
+    /// If writing fails, no replies are awaited and the error is returned.
+    ///
+    /// With a rate limit (see [`Lamp::set_rate_limit`]), the commands are written one by one, each as the limit allows.
+    /// ```no_run
+    /// # use yeerugina_lib::{cmd::{Action, Command, Effect, Power}, lamp::Lamp};
+    /// # fn main() -> std::io::Result<()> {
+    /// let mut lamp = Lamp::connect("192.168.1.20:55443")?;
+    /// let scene = [
+    ///     Command::new(Action::new_power(Power::On), Effect::Sudden),
+    ///     Command::new(Action::new_ct(2700), Effect::smooth_ms(500)),
+    /// ];
+    /// for result in lamp.send_batch(&scene)? {
+    ///     if let Err(err) = result {
+    ///         eprintln!("{err}");
+    ///     }
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn send_batch(
+        &mut self,
+        cmds: &[Command],
+    ) -> std::io::Result<Vec<Result<Vec<Value>, CallError>>> {
+        cmds.iter()
+            .try_for_each(|cmd| self.check_capabilities(cmd))?;
+        self.end_idle_music();
+        self.ensure_connected()?;
+        let ids = cmds
+            .iter()
+            .map(|cmd| cmd.id.unwrap_or_else(|| self.ids.next_id()))
+            .collect::<Vec<_>>();
+        // The ids are registered first, so that fast replies can't be dropped
+        for &id in &ids {
+            self.inbox.expect(id);
+        }
+        let written = if self.rate_limiter.is_some() {
+            cmds.iter()
+                .zip(&ids)
+                .try_for_each(|(cmd, &id)| self.send_with_id(cmd, id))
+        } else {
+            self.write_batch(cmds, &ids)
+        };
+        if let Err(err) = written {
+            for &id in &ids {
+                self.inbox.forget(id);
+                self.inbox.acknowledge(id);
+            }
+            return Err(err);
+        }
+        let deadline = Instant::now() + self.call_timeout;
+        Ok(cmds
+            .iter()
+            .zip(ids)
+            .map(|(cmd, id)| self.pending_reply(cmd, id).wait_until(deadline))
+            .collect())
+    }
+
+    /// Encode the commands into one buffer and write it at once, see [`Lamp::send_batch`].
+    fn write_batch(&mut self, cmds: &[Command], ids: &[u32]) -> std::io::Result<()> {
+        self.follow()?;
+        self.inbox.touch();
+        let mut batch = Vec::new();
+        for (cmd, &id) in cmds.iter().zip(ids) {
+            self.encode(cmd, id);
+            if self.resend_unacked {
+                self.inbox.track(id, &self.buf);
+            }
+            batch.extend_from_slice(&self.buf);
+        }
+        debug!("Lamp | Sending a batch of {} commands", cmds.len());
+        let res = if self.nonblocking {
+            self.pending.extend_from_slice(&batch);
+            self.poll_flush().map(|_flushed| ())
+        } else {
+            self.stream
+                .write_all(&batch)
+                .and_then(|()| self.stream.flush())
+        };
+        self.inbox.record_sent(&res, cmds.len() as u64);
+        if let Err(err) = &res {
+            self.inbox.lose(self.epoch, err);
+        }
+        res
+    }
+
+    /// Get how long [`Lamp::call`] waits for a response.
+    pub fn call_timeout(&self) -> Duration {
+        self.call_timeout
+    }
+
+    /// Set how long [`Lamp::call`] waits for a response (by default 5 seconds).
+    pub fn set_call_timeout(&mut self, timeout: Duration) {
+        self.call_timeout = timeout;
+    }
+
+    /// Restore the default settings and remove the callbacks, e.g. before a pooled connection is handed out again.
+    ///
+    /// The background threads are stopped, music mode is switched off and the stream is switched back to blocking mode.
+    /// The connection, the cached state, the id generator (so that late replies can't be mistaken for new ones)
+    /// and what's known about the lamp (its model, supported methods, device id and resolver) are kept.
+    pub(crate) fn reset(&mut self) {
+        if let Some(heartbeat) = self.heartbeat.take() {
+            heartbeat.stop();
+        }
+        if let Some(idle_watch) = self.idle_watch.take() {
+            idle_watch.stop();
+        }
+        self.idle_timeout = None;
+        self.end_music();
+        self.music = None;
+        if let Err(err) = self.set_nonblocking(false) {
+            debug!("Lamp | Switching back to blocking mode failed: {err}");
+        }
+        self.ct_fallback = false;
+        self.default_effect = Effect::default();
+        self.call_timeout = DEFAULT_CALL_TIMEOUT;
+        self.quota_backoff = None;
+        self.retry_policy = None;
+        self.reconnect_policy = None;
+        self.resend_unacked = false;
+        self.rate_limiter = None;
+        self.inbox.reset();
+    }
+
+    /// Get how the lines sent by the lamp are parsed.
+    pub fn parse_mode(&self) -> ParseMode {
+        self.inbox.parse_mode()
+    }
+
+    /// Set how the lines sent by the lamp are parsed (by default [`ParseMode::Lenient`]).
+    ///
+    /// In [`ParseMode::Strict`], a reply with unknown fields fails its call with [`CallError::InvalidResponse`],
+    /// and notifications with unknown properties are skipped.
+    pub fn set_parse_mode(&mut self, mode: ParseMode) {
+        self.inbox.set_parse_mode(mode);
+    }
+
+    /// Register a callback invoked whenever the lamp sends a notification of changed properties.
+    ///
+    /// Lamps notify all connected clients when their state changes,
+    /// e.g. because of a physical switch, the official app, or a command sent by this Lamp.
+    /// The callback runs on the background reader thread, so it should return quickly;
+    /// long-running work should be sent elsewhere (e.g. through a channel).
+    /// ```no_run
+    /// # use yeerugina_lib::lamp::Lamp;
+    /// # fn main() -> std::io::Result<()> {
+    /// let mut lamp = Lamp::connect("192.168.1.20:55443")?;
+    /// lamp.on_notification(|notification| {
+    ///     if let Some(power) = notification.get("power") {
+    ///         println!("The lamp was turned {power}");
+    ///     }
+    /// });
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn on_notification<F>(&mut self, mut callback: F)
+    where
+        F: FnMut(&Notification) + Send + 'static,
+    {
+        self.inbox.add_callback(Box::new(move |notification| {
+            callback(notification);
+            true
+        }));
+    }
+
+    /// Register a callback for notifications that is removed once it returns false.
+    pub(crate) fn add_notification_callback(&self, callback: NotificationCallback) {
+        self.inbox.add_callback(callback);
+    }
+
+    /// Get a channel receiving the notifications sent by the lamp from now on.
+    ///
+    /// This is an alternative to [`Lamp::on_notification`] for integrating notifications into an event loop.
+    /// The receiver can also be used as a blocking iterator, which ends when the connection is closed:
+    /// ```no_run
+    /// # use yeerugina_lib::lamp::Lamp;
+    /// # fn main() -> std::io::Result<()> {
+    /// let mut lamp = Lamp::connect("192.168.1.20:55443")?;
+    /// for notification in lamp.notifications() {
+    ///     println!("{:?}", notification.props);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    /// Once the receiver is dropped, the channel is removed on the next notification.
+    pub fn notifications(&mut self) -> mpsc::Receiver<Notification> {
+        let (tx, rx) = mpsc::channel();
+        self.inbox.add_callback(Box::new(move |notification| {
+            tx.send(notification.clone()).is_ok()
+        }));
+        rx
+    }
+
+    /// Register a callback invoked with each change of the cached state (see [`Lamp::state`]).
+    ///
+    /// Unlike [`Lamp::on_notification`], the callback only sees properties whose values actually changed,
+    /// whether the change was notified by the lamp or confirmed through [`Lamp::call`].
+    /// The callback may run on the background reader thread, so it should return quickly.
+    pub fn on_state_change<F>(&mut self, mut callback: F)
+    where
+        F: FnMut(&StateChange) + Send + 'static,
+    {
+        self.inbox.add_change_callback(Box::new(move |change| {
+            callback(change);
+            true
+        }));
+    }
+
+    /// Get a channel receiving the changes of the cached state from now on, see [`Lamp::on_state_change`].
+    /// ```no_run
+    /// # use yeerugina_lib::{lamp::Lamp, state::StateChange};
+    /// # fn main() -> std::io::Result<()> {
+    /// let mut lamp = Lamp::connect("192.168.1.20:55443")?;
+    /// for change in lamp.state_changes() {
+    ///     if let StateChange::BrightnessChanged { to: Some(bright), .. } = change {
+    ///         println!("The brightness is now {bright}%");
+    ///     }
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    /// Once the receiver is dropped, the channel is removed on the next change.
+    pub fn state_changes(&mut self) -> mpsc::Receiver<StateChange> {
+        let (tx, rx) = mpsc::channel();
+        self.inbox
+            .add_change_callback(Box::new(move |change| tx.send(change.clone()).is_ok()));
+        rx
+    }
+
+    /// Query some properties of the lamp in one request.
+    ///
+    /// The replied values are parsed into the fields of the returned state, which also updates the cached state.
+    /// Properties the lamp doesn't support (replied as empty strings) stay None.
+    pub fn get_props(&mut self, props: &[Property]) -> Result<LampState, CallError> {
+        let values = self.call(&Command::get_prop(props))?;
+        let state = LampState::from_props(props, &values);
+        self.inbox
+            .update_state(|cached| cached.merge_props(props, &values));
+        Ok(state)
+    }
+
+    /// Query all properties of the lamp and update the cached state, returning the properties that changed.
+    ///
+    /// This is useful at startup, or after a reconnect when the cached state may be stale.
+    pub fn refresh_state(&mut self) -> Result<Vec<Property>, CallError> {
+        let before = self.state();
+        let _fetched = self.get_props(&Property::ALL)?;
+        Ok(before.changed(&self.state()))
+    }
+
+    /// Get the last known state of the lamp, without asking the lamp.
+    ///
+    /// The state is updated from the notifications sent by the lamp
+    /// and from the commands it confirmed through [`Lamp::call`].
+    /// Values that haven't been reported yet are None.
+    pub fn state(&self) -> LampState {
+        self.inbox.state()
+    }
+
+    /// Replace the generator used for assigning ids to commands without an id.
+    pub fn set_id_generator<G: IdGenerator + 'static>(&mut self, ids: G) {
+        self.ids = Arc::new(ids);
+    }
+
+    /// Send an action to the lamp, using the default effect of the lamp.
+    ///
+    /// An id is assigned automatically and returned, as with [`Lamp::send_cmd`].
+    /// See [`Lamp::set_default_effect`] for changing the default effect.
+    pub fn send_action(&mut self, action: impl Into<Action>) -> std::io::Result<u32> {
+        let cmd = Command::new(action.into(), self.default_effect);
+        self.send_cmd(&cmd)
+    }
+
+    /// Get the effect used for commands built without an explicit effect.
+    pub fn default_effect(&self) -> Effect {
+        self.default_effect
+    }
+
+    /// Set the effect used for commands built without an explicit effect (by default [`Effect::Sudden`]).
+    ///
+    /// This applies to [`Lamp::send_action`] and [`CommandBuilder::build_with_default_effect`](crate::cmd::CommandBuilder::build_with_default_effect).
+    pub fn set_default_effect(&mut self, eff: Effect) {
+        self.default_effect = eff;
+    }
+
+    /// Enable or disable the color temperature fallback.
+    ///
+    /// Some models don't support the set_ct_abx method. When the fallback is enabled,
+    /// [`Lamp::send_cmd`] approximates the color temperature as an RGB color and sends set_rgb instead.
+    /// See [`kelvin_to_rgb`](crate::colors::kelvin_to_rgb) for the approximation used.
+    pub fn set_ct_fallback(&mut self, enabled: bool) {
+        self.ct_fallback = enabled;
+    }
+
+    /// Close the connection, making sure the commands sent so far reach the lamp.
+    ///
+    /// The written data is flushed, and the sending half is shut down first,
+    /// so the lamp receives everything before the end of the connection.
+    /// Dropping a Lamp does the same, but can't report errors.
+    pub fn close(mut self) -> std::io::Result<()> {
+        self.shutdown()
+    }
+
+    /// Flush the stream and shut it down gracefully, stopping the background threads.
+    fn shutdown(&mut self) -> std::io::Result<()> {
+        if self.closed {
+            return Ok(());
+        }
+        self.closed = true;
+        if let Some(heartbeat) = self.heartbeat.take() {
+            heartbeat.stop();
+        }
+        if let Some(idle_watch) = self.idle_watch.take() {
+            idle_watch.stop();
+        }
+        self.end_music();
+        // the connection may have been replaced by a clone, which is the one to close then
+        if let Err(err) = self.follow() {
+            debug!("Lamp | Moving to the current connection failed: {err}");
+        }
+        // requests buffered in nonblocking mode are written before closing
+        let flushed = if self.pending.is_empty() {
+            self.stream.flush()
+        } else {
+            self.stream
+                .set_nonblocking(false)
+                .and_then(|()| self.stream.write_all(&self.pending))
+        };
+        // Shutting down the sending half first sends a FIN after the pending data, instead of possibly resetting the connection
+        let shut = match self.stream.shutdown(Shutdown::Write) {
+            // the connection was already closed, e.g. by the idle timeout
+            Err(err) if err.kind() == ErrorKind::NotConnected => Ok(()),
+            res => res,
+        };
+        // Stop the reader thread, which is blocked on reading from a clone of the stream
+        let _res = self.stream.shutdown(Shutdown::Read);
+        flushed.and(shut)
+    }
+
+    /// Split the lamp into a reader receiving its notifications and a writer sending commands.
+    ///
+    /// The halves can be moved to different threads, so one thread can consume notifications
+    /// while another sends commands, without sharing a `&mut Lamp`.
+    /// Replies are still matched to the commands of the writer, and notifications are still applied to the cached state.
+    /// ```no_run
+    /// # use yeerugina_lib::{cmd::{Action, Power}, lamp::Lamp};
+    /// # fn main() -> std::io::Result<()> {
+    /// let (reader, mut writer) = Lamp::connect("192.168.1.20:55443")?.split();
+    /// std::thread::spawn(move || {
+    ///     for notification in reader {
+    ///         println!("{:?}", notification.props);
+    ///     }
+    /// });
+    /// writer.send_action(Action::new_power(Power::On))?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn split(mut self) -> (LampReader, LampWriter<T>) {
+        let reader = LampReader {
+            notifications: self.notifications(),
+        };
+        (reader, LampWriter { lamp: self })
+    }
+
+    /// Send a raw request to the lamp.
+    ///
+    /// The request should be a complete JSON request such as `{"id":1,"method":"toggle","params":[]}`.
+    /// The terminating `\r\n` is added by this method, so don't include it in the request.
+    /// This is an escape hatch for methods that can't be expressed with a [`Command`] (see also [`Command::custom`]).
+    pub fn send_raw(&mut self, req: &str) -> std::io::Result<()> {
+        debug!("Lamp | Sending raw request {req}");
+        self.inbox.tap(Direction::Sent, req);
+        self.buf.clear();
+        self.buf.extend_from_slice(req.as_bytes());
+        self.buf.extend_from_slice(b"\r\n");
+        self.write_request(None, None)
+    }
+}
+
+/// Whether an I/O error means that the connection was lost, so that it needs to be re-established.
+pub(crate) fn connection_lost(err: &Error) -> bool {
+    matches!(
+        err.kind(),
+        ErrorKind::BrokenPipe
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::NotConnected
+            | ErrorKind::UnexpectedEof
+    )
+}
+
+impl<T: Transport> Drop for Lamp<T> {
+    fn drop(&mut self) {
+        // the connection is kept open for the other Lamps sharing it
+        if self.handles.fetch_sub(1, Ordering::AcqRel) > 1 {
+            if let Some(heartbeat) = self.heartbeat.take() {
+                heartbeat.stop();
+            }
+            if let Some(idle_watch) = self.idle_watch.take() {
+                idle_watch.stop();
+            }
+            return;
+        }
+        if let Err(err) = self.shutdown() {
+            debug!("Lamp | Closing the connection failed: {err}");
+        }
+    }
+}
+
+// derived Clone would require the transport to be Clone
+impl<T: Transport> Clone for SharedLamp<T> {
+    fn clone(&self) -> Self {
+        Self(Arc::clone(&self.0))
+    }
+}
+
+impl<T: Transport> From<Lamp<T>> for SharedLamp<T> {
+    fn from(value: Lamp<T>) -> Self {
+        Self::new(value)
+    }
+}
+
+impl Iterator for LampReader {
+    type Item = Notification;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.recv()
+    }
+}
+
+impl<T: Transport> Deref for LampWriter<T> {
+    type Target = Lamp<T>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.lamp
+    }
+}
+
+impl<T: Transport> DerefMut for LampWriter<T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.lamp
+    }
+}
+
+impl std::fmt::Display for SupportedHint<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.0 {
+            Some(methods) => write!(f, " (it supports {})", methods.join(", ")),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Default for QuotaBackoff {
+    fn default() -> Self {
+        Self {
+            delay: Duration::from_secs(60),
+            max_retries: 1,
+        }
+    }
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(30),
+            timeout: Duration::from_secs(5),
+            max_missed: 2,
+        }
+    }
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        Self {
+            capacity: 60,
+            refill_interval: Duration::from_secs(1),
+            mode: RateLimitMode::default(),
+            queue: QueueBound::default(),
+        }
+    }
+}
+
+impl Default for QueueBound {
+    fn default() -> Self {
+        Self {
+            capacity: 64,
+            overflow: QueueOverflow::default(),
+            coalesce: false,
+        }
+    }
+}
+
+impl Drop for QueueOwner {
+    fn drop(&mut self) {
+        // the queued requests are dropped along with the Lamps
+        self.0.lock().closed = true;
+        self.0.changed.notify_all();
+    }
+}
+
+impl Default for MusicUpgrade {
+    fn default() -> Self {
+        Self {
+            reserve: 10,
+            idle: Duration::from_secs(5),
+            accept_timeout: Duration::from_secs(3),
+        }
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(200),
+            max_backoff: Duration::from_secs(5),
+            jitter: true,
+        }
+    }
+}
+
+impl Drop for PendingReply {
+    fn drop(&mut self) {
+        if !self.done {
+            self.inbox.forget(self.id);
+        }
+    }
+}
+
+impl From<ErrorKind> for DisconnectReason {
+    fn from(kind: ErrorKind) -> Self {
+        match kind {
+            ErrorKind::UnexpectedEof | ErrorKind::NotConnected => Self::Closed,
+            ErrorKind::BrokenPipe => Self::BrokenPipe,
+            ErrorKind::ConnectionReset => Self::Reset,
+            ErrorKind::ConnectionAborted => Self::Aborted,
+            kind => Self::Other(kind),
+        }
+    }
+}
+
+impl From<Error> for ConnectError {
+    fn from(value: Error) -> Self {
+        Self::Io(value)
+    }
+}
+
+impl From<ConnectError> for Error {
+    fn from(value: ConnectError) -> Self {
+        match value {
+            ConnectError::Io(err) => err,
+            ConnectError::LanControlDisabled { ref source, .. } => Self::new(source.kind(), value),
+        }
+    }
+}
+
+impl std::error::Error for ConnectError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(err) | Self::LanControlDisabled { source: err, .. } => Some(err),
+        }
+    }
+}
+
+impl std::error::Error for CallError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(err) => Some(err),
+            Self::Lamp(err) | Self::QuotaExceeded(err) | Self::Unsupported { error: err, .. } => {
+                Some(err)
+            }
+            Self::InvalidResponse(err) => Some(err),
+            Self::Timeout | Self::Disconnected(_) | Self::Dropped => None,
+        }
+    }
+}
+
+impl Default for IdCounter {
+    fn default() -> Self {
+        Self::starting_at(1)
+    }
+}
+
+impl IdGenerator for IdCounter {
+    fn next_id(&self) -> u32 {
+        loop {
+            let id = self.0.fetch_add(1, Ordering::Relaxed);
+            if id != 0 {
+                return id;
+            }
+        }
+    }
+}
+
+// Delegate reading to the internal stream.
+// Note that the reader thread reads from the same connection, so direct reads only get part of the data.
+impl<T: Transport> Read for Lamp<T> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.follow()?;
+        self.stream.read(buf)
+    }
+}
+
+// Writes are sent like requests, so each one counts against the rate limit,
+// and in nonblocking mode they are buffered behind the requests that weren't written yet.
+impl<T: Transport> Write for Lamp<T> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.buf.clear();
+        self.buf.extend_from_slice(buf);
+        self.write_request(None, None)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        if !self.poll_flush()? {
+            return Err(Error::from(ErrorKind::WouldBlock));
+        }
+        self.stream.flush()
+    }
+}
+
+/// Fixtures for tests talking to a lamp over a local connection.
+#[cfg(test)]
+pub(crate) mod testing {
+    use super::Lamp;
+    use serde_json::Value;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::string::String;
+    use std::thread::JoinHandle;
+
+    /// Connect a lamp to a local listener, returning the lamp and the other end of the connection.
+    pub(crate) fn connected_pair() -> (Lamp, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let lamp = Lamp::connect(listener.local_addr().unwrap()).unwrap();
+        let (peer, _) = listener.accept().unwrap();
+        (lamp, peer)
+    }
+
+    /// Reply to the requests received by the peer using the given function, on a separate thread.
+    ///
+    /// The thread stops when the connection is closed.
+    pub(crate) fn respond<S>(
+        peer: S,
+        reply: impl Fn(&str) -> String + Send + 'static,
+    ) -> JoinHandle<()>
+    where
+        S: Send + 'static,
+        for<'a> &'a S: Read + Write,
+    {
+        std::thread::spawn(move || {
+            for line in BufReader::new(&peer).lines() {
+                let Ok(line) = line else { break };
+                if (&peer).write_all(reply(&line).as_bytes()).is_err() {
+                    break;
+                }
+            }
+        })
+    }
+
+    /// Get the id of a request received by the peer.
+    pub(crate) fn request_id(line: &str) -> u64 {
+        let request: Value = serde_json::from_str(line).unwrap();
+        request["id"].as_u64().unwrap()
+    }
+
+    /// Reply to a request with the given body, such as `"result":["ok"]`.
+    pub(crate) fn reply_to(line: &str, body: &str) -> String {
+        std::format!("{{\"id\":{},{body}}}\r\n", request_id(line))
+    }
+
+    /// Get the number of notification callbacks registered on a lamp.
+    pub(crate) fn callbacks(lamp: &Lamp) -> usize {
+        lamp.inbox.callbacks()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::testing::{connected_pair, reply_to, request_id, respond};
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+    use std::vec;
+
+    #[test]
+    fn assigns_ids() {
+        let (mut lamp, peer) = connected_pair();
+        let cmd = Command::new(Action::new_ct(3200), Effect::Sudden);
+        assert_eq!(lamp.send_cmd(&cmd).unwrap(), 1);
+        assert_eq!(lamp.send_cmd(&cmd).unwrap(), 2);
+        let explicit = Command {
+            id: Some(42),
+            ..cmd.clone()
+        };
+        assert_eq!(lamp.send_cmd(&explicit).unwrap(), 42);
+        assert_eq!(cmd.id, None);
+        let mut lines = BufReader::new(peer).lines();
+        let line = lines.next().unwrap().unwrap();
+        assert_eq!(
+            line,
+            r#"{"id":1,"method":"set_ct_abx","params":[3200,"sudden",0]}"#
+        );
+        assert!(lines.next().unwrap().unwrap().starts_with(r#"{"id":2,"#));
+        assert!(lines.next().unwrap().unwrap().starts_with(r#"{"id":42,"#));
+    }
+
+    #[test]
+    fn default_effect() {
+        let (mut lamp, peer) = connected_pair();
+        lamp.set_default_effect(Duration::from_millis(300).into());
+        assert_eq!(lamp.send_action(Action::new_ct(3200)).unwrap(), 1);
+        let cmd = Command::builder()
+            .ct(4000)
+            .build_with_default_effect(lamp.default_effect())
+            .unwrap();
+        let _id = lamp.send_cmd(&cmd).unwrap();
+        let mut lines = BufReader::new(peer).lines();
+        assert_eq!(
+            lines.next().unwrap().unwrap(),
+            r#"{"id":1,"method":"set_ct_abx","params":[3200,"smooth",300]}"#
+        );
+        assert_eq!(
+            lines.next().unwrap().unwrap(),
+            r#"{"id":2,"method":"set_ct_abx","params":[4000,"smooth",300]}"#
+        );
+    }
+
+    #[test]
+    fn custom_id_generator() {
+        #[derive(Debug)]
+        struct Fixed;
+        impl IdGenerator for Fixed {
+            fn next_id(&self) -> u32 {
+                7
+            }
+        }
+        let (mut lamp, _peer) = connected_pair();
+        lamp.set_id_generator(Fixed);
+        let cmd = Command::new(Action::new_ct(3200), Effect::Sudden);
+        assert_eq!(lamp.send_cmd(&cmd).unwrap(), 7);
+        assert_eq!(lamp.send_cmd(&cmd).unwrap(), 7);
+    }
+
+    #[test]
+    fn counter_skips_zero() {
+        let counter = IdCounter::starting_at(u32::MAX);
+        assert_eq!(counter.next_id(), u32::MAX);
+        assert_eq!(counter.next_id(), 1);
+    }
+
+    #[test]
+    fn call_skips_other_messages() {
+        let (mut lamp, peer) = connected_pair();
+        let _responder = respond(peer, |_| {
+            // a notification and a stale reply arrive before the reply, split over several lines
+            concat!(
+                "{\"method\":\"props\",\"params\":{\"ct\":\"3200\"}}\r\n",
+                "{\"id\":9,\"result\":[\"ok\"]}\r\n{\"id\":1,",
+                "\"result\":[\"ok\"]}\r\n"
+            )
+            .to_owned()
+        });
+        let cmd = Command::new(Action::new_ct(3200), Effect::Sudden);
+        assert_eq!(lamp.call(&cmd).unwrap(), vec![Value::from("ok")]);
+    }
+
+    #[test]
+    fn call_returns_lamp_errors() {
+        let (mut lamp, peer) = connected_pair();
+        let _responder = respond(peer, |_| {
+            "{\"id\":1,\"error\":{\"code\":-5000,\"message\":\"general error\"}}\r\n".to_owned()
+        });
+        let cmd = Command::custom("toggle", vec![]);
+        match lamp.call(&cmd) {
+            Err(CallError::Lamp(err)) => assert_eq!(err.message, "general error"),
+            other => panic!("expected a lamp error, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn notifications() {
+        let (mut lamp, mut peer) = connected_pair();
+        let (tx, rx) = mpsc::channel();
+        lamp.on_notification(move |notification| tx.send(notification.clone()).unwrap());
+        let channel = lamp.notifications();
+        let dropped = lamp.notifications();
+        drop(dropped);
+        peer.write_all(b"{\"method\":\"props\",\"params\":{\"power\":\"off\"}}\r\n")
+            .unwrap();
+        let notification = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(notification.get("power"), Some(&Value::from("off")));
+        let received = channel.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(received, notification);
+        assert_eq!(lamp.inbox.callbacks(), 2);
+        drop(lamp);
+        // the iterator ends once the connection is closed
+        assert_eq!(channel.iter().count(), 0);
+    }
+
+    #[test]
+    fn state_cache() {
+        let (mut lamp, peer) = connected_pair();
+        let _responder = respond(peer, |line| {
+            let id = if line.contains("set_bright") { 2 } else { 1 };
+            std::format!(
+                "{{\"method\":\"props\",\"params\":{{\"power\":\"on\",\"ct\":\"2700\"}}}}\r\n{{\"id\":{id},\"result\":[\"ok\"]}}\r\n"
+            )
+        });
+        assert_eq!(lamp.state(), LampState::default());
+        let rgb = Command::new(Action::new_rgb_from_parts(255, 0, 0), Effect::Sudden);
+        let _result = lamp.call(&rgb).unwrap();
+        let bright = Command::new(
+            Action::new_bright(crate::cmd::Brightness::new(40).unwrap()),
+            Effect::Sudden,
+        );
+        let _result = lamp.call(&bright).unwrap();
+        let state = lamp.state();
+        assert_eq!(state.power, Some(crate::cmd::Power::On));
+        assert_eq!(state.ct, Some(2700));
+        assert_eq!(state.rgb, Some(crate::colors::Rgb::new(255, 0, 0)));
+        assert_eq!(state.color_mode, Some(crate::state::ColorMode::Rgb));
+        assert_eq!(state.bright.map(|bright| bright.get()), Some(40));
+    }
+
+    #[test]
+    fn get_props() {
+        let (mut lamp, peer) = connected_pair();
+        let _responder = respond(peer, |line| {
+            assert!(line.contains(r#""method":"get_prop","params":["power","bright","name"]"#));
+            "{\"id\":1,\"result\":[\"on\",\"75\",\"\"]}\r\n".to_owned()
+        });
+        let props = [Property::Power, Property::Bright, Property::Name];
+        let state = lamp.get_props(&props).unwrap();
+        assert_eq!(state.power, Some(crate::cmd::Power::On));
+        assert_eq!(state.bright.map(|bright| bright.get()), Some(75));
+        assert_eq!(state.name, None);
+        assert_eq!(lamp.state(), state);
+    }
+
+    #[test]
+    fn refresh_state() {
+        let (mut lamp, peer) = connected_pair();
+        let _responder = respond(peer, |line| {
+            reply_to(
+                line,
+                r#""result":["on","50","2700","","","","2","0","0","",""]"#,
+            )
+        });
+        assert_eq!(
+            lamp.refresh_state().unwrap(),
+            vec![
+                Property::Power,
+                Property::Bright,
+                Property::Ct,
+                Property::ColorMode,
+                Property::Flowing,
+                Property::Delayoff
+            ]
+        );
+        assert_eq!(lamp.state().ct, Some(2700));
+        assert_eq!(lamp.refresh_state().unwrap(), vec![]);
+    }
+
+    #[test]
+    fn state_changes() {
+        let (mut lamp, mut peer) = connected_pair();
+        let changes = lamp.state_changes();
+        let notify = b"{\"method\":\"props\",\"params\":{\"power\":\"on\",\"bright\":\"20\"}}\r\n";
+        peer.write_all(notify).unwrap();
+        // the second notification doesn't change anything
+        peer.write_all(notify).unwrap();
+        peer.write_all(b"{\"method\":\"props\",\"params\":{\"bright\":\"30\"}}\r\n")
+            .unwrap();
+        let timeout = Duration::from_secs(5);
+        let received: Vec<_> = (0..3)
+            .map(|_| changes.recv_timeout(timeout).unwrap())
+            .collect();
+        let bright = |value| Some(crate::cmd::Brightness::new(value).unwrap());
+        assert_eq!(
+            received,
+            vec![
+                StateChange::PowerChanged {
+                    from: None,
+                    to: Some(crate::cmd::Power::On)
+                },
+                StateChange::BrightnessChanged {
+                    from: None,
+                    to: bright(20)
+                },
+                StateChange::BrightnessChanged {
+                    from: bright(20),
+                    to: bright(30)
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn strict_parse_mode() {
+        let (mut lamp, peer) = connected_pair();
+        let _responder = respond(peer, |line| {
+            reply_to(line, r#""result":["ok"],"extra":true"#)
+        });
+        let cmd = Command::new(Action::new_ct(3200), Effect::Sudden);
+        assert_eq!(lamp.call(&cmd).unwrap(), vec![Value::from("ok")]);
+        lamp.set_parse_mode(ParseMode::Strict);
+        match lamp.call(&cmd) {
+            Err(CallError::InvalidResponse(err)) => {
+                assert_eq!(err, ParseResponseError::UnknownField("extra".to_owned()));
+            }
+            other => panic!("expected an invalid response, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn late_replies_are_discarded() {
+        let (mut lamp, peer) = connected_pair();
+        let _responder = respond(peer, |line| {
+            if line.contains(r#""id":1,"#) {
+                std::thread::sleep(Duration::from_millis(200));
+                "{\"id\":1,\"result\":[\"late\"]}\r\n".to_owned()
+            } else {
+                "{\"id\":2,\"result\":[\"ok\"]}\r\n".to_owned()
+            }
+        });
+        let cmd = Command::new(Action::new_ct(3200), Effect::Sudden);
+        assert!(matches!(
+            lamp.call_with_timeout(&cmd, Duration::from_millis(50)),
+            Err(CallError::Timeout)
+        ));
+        assert_eq!(lamp.call(&cmd).unwrap(), vec![Value::from("ok")]);
+    }
+
+    #[test]
+    fn pipelined_replies() {
+        let (mut lamp, peer) = connected_pair();
+        let (tx, rx) = mpsc::channel::<()>();
+        let rx = Mutex::new(rx);
+        let _responder = respond(peer, move |line| {
+            // reply to both requests at once, in reverse order, once the test allows it
+            if line.contains(r#""id":1,"#) {
+                return String::new();
+            }
+            rx.lock().unwrap().recv().unwrap();
+            "{\"id\":2,\"result\":[\"two\"]}\r\n{\"id\":1,\"result\":[\"one\"]}\r\n".to_owned()
+        });
+        let cmd = Command::new(Action::new_ct(3200), Effect::Sudden);
+        let mut first = lamp.send_cmd_no_wait(&cmd).unwrap();
+        let second = lamp.send_cmd_no_wait(&cmd).unwrap();
+        assert_eq!((first.id(), second.id()), (1, 2));
+        assert!(first.poll().is_none());
+        tx.send(()).unwrap();
+        let timeout = Duration::from_secs(5);
+        assert_eq!(second.wait(timeout).unwrap(), vec![Value::from("two")]);
+        // the first reply is routed right after the second one
+        let deadline = Instant::now() + timeout;
+        let reply = loop {
+            match first.poll() {
+                Some(reply) => break reply,
+                None if Instant::now() < deadline => std::thread::sleep(Duration::from_millis(1)),
+                None => panic!("the first reply didn't arrive"),
+            }
+        };
+        assert_eq!(reply.unwrap(), vec![Value::from("one")]);
+        assert!(first.poll().is_none());
+        assert_eq!(lamp.state().ct, Some(3200));
+    }
+
+    #[test]
+    fn rate_limit() {
+        let (mut lamp, mut peer) = connected_pair();
+        let cmd = Command::new(Action::new_ct(3200), Effect::Sudden);
+        lamp.set_rate_limit(Some(RateLimit {
+            capacity: 2,
+            refill_interval: Duration::from_secs(3600),
+            mode: RateLimitMode::Error,
+            ..RateLimit::default()
+        }));
+        assert_eq!(lamp.send_cmd(&cmd).unwrap(), 1);
+        assert_eq!(lamp.send_cmd(&cmd).unwrap(), 2);
+        let err = lamp.send_cmd(&cmd).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::QuotaExceeded);
+
+        let limit = RateLimit {
+            capacity: 1,
+            refill_interval: Duration::from_millis(50),
+            mode: RateLimitMode::Block,
+            ..RateLimit::default()
+        };
+        lamp.set_rate_limit(Some(limit));
+        let start = Instant::now();
+        for _ in 0..3 {
+            let _id = lamp.send_cmd(&cmd).unwrap();
+        }
+        assert!(start.elapsed() >= Duration::from_millis(100));
+
+        lamp.set_rate_limit(Some(RateLimit {
+            mode: RateLimitMode::Queue,
+            ..limit
+        }));
+        let start = Instant::now();
+        for _ in 0..3 {
+            let _id = lamp.send_cmd(&cmd).unwrap();
+        }
+        assert!(start.elapsed() < Duration::from_millis(50));
+        let mut lines = BufReader::new(peer.try_clone().unwrap()).lines();
+        let ids = (0..8)
+            .map(|_| request_id(&lines.next().unwrap().unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(ids, [1, 2, 4, 5, 6, 7, 8, 9]);
+
+        // music mode opened by another client doesn't bypass the quota of this connection
+        lamp.set_rate_limit(Some(RateLimit {
+            mode: RateLimitMode::Error,
+            ..limit
+        }));
+        peer.write_all(b"{\"method\":\"props\",\"params\":{\"music_on\":1}}\r\n")
+            .unwrap();
+        while lamp.state().music_on != Some(true) {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        let _id = lamp.send_cmd(&cmd).unwrap();
+        let err = lamp.send_cmd(&cmd).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::QuotaExceeded);
+    }
+
+    #[test]
+    fn bounded_queue() {
+        let queueing = |overflow| {
+            Some(RateLimit {
+                capacity: 1,
+                refill_interval: Duration::from_millis(100),
+                mode: RateLimitMode::Queue,
+                queue: QueueBound {
+                    capacity: 2,
+                    overflow,
+                    coalesce: false,
+                },
+            })
+        };
+        let ct = |ct| Command::new(Action::new_ct(ct), Effect::Sudden);
+        let bright = |bright| {
+            Command::new(
+                Action::new_bright(crate::cmd::Brightness::new(bright).unwrap()),
+                Effect::Sudden,
+            )
+        };
+        let sent = |peer: TcpStream, count| {
+            let mut lines = BufReader::new(peer).lines();
+            (0..count)
+                .map(|_| lines.next().unwrap().unwrap()[6..7].to_owned())
+                .collect::<Vec<_>>()
+        };
+
+        let (mut lamp, peer) = connected_pair();
+        lamp.set_rate_limit(queueing(QueueOverflow::DropOldest));
+        for val in 0..6 {
+            let _id = lamp.send_cmd(&ct(3200 + val)).unwrap();
+        }
+        assert_eq!(sent(peer, 3), ["1", "5", "6"]);
+
+        let (mut lamp, peer) = connected_pair();
+        lamp.set_rate_limit(queueing(QueueOverflow::Coalesce));
+        let _id = lamp.send_cmd(&ct(2700)).unwrap();
+        let _id = lamp.send_cmd(&bright(10)).unwrap();
+        let _id = lamp.send_cmd(&ct(3200)).unwrap();
+        // the queue is full, so these replace the queued commands of the same kind
+        let _id = lamp.send_cmd(&ct(4000)).unwrap();
+        let _id = lamp.send_cmd(&bright(20)).unwrap();
+        assert_eq!(sent(peer, 3), ["1", "4", "5"]);
+    }
+
+    #[test]
+    fn dropped_from_queue() {
+        let (mut lamp, _peer) = connected_pair();
+        lamp.set_resend_unacknowledged(true);
+        lamp.set_rate_limit(Some(RateLimit {
+            capacity: 1,
+            refill_interval: Duration::from_secs(1),
+            mode: RateLimitMode::Queue,
+            queue: QueueBound {
+                capacity: 1,
+                overflow: QueueOverflow::DropOldest,
+                coalesce: true,
+            },
+        }));
+        let ct = |ct| Command::new(Action::new_ct(ct), Effect::Sudden);
+        let _sent = lamp.send_cmd_no_wait(&ct(2700)).unwrap();
+        let replaced = lamp.send_cmd_no_wait(&ct(3200)).unwrap();
+        let dropped = lamp.send_cmd_no_wait(&ct(4000)).unwrap();
+        let bright = crate::cmd::Brightness::new(10).unwrap();
+        let queued = lamp
+            .send_cmd_no_wait(&Command::new(Action::new_bright(bright), Effect::Sudden))
+            .unwrap();
+        // the replies to the dropped requests fail right away, and they aren't resent
+        let timeout = Duration::from_millis(100);
+        assert!(matches!(replaced.wait(timeout), Err(CallError::Dropped)));
+        assert!(matches!(dropped.wait(timeout), Err(CallError::Dropped)));
+        let unacked = lamp.inbox.unacknowledged();
+        assert_eq!(
+            unacked.iter().map(|(id, _)| *id).collect::<Vec<_>>(),
+            [1, queued.id()]
+        );
+    }
+
+    #[test]
+    fn coalescing_queue() {
+        let (mut lamp, peer) = connected_pair();
+        lamp.set_rate_limit(Some(RateLimit {
+            capacity: 1,
+            refill_interval: Duration::from_millis(200),
+            mode: RateLimitMode::Queue,
+            queue: QueueBound {
+                coalesce: true,
+                ..QueueBound::default()
+            },
+        }));
+        // a slider sending 100 brightness updates, with a color change in between
+        for val in 1..=100 {
+            let bright = crate::cmd::Brightness::new(val).unwrap();
+            let _id = lamp.send_action(Action::new_bright(bright)).unwrap();
+            if val == 50 {
+                let _id = lamp.send_action(Action::new_ct(2700)).unwrap();
+            }
+        }
+        peer.set_read_timeout(Some(Duration::from_millis(500)))
+            .unwrap();
+        let sent = BufReader::new(peer)
+            .lines()
+            .map_while(Result::ok)
+            .collect::<Vec<_>>();
+        assert_eq!(sent.len(), 3);
+        assert!(sent[0].contains(r#""set_bright","params":[1,"#));
+        assert!(sent[1].contains("set_ct_abx"));
+        assert!(sent[2].contains(r#""set_bright","params":[100,"#));
+    }
+
+    #[test]
+    fn send_batch() {
+        let (mut lamp, peer) = connected_pair();
+        let _responder = respond(peer, |line| {
+            if line.contains("set_power") {
+                reply_to(line, r#""error":{"code":-5000,"message":"general error"}"#)
+            } else {
+                reply_to(line, r#""result":["ok"]"#)
+            }
+        });
+        let scene = [
+            Command::new(Action::new_ct(2700), Effect::Sudden),
+            Command::new(Action::new_power(crate::cmd::Power::On), Effect::Sudden),
+            Command::new(Action::new_rgb_from_int(0xFF0000).unwrap(), Effect::Sudden),
+        ];
+        let results = lamp.send_batch(&scene).unwrap();
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].as_ref().unwrap(), &vec![Value::from("ok")]);
+        assert!(matches!(results[1], Err(CallError::Lamp(_))));
+        assert_eq!(results[2].as_ref().unwrap(), &vec![Value::from("ok")]);
+        assert_eq!(lamp.state().ct, Some(2700));
+        assert_eq!(lamp.state().power, None);
+    }
+
+    #[test]
+    fn stats() {
+        let (mut lamp, peer) = connected_pair();
+        assert_eq!(lamp.peer_addr(), peer.local_addr().ok());
+        assert_eq!(lamp.local_addr(), peer.peer_addr().ok());
+        assert!(lamp.connected_since() <= SystemTime::now());
+        let _responder = respond(peer, |line| {
+            if line.contains("set_power") {
+                reply_to(line, r#""error":{"code":-5000,"message":"general error"}"#)
+            } else {
+                reply_to(line, r#""result":["ok"]"#)
+            }
+        });
+        let _result = lamp
+            .call(&Command::new(Action::new_ct(2700), Effect::Sudden))
+            .unwrap();
+        assert_eq!(
+            lamp.stats(),
+            LampStats {
+                commands_sent: 1,
+                responses_received: 1,
+                ..LampStats::default()
+            }
+        );
+        let _err = lamp
+            .call(&Command::new(
+                Action::new_power(crate::cmd::Power::On),
+                Effect::Sudden,
+            ))
+            .unwrap_err();
+        let stats = lamp.stats();
+        assert_eq!((stats.commands_sent, stats.responses_received), (2, 2));
+        let last_error = stats.last_error.unwrap();
+        assert_eq!(last_error.kind, None);
+        assert!(last_error.message.contains("general error"));
+    }
+
+    #[test]
+    fn music_upgrade() {
+        let (mut lamp, peer) = connected_pair();
+        let (tx, rx) = mpsc::channel();
+        let music_tx = tx.clone();
+        let _responder = respond(peer, move |line| {
+            let request = serde_json::from_str::<Value>(line).unwrap();
+            let method = request["method"].as_str().unwrap().to_owned();
+            if method == "set_music" && request["params"][0] == 1 {
+                let host = request["params"][1].as_str().unwrap();
+                let port = request["params"][2].as_u64().unwrap();
+                let music = TcpStream::connect(std::format!("{host}:{port}")).unwrap();
+                let music_tx = music_tx.clone();
+                let _reader = std::thread::spawn(move || {
+                    for line in BufReader::new(music).lines() {
+                        let Ok(line) = line else { break };
+                        music_tx.send(("music", line)).unwrap();
+                    }
+                });
+            }
+            tx.send(("control", method)).unwrap();
+            reply_to(line, r#""result":["ok"]"#)
+        });
+        lamp.set_rate_limit(Some(RateLimit {
+            capacity: 10,
+            refill_interval: Duration::from_secs(3600),
+            mode: RateLimitMode::Error,
+            ..RateLimit::default()
+        }));
+        lamp.set_music_upgrade(Some(MusicUpgrade {
+            reserve: 4,
+            idle: Duration::from_millis(100),
+            accept_timeout: Duration::from_secs(5),
+        }));
+        let cmd = Command::new(Action::new_ct(3200), Effect::Sudden);
+        // 6 commands are sent regularly, then the rate limit runs low
+        for _ in 0..10 {
+            let _id = lamp.send_cmd(&cmd).unwrap();
+        }
+        assert!(lamp.is_music_active());
+        assert_eq!(lamp.state().music_on, Some(true));
+        // the burst ended, so the next command switches back
+        std::thread::sleep(Duration::from_millis(150));
+        let _id = lamp.send_cmd(&cmd).unwrap();
+        assert!(!lamp.is_music_active());
+        let mut control = Vec::new();
+        let mut music = 0;
+        while control.len() < 9 || music < 4 {
+            match rx.recv_timeout(Duration::from_secs(5)).unwrap() {
+                ("music", line) => {
+                    assert!(line.contains("set_ct_abx"));
+                    music += 1;
+                }
+                (_, method) => control.push(method),
+            }
+        }
+        assert_eq!(
+            control,
+            [
+                "set_ct_abx",
+                "set_ct_abx",
+                "set_ct_abx",
+                "set_ct_abx",
+                "set_ct_abx",
+                "set_ct_abx",
+                "set_music",
+                "set_music",
+                "set_ct_abx"
+            ]
+        );
+        assert_eq!(music, 4);
+    }
+
+    #[test]
+    fn quota_backoff() {
+        let (mut lamp, peer) = connected_pair();
+        let attempts = Arc::new(AtomicU32::new(0));
+        let counter = Arc::clone(&attempts);
+        let _responder = respond(peer, move |line| {
+            if counter.fetch_add(1, Ordering::Relaxed) % 3 < 2 {
+                reply_to(
+                    line,
+                    r#""error":{"code":-1,"message":"client quota exceeded"}"#,
+                )
+            } else {
+                reply_to(line, r#""result":["ok"]"#)
+            }
+        });
+        let cmd = Command::new(Action::new_ct(3200), Effect::Sudden);
+        assert!(matches!(lamp.call(&cmd), Err(CallError::QuotaExceeded(_))));
+        lamp.set_quota_backoff(Some(QuotaBackoff {
+            delay: Duration::from_millis(10),
+            max_retries: 2,
+        }));
+        // the second attempt fails, the third one succeeds
+        assert_eq!(lamp.call(&cmd).unwrap(), vec![Value::from("ok")]);
+        assert_eq!(attempts.load(Ordering::Relaxed), 3);
+    }
+
+    #[test]
+    fn retry_delays() {
+        let policy = RetryPolicy {
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(300),
+            jitter: false,
+        };
+        let delays: Vec<_> = (1..=4)
+            .map(|retry| policy.delay(retry).as_millis())
+            .collect();
+        assert_eq!(delays, vec![100, 200, 300, 300]);
+        let jittered = RetryPolicy {
+            jitter: true,
+            ..policy
+        };
+        let delay = jittered.delay(2);
+        assert!(delay >= Duration::from_millis(100) && delay <= Duration::from_millis(200));
+        assert!(CallError::Timeout.is_retryable());
+        assert!(!CallError::Io(Error::from(ErrorKind::UnexpectedEof)).is_retryable());
+    }
+
+    #[test]
+    fn retries_timeouts() {
+        let (mut lamp, peer) = connected_pair();
+        // the first request is never answered
+        let _responder = respond(peer, |line| {
+            if line.contains(r#""id":1,"#) {
+                String::new()
+            } else {
+                "{\"id\":2,\"result\":[\"ok\"]}\r\n".to_owned()
+            }
+        });
+        lamp.set_call_timeout(Duration::from_millis(50));
+        lamp.set_retry_policy(Some(RetryPolicy {
+            initial_backoff: Duration::from_millis(10),
+            ..RetryPolicy::default()
+        }));
+        let cmd = Command::new(Action::new_ct(3200), Effect::Sudden);
+        assert_eq!(lamp.call(&cmd).unwrap(), vec![Value::from("ok")]);
+    }
+
+    #[test]
+    fn diagnoses_refused_connections() {
+        let refused = |port| {
+            let addr = SocketAddr::from(([127, 0, 0, 1], port));
+            ConnectError::diagnose(Some((addr, Error::from(ErrorKind::ConnectionRefused))))
+        };
+        let err = refused(LAMP_PORT);
+        assert!(matches!(err, ConnectError::LanControlDisabled { .. }));
+        assert!(err.to_string().contains("LAN Control"));
+        let io = Error::from(err);
+        assert_eq!(io.kind(), ErrorKind::ConnectionRefused);
+        assert!(io.to_string().contains("LAN Control"));
+        assert!(matches!(refused(1234), ConnectError::Io(_)));
+        assert!(matches!(ConnectError::diagnose(None), ConnectError::Io(_)));
+    }
+
+    #[test]
+    fn unsupported_methods() {
+        let (mut lamp, peer) = connected_pair();
+        let _responder = respond(peer, |line| {
+            reply_to(
+                line,
+                r#""error":{"code":-1,"message":"method not supported"}"#,
+            )
+        });
+        let hsv = Action::new_hsv(
+            crate::cmd::Hue::new(120).unwrap(),
+            crate::cmd::Saturation::new(50).unwrap(),
+        );
+        let cmd = Command::new(hsv, Effect::Sudden);
+        let err = lamp.call(&cmd).unwrap_err();
+        assert!(matches!(
+            err,
+            CallError::Unsupported {
+                kind: CommandKind::SetHsv,
+                supported: None,
+                ..
+            }
+        ));
+        assert_eq!(err.to_string(), "the lamp doesn't support set_hsv");
+        lamp.set_supported_methods(Some(vec!["get_prop".to_owned(), "set_power".to_owned()]));
+        assert_eq!(
+            lamp.call(&cmd).unwrap_err().to_string(),
+            "the lamp doesn't support set_hsv (it supports get_prop, set_power)"
+        );
+    }
+
+    #[test]
+    fn wiretap() {
+        let (mut lamp, peer) = connected_pair();
+        let _responder = respond(peer, |_| "{\"id\":1,\"result\":[\"ok\"]}\r\n".to_owned());
+        let frames = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&frames);
+        lamp.set_wiretap_sink(move |frame| {
+            assert!(frame.peer.is_some());
+            sink.lock()
+                .unwrap()
+                .push((frame.direction, frame.line.to_owned()));
+        });
+        let cmd = Command::new(Action::new_ct(3200), Effect::Sudden);
+        let _result = lamp.call(&cmd).unwrap();
+        assert_eq!(
+            *frames.lock().unwrap(),
+            vec![
+                (
+                    Direction::Sent,
+                    r#"{"id":1,"method":"set_ct_abx","params":[3200,"sudden",0]}"#.to_owned()
+                ),
+                (
+                    Direction::Received,
+                    r#"{"id":1,"result":["ok"]}"#.to_owned()
+                ),
+            ]
+        );
+        lamp.set_wiretap(false);
+        lamp.send_raw(r#"{"id":2,"method":"toggle","params":[]}"#)
+            .unwrap();
+        assert_eq!(frames.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn builder_options() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let timeout = Duration::from_millis(700);
+        let lamp = Lamp::builder()
+            .connect_timeout(Duration::from_secs(1))
+            .read_timeout(timeout)
+            .write_timeout(timeout)
+            .nodelay(true)
+            .keepalive(Duration::from_secs(30))
+            .recv_buffer_size(8192)
+            .connect(listener.local_addr().unwrap())
+            .unwrap();
+        assert_eq!(
+            lamp.connection_options().unwrap(),
+            ConnectionOptions {
+                read_timeout: Some(timeout),
+                write_timeout: Some(timeout),
+                nodelay: true,
+                nonblocking: false
+            }
+        );
+        assert!(matches!(
+            Lamp::builder()
+                .connect_timeout(Duration::ZERO)
+                .connect(listener.local_addr().unwrap()),
+            Err(ConnectError::Io(err)) if err.kind() == ErrorKind::InvalidInput
+        ));
+    }
+
+    #[test]
+    fn binds_local_addr() {
+        let listener = TcpListener::bind("0.0.0.0:0").unwrap();
+        let addr = SocketAddr::from(([127, 0, 0, 1], listener.local_addr().unwrap().port()));
+        let local = SocketAddr::from(([127, 0, 0, 2], 0));
+        let mut lamp = Lamp::builder().local_addr(local).connect(addr).unwrap();
+        let (_peer, from) = listener.accept().unwrap();
+        assert_eq!(from.ip(), local.ip());
+        assert_eq!(lamp.local_addr().map(|addr| addr.ip()), Some(local.ip()));
+        // reconnecting binds again
+        lamp.reconnect().unwrap();
+        let (_peer, from) = listener.accept().unwrap();
+        assert_eq!(from.ip(), local.ip());
+        assert!(matches!(
+            Lamp::builder().local_addr(local).connect("[::1]:55443"),
+            Err(ConnectError::Io(err)) if err.kind() == ErrorKind::InvalidInput
+        ));
+    }
+
+    #[test]
+    fn connects_from_info() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let reply = std::format!(
+            "HTTP/1.1 200 OK\r\nLocation: yeelight://{}\r\nid: 0x15243f\r\nmodel: ceiling4\r\n\
+            support: get_prop set_power bg_set_power\r\npower: on\r\nbright: 40\r\n",
+            listener.local_addr().unwrap()
+        );
+        let info = LampInfo::parse(&reply).unwrap();
+        let lamp = info.connect().unwrap();
+        let (_peer, _) = listener.accept().unwrap();
+        assert_eq!(lamp.device_id(), Some("0x15243f"));
+        assert_eq!(lamp.supported_methods().map(<[_]>::len), Some(3));
+        assert_eq!(lamp.model(), Some(&Model::Ceiling));
+        // the advertised methods add the background light of this ceiling light, and leave out music mode
+        let capabilities = lamp.capabilities().unwrap();
+        assert!(capabilities.background && !capabilities.music);
+        assert_eq!(lamp.state(), info.state);
+        assert_eq!(lamp.state().power, Some(crate::cmd::Power::On));
+    }
+
+    #[test]
+    fn rejects_unsupported_commands() {
+        let (mut lamp, mut peer) = connected_pair();
+        lamp.set_model(Some(Model::Mono));
+        let err = lamp
+            .send_cmd(&Command::new(
+                Action::new_rgb_from_int(0xFF0000).unwrap(),
+                Effect::Sudden,
+            ))
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        assert_eq!(err.to_string(), "the lamp doesn't support color");
+        lamp.set_model(Some(Model::Ceiling));
+        let ct = Command::new(Action::new_ct(2000), Effect::Sudden);
+        assert!(
+            matches!(lamp.call(&ct), Err(CallError::Io(err)) if err.kind() == ErrorKind::InvalidInput)
+        );
+        // nothing was sent
+        lamp.set_model(None);
+        let _id = lamp.send_cmd(&ct).unwrap();
+        let mut line = String::new();
+        let _len = BufReader::new(&mut peer).read_line(&mut line).unwrap();
+        assert!(line.contains("\"set_ct_abx\""), "{line}");
+    }
+
+    #[test]
+    fn connection_options() {
+        let (mut lamp, _peer) = connected_pair();
+        let options = ConnectionOptions {
+            read_timeout: Some(Duration::from_secs(1)),
+            write_timeout: None,
+            nodelay: true,
+            nonblocking: true,
+        };
+        lamp.apply_connection_options(&options).unwrap();
+        assert_eq!(lamp.connection_options().unwrap(), options);
+        // a zero timeout is rejected, and nothing is changed
+        let invalid = ConnectionOptions {
+            read_timeout: None,
+            write_timeout: Some(Duration::ZERO),
+            nodelay: false,
+            nonblocking: false,
+        };
+        assert!(lamp.apply_connection_options(&invalid).is_err());
+        assert_eq!(lamp.connection_options().unwrap(), options);
+    }
+
+    #[test]
+    fn reconnects() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut lamp = Lamp::connect(listener.local_addr().unwrap()).unwrap();
+        lamp.set_reconnect_policy(Some(RetryPolicy {
+            initial_backoff: Duration::from_millis(10),
+            jitter: false,
+            ..RetryPolicy::default()
+        }));
+        let events = lamp.connection_events();
+        // the lamp drops the connection, and accepts the next one
+        drop(listener.accept().unwrap().0);
+        let _responder = std::thread::spawn(move || {
+            let (peer, _) = listener.accept().unwrap();
+            respond(peer, |line| reply_to(line, r#""result":["ok"]"#))
+        });
+        let cmd = Command::new(Action::new_ct(3200), Effect::Sudden);
+        assert_eq!(lamp.call(&cmd).unwrap(), vec![Value::from("ok")]);
+        assert!(matches!(
+            events.try_recv(),
+            Ok(ConnectionEvent::Disconnected { .. })
+        ));
+        assert_eq!(
+            events.try_recv(),
+            Ok(ConnectionEvent::Reconnected { attempts: 1 })
+        );
+        // the new connection is kept
+        assert_eq!(lamp.call(&cmd).unwrap(), vec![Value::from("ok")]);
+        assert!(events.try_recv().is_err());
+    }
+
+    #[test]
+    fn resends_unacknowledged() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut lamp = Lamp::connect(listener.local_addr().unwrap()).unwrap();
+        lamp.set_reconnect_policy(Some(RetryPolicy::default()));
+        lamp.set_resend_unacknowledged(true);
+        // the lamp receives the first command, but drops the connection before replying
+        let (peer, _) = listener.accept().unwrap();
+        let cmd = Command::new(Action::new_ct(3200), Effect::Sudden);
+        assert_eq!(lamp.send_cmd(&cmd).unwrap(), 1);
+        let mut lines = BufReader::new(peer).lines();
+        assert!(lines.next().unwrap().unwrap().starts_with(r#"{"id":1,"#));
+        drop(lines);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !lamp.inbox.is_closed() && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        let (tx, rx) = mpsc::channel();
+        let _responder = std::thread::spawn(move || {
+            let (peer, _) = listener.accept().unwrap();
+            respond(peer, move |line| {
+                tx.send(request_id(line)).unwrap();
+                reply_to(line, r#""result":["ok"]"#)
+            })
+        });
+        assert_eq!(lamp.call(&cmd).unwrap(), vec![Value::from("ok")]);
+        // the first command was resent once, before the second one
+        assert_eq!(rx.try_iter().collect::<Vec<_>>(), [1, 2]);
+        assert!(lamp.inbox.unacknowledged().is_empty());
+    }
+
+    #[test]
+    fn resend_rate_limited() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut lamp = Lamp::connect(listener.local_addr().unwrap()).unwrap();
+        lamp.set_resend_unacknowledged(true);
+        let (_first, _) = listener.accept().unwrap();
+        let cmd = Command::new(Action::new_ct(3200), Effect::Sudden);
+        assert_eq!(lamp.send_cmd(&cmd).unwrap(), 1);
+        assert_eq!(lamp.send_cmd(&cmd).unwrap(), 2);
+        lamp.set_rate_limit(Some(RateLimit {
+            capacity: 1,
+            refill_interval: Duration::from_secs(3600),
+            mode: RateLimitMode::Error,
+            ..RateLimit::default()
+        }));
+        let events = lamp.connection_events();
+        lamp.reconnect().unwrap();
+        let (second, _) = listener.accept().unwrap();
+        // only the first request fits the limit, and the connection is reported as re-established anyway
+        assert_eq!(
+            events.try_iter().collect::<Vec<_>>(),
+            [ConnectionEvent::Reconnected { attempts: 1 }]
+        );
+        let mut lines = BufReader::new(second).lines();
+        assert_eq!(request_id(&lines.next().unwrap().unwrap()), 1);
+        // both stay tracked until they're replied to
+        assert_eq!(lamp.inbox.unacknowledged().len(), 2);
+    }
+
+    #[test]
+    fn connect_with_retries() {
+        let policy = RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(10),
+            jitter: false,
+        };
+        // nothing listens on the port, so all attempts are refused
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let start = Instant::now();
+        let err = Lamp::connect_with_retries(("127.0.0.1", port), policy).unwrap_err();
+        assert!(matches!(err, ConnectError::Io(err) if err.kind() == ErrorKind::ConnectionRefused));
+        assert!(start.elapsed() >= Duration::from_millis(20));
+        let listener = TcpListener::bind(("127.0.0.1", port)).unwrap();
+        let _lamp = Lamp::connect_with_retries(listener.local_addr().unwrap(), policy).unwrap();
+    }
+
+    #[test]
+    fn total_connect_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let closed = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let addrs = [closed, listener.local_addr().unwrap()];
+        let _lamp = Lamp::connect_total_timeout(&addrs[..], Duration::from_secs(1)).unwrap();
+        assert!(matches!(
+            Lamp::connect_total_timeout(&addrs[..], Duration::ZERO),
+            Err(ConnectError::Io(err)) if err.kind() == ErrorKind::InvalidInput
+        ));
+    }
+
+    #[test]
+    fn reconnect_fails() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut lamp = Lamp::connect(listener.local_addr().unwrap()).unwrap();
+        let events = lamp.connection_events();
+        drop(listener);
+        lamp.set_reconnect_policy(Some(RetryPolicy {
+            max_attempts: 2,
+            initial_backoff: Duration::from_millis(10),
+            ..RetryPolicy::default()
+        }));
+        assert!(lamp.reconnect().is_err());
+        // the old connection may be reported as lost by the reader thread in the meantime
+        let events = events
+            .try_iter()
+            .filter(|event| !matches!(event, ConnectionEvent::Disconnected { .. }))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            events,
+            [ConnectionEvent::ReconnectFailed {
+                attempts: 2,
+                kind: ErrorKind::ConnectionRefused
+            }]
+        );
+    }
+
+    #[test]
+    fn idle_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut lamp = Lamp::connect(listener.local_addr().unwrap()).unwrap();
+        let (peer, _) = listener.accept().unwrap();
+        let events = lamp.connection_events();
+        lamp.set_idle_timeout(Some(Duration::from_millis(50)))
+            .unwrap();
+        assert_eq!(
+            events.recv_timeout(Duration::from_secs(5)),
+            Ok(ConnectionEvent::Disconnected {
+                reason: DisconnectReason::Idle
+            })
+        );
+        // the lamp sees the connection closed
+        assert_eq!(BufReader::new(peer).lines().count(), 0);
+        let _responder = std::thread::spawn(move || {
+            let (peer, _) = listener.accept().unwrap();
+            respond(peer, |line| reply_to(line, r#""result":["ok"]"#))
+        });
+        // the next command reconnects without a reconnect policy
+        let cmd = Command::new(Action::new_ct(3200), Effect::Sudden);
+        assert_eq!(lamp.call(&cmd).unwrap(), vec![Value::from("ok")]);
+        assert_eq!(
+            events.try_recv(),
+            Ok(ConnectionEvent::Reconnected { attempts: 1 })
+        );
+        assert!(lamp.is_connected());
+    }
+
+    #[test]
+    fn idle_timeout_ends_music() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut lamp = Lamp::connect(listener.local_addr().unwrap()).unwrap();
+        let (peer, _) = listener.accept().unwrap();
+        let (tx, rx) = mpsc::channel();
+        let _responder = respond(peer, move |line| {
+            let request = serde_json::from_str::<Value>(line).unwrap();
+            if request["method"] == "set_music" && request["params"][0] == 1 {
+                let host = request["params"][1].as_str().unwrap();
+                let port = request["params"][2].as_u64().unwrap();
+                let music = TcpStream::connect(std::format!("{host}:{port}")).unwrap();
+                tx.send(music).unwrap();
+            }
+            reply_to(line, r#""result":["ok"]"#)
+        });
+        lamp.set_rate_limit(Some(RateLimit {
+            capacity: 1,
+            refill_interval: Duration::from_secs(3600),
+            mode: RateLimitMode::Error,
+            ..RateLimit::default()
+        }));
+        lamp.set_music_upgrade(Some(MusicUpgrade {
+            reserve: 1,
+            idle: Duration::from_secs(3600),
+            accept_timeout: Duration::from_secs(5),
+        }));
+        let cmd = Command::new(Action::new_ct(3200), Effect::Sudden);
+        let _id = lamp.send_cmd(&cmd).unwrap();
+        assert!(lamp.is_music_active());
+        let _music = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        let events = lamp.connection_events();
+        lamp.set_idle_timeout(Some(Duration::from_millis(50)))
+            .unwrap();
+        assert_eq!(
+            events.recv_timeout(Duration::from_secs(5)),
+            Ok(ConnectionEvent::Disconnected {
+                reason: DisconnectReason::Idle
+            })
+        );
+        // closing doesn't reconnect just to switch music mode off
+        lamp.close().unwrap();
+        listener.set_nonblocking(true).unwrap();
+        assert_eq!(listener.accept().unwrap_err().kind(), ErrorKind::WouldBlock);
+    }
+
+    #[test]
+    fn follows_device_id() {
+        #[derive(Debug)]
+        struct Registry(SocketAddr);
+        impl Resolver for Registry {
+            fn resolve(&self, device_id: &str) -> std::io::Result<Option<SocketAddr>> {
+                Ok((device_id == "0x15243f").then_some(self.0))
+            }
+        }
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut lamp = Lamp::connect(listener.local_addr().unwrap()).unwrap();
+        let events = lamp.connection_events();
+        drop(listener);
+        // the lamp got a new address
+        let moved = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = moved.local_addr().unwrap();
+        lamp.set_resolver(Registry(addr));
+        assert!(lamp.reconnect().is_err());
+        lamp.set_device_id(Some("0x15243f".to_owned()));
+        assert_eq!(lamp.device_id(), Some("0x15243f"));
+        lamp.reconnect().unwrap();
+        assert_eq!(lamp.peer_addr(), Some(addr));
+        let events = events
+            .try_iter()
+            .filter(|event| !matches!(event, ConnectionEvent::Disconnected { .. }))
+            .collect::<Vec<_>>();
+        assert!(matches!(events[0], ConnectionEvent::ReconnectFailed { .. }));
+        assert_eq!(
+            events[1..],
+            [
+                ConnectionEvent::Moved { to: addr },
+                ConnectionEvent::Reconnected { attempts: 1 }
+            ]
+        );
+    }
+
+    #[test]
+    fn heartbeat() {
+        let (mut lamp, peer) = connected_pair();
+        let answering = Arc::new(AtomicBool::new(false));
+        let answer = Arc::clone(&answering);
+        let _responder = respond(peer, move |line| {
+            assert!(line.contains(r#""method":"get_prop","params":["power"]"#));
+            if !answer.load(Ordering::Relaxed) {
+                return String::new();
+            }
+            reply_to(line, r#""result":["on"]"#)
+        });
+        let events = lamp.connection_events();
+        lamp.set_heartbeat(Some(Heartbeat {
+            interval: Duration::from_millis(10),
+            timeout: Duration::from_millis(50),
+            max_missed: 2,
+        }))
+        .unwrap();
+        let timeout = Duration::from_secs(5);
+        assert_eq!(
+            events.recv_timeout(timeout),
+            Ok(ConnectionEvent::Unhealthy { missed: 2 })
+        );
+        assert!(!lamp.is_healthy());
+        answering.store(true, Ordering::Relaxed);
+        assert_eq!(events.recv_timeout(timeout), Ok(ConnectionEvent::Healthy));
+        assert!(lamp.is_healthy());
+        lamp.set_heartbeat(None).unwrap();
+    }
+
+    #[test]
+    fn heartbeat_rate_limit() {
+        let (mut lamp, peer) = connected_pair();
+        let pings = Arc::new(AtomicU32::new(0));
+        let count = Arc::clone(&pings);
+        let _responder = respond(peer, move |line| {
+            let _prev = count.fetch_add(1, Ordering::Relaxed);
+            reply_to(line, r#""result":["on"]"#)
+        });
+        lamp.set_rate_limit(Some(RateLimit {
+            capacity: 2,
+            refill_interval: Duration::from_secs(3600),
+            mode: RateLimitMode::Error,
+            ..RateLimit::default()
+        }));
+        lamp.set_heartbeat(Some(Heartbeat {
+            interval: Duration::from_millis(10),
+            timeout: Duration::from_millis(50),
+            max_missed: 2,
+        }))
+        .unwrap();
+        std::thread::sleep(Duration::from_millis(200));
+        lamp.set_heartbeat(None).unwrap();
+        // the pings used up the limit, and the skipped pings don't make the lamp unhealthy
+        assert_eq!(pings.load(Ordering::Relaxed), 2);
+        assert!(lamp.is_healthy());
+        let cmd = Command::new(Action::new_ct(3200), Effect::Sudden);
+        let err = lamp.send_cmd(&cmd).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::QuotaExceeded);
+    }
+
+    #[test]
+    fn close() {
+        let (mut lamp, peer) = connected_pair();
+        let cmd = Command::new(Action::new_ct(3200), Effect::Sudden);
+        let _id = lamp.send_cmd(&cmd).unwrap();
+        lamp.close().unwrap();
+        // the lamp receives the command, and then the end of the connection
+        let mut lines = BufReader::new(peer).lines();
+        assert!(lines.next().unwrap().unwrap().starts_with(r#"{"id":1,"#));
+        assert!(lines.next().is_none());
+    }
+
+    #[test]
+    fn split() {
+        let (lamp, mut peer) = connected_pair();
+        let (reader, mut writer) = lamp.split();
+        let consumer = std::thread::spawn(move || {
+            let first = reader.recv_timeout(Duration::from_secs(5)).unwrap();
+            // the iterator ends once the connection is closed
+            (first, reader.count())
+        });
+        peer.write_all(b"{\"method\":\"props\",\"params\":{\"power\":\"off\"}}\r\n")
+            .unwrap();
+        let _id = writer.send_action(Action::new_ct(3200)).unwrap();
+        let mut lines = BufReader::new(peer.try_clone().unwrap()).lines();
+        assert!(lines.next().unwrap().unwrap().contains("set_ct_abx"));
+        writer.into_inner().close().unwrap();
+        let (first, rest) = consumer.join().unwrap();
+        assert_eq!(first.get("power"), Some(&Value::from("off")));
+        assert_eq!(rest, 0);
+    }
+
+    #[test]
+    fn try_clone() {
+        let (mut lamp, peer) = connected_pair();
+        lamp.set_call_timeout(Duration::from_secs(1));
+        let _responder = respond(peer, |line| reply_to(line, r#""result":["ok"]"#));
+        let mut clone = lamp.try_clone().unwrap();
+        assert_eq!(clone.call_timeout(), Duration::from_secs(1));
+        let cmd = Command::new(Action::new_ct(3200), Effect::Sudden);
+        assert_eq!(lamp.send_cmd(&cmd).unwrap(), 1);
+        assert_eq!(clone.send_cmd(&cmd).unwrap(), 2);
+        // dropping the clone keeps the connection open
+        drop(clone);
+        assert_eq!(lamp.call(&cmd).unwrap(), vec![Value::from("ok")]);
+    }
+
+    #[test]
+    fn try_clone_reconnect() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut lamp = Lamp::connect(listener.local_addr().unwrap()).unwrap();
+        let mut clone = lamp.try_clone().unwrap();
+        let (first, _) = listener.accept().unwrap();
+        lamp.reconnect().unwrap();
+        let (second, _) = listener.accept().unwrap();
+        // the previous connection was shut down
+        assert_eq!(BufReader::new(first).lines().count(), 0);
+        let responder = respond(second, |line| reply_to(line, r#""result":["ok"]"#));
+        let cmd = Command::new(Action::new_ct(3200), Effect::Sudden);
+        // the clone moves to the new connection as well
+        assert_eq!(clone.call(&cmd).unwrap(), vec![Value::from("ok")]);
+        assert_eq!(lamp.call(&cmd).unwrap(), vec![Value::from("ok")]);
+        assert_eq!(clone.peer_addr(), lamp.peer_addr());
+        assert_eq!(clone.connected_since(), lamp.connected_since());
+        drop(lamp);
+        assert_eq!(clone.call(&cmd).unwrap(), vec![Value::from("ok")]);
+        // the new connection is shut down once the last Lamp sharing it is dropped
+        drop(clone);
+        responder.join().unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unix_transport() {
+        use std::os::unix::net::UnixStream;
+        let (stream, peer) = UnixStream::pair().unwrap();
+        let mut lamp = Lamp::with_transport(stream).unwrap();
+        let _responder = respond(peer, |line| reply_to(line, r#""result":["ok"]"#));
+        let cmd = Command::new(Action::new_ct(3200), Effect::Sudden);
+        assert_eq!(lamp.call(&cmd).unwrap(), vec![Value::from("ok")]);
+        assert_eq!(lamp.state().ct, Some(3200));
+        // unix sockets can't reconnect, so the lamp keeps using its connection
+        assert_eq!(lamp.reconnect().unwrap_err().kind(), ErrorKind::Unsupported);
+        let cmd = Command::new(Action::new_ct(4000), Effect::Sudden);
+        assert_eq!(lamp.call(&cmd).unwrap(), vec![Value::from("ok")]);
+        assert_eq!(lamp.state().ct, Some(4000));
+    }
+
+    #[test]
+    fn shared_lamp() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<SharedLamp>();
+        let (lamp, peer) = connected_pair();
+        let _responder = respond(peer, |line| reply_to(line, r#""result":["ok"]"#));
+        let lamp = SharedLamp::from(lamp);
+        let threads = (0..4)
+            .map(|_| {
+                let lamp = lamp.clone();
+                std::thread::spawn(move || {
+                    let cmd = Command::new(Action::new_ct(3200), Effect::Sudden);
+                    for _ in 0..5 {
+                        assert_eq!(lamp.call(&cmd).unwrap(), vec![Value::from("ok")]);
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(lamp.state().ct, Some(3200));
+    }
+
+    #[test]
+    fn nonblocking() {
+        let (mut lamp, mut peer) = connected_pair();
+        lamp.set_nonblocking(true).unwrap();
+        assert!(lamp.connection_options().unwrap().nonblocking);
+        assert!(lamp.poll_read_response().unwrap().is_none());
+        let cmd = Command::new(Action::new_ct(3200), Effect::Sudden);
+        assert_eq!(lamp.send_cmd(&cmd).unwrap(), 1);
+        assert!(!lamp.has_pending_writes());
+        peer.write_all(b"{\"method\":\"props\",\"params\":{\"ct\":3200}}\r\n{\"id\":1,\"result\":[\"ok\"]}\r\n")
+            .unwrap();
+        let mut responses = Vec::new();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while responses.len() < 2 && Instant::now() < deadline {
+            match lamp.poll_read_response().unwrap() {
+                Some(response) => responses.push(response),
+                None => std::thread::sleep(Duration::from_millis(5)),
+            }
+        }
+        assert!(matches!(responses[0], Response::Props(_)));
+        assert_eq!(responses[1].id(), Some(1));
+        drop(peer);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let err = loop {
+            match lamp.poll_read_response() {
+                Ok(None) if Instant::now() < deadline => {
+                    std::thread::sleep(Duration::from_millis(5))
+                }
+                other => break other.unwrap_err(),
+            }
+        };
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn buffers_partial_writes() {
+        let (mut lamp, peer) = connected_pair();
+        lamp.set_nonblocking(true).unwrap();
+        // the request is too large for the socket buffers, so the peer needs to read before it's written
+        let param = "a".repeat(16 << 20);
+        let req = std::format!(r#"{{"id":1,"method":"x","params":["{param}"]}}"#);
+        lamp.send_raw(&req).unwrap();
+        assert!(lamp.has_pending_writes());
+        let reader =
+            std::thread::spawn(move || BufReader::new(peer).lines().next().unwrap().unwrap().len());
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !lamp.poll_flush().unwrap() && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert!(!lamp.has_pending_writes());
+        assert_eq!(reader.join().unwrap(), req.len());
+    }
+
+    #[test]
+    fn raw_writes() {
+        let (mut lamp, peer) = connected_pair();
+        lamp.set_nonblocking(true).unwrap();
+        lamp.set_rate_limit(Some(RateLimit {
+            capacity: 2,
+            refill_interval: Duration::from_secs(3600),
+            mode: RateLimitMode::Error,
+            ..RateLimit::default()
+        }));
+        let param = "a".repeat(16 << 20);
+        lamp.send_raw(&std::format!(
+            r#"{{"id":1,"method":"x","params":["{param}"]}}"#
+        ))
+        .unwrap();
+        assert!(lamp.has_pending_writes());
+        // the write waits behind the partially written request
+        lamp.write_all(b"{\"id\":2,\"method\":\"toggle\",\"params\":[]}\r\n")
+            .unwrap();
+        let err = lamp.write(b"{}\r\n").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::QuotaExceeded);
+        let reader = std::thread::spawn(move || {
+            BufReader::new(peer)
+                .lines()
+                .take(2)
+                .map(|line| request_id(&line.unwrap()))
+                .collect::<Vec<_>>()
+        });
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while let Err(err) = lamp.flush() {
+            assert_eq!(err.kind(), ErrorKind::WouldBlock);
+            assert!(Instant::now() < deadline);
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(reader.join().unwrap(), [1, 2]);
+    }
+
+    #[test]
+    fn call_fails_on_disconnect() {
+        let (mut lamp, peer) = connected_pair();
+        drop(peer);
+        let cmd = Command::new(Action::new_ct(3200), Effect::Sudden);
+        assert!(matches!(lamp.call(&cmd), Err(CallError::Disconnected(_))));
+    }
+
+    #[test]
+    fn reports_disconnect() {
+        let (mut lamp, peer) = connected_pair();
+        let events = lamp.connection_events();
+        assert_eq!(lamp.disconnect_reason(), None);
+        drop(peer);
+        assert_eq!(
+            events.recv_timeout(Duration::from_secs(5)),
+            Ok(ConnectionEvent::Disconnected {
+                reason: DisconnectReason::Closed
+            })
+        );
+        assert_eq!(lamp.disconnect_reason(), Some(DisconnectReason::Closed));
+        assert!(!lamp.is_connected());
+        // commands fail right away instead of being written to the closed connection
+        let cmd = Command::new(Action::new_ct(3200), Effect::Sudden);
+        let err = lamp.send_cmd(&cmd).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotConnected);
+        assert!(matches!(
+            lamp.call(&cmd),
+            Err(CallError::Disconnected(DisconnectReason::Closed))
+        ));
+        assert!(events.try_recv().is_err());
+    }
+
+    #[test]
+    fn call_times_out() {
+        let (mut lamp, _peer) = connected_pair();
+        lamp.set_call_timeout(Duration::from_millis(50));
+        let cmd = Command::new(Action::new_ct(3200), Effect::Sudden);
+        assert!(matches!(lamp.call(&cmd), Err(CallError::Timeout)));
     }
 }
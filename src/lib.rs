@@ -1,9 +1,61 @@
 //! Library for controlling Yeelight lamps through Rust.
+//!
+//! The protocol layer (the `cmd`, `colors`, `framing`, `limits`, `model`, `response` and `state` modules) only needs `alloc`,
+//! so it can be used on embedded targets with their own transport by disabling the default `std` feature.
+//! The `lamp` module, which connects to lamps over TCP, as well as the `actor`, `discovery`, `manager`, `music`, `pool`, `record` and `transport` modules require `std`.
+//! The `event_loop` module, which reads from many lamps on a single thread, requires the `event-loop` feature.
+#![no_std]
 
+extern crate alloc;
+#[cfg(any(test, feature = "std"))]
+extern crate std;
+
+/// Module for driving a lamp from a background thread through a channel.
+#[cfg(feature = "std")]
+pub mod actor;
 /// Module for commands.
 pub mod cmd;
+/// Module for colors, such as the named CSS colors.
+pub mod colors;
+/// Module for finding lamps on the network.
+#[cfg(feature = "std")]
+pub mod discovery;
+/// Module for reading from many lamps on a single thread.
+#[cfg(feature = "event-loop")]
+pub mod event_loop;
+/// Module for splitting the data received from lamps into lines.
+pub mod framing;
 /// Module for code related to interfacing with lamps.
+#[cfg(feature = "std")]
 pub mod lamp;
+/// Module for the limits of the protocol, such as the range of color temperatures.
+pub mod limits;
+/// Module for macros constructing actions from constants.
+mod macros;
+/// Module for managing many lamps at once.
+#[cfg(feature = "std")]
+pub mod manager;
+/// Module for the models of lamps and their capabilities.
+pub mod model;
+/// Module for music mode, in which lamps read commands from a connection they open to the host.
+#[cfg(feature = "std")]
+pub mod music;
+/// Module for pooling connections to lamps by address.
+#[cfg(feature = "std")]
+pub mod pool;
+/// Module for the background thread reading from lamps.
+#[cfg(feature = "std")]
+mod reader;
+/// Module for recording and replaying the lines exchanged with lamps.
+#[cfg(feature = "std")]
+pub mod record;
+/// Module for responses sent by lamps, such as replies to commands.
+pub mod response;
+/// Module for the cached state of lamps.
+pub mod state;
+/// Module for the byte streams connecting lamps.
+#[cfg(feature = "std")]
+pub mod transport;
 
 /*
 pub fn add(left: u64, right: u64) -> u64 {
@@ -0,0 +1,162 @@
+use core::{fmt::Display, time::Duration};
+use log::info;
+
+use crate::cmd::ValidationError;
+
+/*
+ * The limits of the protocol, as given in the Yeelight WiFi Light Inter-Operation Specification,
+ * for the values of the built-in commands (see cmd.rs).
+ * Commands declared with define_command! give their own ranges.
+ */
+
+/// The lowest color temperature (in kelvins) accepted by `set_ct_abx`.
+pub const CT_MIN: u16 = 1700;
+/// The highest color temperature (in kelvins) accepted by `set_ct_abx`.
+pub const CT_MAX: u16 = 6500;
+/// The lowest color accepted by `set_rgb`; black (zero) isn't allowed.
+pub const RGB_MIN: u32 = 0x000001;
+/// The highest color accepted by `set_rgb`.
+pub const RGB_MAX: u32 = 0xFFFFFF;
+/// The lowest brightness (in percent) accepted by `set_bright`.
+pub const BRIGHT_MIN: u8 = 1;
+/// The highest brightness (in percent) accepted by `set_bright`.
+pub const BRIGHT_MAX: u8 = 100;
+/// The lowest hue (in degrees) accepted by `set_hsv`.
+pub const HUE_MIN: u16 = 0;
+/// The highest hue (in degrees) accepted by `set_hsv`.
+pub const HUE_MAX: u16 = 359;
+/// The lowest saturation (in percent) accepted by `set_hsv`.
+pub const SAT_MIN: u8 = 0;
+/// The highest saturation (in percent) accepted by `set_hsv`.
+pub const SAT_MAX: u8 = 100;
+/// The lowest percentage accepted by the `adjust_*` methods.
+pub const PERCENTAGE_MIN: i8 = -100;
+/// The highest percentage accepted by the `adjust_*` methods.
+pub const PERCENTAGE_MAX: i8 = 100;
+/// The shortest duration of a smooth transition.
+pub const SMOOTH_MIN: Duration = Duration::from_millis(30);
+
+/// What to do with a value outside of the limits of the protocol.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum LimitPolicy {
+    /// Clamp the value to the nearest accepted value (the default of most constructors).
+    #[default]
+    Clamp,
+    /// Reject the value with a [ValidationError].
+    Reject,
+}
+
+impl LimitPolicy {
+    /// Apply the policy to a value that must be between `min` and `max` (inclusive).
+    ///
+    /// The field is the name of the value, used in the [ValidationError::OutOfRange] error.
+    pub fn apply<T>(
+        self,
+        field: &'static str,
+        value: T,
+        min: T,
+        max: T,
+    ) -> Result<T, ValidationError>
+    where
+        T: Copy + Ord + Display + Into<i64>,
+    {
+        if (min..=max).contains(&value) {
+            return Ok(value);
+        }
+        match self {
+            Self::Clamp => Ok(clamp(field, value, min, max)),
+            Self::Reject => Err(ValidationError::OutOfRange {
+                field,
+                min: min.into(),
+                max: max.into(),
+                got: value.into(),
+            }),
+        }
+    }
+
+    /// Apply the policy to a duration that must be at least `min`.
+    pub(crate) fn apply_min_duration(
+        self,
+        field: &'static str,
+        value: Duration,
+        min: Duration,
+    ) -> Result<Duration, ValidationError> {
+        match self {
+            _ if value >= min => Ok(value),
+            Self::Clamp => {
+                info!("Limits | Clamping {field} to {}ms", min.as_millis());
+                Ok(min)
+            }
+            Self::Reject => Err(ValidationError::TooShort {
+                field,
+                min,
+                got: value,
+            }),
+        }
+    }
+}
+
+/// Clamp a value to be between `min` and `max` (inclusive), logging when it's changed.
+pub(crate) fn clamp<T: Copy + Ord + Display>(field: &'static str, value: T, min: T, max: T) -> T {
+    if value < min {
+        info!("Limits | Clamping {field} to {min}");
+        min
+    } else if value > max {
+        info!("Limits | Clamping {field} to {max}");
+        max
+    } else {
+        value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn apply_in_range() {
+        for policy in [LimitPolicy::Clamp, LimitPolicy::Reject] {
+            assert_eq!(policy.apply("ct", 4000, CT_MIN, CT_MAX), Ok(4000));
+            assert_eq!(policy.apply("ct", CT_MIN, CT_MIN, CT_MAX), Ok(CT_MIN));
+            assert_eq!(policy.apply("ct", CT_MAX, CT_MIN, CT_MAX), Ok(CT_MAX));
+        }
+    }
+
+    #[test]
+    fn apply_out_of_range() {
+        let clamp = LimitPolicy::Clamp;
+        assert_eq!(clamp.apply("ct", 1000, CT_MIN, CT_MAX), Ok(CT_MIN));
+        assert_eq!(clamp.apply("ct", 9000, CT_MIN, CT_MAX), Ok(CT_MAX));
+        assert_eq!(
+            LimitPolicy::Reject.apply("ct", 9000, CT_MIN, CT_MAX),
+            Err(ValidationError::OutOfRange {
+                field: "ct",
+                min: 1700,
+                max: 6500,
+                got: 9000
+            })
+        );
+    }
+
+    #[test]
+    fn apply_min_duration() {
+        let short = Duration::from_millis(10);
+        assert_eq!(
+            LimitPolicy::Clamp.apply_min_duration("duration", short, SMOOTH_MIN),
+            Ok(SMOOTH_MIN)
+        );
+        assert_eq!(
+            LimitPolicy::Reject.apply_min_duration("duration", short, SMOOTH_MIN),
+            Err(ValidationError::TooShort {
+                field: "duration",
+                min: SMOOTH_MIN,
+                got: short
+            })
+        );
+        assert_eq!(
+            LimitPolicy::Reject.apply_min_duration("duration", SMOOTH_MIN, SMOOTH_MIN),
+            Ok(SMOOTH_MIN)
+        );
+    }
+}
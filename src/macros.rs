@@ -0,0 +1,199 @@
+// Macros for constructing actions from constants.
+// The values are checked at compile time, so there is nothing to unwrap.
+
+/// Create an [Action](crate::cmd::Action) for changing the color temperature of the lamp.
+///
+/// The color temperature must be a constant between 1700 and 6500 (kelvins),
+/// otherwise the code doesn't compile.
+/// ```
+/// # use yeerugina_lib::{cmd::Action, ct};
+/// assert_eq!(ct!(4000), Action::new_ct(4000));
+/// ```
+/// ```compile_fail
+/// # use yeerugina_lib::ct;
+/// let action = ct!(9000);
+/// ```
+#[macro_export]
+macro_rules! ct {
+    ($ct:expr) => {{
+        const CT: u16 = $ct;
+        const _: () = assert!(
+            CT >= $crate::limits::CT_MIN && CT <= $crate::limits::CT_MAX,
+            "color temperature must be between 1700K and 6500K"
+        );
+        $crate::cmd::Action::new_ct(CT)
+    }};
+}
+
+/// Create an [Action](crate::cmd::Action) for changing the color of the lamp to some RGB color.
+///
+/// The color can be given as a constant u32 of the form 0x00RRGGBB, or as three constant u8 values.
+/// The color must not be zero and must fit in 24 bits, otherwise the code doesn't compile.
+/// ```
+/// # use yeerugina_lib::{cmd::Action, rgb};
+/// assert_eq!(rgb!(0xA61A3A), Action::new_rgb_from_int(0xA61A3A).unwrap());
+/// assert_eq!(rgb!(166, 26, 58), Action::new_rgb_from_int(0xA61A3A).unwrap());
+/// ```
+/// ```compile_fail
+/// # use yeerugina_lib::rgb;
+/// let action = rgb!(0x1000000);
+/// ```
+/// ```compile_fail
+/// # use yeerugina_lib::rgb;
+/// let action = rgb!(0, 0, 0);
+/// ```
+#[macro_export]
+macro_rules! rgb {
+    ($r:expr, $g:expr, $b:expr) => {
+        $crate::rgb!(u32::from_be_bytes([0, $r, $g, $b]))
+    };
+    ($rgb:expr) => {{
+        const RGB: u32 = $rgb;
+        const _: () = assert!(
+            RGB >= $crate::limits::RGB_MIN && RGB <= $crate::limits::RGB_MAX,
+            "RGB value must be between 0x000001 and 0xFFFFFF"
+        );
+        $crate::cmd::Action::new_rgb_lenient(RGB)
+    }};
+}
+
+/// Create an [Action](crate::cmd::Action) for changing the color of the lamp to some hue and saturation.
+///
+/// The hue must be a constant between 0 and 359, and the saturation must be a constant between 0 and 100,
+/// otherwise the code doesn't compile.
+/// ```
+/// # use yeerugina_lib::{cmd::{Action, Hue, Saturation}, hsv};
+/// let expect = Action::new_hsv(Hue::new(270).unwrap(), Saturation::new(67).unwrap());
+/// assert_eq!(hsv!(270, 67), expect);
+/// ```
+/// ```compile_fail
+/// # use yeerugina_lib::hsv;
+/// let action = hsv!(360, 50);
+/// ```
+#[macro_export]
+macro_rules! hsv {
+    ($hue:expr, $sat:expr) => {{
+        const HUE: $crate::cmd::Hue = match $crate::cmd::Hue::new($hue) {
+            Ok(hue) => hue,
+            Err(_) => panic!("hue must be between 0 and 359"),
+        };
+        const SAT: $crate::cmd::Saturation = match $crate::cmd::Saturation::new($sat) {
+            Ok(sat) => sat,
+            Err(_) => panic!("saturation must be between 0 and 100"),
+        };
+        $crate::cmd::Action::new_hsv(HUE, SAT)
+    }};
+}
+
+/// Declare a typed command for a protocol method that is not modelled by this crate.
+///
+/// This generates a struct with one field per parameter, a constructor `new` validating the parameters,
+/// a getter per parameter, a `METHOD` constant with the method name,
+/// and a conversion into [Action](crate::cmd::Action) (as a custom action, see [Command::custom](crate::cmd::Command::custom)).
+/// Integer parameters can be restricted to an inclusive range with `in min..=max`;
+/// `new` returns a [ValidationError](crate::cmd::ValidationError) if a value is outside of it.
+/// Parameters must be convertible into a [Param](crate::cmd::Param), such as integers and Strings,
+/// and their types can be given as paths or with generic arguments.
+///
+/// The macro only wraps [Action::new_custom](crate::cmd::Action::new_custom): the built-in methods of the crate aren't declared with it,
+/// and the generated commands have the [CommandKind::Custom](crate::cmd::CommandKind::Custom) kind,
+/// so e.g. they don't update the cached state of a lamp.
+/// ```
+/// # use yeerugina_lib::{cmd::{Action, Param, ValidationError}, define_command};
+/// define_command! {
+///     /// Add a timer turning off the lamp after some minutes.
+///     pub CronAdd = "cron_add" { kind: u8 in 0..=0, minutes: u32 in 1..=1440 }
+/// }
+///
+/// let cron = CronAdd::new(0, 15).unwrap();
+/// assert_eq!(cron.minutes(), &15);
+/// assert_eq!(
+///     Action::from(cron),
+///     Action::new_custom(CronAdd::METHOD, vec![Param::Int(0), Param::Int(15)])
+/// );
+/// assert_eq!(
+///     CronAdd::new(0, 0),
+///     Err(ValidationError::OutOfRange { field: "minutes", min: 1, max: 1440, got: 0 })
+/// );
+/// ```
+/// ```
+/// # use yeerugina_lib::{cmd::{Action, Command, Effect}, define_command};
+/// define_command! {
+///     /// Set the name of the lamp.
+///     pub SetName = "set_name" { name: std::string::String }
+/// }
+///
+/// let name = SetName::new("desk \"lamp\"".to_owned()).unwrap();
+/// let cmd = Command::new(name.into(), Effect::Sudden);
+/// assert_eq!(cmd.to_string(), r#"{"id":0,"method":"set_name","params":["desk \"lamp\""]}"#);
+/// ```
+#[macro_export]
+macro_rules! define_command {
+    // Normalize the types of the parameters, so that they can be paths or have generic arguments.
+    (
+        $(#[$meta:meta])*
+        $vis:vis $name:ident = $method:literal {
+            $(
+                $field:ident: $ty:ident $(:: $path:ident)* $(<$($generic:ty),+>)?
+                $(in $min:literal ..= $max:literal)?
+            ),* $(,)?
+        }
+    ) => {
+        $crate::define_command! {
+            @custom
+            $(#[$meta])*
+            $vis $name = $method {
+                $($field: ($ty $(:: $path)* $(<$($generic),+>)?) [$($min, $max)?]),*
+            }
+        }
+    };
+    (
+        @custom
+        $(#[$meta:meta])*
+        $vis:vis $name:ident = $method:literal {
+            $($field:ident: ($ty:ty) [$($min:literal, $max:literal)?]),*
+        }
+    ) => {
+        $(#[$meta])*
+        #[derive(Clone, Debug, PartialEq, Eq, Hash)]
+        $vis struct $name {
+            $($field: $ty,)*
+        }
+
+        impl $name {
+            /// The name of the method on the wire.
+            pub const METHOD: &'static str = $method;
+
+            /// Create a new command, returning a [ValidationError]($crate::cmd::ValidationError)
+            /// if a parameter is outside of its accepted range.
+            pub fn new($($field: $ty),*) -> ::core::result::Result<Self, $crate::cmd::ValidationError> {
+                $($(
+                    let $field = $crate::limits::LimitPolicy::Reject.apply(
+                        stringify!($field),
+                        $field,
+                        $min,
+                        $max,
+                    )?;
+                )?)*
+                Ok(Self { $($field,)* })
+            }
+
+            $(
+                #[doc = concat!("Get the `", stringify!($field), "` parameter.")]
+                pub fn $field(&self) -> &$ty {
+                    &self.$field
+                }
+            )*
+        }
+
+        impl ::core::convert::From<$name> for $crate::cmd::Action {
+            fn from(cmd: $name) -> Self {
+                $crate::cmd::Action::new_custom(
+                    $method,
+                    // converted from an array, so that crates without std don't need the vec! macro
+                    [$($crate::cmd::Param::from(cmd.$field)),*].into(),
+                )
+            }
+        }
+    };
+}
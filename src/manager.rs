@@ -0,0 +1,275 @@
+use serde_json::Value;
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, mpsc};
+use std::time::Instant;
+use std::{boxed::Box, string::String, vec::Vec};
+
+use crate::cmd::Command;
+use crate::lamp::{CallError, ConnectionEvent, Lamp};
+use crate::response::Notification;
+
+/*
+ * Please follow this order:
+ * - structs/enums, ordered s.t. dependencies are above dependents
+ * - impls (e.g. impl LampManager)
+ * - impl _ for _ (like Display, From<T>,...)
+ */
+
+/// The channels receiving the notifications or events of all lamps of a [LampManager], tagged with the name of the lamp.
+type Subscribers<T> = Arc<Mutex<Vec<mpsc::Sender<(String, T)>>>>;
+
+/// A lamp owned by a [LampManager].
+#[derive(Debug)]
+struct Managed {
+    lamp: Lamp,
+    /// Whether the lamp is still managed, so that its callbacks pass its notifications and events on.
+    ///
+    /// Once the lamp is detached, its callbacks are removed on its next notification or event.
+    attached: Arc<AtomicBool>,
+}
+
+/// A collection of lamps keyed by name, e.g. for controlling all lamps of an apartment.
+///
+/// The manager looks up lamps by name, sends commands to all of them at once,
+/// reports their health, and gathers their notifications and connection events in one place.
+/// ```no_run
+/// # use yeerugina_lib::{cmd::{Action, Command, Effect, Power}, lamp::Lamp, manager::LampManager};
+/// # fn main() -> std::io::Result<()> {
+/// let mut manager = LampManager::new();
+/// let _prev = manager.insert("kitchen", Lamp::connect("192.168.1.20:55443")?);
+/// let _prev = manager.insert("hallway", Lamp::connect("192.168.1.21:55443")?);
+/// let notifications = manager.notifications();
+/// for (name, result) in manager.broadcast(&Command::new(Action::new_power(Power::On), Effect::Sudden)) {
+///     if let Err(err) = result {
+///         eprintln!("{name}: {err}");
+///     }
+/// }
+/// for (name, notification) in notifications {
+///     println!("{name}: {:?}", notification.props);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Default)]
+pub struct LampManager {
+    /// The lamps, keyed by name.
+    lamps: BTreeMap<String, Managed>,
+    /// The channels receiving the notifications of all lamps.
+    notifications: Subscribers<Notification>,
+    /// The channels receiving the connection events of all lamps.
+    events: Subscribers<ConnectionEvent>,
+}
+
+impl LampManager {
+    /// Create a manager without lamps.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a lamp under a name, returning the lamp previously added under the name, if any.
+    pub fn insert(&mut self, name: impl Into<String>, lamp: Lamp) -> Option<Lamp> {
+        let name = name.into();
+        let attached = Arc::new(AtomicBool::new(true));
+        let (tag, active, subscribers) = (
+            name.clone(),
+            Arc::clone(&attached),
+            Arc::clone(&self.notifications),
+        );
+        lamp.add_notification_callback(Box::new(move |notification| {
+            let attached = active.load(Ordering::Relaxed);
+            if attached {
+                publish(&subscribers, &tag, notification);
+            }
+            attached
+        }));
+        let (tag, active, subscribers) = (
+            name.clone(),
+            Arc::clone(&attached),
+            Arc::clone(&self.events),
+        );
+        lamp.add_event_callback(Box::new(move |event| {
+            let attached = active.load(Ordering::Relaxed);
+            if attached {
+                publish(&subscribers, &tag, event);
+            }
+            attached
+        }));
+        let prev = self.lamps.insert(name, Managed { lamp, attached })?;
+        Some(prev.detach())
+    }
+
+    /// Remove a lamp, whose notifications and events are no longer passed on.
+    pub fn remove(&mut self, name: &str) -> Option<Lamp> {
+        Some(self.lamps.remove(name)?.detach())
+    }
+
+    /// Get a lamp by name.
+    pub fn get(&self, name: &str) -> Option<&Lamp> {
+        Some(&self.lamps.get(name)?.lamp)
+    }
+
+    /// Get a lamp by name, e.g. for sending it a command.
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut Lamp> {
+        Some(&mut self.lamps.get_mut(name)?.lamp)
+    }
+
+    /// Get the names of the lamps, in order.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.lamps.keys().map(String::as_str)
+    }
+
+    /// Get the number of lamps.
+    pub fn len(&self) -> usize {
+        self.lamps.len()
+    }
+
+    /// Whether there are no lamps.
+    pub fn is_empty(&self) -> bool {
+        self.lamps.is_empty()
+    }
+
+    /// Send a command to all lamps and wait for their replies, returning the result of each lamp.
+    ///
+    /// The command is sent to all lamps before any reply is awaited, so slow lamps don't delay the others.
+    /// Each lamp waits for its reply for its call timeout at most (see [Lamp::set_call_timeout]).
+    pub fn broadcast(&mut self, cmd: &Command) -> BTreeMap<String, Result<Vec<Value>, CallError>> {
+        let start = Instant::now();
+        let pending = self
+            .lamps
+            .iter_mut()
+            .map(|(name, managed)| {
+                let timeout = managed.lamp.call_timeout();
+                (name, managed.lamp.send_cmd_no_wait(cmd), timeout)
+            })
+            .collect::<Vec<_>>();
+        pending
+            .into_iter()
+            .map(|(name, sent, timeout)| {
+                let result = match sent {
+                    Ok(reply) => reply.wait(timeout.saturating_sub(start.elapsed())),
+                    Err(err) => Err(CallError::Io(err)),
+                };
+                (name.clone(), result)
+            })
+            .collect()
+    }
+
+    /// Get whether each lamp is healthy (see [Lamp::is_healthy]), in the order of their names.
+    pub fn health(&self) -> impl Iterator<Item = (&str, bool)> {
+        self.lamps
+            .iter()
+            .map(|(name, managed)| (name.as_str(), managed.lamp.is_healthy()))
+    }
+
+    /// Get a channel receiving the notifications of all lamps from now on, tagged with the names of the lamps.
+    ///
+    /// This includes the lamps added later. Once the receiver is dropped, the channel is removed on the next notification.
+    pub fn notifications(&self) -> mpsc::Receiver<(String, Notification)> {
+        subscribe(&self.notifications)
+    }
+
+    /// Get a channel receiving the connection events of all lamps from now on (such as health transitions),
+    /// tagged with the names of the lamps.
+    pub fn connection_events(&self) -> mpsc::Receiver<(String, ConnectionEvent)> {
+        subscribe(&self.events)
+    }
+}
+
+impl Managed {
+    /// Stop passing the notifications and events of the lamp on, and return it.
+    fn detach(self) -> Lamp {
+        self.attached.store(false, Ordering::Relaxed);
+        self.lamp
+    }
+}
+
+/// Add a channel to the subscribers.
+fn subscribe<T>(subscribers: &Subscribers<T>) -> mpsc::Receiver<(String, T)> {
+    let (tx, rx) = mpsc::channel();
+    subscribers
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .push(tx);
+    rx
+}
+
+/// Pass a notification or event of a lamp to the subscribers, removing the ones whose receiver was dropped.
+fn publish<T: Clone>(subscribers: &Subscribers<T>, name: &str, item: &T) {
+    subscribers
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .retain(|tx| tx.send((name.into(), item.clone())).is_ok());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cmd::{Action, Effect};
+    use crate::lamp::testing::{callbacks, reply_to, respond};
+    use pretty_assertions::assert_eq;
+    use std::io::Write;
+    use std::net::{TcpListener, TcpStream};
+    use std::time::Duration;
+    use std::vec;
+
+    /// Connect a lamp to a local listener, replying to its requests with the given result.
+    fn lamp(listener: &TcpListener, result: &'static str) -> (Lamp, TcpStream) {
+        let lamp = Lamp::connect(listener.local_addr().unwrap()).unwrap();
+        let (peer, _) = listener.accept().unwrap();
+        let notifier = peer.try_clone().unwrap();
+        let _responder = respond(peer, move |line| reply_to(line, result));
+        (lamp, notifier)
+    }
+
+    #[test]
+    fn manager() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut manager = LampManager::new();
+        let (kitchen, _) = lamp(&listener, r#""result":["ok"]"#);
+        let (hallway, mut notifier) = lamp(
+            &listener,
+            r#""error":{"code":-5000,"message":"general error"}"#,
+        );
+        assert!(manager.insert("kitchen", kitchen).is_none());
+        assert!(manager.insert("hallway", hallway).is_none());
+        assert_eq!(manager.names().collect::<Vec<_>>(), ["hallway", "kitchen"]);
+        assert!(manager.get("kitchen").is_some());
+        assert_eq!(
+            manager.health().collect::<Vec<_>>(),
+            [("hallway", true), ("kitchen", true)]
+        );
+        let results = manager.broadcast(&Command::new(Action::new_ct(3200), Effect::Sudden));
+        assert_eq!(
+            results["kitchen"].as_ref().unwrap(),
+            &vec![Value::from("ok")]
+        );
+        assert!(matches!(results["hallway"], Err(CallError::Lamp(_))));
+
+        let notifications = manager.notifications();
+        notifier
+            .write_all(b"{\"method\":\"props\",\"params\":{\"power\":\"off\"}}\r\n")
+            .unwrap();
+        let (name, notification) = notifications.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(name, "hallway");
+        assert_eq!(notification.get("power"), Some(&Value::from("off")));
+        // removed lamps are no longer passed on, and their callbacks are removed
+        let hallway = manager.remove("hallway").unwrap();
+        assert_eq!(callbacks(&hallway), 1);
+        notifier
+            .write_all(b"{\"method\":\"props\",\"params\":{\"power\":\"on\"}}\r\n")
+            .unwrap();
+        assert!(
+            notifications
+                .recv_timeout(Duration::from_millis(100))
+                .is_err()
+        );
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while callbacks(&hallway) > 0 && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(callbacks(&hallway), 0);
+        assert_eq!(manager.len(), 1);
+    }
+}
@@ -0,0 +1,254 @@
+use alloc::string::String;
+use core::fmt::Display;
+
+use crate::cmd::{Action, CommandKind, ValidationError};
+use crate::limits::{self, LimitPolicy};
+
+/*
+ * Please follow this order:
+ * - structs/enums, ordered s.t. dependencies are above dependents
+ * - impls (e.g. impl Model)
+ * - impl _ for _ (like Display, From<T>,...)
+ */
+
+/// The features of a lamp model, see [Model::capabilities].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Capabilities {
+    /// The color temperatures (in kelvins, inclusive) the lamp displays, or None if it only has one white.
+    pub ct_range: Option<(u16, u16)>,
+    /// Whether the lamp displays colors (`set_rgb`, `set_hsv`).
+    pub color: bool,
+    /// Whether the lamp has a background light, controlled with the `bg_*` methods.
+    pub background: bool,
+    /// Whether the lamp has a moonlight (night light) mode.
+    pub moonlight: bool,
+    /// Whether the lamp supports music mode (`set_music`).
+    pub music: bool,
+}
+
+/// A feature of a lamp, see [Capabilities::has].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Feature {
+    /// Adjustable color temperature.
+    ColorTemperature,
+    /// Colors.
+    Color,
+    /// A background light.
+    Background,
+    /// A moonlight mode.
+    Moonlight,
+    /// Music mode.
+    Music,
+}
+
+/// The model of a lamp, as advertised in the `model` header of discovery replies.
+///
+/// Lamps advertise a family name, sometimes followed by a generation (such as `color4` or `ceiling10`),
+/// which is parsed into the family. Unknown models are kept as they were advertised.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Model {
+    /// A white bulb without adjustable color temperature (`mono`).
+    Mono,
+    /// A color bulb (`color`).
+    Color,
+    /// A light strip (`stripe`, `strip`).
+    Stripe,
+    /// A ceiling light (`ceiling`, `ceila`).
+    Ceiling,
+    /// A bedside lamp (`bslamp`).
+    Bslamp,
+    /// A white bulb with adjustable color temperature (`ct_bulb`).
+    CtBulb,
+    /// A desk lamp (`lamp`, `desklamp`).
+    DeskLamp,
+    /// A model this crate doesn't know, with its advertised name.
+    Other(String),
+}
+
+impl Capabilities {
+    /// The capabilities of an unknown model: everything the protocol allows, so that nothing is rejected.
+    pub const ALL: Self = Self {
+        ct_range: Some((limits::CT_MIN, limits::CT_MAX)),
+        color: true,
+        background: true,
+        moonlight: true,
+        music: true,
+    };
+
+    /// Check that the lamp can carry out an action, such as a color temperature within its range.
+    ///
+    /// Custom actions are checked by their method name, e.g. `bg_set_power` needs a background light.
+    /// ```
+    /// # use yeerugina_lib::{cmd::{Action, Brightness, ValidationError}, model::Model};
+    /// let mono = Model::Mono.capabilities().unwrap();
+    /// assert_eq!(mono.check(&Action::new_rgb_from_int(0xFF0000).unwrap()), Err(ValidationError::Unsupported("color")));
+    /// assert!(mono.check(&Action::new_bright(Brightness::new(50).unwrap())).is_ok());
+    /// ```
+    pub fn check(&self, action: &Action) -> Result<(), ValidationError> {
+        let (needs, supported) = match action.kind() {
+            CommandKind::SetCtAbx | CommandKind::AdjustCt => {
+                ("color temperature", self.ct_range.is_some())
+            }
+            CommandKind::SetRgb | CommandKind::SetHsv | CommandKind::AdjustColor => {
+                ("color", self.color)
+            }
+            CommandKind::Custom if action.method().starts_with("bg_") => {
+                ("a background light", self.background)
+            }
+            CommandKind::Custom if action.method() == "set_music" => ("music mode", self.music),
+            _ => return Ok(()),
+        };
+        if !supported {
+            return Err(ValidationError::Unsupported(needs));
+        }
+        if let (Some(ct), Some((min, max))) = (action.ct(), self.ct_range) {
+            let _ct = LimitPolicy::Reject.apply("ct", ct, min, max)?;
+        }
+        Ok(())
+    }
+
+    /// Whether the lamp has a feature.
+    pub fn has(&self, feature: Feature) -> bool {
+        match feature {
+            Feature::ColorTemperature => self.ct_range.is_some(),
+            Feature::Color => self.color,
+            Feature::Background => self.background,
+            Feature::Moonlight => self.moonlight,
+            Feature::Music => self.music,
+        }
+    }
+
+    /// Adjust the capabilities to the methods a lamp advertises (such as `bg_set_power`), which are more precise than the table.
+    pub fn with_methods<S: AsRef<str>>(mut self, methods: &[S]) -> Self {
+        if methods.is_empty() {
+            return self;
+        }
+        let supports = |method| methods.iter().any(|supported| supported.as_ref() == method);
+        self.background |= supports("bg_set_power");
+        self.music = supports("set_music");
+        self
+    }
+}
+
+impl Model {
+    /// Parse an advertised model name, such as `color4`, into its family.
+    pub fn from_name(name: &str) -> Self {
+        match name {
+            _ if name.starts_with("mono") => Self::Mono,
+            _ if name.starts_with("color") => Self::Color,
+            _ if name.starts_with("strip") => Self::Stripe,
+            _ if name.starts_with("ceil") => Self::Ceiling,
+            _ if name.starts_with("bslamp") => Self::Bslamp,
+            _ if name.starts_with("ct") => Self::CtBulb,
+            _ if name.starts_with("lamp") || name.starts_with("desklamp") => Self::DeskLamp,
+            _ => Self::Other(name.into()),
+        }
+    }
+
+    /// The name of the family, such as `color`, or the advertised name of unknown models.
+    pub fn name(&self) -> &str {
+        match self {
+            Self::Mono => "mono",
+            Self::Color => "color",
+            Self::Stripe => "stripe",
+            Self::Ceiling => "ceiling",
+            Self::Bslamp => "bslamp",
+            Self::CtBulb => "ct_bulb",
+            Self::DeskLamp => "desklamp",
+            Self::Other(name) => name,
+        }
+    }
+
+    /// Get the features of the model, or None if the model is unknown.
+    ///
+    /// The table covers the common variant of each family; some variants have more features,
+    /// such as ceiling lights with a background light, which they advertise in the `support` header.
+    pub fn capabilities(&self) -> Option<Capabilities> {
+        let table = |ct_range, color, moonlight| Capabilities {
+            ct_range,
+            color,
+            background: false,
+            moonlight,
+            music: true,
+        };
+        Some(match self {
+            Self::Mono => table(None, false, false),
+            Self::Color | Self::Stripe => {
+                table(Some((limits::CT_MIN, limits::CT_MAX)), true, false)
+            }
+            Self::Ceiling => table(Some((2700, 6500)), false, true),
+            Self::Bslamp => table(Some((limits::CT_MIN, limits::CT_MAX)), true, true),
+            Self::CtBulb | Self::DeskLamp => table(Some((2700, 6500)), false, false),
+            Self::Other(_) => return None,
+        })
+    }
+}
+
+impl Display for Model {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl From<&str> for Model {
+    fn from(name: &str) -> Self {
+        Self::from_name(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cmd::{Brightness, Param};
+    use pretty_assertions::assert_eq;
+    use std::{string::ToString, vec::Vec};
+
+    #[test]
+    fn from_name() {
+        assert_eq!(Model::from_name("color4"), Model::Color);
+        assert_eq!(Model::from_name("strip6"), Model::Stripe);
+        assert_eq!(Model::from_name("ceila"), Model::Ceiling);
+        assert_eq!(Model::from_name("ct_bulb"), Model::CtBulb);
+        assert_eq!(Model::from_name("lamp15"), Model::DeskLamp);
+        assert_eq!(Model::from_name("plug"), Model::Other("plug".into()));
+        assert_eq!(Model::from_name("plug").capabilities(), None);
+        assert_eq!(Model::Bslamp.to_string(), "bslamp");
+    }
+
+    #[test]
+    fn check() {
+        let ceiling = Model::Ceiling.capabilities().unwrap();
+        assert_eq!(ceiling.check(&Action::new_ct(4000)), Ok(()));
+        assert_eq!(
+            ceiling.check(&Action::new_ct(1700)),
+            Err(ValidationError::OutOfRange {
+                field: "ct",
+                min: 2700,
+                max: 6500,
+                got: 1700
+            })
+        );
+        assert_eq!(
+            ceiling.check(&Action::new_hsv(
+                crate::cmd::Hue::new(10).unwrap(),
+                crate::cmd::Saturation::new(10).unwrap()
+            )),
+            Err(ValidationError::Unsupported("color"))
+        );
+        let bg_power = Action::new_custom("bg_set_power", Vec::from([Param::from("on")]));
+        assert_eq!(
+            ceiling.check(&bg_power),
+            Err(ValidationError::Unsupported("a background light"))
+        );
+        assert_eq!(Capabilities::ALL.check(&bg_power), Ok(()));
+        let mono = Model::Mono.capabilities().unwrap();
+        assert_eq!(
+            mono.check(&Action::new_ct(4000)),
+            Err(ValidationError::Unsupported("color temperature"))
+        );
+        assert_eq!(
+            mono.check(&Action::new_bright(Brightness::new(10).unwrap())),
+            Ok(())
+        );
+    }
+}
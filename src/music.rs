@@ -0,0 +1,343 @@
+use log::debug;
+
+use std::io::{Error, ErrorKind, Write};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream};
+use std::time::{Duration, Instant};
+use std::{string::ToString, vec::Vec};
+
+use crate::cmd::{Action, Command, Param};
+use crate::lamp::{IdCounter, IdGenerator, Lamp};
+
+/*
+ * Please follow this order:
+ * - structs/enums, ordered s.t. dependencies are above dependents
+ * - impls (e.g. impl MusicServer)
+ * - impl _ for _ (like Display, From<T>,...)
+ */
+
+/// A local server a lamp connects to in music mode, see [`Lamp::start_music`](crate::lamp::Lamp::start_music).
+///
+/// In music mode, the lamp connects to the host given in `set_music` and reads commands from that connection
+/// without replying to them and without counting them against its quota of 60 commands per minute.
+/// This is the way to send many commands, e.g. for animations or following music.
+#[derive(Debug)]
+pub struct MusicServer {
+    listener: TcpListener,
+}
+
+/// The connection a lamp opened in music mode, which commands are streamed over.
+///
+/// The lamp doesn't reply to the commands, so errors (such as invalid parameters) go unnoticed.
+#[derive(Debug)]
+pub struct MusicConnection {
+    stream: TcpStream,
+    /// The ids of the commands, which the lamp requires even though it doesn't reply.
+    ids: IdCounter,
+    /// The buffer the commands are encoded into.
+    buf: Vec<u8>,
+}
+
+/// A lamp in music mode, streaming commands over the connection the lamp opened (see [`Lamp::start_music`]).
+///
+/// Commands are only written: the lamp doesn't reply to them, so nothing is parsed or awaited,
+/// and they aren't counted against the quota of the lamp (nor the rate limit of the [Lamp]).
+/// The Lamp is borrowed for switching music mode off again with [`MusicLamp::stop`], which also happens on drop.
+/// ```no_run
+/// # use std::time::Duration;
+/// # use yeerugina_lib::{cmd::{Action, Brightness}, lamp::Lamp, music::MusicLamp};
+/// # fn main() -> std::io::Result<()> {
+/// let mut lamp = Lamp::connect("192.168.1.20:55443")?;
+/// let mut music = MusicLamp::start(&mut lamp, Duration::from_secs(3))?;
+/// for bright in (1..=100).chain((1..100).rev()) {
+///     let _id = music.send_action(Action::new_bright(Brightness::new(bright).unwrap()))?;
+///     std::thread::sleep(Duration::from_millis(20));
+/// }
+/// music.stop()
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct MusicLamp<'a> {
+    lamp: &'a mut Lamp,
+    connection: MusicConnection,
+    /// Whether music mode was switched off already, so that it isn't switched off again on drop.
+    stopped: bool,
+}
+
+impl MusicServer {
+    /// Listen on a free port of a local address, which the lamp has to be able to connect to.
+    ///
+    /// The address is usually the local address of the connection to the lamp (see [`Lamp::local_addr`](crate::lamp::Lamp::local_addr)).
+    pub fn bind(ip: IpAddr) -> std::io::Result<Self> {
+        let listener = TcpListener::bind((ip, 0))?;
+        listener.set_nonblocking(true)?;
+        Ok(Self { listener })
+    }
+
+    /// Get the address the server listens on.
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Get the `set_music` command asking the lamp to connect to the server.
+    pub fn command(&self) -> std::io::Result<Command> {
+        let addr = self.local_addr()?;
+        Ok(Command::custom(
+            "set_music",
+            Vec::from([
+                Param::Int(1),
+                Param::Str(addr.ip().to_string()),
+                Param::Int(addr.port().into()),
+            ]),
+        ))
+    }
+
+    /// Wait for the lamp to connect (after it was sent [`MusicServer::command`]) for a timeout at most.
+    ///
+    /// If it doesn't connect in time, e.g. because a firewall blocks the connection, [`ErrorKind::TimedOut`] is returned.
+    pub fn accept(&self, timeout: Duration) -> std::io::Result<MusicConnection> {
+        let deadline = Instant::now() + timeout;
+        let (stream, from) = loop {
+            match self.listener.accept() {
+                Ok(accepted) => break accepted,
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                    if Instant::now() >= deadline {
+                        return Err(Error::new(
+                            ErrorKind::TimedOut,
+                            "the lamp didn't connect in music mode",
+                        ));
+                    }
+                    std::thread::sleep(Duration::from_millis(5));
+                }
+                Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        };
+        debug!("Lamp | {from} connected in music mode");
+        stream.set_nonblocking(false)?;
+        stream.set_nodelay(true)?;
+        Ok(MusicConnection {
+            stream,
+            ids: IdCounter::default(),
+            buf: Vec::new(),
+        })
+    }
+}
+
+impl MusicConnection {
+    /// Send a command to the lamp, returning its id (which is assigned if the command has none).
+    ///
+    /// Unlike [`Lamp::send_cmd`](crate::lamp::Lamp::send_cmd), the command is sent as it is,
+    /// without checking the capabilities of the lamp or applying the color temperature fallback.
+    pub fn send_cmd(&mut self, cmd: &Command) -> std::io::Result<u32> {
+        let id = cmd.id.unwrap_or_else(|| self.ids.next_id());
+        debug!("Lamp | Sending command {cmd:?} with id {id} in music mode");
+        cmd.encode_with_id(id, &mut self.buf);
+        self.stream.write_all(&self.buf)?;
+        Ok(id)
+    }
+
+    /// Send several commands in a single write, returning their ids.
+    ///
+    /// The lamp carries out the commands in order, so they take effect (almost) at once, e.g. for several properties of a frame.
+    pub fn send_batch(&mut self, cmds: &[Command]) -> std::io::Result<Vec<u32>> {
+        let mut frame = Vec::new();
+        let mut ids = Vec::with_capacity(cmds.len());
+        for cmd in cmds {
+            let id = cmd.id.unwrap_or_else(|| self.ids.next_id());
+            cmd.encode_with_id(id, &mut self.buf);
+            frame.extend_from_slice(&self.buf);
+            ids.push(id);
+        }
+        debug!(
+            "Lamp | Sending a batch of {} commands in music mode",
+            cmds.len()
+        );
+        self.stream.write_all(&frame)?;
+        Ok(ids)
+    }
+
+    /// Get the address the lamp connected from.
+    pub fn peer_addr(&self) -> std::io::Result<SocketAddr> {
+        self.stream.peer_addr()
+    }
+
+    /// Get the underlying connection.
+    pub fn into_inner(self) -> TcpStream {
+        self.stream
+    }
+}
+
+impl<'a> MusicLamp<'a> {
+    /// Switch the lamp to music mode, waiting for the reply and for the lamp to connect for the timeout at most.
+    pub fn start(lamp: &'a mut Lamp, accept_timeout: Duration) -> std::io::Result<Self> {
+        let connection = lamp.start_music(accept_timeout)?;
+        Ok(Self {
+            lamp,
+            connection,
+            stopped: false,
+        })
+    }
+
+    /// Send a command to the lamp, returning its id.
+    ///
+    /// As with [`Lamp::send_cmd`], commands the model can't carry out fail with [`ErrorKind::InvalidInput`],
+    /// and the color temperature fallback is applied.
+    pub fn send_cmd(&mut self, cmd: &Command) -> std::io::Result<u32> {
+        match self.lamp.prepare_music(cmd)? {
+            Some(replaced) => self.connection.send_cmd(&replaced),
+            None => self.connection.send_cmd(cmd),
+        }
+    }
+
+    /// Send an action to the lamp with the default effect of the Lamp, see [`Lamp::send_action`].
+    pub fn send_action(&mut self, action: impl Into<Action>) -> std::io::Result<u32> {
+        let cmd = Command::new(action.into(), self.lamp.default_effect());
+        self.send_cmd(&cmd)
+    }
+
+    /// Send several commands in a single write (a frame of an animation), returning their ids.
+    ///
+    /// All commands are checked before any is sent, see [`MusicLamp::send_cmd`].
+    pub fn send_batch(&mut self, cmds: &[Command]) -> std::io::Result<Vec<u32>> {
+        let prepared = cmds
+            .iter()
+            .map(|cmd| Ok(self.lamp.prepare_music(cmd)?.unwrap_or_else(|| cmd.clone())))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        self.connection.send_batch(&prepared)
+    }
+
+    /// Get the Lamp, e.g. for commands waiting for replies (which use its regular connection).
+    pub fn lamp(&mut self) -> &mut Lamp {
+        self.lamp
+    }
+
+    /// Switch music mode off (`set_music` with `0`), which closes the connection.
+    pub fn stop(mut self) -> std::io::Result<()> {
+        self.stopped = true;
+        self.lamp.stop_music()
+    }
+}
+
+impl Drop for MusicLamp<'_> {
+    fn drop(&mut self) {
+        if !self.stopped
+            && let Err(err) = self.lamp.stop_music()
+        {
+            debug!("Lamp | Switching music mode off failed: {err}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cmd::{Brightness, Effect, Power};
+    use crate::lamp::testing::{reply_to, respond};
+    use crate::model::Model;
+    use pretty_assertions::assert_eq;
+    use serde_json::Value;
+    use std::borrow::ToOwned;
+    use std::io::{BufRead, BufReader};
+    use std::net::Ipv4Addr;
+    use std::string::String;
+    use std::sync::mpsc;
+
+    /// Start a fake lamp replying to every request and connecting in music mode,
+    /// returning its address and the lines it received, with the connection they arrived on.
+    fn fake_lamp() -> (SocketAddr, mpsc::Receiver<(&'static str, String)>) {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = mpsc::channel();
+        let _fake_lamp = std::thread::spawn(move || {
+            let (control, _) = listener.accept().unwrap();
+            respond(control, move |line| {
+                let request = serde_json::from_str::<Value>(line).unwrap();
+                let params = &request["params"];
+                if request["method"] == "set_music" && params[0] == 1 {
+                    let host = params[1].as_str().unwrap().to_owned();
+                    let port = params[2].as_u64().unwrap();
+                    let music = TcpStream::connect(std::format!("{host}:{port}")).unwrap();
+                    let tx = tx.clone();
+                    let _reader = std::thread::spawn(move || {
+                        for line in BufReader::new(music).lines().map_while(Result::ok) {
+                            let _sent = tx.send(("music", line));
+                        }
+                    });
+                }
+                let _sent = tx.send(("control", line.to_owned()));
+                reply_to(line, r#""result":["ok"]"#)
+            })
+        });
+        (addr, rx)
+    }
+
+    /// Wait for the next line the fake lamp received.
+    fn received(rx: &mpsc::Receiver<(&'static str, String)>) -> (&'static str, String) {
+        rx.recv_timeout(Duration::from_secs(5)).unwrap()
+    }
+
+    #[test]
+    fn handshake() {
+        let (addr, rx) = fake_lamp();
+        let mut lamp = Lamp::connect(addr).unwrap();
+        let mut music = lamp.start_music(Duration::from_secs(5)).unwrap();
+        assert_eq!(received(&rx).0, "control");
+        assert_eq!(lamp.state().music_on, Some(true));
+        let cmd = Command::new(Action::new_power(Power::On), Effect::Sudden);
+        assert_eq!(music.send_cmd(&cmd).unwrap(), 1);
+        assert_eq!(music.send_cmd(&cmd).unwrap(), 2);
+        assert_eq!(
+            received(&rx),
+            (
+                "music",
+                r#"{"id":1,"method":"set_power","params":["on","sudden",0]}"#.to_owned()
+            )
+        );
+        assert_eq!(received(&rx).1[..7], *r#"{"id":2"#);
+
+        // nothing connects to a server that wasn't announced
+        let server = MusicServer::bind(Ipv4Addr::LOCALHOST.into()).unwrap();
+        let err = server.accept(Duration::from_millis(50)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn music_lamp() {
+        let (addr, rx) = fake_lamp();
+        let mut lamp = Lamp::connect(addr).unwrap();
+        lamp.set_model(Some(Model::Mono));
+        let mut music = MusicLamp::start(&mut lamp, Duration::from_secs(5)).unwrap();
+        assert_eq!(received(&rx).0, "control");
+        let bright = |bright| {
+            Command::new(
+                Action::new_bright(Brightness::new(bright).unwrap()),
+                Effect::Sudden,
+            )
+        };
+        // a frame is checked as a whole, so nothing is sent if a command is unsupported
+        let red = Command::new(Action::new_rgb_from_int(0xFF0000).unwrap(), Effect::Sudden);
+        let err = music.send_batch(&[bright(10), red]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        assert_eq!(music.send_batch(&[bright(10), bright(20)]).unwrap(), [1, 2]);
+        assert_eq!(music.send_cmd(&bright(30)).unwrap(), 3);
+        for bright in [10, 20, 30] {
+            let (connection, line) = received(&rx);
+            assert_eq!(connection, "music");
+            assert!(line.contains(&std::format!("[{bright},\"sudden\",0]")));
+        }
+        music.stop().unwrap();
+        assert_eq!(
+            received(&rx),
+            (
+                "control",
+                r#"{"id":2,"method":"set_music","params":[0]}"#.to_owned()
+            )
+        );
+        assert_eq!(lamp.state().music_on, Some(false));
+
+        // dropping the handle switches music mode off as well
+        drop(MusicLamp::start(&mut lamp, Duration::from_secs(5)).unwrap());
+        assert_eq!(received(&rx).0, "control");
+        assert!(received(&rx).1.contains(r#""params":[0]"#));
+    }
+}
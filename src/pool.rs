@@ -0,0 +1,407 @@
+use log::debug;
+
+use core::ops::{Deref, DerefMut};
+
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use std::vec::Vec;
+
+use crate::lamp::{Lamp, LampBuilder};
+
+/*
+ * Please follow this order:
+ * - structs/enums, ordered s.t. dependencies are above dependents
+ * - impls (e.g. impl LampPool)
+ * - impl _ for _ (like Display, From<T>,...)
+ */
+
+/// How many connections a [LampPool] opens to one address by default, as lamps only accept about 4 TCP clients.
+pub const MAX_CONNECTIONS_PER_ADDR: usize = 4;
+
+/// The connections of a [LampPool] to one address.
+#[derive(Debug, Default)]
+struct Slots {
+    /// The connections that were returned to the pool, the most recently returned last.
+    idle: Vec<Lamp>,
+    /// The number of connections that are open, idle or handed out.
+    open: usize,
+}
+
+/// A pool of connections keyed by the address of the lamp, e.g. for sharing lamps among the requests of a server.
+///
+/// Connections are opened lazily, and returned to the pool when the [PooledLamp] handed out is dropped.
+/// Returned connections are reset to the default settings, so the callbacks and settings of a borrower
+/// (such as its heartbeat, rate limit or call timeout) don't carry over to the next one;
+/// the cached state and what's known about the lamp (such as its model) are kept.
+/// Idle connections that were closed in the meantime, or whose heartbeat had failed when they were returned
+/// (see [Lamp::set_heartbeat]), are reconnected when they're handed out again.
+/// As lamps only accept a few concurrent clients, the number of connections per address is bounded;
+/// once all of them are handed out, [LampPool::get] waits for one to be returned.
+/// ```no_run
+/// # use yeerugina_lib::{cmd::{Action, Command, Effect, Power}, pool::LampPool};
+/// # fn main() -> std::io::Result<()> {
+/// let pool = LampPool::new();
+/// let mut lamp = pool.get("192.168.1.20:55443".parse().unwrap())?;
+/// let _id = lamp.send_cmd(&Command::new(Action::new_power(Power::On), Effect::Sudden))?;
+/// // dropping the lamp returns the connection to the pool
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct LampPool {
+    /// The options of the connections opened by the pool.
+    builder: LampBuilder,
+    max_per_addr: usize,
+    acquire_timeout: Option<Duration>,
+    slots: Mutex<HashMap<SocketAddr, Slots>>,
+    /// Signalled whenever a connection is returned to the pool or closed.
+    released: Condvar,
+}
+
+/// A connection handed out by a [LampPool], which is returned to the pool when dropped.
+///
+/// This dereferences to the [Lamp], so commands can be sent directly.
+/// The settings changed and the callbacks registered through it are reset when it's returned, see [LampPool].
+#[derive(Debug)]
+pub struct PooledLamp<'a> {
+    /// The connection, which is only taken when the handle is consumed or dropped.
+    lamp: Option<Lamp>,
+    pool: &'a LampPool,
+    addr: SocketAddr,
+}
+
+impl LampPool {
+    /// Create an empty pool opening up to [MAX_CONNECTIONS_PER_ADDR] connections per address, using the default settings.
+    pub fn new() -> Self {
+        Self::with_builder(LampBuilder::default())
+    }
+
+    /// Create an empty pool opening connections with the options of a builder.
+    pub fn with_builder(builder: LampBuilder) -> Self {
+        Self {
+            builder,
+            max_per_addr: MAX_CONNECTIONS_PER_ADDR,
+            acquire_timeout: None,
+            slots: Mutex::new(HashMap::new()),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Get how many connections are opened to one address at most.
+    pub fn max_per_addr(&self) -> usize {
+        self.max_per_addr
+    }
+
+    /// Set how many connections are opened to one address at most (at least one).
+    ///
+    /// Lowering the limit doesn't close connections that are open already.
+    pub fn set_max_per_addr(&mut self, max: usize) {
+        self.max_per_addr = max.max(1);
+    }
+
+    /// Get how long [LampPool::get] waits for a connection to be returned, if it gives up at all.
+    pub fn acquire_timeout(&self) -> Option<Duration> {
+        self.acquire_timeout
+    }
+
+    /// Set how long [LampPool::get] waits for a connection to be returned, or None to wait forever (the default).
+    pub fn set_acquire_timeout(&mut self, timeout: Option<Duration>) {
+        self.acquire_timeout = timeout;
+    }
+
+    /// Get a connection to a lamp, reusing an idle one if possible.
+    ///
+    /// An idle connection that was lost is reconnected first. If all connections to the address are handed out,
+    /// this waits for one to be returned, and fails with [ErrorKind::TimedOut] after the acquire timeout.
+    pub fn get(&self, addr: SocketAddr) -> std::io::Result<PooledLamp<'_>> {
+        let deadline = self.acquire_timeout.map(|timeout| Instant::now() + timeout);
+        let mut slots = self.lock();
+        loop {
+            let slot = slots.entry(addr).or_default();
+            if let Some(lamp) = slot.idle.pop() {
+                drop(slots);
+                return self.revive(addr, lamp);
+            }
+            if slot.open < self.max_per_addr {
+                slot.open += 1;
+                drop(slots);
+                debug!("Lamp | Opening a pooled connection to {addr}");
+                return match self.builder.connect(addr) {
+                    Ok(lamp) => Ok(self.hand_out(addr, lamp)),
+                    Err(err) => {
+                        self.release(addr);
+                        Err(err.into())
+                    }
+                };
+            }
+            slots = match deadline {
+                Some(deadline) => {
+                    let timeout = deadline.saturating_duration_since(Instant::now());
+                    if timeout.is_zero() {
+                        return Err(Error::new(
+                            ErrorKind::TimedOut,
+                            std::format!("all connections to {addr} are in use"),
+                        ));
+                    }
+                    self.released
+                        .wait_timeout(slots, timeout)
+                        .unwrap_or_else(|poisoned| poisoned.into_inner())
+                        .0
+                }
+                None => self
+                    .released
+                    .wait(slots)
+                    .unwrap_or_else(|poisoned| poisoned.into_inner()),
+            };
+        }
+    }
+
+    /// Get how many connections to an address are open, idle or handed out.
+    pub fn open_count(&self, addr: SocketAddr) -> usize {
+        self.lock().get(&addr).map_or(0, |slot| slot.open)
+    }
+
+    /// Get how many connections to an address are idle in the pool.
+    pub fn idle_count(&self, addr: SocketAddr) -> usize {
+        self.lock().get(&addr).map_or(0, |slot| slot.idle.len())
+    }
+
+    /// Close all idle connections. The connections handed out are still returned to the pool.
+    pub fn clear(&self) {
+        let idle = self
+            .lock()
+            .values_mut()
+            .flat_map(|slot| {
+                slot.open -= slot.idle.len();
+                core::mem::take(&mut slot.idle)
+            })
+            .collect::<Vec<_>>();
+        drop(idle);
+        self.released.notify_all();
+    }
+
+    /// Hand out an idle connection, reconnecting it if it was lost or is unresponsive.
+    fn revive(&self, addr: SocketAddr, mut lamp: Lamp) -> std::io::Result<PooledLamp<'_>> {
+        if !lamp.is_connected() || !lamp.is_healthy() {
+            debug!("Lamp | Reconnecting a pooled connection to {addr}");
+            if let Err(err) = lamp.reconnect() {
+                drop(lamp);
+                self.release(addr);
+                return Err(err);
+            }
+        }
+        Ok(self.hand_out(addr, lamp))
+    }
+
+    /// Wrap a connection counted as open, so that it's returned to the pool when dropped.
+    fn hand_out(&self, addr: SocketAddr, lamp: Lamp) -> PooledLamp<'_> {
+        PooledLamp {
+            lamp: Some(lamp),
+            pool: self,
+            addr,
+        }
+    }
+
+    /// Stop counting a connection to an address as open, letting a waiter open another one.
+    fn release(&self, addr: SocketAddr) {
+        if let Some(slot) = self.lock().get_mut(&addr) {
+            slot.open = slot.open.saturating_sub(1);
+        }
+        self.released.notify_one();
+    }
+
+    /// Lock the connections, ignoring poisoning.
+    fn lock(&self) -> MutexGuard<'_, HashMap<SocketAddr, Slots>> {
+        self.slots
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl PooledLamp<'_> {
+    /// Get the address the connection was requested for.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Take the connection out of the pool, which no longer counts it as open.
+    pub fn detach(mut self) -> Lamp {
+        self.pool.release(self.addr);
+        match self.lamp.take() {
+            Some(lamp) => lamp,
+            None => unreachable!("the lamp is only taken when the handle is consumed"),
+        }
+    }
+}
+
+impl Default for LampPool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Deref for PooledLamp<'_> {
+    type Target = Lamp;
+
+    fn deref(&self) -> &Self::Target {
+        match &self.lamp {
+            Some(lamp) => lamp,
+            None => unreachable!("the lamp is only taken when the handle is consumed"),
+        }
+    }
+}
+
+impl DerefMut for PooledLamp<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        match &mut self.lamp {
+            Some(lamp) => lamp,
+            None => unreachable!("the lamp is only taken when the handle is consumed"),
+        }
+    }
+}
+
+impl Drop for PooledLamp<'_> {
+    fn drop(&mut self) {
+        // a detached connection was taken out of the pool already
+        let Some(mut lamp) = self.lamp.take() else {
+            return;
+        };
+        lamp.reset();
+        // lost connections are kept as well, as they're reconnected lazily
+        self.pool
+            .lock()
+            .entry(self.addr)
+            .or_default()
+            .idle
+            .push(lamp);
+        self.pool.released.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cmd::Effect;
+    use crate::lamp::testing::callbacks;
+    use crate::lamp::{RateLimit, RateLimitMode};
+    use crate::response::ParseMode;
+    use pretty_assertions::assert_eq;
+    use std::net::{Shutdown, TcpListener};
+
+    /// Create a pool of up to two connections per address, waiting briefly for one to be returned.
+    fn pool() -> LampPool {
+        let mut pool = LampPool::new();
+        pool.set_max_per_addr(2);
+        pool.set_acquire_timeout(Some(Duration::from_millis(50)));
+        pool
+    }
+
+    #[test]
+    fn bounded() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let pool = pool();
+        let _first = pool.get(addr).unwrap();
+        let _second = pool.get(addr).unwrap();
+        assert_eq!(pool.open_count(addr), 2);
+        let start = Instant::now();
+        let err = pool.get(addr).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        assert!(start.elapsed() >= Duration::from_millis(50));
+    }
+
+    #[test]
+    fn reuse() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let pool = pool();
+        let first = pool.get(addr).unwrap();
+        let local = first.local_addr();
+        drop(first);
+        assert_eq!((pool.open_count(addr), pool.idle_count(addr)), (1, 1));
+        let first = pool.get(addr).unwrap();
+        assert_eq!(first.local_addr(), local);
+        assert_eq!((pool.open_count(addr), pool.idle_count(addr)), (1, 0));
+    }
+
+    #[test]
+    fn reset_on_return() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let pool = pool();
+        let mut lamp = pool.get(addr).unwrap();
+        lamp.on_notification(|_| {});
+        lamp.set_call_timeout(Duration::from_millis(10));
+        lamp.set_default_effect(Effect::Smooth(Duration::from_millis(500).into()));
+        lamp.set_parse_mode(ParseMode::Strict);
+        lamp.set_rate_limit(Some(RateLimit {
+            mode: RateLimitMode::Error,
+            ..RateLimit::default()
+        }));
+        drop(lamp);
+        // the next borrower gets the connection with the default settings
+        let lamp = pool.get(addr).unwrap();
+        assert_eq!(callbacks(&lamp), 0);
+        assert_eq!(lamp.call_timeout(), Duration::from_secs(5));
+        assert_eq!(lamp.default_effect(), Effect::default());
+        assert_eq!(lamp.parse_mode(), ParseMode::Lenient);
+        assert_eq!(lamp.rate_limit(), None);
+    }
+
+    #[test]
+    fn reconnects_lost() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let pool = pool();
+        let lamp = pool.get(addr).unwrap();
+        let (peer, _) = listener.accept().unwrap();
+        peer.shutdown(Shutdown::Both).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while lamp.is_connected() && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        // lost connections are kept, and reconnected when they're handed out again
+        drop(lamp);
+        assert_eq!((pool.open_count(addr), pool.idle_count(addr)), (1, 1));
+        let lamp = pool.get(addr).unwrap();
+        let (_peer, _) = listener.accept().unwrap();
+        assert!(lamp.is_connected());
+        assert_eq!(pool.open_count(addr), 1);
+    }
+
+    #[test]
+    fn detach() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let pool = pool();
+        let _first = pool.get(addr).unwrap();
+        let second = pool.get(addr).unwrap();
+        let detached = second.detach();
+        // the detached connection no longer counts, so another one can be opened
+        assert_eq!(pool.open_count(addr), 1);
+        let _third = pool.get(addr).unwrap();
+        assert_eq!(pool.open_count(addr), 2);
+        drop(detached);
+        assert_eq!((pool.open_count(addr), pool.idle_count(addr)), (2, 0));
+    }
+
+    #[test]
+    fn clear() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let pool = pool();
+        let first = pool.get(addr).unwrap();
+        let second = pool.get(addr).unwrap();
+        drop(first);
+        // only idle connections are closed; the handed out one is still returned afterwards
+        pool.clear();
+        assert_eq!((pool.open_count(addr), pool.idle_count(addr)), (1, 0));
+        drop(second);
+        assert_eq!((pool.open_count(addr), pool.idle_count(addr)), (1, 1));
+        pool.clear();
+        assert_eq!((pool.open_count(addr), pool.idle_count(addr)), (0, 0));
+    }
+}
@@ -0,0 +1,807 @@
+use derive_more::Debug;
+use log::{debug, trace};
+
+use std::any::Any;
+use std::collections::{HashMap, VecDeque};
+use std::io::{Error, ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime};
+use std::{boxed::Box, string::ToString, vec::Vec};
+
+use crate::framing::LineReader;
+use crate::lamp::{
+    CallError, ConnectionEvent, Direction, DisconnectReason, LampStats, LastError, WireFrame,
+    connection_lost,
+};
+use crate::response::{Notification, ParseMode, ParseResponseError, Response};
+use crate::state::{LampState, StateChange};
+use crate::transport::Transport;
+
+/// A callback invoked with each notification, which is removed once it returns false.
+pub(crate) type NotificationCallback = Box<dyn FnMut(&Notification) -> bool + Send>;
+
+/// A callback invoked with each change of the cached state, which is removed once it returns false.
+pub(crate) type ChangeCallback = Box<dyn FnMut(&StateChange) -> bool + Send>;
+
+/// A callback invoked with each connection event, which is removed once it returns false.
+pub(crate) type EventCallback = Box<dyn FnMut(&ConnectionEvent) -> bool + Send>;
+
+/// A sink receiving the lines seen by the wiretap, see [Tap].
+pub(crate) type WireSink = Box<dyn FnMut(&WireFrame<'_>) + Send>;
+
+/// How many responses are queued for [Inbox::pop_response] at most; older ones are dropped.
+const QUEUE_CAPACITY: usize = 1024;
+
+/// How many unacknowledged requests are kept for resending at most (see [Inbox::track]); older ones are dropped.
+const UNACKED_CAPACITY: usize = 64;
+
+/// The writing half of a connection, which can also shut the connection down, see [Inbox::attach].
+trait Writer: Write + Send {
+    /// Shut both halves of the connection down.
+    fn shutdown(&self) -> std::io::Result<()>;
+
+    /// Get the transport, for cloning it into a Lamp following a reconnect (see [Inbox::connection]).
+    fn as_any(&self) -> &dyn Any;
+}
+
+/// The state shared between a lamp and its background reader thread.
+///
+/// The reader thread routes the replies that are awaited by [Inbox::wait] to their waiters,
+/// and passes notifications to the registered callbacks after applying them to the cached state.
+#[derive(Debug, Default)]
+pub(crate) struct Inbox {
+    replies: Mutex<Replies>,
+    /// Signalled whenever a reply arrives or the connection is closed.
+    arrived: Condvar,
+    state: Mutex<LampState>,
+    parse_mode: Mutex<ParseMode>,
+    tap: Mutex<Tap>,
+    /// A clone of the current connection, for writing from other threads (such as the heartbeat).
+    #[debug(skip)]
+    writer: Mutex<Option<Box<dyn Writer>>>,
+    /// When the last command was sent, or the connection was established (see [Inbox::close_if_idle]).
+    active: Mutex<Option<Instant>>,
+    /// Whether the heartbeat found the lamp unresponsive.
+    unhealthy: AtomicBool,
+    /// The responses nobody waits for, if they are queued (see [Inbox::set_queueing]).
+    queue: Mutex<Option<VecDeque<Response>>>,
+    /// The encoded requests that weren't replied to yet, by id, if they are tracked.
+    unacked: Mutex<VecDeque<(u32, Vec<u8>)>>,
+    /// The counters of the connection, see [Inbox::stats].
+    stats: Mutex<LampStats>,
+    #[debug(skip)]
+    callbacks: Mutex<Vec<NotificationCallback>>,
+    #[debug(skip)]
+    change_callbacks: Mutex<Vec<ChangeCallback>>,
+    #[debug(skip)]
+    event_callbacks: Mutex<Vec<EventCallback>>,
+}
+
+/// The replies awaited by callers, see [Inbox].
+#[derive(Debug, Default)]
+struct Replies {
+    /// The awaited ids, with the reply once it arrived (or the reason it was rejected or can't arrive).
+    awaited: HashMap<u32, Option<Result<Response, CallError>>>,
+    /// Whether the connection was closed, so that no more replies can arrive.
+    closed: bool,
+    /// Why the connection was closed.
+    reason: Option<DisconnectReason>,
+    /// The number of reader threads started, so that a reader of a replaced connection can't close the inbox.
+    epoch: u64,
+    /// When the current connection was attached, see [Inbox::connected_since].
+    attached: Option<SystemTime>,
+}
+
+/// The wiretap of a lamp, which logs all lines sent and received when it's enabled.
+#[derive(Debug, Default)]
+pub(crate) struct Tap {
+    /// Whether lines are logged.
+    pub(crate) enabled: bool,
+    /// The address of the lamp, used in the log messages.
+    pub(crate) peer: Option<SocketAddr>,
+    /// The sink also receiving the lines, if any.
+    #[debug(skip)]
+    pub(crate) sink: Option<WireSink>,
+}
+
+impl Inbox {
+    /// Start a background thread reading from the stream into this inbox, returning the epoch of the connection.
+    ///
+    /// When the connection was re-established, the inbox is reopened,
+    /// and the reader of the previous connection stops without affecting it.
+    pub(crate) fn spawn_reader<T: Transport>(self: &Arc<Self>, stream: T) -> std::io::Result<u64> {
+        let epoch = self.attach(&stream)?;
+        let inbox = Arc::clone(self);
+        let _handle = std::thread::Builder::new()
+            .name("yeelight-reader".into())
+            .spawn(move || inbox.run(stream, epoch))?;
+        Ok(epoch)
+    }
+
+    /// Reopen the inbox for a new connection, returning its epoch (see [Inbox::close]).
+    ///
+    /// The data read from the connection is passed to [Inbox::receive], by a reader thread or an event loop.
+    pub(crate) fn attach<T: Transport>(&self, stream: &T) -> std::io::Result<u64> {
+        self.lock_tap().peer = stream.peer_addr();
+        let writer = Box::new(stream.try_clone()?);
+        self.touch();
+        // the writer is replaced under the same lock as the epoch, so that they always match (see Inbox::connection)
+        let mut current = self
+            .writer
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        *current = Some(writer);
+        let mut replies = self.lock_replies();
+        replies.epoch += 1;
+        replies.attached = Some(SystemTime::now());
+        replies.closed = false;
+        replies.reason = None;
+        Ok(replies.epoch)
+    }
+
+    /// Get the epoch of the current connection, see [Inbox::attach].
+    pub(crate) fn epoch(&self) -> u64 {
+        self.lock_replies().epoch
+    }
+
+    /// Get when the current connection was attached, i.e. established.
+    pub(crate) fn connected_since(&self) -> SystemTime {
+        self.lock_replies().attached.unwrap_or_else(SystemTime::now)
+    }
+
+    /// Clone the current connection, along with its epoch.
+    ///
+    /// This lets a Lamp sharing the inbox follow another Lamp that reconnected (see [Lamp::try_clone](crate::lamp::Lamp::try_clone)).
+    /// Returns None if no connection of this type is attached.
+    pub(crate) fn connection<T: Transport>(&self) -> Option<std::io::Result<(u64, T)>> {
+        let writer = self
+            .writer
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let stream = writer.as_ref()?.as_any().downcast_ref::<T>()?;
+        let epoch = self.epoch();
+        Some(stream.try_clone().map(|stream| (epoch, stream)))
+    }
+
+    /// Route the complete responses in the data read from the connection.
+    pub(crate) fn receive(&self, lines: &mut LineReader, data: &[u8]) {
+        lines.push(data);
+        while let Some(line) = lines.next_line() {
+            match line {
+                Ok(line) => {
+                    self.tap(Direction::Received, &line);
+                    match Response::parse(&line, self.parse_mode()) {
+                        Ok(response) => self.route(response),
+                        Err(err) => self.reject(&line, err),
+                    }
+                }
+                Err(err) => debug!("Lamp | Skipping invalid line: {err}"),
+            }
+        }
+    }
+
+    /// Mark the inbox as closed after the connection of the given epoch was closed, so that no more replies can arrive.
+    ///
+    /// If the connection was replaced in the meantime, the inbox stays open.
+    /// The first time the connection is closed, this is reported as [ConnectionEvent::Disconnected].
+    pub(crate) fn close(&self, epoch: u64, reason: DisconnectReason) {
+        debug!("Lamp | Connection closed: {reason}");
+        let replies = self.lock_replies();
+        if replies.epoch == epoch && !replies.closed {
+            self.mark_closed(replies, reason);
+        }
+    }
+
+    /// Remember that a command is sent, so that the connection isn't idle.
+    pub(crate) fn touch(&self) {
+        *self
+            .active
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(Instant::now());
+    }
+
+    /// Close and shut down the connection if no command was sent and no reply awaited for a timeout.
+    ///
+    /// Returns when to check again.
+    pub(crate) fn close_if_idle(&self, timeout: Duration) -> Instant {
+        let active = self
+            .active
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .unwrap_or_else(Instant::now);
+        if Instant::now() < active + timeout {
+            return active + timeout;
+        }
+        // checked under the lock, so that no reply can be awaited in the meantime
+        let replies = self.lock_replies();
+        if replies.closed || !replies.awaited.is_empty() {
+            return Instant::now() + timeout;
+        }
+        debug!("Lamp | Closing the idle connection");
+        self.mark_closed(replies, DisconnectReason::Idle);
+        if let Some(writer) = self
+            .writer
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .as_ref()
+            && let Err(err) = writer.shutdown()
+        {
+            debug!("Lamp | Closing the idle connection failed: {err}");
+        }
+        Instant::now() + timeout
+    }
+
+    /// Close the connection of the given epoch if writing to it failed because it was lost (see [Inbox::close]).
+    ///
+    /// A write to a replaced connection doesn't affect the current one.
+    pub(crate) fn lose(&self, epoch: u64, err: &Error) {
+        if connection_lost(err) {
+            self.close(epoch, err.kind().into());
+        }
+    }
+
+    /// Whether the connection was closed, so that no more replies can arrive.
+    pub(crate) fn is_closed(&self) -> bool {
+        self.lock_replies().closed
+    }
+
+    /// Why the connection was closed, or None while it's open.
+    pub(crate) fn disconnect_reason(&self) -> Option<DisconnectReason> {
+        let replies = self.lock_replies();
+        if replies.closed {
+            Some(replies.reason.unwrap_or(DisconnectReason::Closed))
+        } else {
+            None
+        }
+    }
+
+    /// Register an id whose reply should be kept, before the request is sent.
+    pub(crate) fn expect(&self, id: u32) {
+        let _prev = self.lock_replies().awaited.insert(id, None);
+    }
+
+    /// Stop waiting for the reply to an id, e.g. because the request couldn't be sent.
+    pub(crate) fn forget(&self, id: u32) {
+        let _reply = self.lock_replies().awaited.remove(&id);
+    }
+
+    /// Wait for the reply to an id registered with [Inbox::expect], until the deadline at most.
+    pub(crate) fn wait(&self, id: u32, deadline: Instant) -> Result<Response, CallError> {
+        let mut replies = self.lock_replies();
+        loop {
+            if let Some(reply) = Self::take(&mut replies, id) {
+                return reply;
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                let _reply = replies.awaited.remove(&id);
+                return Err(CallError::Timeout);
+            }
+            replies = match self.arrived.wait_timeout(replies, remaining) {
+                Ok((replies, _)) => replies,
+                Err(poisoned) => poisoned.into_inner().0,
+            };
+        }
+    }
+
+    /// Take the reply to an id registered with [Inbox::expect] without waiting, or None if it didn't arrive yet.
+    pub(crate) fn try_take(&self, id: u32) -> Option<Result<Response, CallError>> {
+        Self::take(&mut self.lock_replies(), id)
+    }
+
+    /// Take the reply to an id, or the reason it can't arrive anymore.
+    fn take(replies: &mut Replies, id: u32) -> Option<Result<Response, CallError>> {
+        if let Some(reply) = replies.awaited.get_mut(&id).and_then(Option::take) {
+            let _slot = replies.awaited.remove(&id);
+            return Some(reply);
+        }
+        if replies.closed {
+            let _reply = replies.awaited.remove(&id);
+            let reason = replies.reason.unwrap_or(DisconnectReason::Closed);
+            return Some(Err(CallError::Disconnected(reason)));
+        }
+        None
+    }
+
+    /// Register a callback for notifications.
+    pub(crate) fn add_callback(&self, callback: NotificationCallback) {
+        self.callbacks
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .push(callback);
+    }
+
+    /// Set how the lines read from now on are parsed.
+    pub(crate) fn set_parse_mode(&self, mode: ParseMode) {
+        *self
+            .parse_mode
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = mode;
+    }
+
+    /// Get how lines are parsed.
+    pub(crate) fn parse_mode(&self) -> ParseMode {
+        *self
+            .parse_mode
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Lock the wiretap, e.g. for changing its settings.
+    pub(crate) fn lock_tap(&self) -> MutexGuard<'_, Tap> {
+        self.tap
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Pass a line to the wiretap, if it's enabled.
+    pub(crate) fn tap(&self, direction: Direction, line: &str) {
+        let mut tap = self.lock_tap();
+        if !tap.enabled {
+            return;
+        }
+        let peer = tap.peer;
+        match peer {
+            Some(peer) => trace!("Lamp | {peer} {direction} {line}"),
+            None => trace!("Lamp | {direction} {line}"),
+        }
+        if let Some(sink) = &mut tap.sink {
+            sink(&WireFrame {
+                direction,
+                peer,
+                time: SystemTime::now(),
+                line,
+            });
+        }
+    }
+
+    /// Get a copy of the cached state.
+    pub(crate) fn state(&self) -> LampState {
+        self.lock_state().clone()
+    }
+
+    /// Change the cached state, e.g. after the lamp confirmed a command, passing the changes to the callbacks.
+    pub(crate) fn update_state(&self, update: impl FnOnce(&mut LampState)) {
+        let changes = {
+            let mut state = self.lock_state();
+            let before = state.clone();
+            update(&mut state);
+            before.diff(&state)
+        };
+        if changes.is_empty() {
+            return;
+        }
+        self.change_callbacks
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .retain_mut(|callback| changes.iter().all(callback));
+    }
+
+    /// Register a callback for changes of the cached state.
+    pub(crate) fn add_change_callback(&self, callback: ChangeCallback) {
+        self.change_callbacks
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .push(callback);
+    }
+
+    /// Write an encoded request to the current connection, passing it to the wiretap.
+    ///
+    /// Requests are small enough to be written at once, so they don't interleave with the requests of the lamp.
+    pub(crate) fn send(&self, request: &[u8]) -> std::io::Result<()> {
+        if let Ok(line) = std::str::from_utf8(request) {
+            self.tap(Direction::Sent, line.trim_end());
+        }
+        let mut writer = self
+            .writer
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        match writer.as_mut() {
+            Some(stream) => {
+                let res = stream.write_all(request);
+                self.record_sent(&res, 1);
+                if let Err(err) = &res {
+                    // the writer is only replaced along with the epoch, which is the current one while it's locked
+                    self.lose(self.epoch(), err);
+                }
+                res
+            }
+            None => Err(Error::from(ErrorKind::NotConnected)),
+        }
+    }
+
+    /// A snapshot of the counters of the connection.
+    pub(crate) fn stats(&self) -> LampStats {
+        self.lock_stats().clone()
+    }
+
+    /// Count the given number of requests as sent if the write succeeded, or record its error.
+    pub(crate) fn record_sent(&self, res: &std::io::Result<()>, requests: u64) {
+        match res {
+            Ok(()) => self.lock_stats().commands_sent += requests,
+            Err(err) => self.record_error(Some(err.kind()), err),
+        }
+    }
+
+    /// Remember an error as the last one that occurred on the connection.
+    pub(crate) fn record_error(&self, kind: Option<ErrorKind>, err: &dyn core::fmt::Display) {
+        self.lock_stats().last_error = Some(LastError {
+            kind,
+            message: err.to_string(),
+            at: SystemTime::now(),
+        });
+    }
+
+    /// Count a successful reconnection.
+    pub(crate) fn record_reconnect(&self) {
+        self.lock_stats().reconnects += 1;
+    }
+
+    /// Whether the heartbeat didn't find the lamp unresponsive.
+    pub(crate) fn is_healthy(&self) -> bool {
+        !self.unhealthy.load(Ordering::Relaxed)
+    }
+
+    /// Mark the lamp as healthy or not, returning whether this changed its health.
+    pub(crate) fn set_healthy(&self, healthy: bool) -> bool {
+        self.unhealthy.swap(!healthy, Ordering::Relaxed) == healthy
+    }
+
+    /// Start or stop queueing the responses nobody waits for, i.e. notifications and replies to unawaited requests.
+    ///
+    /// When queueing stops, the queued responses are discarded.
+    pub(crate) fn set_queueing(&self, enabled: bool) {
+        let mut queue = self.lock_queue();
+        match (enabled, queue.is_some()) {
+            (true, false) => *queue = Some(VecDeque::new()),
+            (false, true) => *queue = None,
+            _ => {}
+        }
+    }
+
+    /// Take the oldest queued response, if any.
+    pub(crate) fn pop_response(&self) -> Option<Response> {
+        self.lock_queue().as_mut()?.pop_front()
+    }
+
+    /// Keep an encoded request until its reply arrives, so that it can be resent after reconnecting.
+    ///
+    /// A request with the id of a tracked request replaces it, so it's resent only once.
+    pub(crate) fn track(&self, id: u32, request: &[u8]) {
+        let mut unacked = self.lock_unacked();
+        unacked.retain(|(tracked, _)| *tracked != id);
+        if unacked.len() == UNACKED_CAPACITY {
+            let _dropped = unacked.pop_front();
+        }
+        unacked.push_back((id, request.to_vec()));
+    }
+
+    /// Stop tracking a request, e.g. because it was replied to or will be resent anyway.
+    pub(crate) fn acknowledge(&self, id: u32) {
+        self.lock_unacked().retain(|(tracked, _)| *tracked != id);
+    }
+
+    /// Get the tracked requests that weren't replied to yet with their ids, in the order they were sent.
+    pub(crate) fn unacknowledged(&self) -> Vec<(u32, Vec<u8>)> {
+        self.lock_unacked().iter().cloned().collect()
+    }
+
+    /// Fail the reply to a request that was dropped without being sent, and stop tracking it.
+    pub(crate) fn discard(&self, id: Option<u32>) {
+        let Some(id) = id else {
+            return;
+        };
+        self.acknowledge(id);
+        let mut replies = self.lock_replies();
+        if let Some(slot) = replies.awaited.get_mut(&id) {
+            *slot = Some(Err(CallError::Dropped));
+            self.arrived.notify_all();
+        }
+    }
+
+    /// Remove the callbacks, the tracked requests and the wiretap, and parse lines leniently again, see [Lamp::reset](crate::lamp::Lamp::reset).
+    pub(crate) fn reset(&self) {
+        self.callbacks
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clear();
+        self.change_callbacks
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clear();
+        self.event_callbacks
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clear();
+        self.lock_unacked().clear();
+        let mut tap = self.lock_tap();
+        tap.enabled = false;
+        tap.sink = None;
+        drop(tap);
+        self.set_parse_mode(ParseMode::default());
+    }
+
+    /// Register a callback for connection events.
+    pub(crate) fn add_event_callback(&self, callback: EventCallback) {
+        self.event_callbacks
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .push(callback);
+    }
+
+    /// Pass a connection event to the callbacks.
+    pub(crate) fn emit(&self, event: &ConnectionEvent) {
+        debug!("Lamp | {event}");
+        self.event_callbacks
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .retain_mut(|callback| callback(event));
+    }
+
+    /// Get the number of registered callbacks.
+    #[cfg(test)]
+    pub(crate) fn callbacks(&self) -> usize {
+        self.callbacks
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .len()
+    }
+
+    /// Read from the stream until it's closed, routing every response.
+    fn run(&self, mut stream: impl Read, epoch: u64) {
+        let mut lines = LineReader::default();
+        let mut chunk = [0; 512];
+        let reason = loop {
+            match stream.read(&mut chunk) {
+                Ok(0) => break DisconnectReason::Closed,
+                Ok(len) => self.receive(&mut lines, &chunk[..len]),
+                // a read timeout set on the stream applies to the reader as well, so it's ignored
+                Err(err) if matches!(err.kind(), ErrorKind::Interrupted | ErrorKind::TimedOut) => {
+                    continue;
+                }
+                // so does nonblocking mode, in which case the reader polls instead of spinning
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                    std::thread::sleep(Duration::from_millis(10));
+                    continue;
+                }
+                Err(err) => {
+                    debug!("Lamp | Reader stopped: {err}");
+                    self.record_error(Some(err.kind()), &err);
+                    break err.kind().into();
+                }
+            }
+        };
+        self.close(epoch, reason);
+    }
+
+    /// Pass a response to its waiter or to the callbacks.
+    fn route(&self, response: Response) {
+        match response {
+            Response::Props(notification) => {
+                self.update_state(|state| state.apply_notification(&notification));
+                self.callbacks
+                    .lock()
+                    .unwrap_or_else(|poisoned| poisoned.into_inner())
+                    .retain_mut(|callback| callback(&notification));
+                self.enqueue(Response::Props(notification));
+            }
+            reply => {
+                self.lock_stats().responses_received += 1;
+                if let Some(id) = reply.id() {
+                    self.acknowledge(id);
+                }
+                let mut replies = self.lock_replies();
+                match reply.id().and_then(|id| replies.awaited.get_mut(&id)) {
+                    Some(slot) => {
+                        *slot = Some(Ok(reply));
+                        self.arrived.notify_all();
+                    }
+                    None => {
+                        drop(replies);
+                        self.enqueue(reply);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Queue a response nobody waits for, if responses are queued.
+    fn enqueue(&self, response: Response) {
+        let mut queue = self.lock_queue();
+        let Some(queue) = queue.as_mut() else {
+            debug!("Lamp | Dropping unawaited {response:?}");
+            return;
+        };
+        if queue.len() == QUEUE_CAPACITY {
+            let dropped = queue.pop_front();
+            debug!("Lamp | Queue full, dropping {dropped:?}");
+        }
+        queue.push_back(response);
+    }
+
+    /// Pass the reason a line was rejected to the waiter of its reply, if its id can be found.
+    ///
+    /// This way, replies rejected by [ParseMode::Strict] fail the call instead of letting it time out.
+    fn reject(&self, line: &str, err: ParseResponseError) {
+        let id = Response::parse(line, ParseMode::Lenient)
+            .ok()
+            .and_then(|response| response.id());
+        let mut replies = self.lock_replies();
+        match id.and_then(|id| replies.awaited.get_mut(&id)) {
+            Some(slot) => {
+                *slot = Some(Err(CallError::InvalidResponse(err)));
+                self.arrived.notify_all();
+            }
+            None => debug!("Lamp | Skipping invalid response: {err}"),
+        }
+    }
+
+    /// Lock the replies, ignoring poisoning (the state stays consistent when a waiter panics).
+    fn lock_replies(&self) -> MutexGuard<'_, Replies> {
+        self.replies
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Mark the connection as closed, waking up the waiters and reporting why it was closed.
+    fn mark_closed(&self, mut replies: MutexGuard<'_, Replies>, reason: DisconnectReason) {
+        replies.closed = true;
+        replies.reason = Some(reason);
+        self.arrived.notify_all();
+        drop(replies);
+        self.emit(&ConnectionEvent::Disconnected { reason });
+    }
+
+    /// Lock the counters, ignoring poisoning.
+    fn lock_stats(&self) -> MutexGuard<'_, LampStats> {
+        self.stats
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Lock the queue of responses, ignoring poisoning.
+    fn lock_queue(&self) -> MutexGuard<'_, Option<VecDeque<Response>>> {
+        self.queue
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Lock the unacknowledged requests, ignoring poisoning.
+    fn lock_unacked(&self) -> MutexGuard<'_, VecDeque<(u32, Vec<u8>)>> {
+        self.unacked
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Lock the cached state, ignoring poisoning.
+    fn lock_state(&self) -> MutexGuard<'_, LampState> {
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl<T: Transport> Writer for T {
+    fn shutdown(&self) -> std::io::Result<()> {
+        Transport::shutdown(self, Shutdown::Both)
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::net::{TcpListener, TcpStream};
+
+    /// Open a local connection, returning both of its ends.
+    fn stream_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (peer, _) = listener.accept().unwrap();
+        (stream, peer)
+    }
+
+    #[test]
+    fn close_epochs() {
+        let inbox = Inbox::default();
+        let disconnects = Arc::new(Mutex::new(Vec::new()));
+        let events = Arc::clone(&disconnects);
+        inbox.add_event_callback(Box::new(move |event| {
+            events.lock().unwrap().push(*event);
+            true
+        }));
+        let (first, _first_peer) = stream_pair();
+        let (second, _second_peer) = stream_pair();
+        assert_eq!(inbox.attach(&first).unwrap(), 1);
+        assert_eq!(inbox.attach(&second).unwrap(), 2);
+        // the reader of the replaced connection stops without closing the inbox
+        inbox.close(1, DisconnectReason::Closed);
+        assert!(!inbox.is_closed());
+        // and so does a write to the replaced connection
+        inbox.lose(1, &Error::from(ErrorKind::BrokenPipe));
+        assert!(!inbox.is_closed());
+        inbox.lose(2, &Error::from(ErrorKind::TimedOut));
+        assert!(!inbox.is_closed());
+        inbox.close(2, DisconnectReason::Reset);
+        inbox.close(2, DisconnectReason::Closed);
+        assert_eq!(inbox.disconnect_reason(), Some(DisconnectReason::Reset));
+        assert_eq!(
+            *disconnects.lock().unwrap(),
+            [ConnectionEvent::Disconnected {
+                reason: DisconnectReason::Reset
+            }]
+        );
+        assert_eq!(inbox.attach(&second).unwrap(), 3);
+        assert_eq!(inbox.disconnect_reason(), None);
+    }
+
+    #[test]
+    fn close_if_idle() {
+        let inbox = Inbox::default();
+        let (stream, peer) = stream_pair();
+        let _epoch = inbox.attach(&stream).unwrap();
+        let timeout = Duration::from_secs(60);
+        assert!(inbox.close_if_idle(timeout) > Instant::now() + timeout / 2);
+        assert!(!inbox.is_closed());
+        // an awaited reply keeps the connection open even after the timeout
+        inbox.expect(1);
+        let _next = inbox.close_if_idle(Duration::ZERO);
+        assert!(!inbox.is_closed());
+        inbox.forget(1);
+        let _next = inbox.close_if_idle(Duration::ZERO);
+        assert_eq!(inbox.disconnect_reason(), Some(DisconnectReason::Idle));
+        // the connection was shut down as well
+        assert_eq!((&peer).read(&mut [0; 8]).unwrap(), 0);
+        inbox.expect(2);
+        assert!(matches!(
+            inbox.try_take(2),
+            Some(Err(CallError::Disconnected(DisconnectReason::Idle)))
+        ));
+    }
+
+    #[test]
+    fn track_evicts_oldest() {
+        let inbox = Inbox::default();
+        let count = u32::try_from(UNACKED_CAPACITY).unwrap() + 2;
+        for id in 0..count {
+            inbox.track(id, id.to_string().as_bytes());
+        }
+        let unacked = inbox.unacknowledged();
+        assert_eq!(unacked.len(), UNACKED_CAPACITY);
+        assert_eq!(unacked[0], (2, b"2".to_vec()));
+        // tracking an id again replaces its request instead of evicting another one
+        inbox.track(10, b"again");
+        inbox.acknowledge(2);
+        let unacked = inbox.unacknowledged();
+        assert_eq!(unacked.len(), UNACKED_CAPACITY - 1);
+        assert_eq!(unacked[0], (3, b"3".to_vec()));
+        assert_eq!(unacked.last(), Some(&(10, b"again".to_vec())));
+    }
+
+    #[test]
+    fn reject_strict() {
+        let inbox = Inbox::default();
+        inbox.set_parse_mode(ParseMode::Strict);
+        let mut lines = LineReader::default();
+        inbox.expect(1);
+        inbox.expect(2);
+        inbox.receive(
+            &mut lines,
+            b"{\"id\":1,\"result\":[\"ok\"],\"seq\":5}\r\n{\"id\":3,\"result\":[\"ok\"],\"seq\":6}\r\n",
+        );
+        assert!(matches!(
+            inbox.try_take(1),
+            Some(Err(CallError::InvalidResponse(ParseResponseError::UnknownField(field)))) if field == "seq"
+        ));
+        // an invalid reply to another id doesn't fail the call
+        assert!(inbox.try_take(2).is_none());
+        inbox.receive(&mut lines, b"{\"id\":2,\"result\":[\"ok\"]}\r\n");
+        assert!(matches!(inbox.try_take(2), Some(Ok(_))));
+    }
+}
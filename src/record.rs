@@ -0,0 +1,269 @@
+use derive_more::Display;
+use serde::{Deserialize, Serialize};
+
+use std::io::{BufRead, BufReader, Error, ErrorKind, Read, Write};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Instant;
+use std::{borrow::ToOwned, string::String, vec::Vec};
+
+use crate::lamp::{Direction, WireFrame};
+
+/*
+ * Please follow this order:
+ * - structs/enums, ordered s.t. dependencies are above dependents
+ * - impls (e.g. impl Recording)
+ * - impl _ for _ (like Display, From<T>,...)
+ */
+
+/// A line sent to or received from a lamp, as part of a [Recording].
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct RecordedFrame {
+    /// Whether the line was sent or received.
+    pub direction: Direction,
+    /// The time since the recording started, in milliseconds.
+    pub elapsed_ms: u64,
+    /// The line, without the `\r\n` terminator.
+    pub line: String,
+}
+
+/// The lines exchanged with a lamp during a session, in order.
+///
+/// Recordings are created with [Lamp::start_recording](crate::lamp::Lamp::start_recording),
+/// and can be stored with serde, e.g. as JSON.
+/// A recording can be replayed from either side of the connection:
+/// [Recording::serve] plays the lamp for a client under test (such as a [Lamp](crate::lamp::Lamp) connected to a mock),
+/// while [Recording::drive] plays the client against a lamp or an emulator.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Recording {
+    /// The recorded lines.
+    pub frames: Vec<RecordedFrame>,
+}
+
+/// A handle to a recording in progress, see [Lamp::start_recording](crate::lamp::Lamp::start_recording).
+///
+/// Clones of the handle share the same recording.
+#[derive(Clone, Debug)]
+pub struct Recorder {
+    /// When the recording started.
+    start: Instant,
+    /// The lines recorded so far.
+    recording: Arc<Mutex<Recording>>,
+}
+
+/// The reason a [Recording] could not be replayed.
+#[derive(Debug, Display)]
+pub enum ReplayError {
+    /// Reading from or writing to the stream failed.
+    #[display("I/O error: {_0}")]
+    Io(Error),
+    /// The stream was closed before the recording ended.
+    #[display("the stream was closed after {_0} frames")]
+    Closed(usize),
+    /// A line differs from the recorded one.
+    #[display("frame {index} differs: expected {expected}, got {got}")]
+    Mismatch {
+        /// The index of the frame in the recording.
+        index: usize,
+        /// The recorded line.
+        expected: String,
+        /// The line read from the stream.
+        got: String,
+    },
+}
+
+impl Recording {
+    /// Play the lamp side of the recording on a stream connected to a client.
+    ///
+    /// For each recorded request, a line is read from the stream and compared with it;
+    /// each recorded reply or notification is written to the stream.
+    /// Returns once all frames were replayed.
+    pub fn serve<S: Read + Write>(&self, stream: S) -> Result<(), ReplayError> {
+        self.replay(stream, Direction::Received)
+    }
+
+    /// Play the client side of the recording on a stream connected to a lamp (or an emulator).
+    ///
+    /// Each recorded request is written to the stream, and for each recorded reply or notification,
+    /// a line is read from the stream and compared with it.
+    /// Returns once all frames were replayed.
+    pub fn drive<S: Read + Write>(&self, stream: S) -> Result<(), ReplayError> {
+        self.replay(stream, Direction::Sent)
+    }
+
+    /// Write the frames going in the given direction, and read and compare the others.
+    fn replay<S: Read + Write>(&self, stream: S, written: Direction) -> Result<(), ReplayError> {
+        let mut stream = BufReader::new(stream);
+        let mut line = String::new();
+        for (index, frame) in self.frames.iter().enumerate() {
+            if frame.direction == written {
+                let writer = stream.get_mut();
+                writer.write_all(frame.line.as_bytes())?;
+                writer.write_all(b"\r\n")?;
+                writer.flush()?;
+                continue;
+            }
+            line.clear();
+            if stream.read_line(&mut line)? == 0 {
+                return Err(ReplayError::Closed(index));
+            }
+            let got = line.trim_end();
+            if got != frame.line {
+                return Err(ReplayError::Mismatch {
+                    index,
+                    expected: frame.line.clone(),
+                    got: got.to_owned(),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Recorder {
+    /// Start a new, empty recording.
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            recording: Arc::default(),
+        }
+    }
+
+    /// Add a line to the recording.
+    pub fn push(&self, frame: &WireFrame<'_>) {
+        let elapsed_ms = u64::try_from(self.start.elapsed().as_millis()).unwrap_or(u64::MAX);
+        self.lock().frames.push(RecordedFrame {
+            direction: frame.direction,
+            elapsed_ms,
+            line: frame.line.to_owned(),
+        });
+    }
+
+    /// Get a copy of the lines recorded so far.
+    pub fn recording(&self) -> Recording {
+        self.lock().clone()
+    }
+
+    /// Lock the recording, ignoring poisoning.
+    fn lock(&self) -> MutexGuard<'_, Recording> {
+        self.recording
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Default for Recorder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl From<Error> for ReplayError {
+    fn from(value: Error) -> Self {
+        Self::Io(value)
+    }
+}
+
+impl From<ReplayError> for Error {
+    fn from(value: ReplayError) -> Self {
+        match value {
+            ReplayError::Io(err) => err,
+            other => Self::new(ErrorKind::InvalidData, other),
+        }
+    }
+}
+
+impl std::error::Error for ReplayError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(err) => Some(err),
+            Self::Closed(_) | Self::Mismatch { .. } => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cmd::{Action, Command, Effect};
+    use crate::lamp::testing::connected_pair;
+    use pretty_assertions::assert_eq;
+    use serde_json::Value;
+    use std::net::{TcpListener, TcpStream};
+    use std::vec;
+
+    fn recording() -> Recording {
+        let frame = |direction, line: &str| RecordedFrame {
+            direction,
+            elapsed_ms: 0,
+            line: line.to_owned(),
+        };
+        Recording {
+            frames: vec![
+                frame(
+                    Direction::Sent,
+                    r#"{"id":1,"method":"set_ct_abx","params":[3200,"sudden",0]}"#,
+                ),
+                frame(
+                    Direction::Received,
+                    r#"{"method":"props","params":{"ct":"3200"}}"#,
+                ),
+                frame(Direction::Received, r#"{"id":1,"result":["ok"]}"#),
+            ],
+        }
+    }
+
+    #[test]
+    fn record_and_serve() {
+        let (mut lamp, peer) = connected_pair();
+        let recorder = lamp.start_recording();
+        let recorded = recording();
+        let lamp_side = recorded.clone();
+        let server = std::thread::spawn(move || lamp_side.serve(peer));
+        let cmd = Command::new(Action::new_ct(3200), Effect::Sudden);
+        assert_eq!(lamp.call(&cmd).unwrap(), vec![Value::from("ok")]);
+        server.join().unwrap().unwrap();
+        let lines: Vec<_> = recorder
+            .recording()
+            .frames
+            .into_iter()
+            .map(|frame| (frame.direction, frame.line))
+            .collect();
+        let expected: Vec<_> = recorded
+            .frames
+            .into_iter()
+            .map(|frame| (frame.direction, frame.line))
+            .collect();
+        assert_eq!(lines, expected);
+    }
+
+    #[test]
+    fn drive_detects_mismatches() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut lamp_side, _) = listener.accept().unwrap();
+        let emulator = std::thread::spawn(move || {
+            let mut request = String::new();
+            let _len = BufReader::new(&lamp_side).read_line(&mut request).unwrap();
+            lamp_side
+                .write_all(b"{\"id\":1,\"result\":[\"ok\"]}\r\n")
+                .unwrap();
+            request
+        });
+        match recording().drive(client) {
+            Err(ReplayError::Mismatch { index, got, .. }) => {
+                assert_eq!(index, 1);
+                assert_eq!(got, r#"{"id":1,"result":["ok"]}"#);
+            }
+            other => panic!("expected a mismatch, got {other:?}"),
+        }
+        assert!(emulator.join().unwrap().contains("set_ct_abx"));
+    }
+
+    #[test]
+    fn serde_roundtrip() {
+        let recording = recording();
+        let json = serde_json::to_string(&recording).unwrap();
+        assert!(json.contains(r#""direction":"sent""#));
+        assert_eq!(serde_json::from_str::<Recording>(&json).unwrap(), recording);
+    }
+}
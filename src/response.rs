@@ -0,0 +1,624 @@
+use alloc::{
+    borrow::Cow,
+    collections::BTreeMap,
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::str::FromStr;
+use derive_more::Display;
+use serde::Deserialize;
+use serde::de::{Deserializer, IgnoredAny, MapAccess, Visitor};
+use serde_json::{Value, value::RawValue};
+
+/*
+ * Please follow this order:
+ * - structs/enums, ordered s.t. dependencies are above dependents
+ * - impls (e.g. impl Response)
+ * - impl _ for _ (like Display, From<T>,...)
+ */
+
+/// The properties documented in the protocol specification, which are accepted by [ParseMode::Strict].
+pub const KNOWN_PROPS: [&str; 23] = [
+    "power",
+    "bright",
+    "ct",
+    "rgb",
+    "hue",
+    "sat",
+    "color_mode",
+    "flowing",
+    "delayoff",
+    "flow_params",
+    "music_on",
+    "name",
+    "bg_power",
+    "bg_flowing",
+    "bg_flow_params",
+    "bg_ct",
+    "bg_lmode",
+    "bg_bright",
+    "bg_rgb",
+    "bg_hue",
+    "bg_sat",
+    "nl_br",
+    "active_mode",
+];
+
+/// How strictly lines sent by a lamp are parsed, see [Response::parse].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum ParseMode {
+    /// Reject unknown fields and unknown notified properties (see [KNOWN_PROPS]) with an error.
+    ///
+    /// This is useful for detecting protocol changes, e.g. when testing against new firmware.
+    Strict,
+    /// Ignore unknown fields, and keep unknown properties in the notification like any other property.
+    #[default]
+    Lenient,
+}
+
+/// The reason a lamp rejected a request, derived from a [LampError].
+///
+/// Lamps use the code -1 for most errors, so the message is taken into account as well.
+#[derive(Clone, Copy, Debug, Display, PartialEq, Eq, Hash)]
+pub enum LampErrorCode {
+    /// The lamp received too many requests (more than 60 per minute), e.g. `client quota exceeded`.
+    #[display("quota exceeded")]
+    QuotaExceeded,
+    /// The lamp doesn't support the method, e.g. `method not supported`.
+    #[display("unsupported method")]
+    UnsupportedMethod,
+    /// The parameters of the request are invalid, e.g. `invalid params` (code -5001).
+    #[display("invalid parameters")]
+    InvalidParams,
+    /// The lamp couldn't carry out the request, e.g. because it's off (code -5000, `general error`).
+    #[display("general error")]
+    General,
+    /// An error that isn't known to this crate, with its code.
+    #[display("unknown error (code {_0})")]
+    Unknown(i64),
+}
+
+/// The error object of a failed request, such as `{"code":-1,"message":"unsupported method"}`.
+#[derive(Clone, Debug, Display, PartialEq, Eq, Deserialize)]
+#[display("{message} (code {code})")]
+pub struct LampError {
+    /// The error code sent by the lamp.
+    pub code: i64,
+    /// The message sent by the lamp.
+    pub message: String,
+}
+
+/// A notification of changed properties, sent unprompted by a lamp.
+///
+/// Lamps notify all connected clients when their state changes,
+/// e.g. because of a physical switch, the official app, or a command.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Notification {
+    /// The changed properties and their new values, such as `"power": "on"`.
+    pub props: BTreeMap<String, Value>,
+}
+
+/// A message sent by a lamp.
+///
+/// Each line the lamp sends is either a reply to a request (matched by its id) or a notification.
+/// ```
+/// # use yeerugina_lib::response::Response;
+/// let response: Response = r#"{"id":1,"result":["ok"]}"#.parse().unwrap();
+/// assert!(response.is_ok());
+/// assert_eq!(response.id(), Some(1));
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub enum Response {
+    /// A successful reply to a request, such as `{"id":1,"result":["ok"]}`.
+    Result {
+        /// The id of the request.
+        id: u32,
+        /// The results, such as `["ok"]` or the values of the requested properties.
+        result: Vec<Value>,
+    },
+    /// A failed reply to a request, such as `{"id":2,"error":{"code":-1,"message":"unsupported method"}}`.
+    Error {
+        /// The id of the request.
+        id: u32,
+        /// The error sent by the lamp.
+        error: LampError,
+    },
+    /// A notification of changed properties, sent unprompted,
+    /// such as `{"method":"props","params":{"power":"on","bright":"10"}}`.
+    Props(Notification),
+}
+
+/// The reason a line sent by a lamp could not be parsed into a [Response].
+#[derive(Clone, Debug, Display, PartialEq, Eq)]
+pub enum ParseResponseError {
+    /// The line isn't valid JSON, or doesn't have the fields of a response.
+    #[display("invalid response: {_0}")]
+    InvalidJson(String),
+    /// The line is a notification with an unknown method.
+    #[display("unknown notification method {_0}")]
+    UnknownMethod(String),
+    /// The line has neither a result, an error, nor a method.
+    #[display("response has neither a result, an error, nor a method")]
+    MissingPayload,
+    /// The line has a field that isn't part of the protocol (only in [ParseMode::Strict]).
+    #[display("unknown field {_0}")]
+    UnknownField(String),
+    /// A notification has a property that isn't in [KNOWN_PROPS] (only in [ParseMode::Strict]).
+    #[display("unknown property {_0}")]
+    UnknownProperty(String),
+}
+
+/// A response borrowing from the line it was parsed from, see [Response] for the owned equivalent.
+///
+/// Parsing doesn't copy the values sent by the lamp: the results and properties are kept as raw JSON,
+/// which can be inspected without allocating, or converted with [RawResponse::to_response].
+/// This matters when consuming many notifications, e.g. from a whole fleet of lamps.
+/// ```
+/// # use yeerugina_lib::response::{ParseMode, RawResponse};
+/// let line = r#"{"method":"props","params":{"power":"on","bright":"10"}}"#;
+/// let raw = RawResponse::parse(line, ParseMode::Lenient).unwrap();
+/// let mut bright = None;
+/// raw.for_each_prop(|prop, value| {
+///     if prop == "bright" {
+///         bright = Some(value.get());
+///     }
+/// })
+/// .unwrap();
+/// assert_eq!(bright, Some(r#""10""#));
+/// ```
+#[derive(Clone, Debug)]
+pub enum RawResponse<'a> {
+    /// A successful reply to a request.
+    Result {
+        /// The id of the request.
+        id: u32,
+        /// The results as a raw JSON array, such as `["ok"]`.
+        result: &'a RawValue,
+    },
+    /// A failed reply to a request.
+    Error {
+        /// The id of the request.
+        id: u32,
+        /// The error code sent by the lamp.
+        code: i64,
+        /// The message sent by the lamp (only copied if it contains escapes).
+        message: Cow<'a, str>,
+    },
+    /// A notification of changed properties.
+    Props {
+        /// The properties as a raw JSON object, such as `{"power":"on"}`, if the lamp sent any.
+        params: Option<&'a RawValue>,
+    },
+}
+
+/// The fields of a response, borrowed from the line, which are checked by [RawResponse::from_fields].
+#[derive(Default)]
+struct Fields<'a> {
+    id: Option<u32>,
+    result: Option<&'a RawValue>,
+    error: Option<RawLampError<'a>>,
+    method: Option<Cow<'a, str>>,
+    params: Option<&'a RawValue>,
+    /// The first field that isn't part of the protocol.
+    unknown: Option<Cow<'a, str>>,
+}
+
+/// The error object of a failed request, borrowed from the line.
+#[derive(Deserialize)]
+struct RawLampError<'a> {
+    code: i64,
+    #[serde(borrow)]
+    message: Cow<'a, str>,
+}
+
+/// A string borrowed from the line, unless it contains escapes.
+#[derive(Deserialize)]
+struct Borrowed<'a>(#[serde(borrow)] Cow<'a, str>);
+
+/// Visitor for [Fields], which are deserialized by hand to find unknown fields without buffering them.
+struct FieldsVisitor;
+
+/// Visitor passing the properties of a notification to a closure, see [RawResponse::for_each_prop].
+struct PropsVisitor<F>(F);
+
+impl LampError {
+    /// Get the typed reason of the error, so that callers can branch on it.
+    /// ```
+    /// # use yeerugina_lib::response::{LampError, LampErrorCode};
+    /// let error = LampError { code: -1, message: "client quota exceeded".to_owned() };
+    /// assert_eq!(error.kind(), LampErrorCode::QuotaExceeded);
+    /// ```
+    pub fn kind(&self) -> LampErrorCode {
+        let message = self.message.to_ascii_lowercase();
+        if message.contains("quota") {
+            LampErrorCode::QuotaExceeded
+        } else if message.contains("not supported") || message.contains("unsupported") {
+            LampErrorCode::UnsupportedMethod
+        } else if self.code == -5001 || message.contains("invalid param") {
+            LampErrorCode::InvalidParams
+        } else if self.code == -5000 || message.contains("general error") {
+            LampErrorCode::General
+        } else {
+            LampErrorCode::Unknown(self.code)
+        }
+    }
+}
+
+impl Notification {
+    /// Get the new value of a property, such as `power`.
+    pub fn get(&self, prop: &str) -> Option<&Value> {
+        self.props.get(prop)
+    }
+}
+
+impl Response {
+    /// Get the id of the request this is a reply to, or None for notifications.
+    pub fn id(&self) -> Option<u32> {
+        match self {
+            Self::Result { id, .. } | Self::Error { id, .. } => Some(*id),
+            Self::Props(_) => None,
+        }
+    }
+
+    /// Whether this is a successful reply.
+    pub fn is_ok(&self) -> bool {
+        matches!(self, Self::Result { .. })
+    }
+
+    /// Parse a line sent by the lamp (with or without the `\r\n` terminator) using the given mode.
+    /// ```
+    /// # use yeerugina_lib::response::{ParseMode, ParseResponseError, Response};
+    /// let line = r#"{"method":"props","params":{"power":"on","dimming_curve":"2"}}"#;
+    /// assert!(Response::parse(line, ParseMode::Lenient).is_ok());
+    /// assert_eq!(
+    ///     Response::parse(line, ParseMode::Strict),
+    ///     Err(ParseResponseError::UnknownProperty("dimming_curve".to_owned()))
+    /// );
+    /// ```
+    pub fn parse(s: &str, mode: ParseMode) -> Result<Self, ParseResponseError> {
+        RawResponse::parse(s.trim_end(), mode)?.to_response()
+    }
+}
+
+impl<'a> RawResponse<'a> {
+    /// Parse a line sent by the lamp (without the `\r\n` terminator) using the given mode, borrowing from it.
+    ///
+    /// In [ParseMode::Strict], the properties of notifications are checked as well.
+    pub fn parse(line: &'a str, mode: ParseMode) -> Result<Self, ParseResponseError> {
+        let fields: Fields<'a> = serde_json::from_str(line)?;
+        if mode == ParseMode::Strict
+            && let Some(field) = &fields.unknown
+        {
+            return Err(ParseResponseError::UnknownField(field.to_string()));
+        }
+        let raw = Self::from_fields(fields)?;
+        if mode == ParseMode::Strict {
+            let mut unknown = None;
+            raw.for_each_prop(|prop, _| {
+                if unknown.is_none() && !KNOWN_PROPS.contains(&prop) {
+                    unknown = Some(prop.to_string());
+                }
+            })?;
+            if let Some(prop) = unknown {
+                return Err(ParseResponseError::UnknownProperty(prop));
+            }
+        }
+        Ok(raw)
+    }
+
+    /// Get the id of the request this is a reply to, or None for notifications.
+    pub fn id(&self) -> Option<u32> {
+        match self {
+            Self::Result { id, .. } | Self::Error { id, .. } => Some(*id),
+            Self::Props { .. } => None,
+        }
+    }
+
+    /// Pass each property of a notification and its raw JSON value (such as `"on"`) to a closure.
+    ///
+    /// The property names are only copied if they contain escapes. Does nothing for replies.
+    pub fn for_each_prop<F>(&self, f: F) -> Result<(), ParseResponseError>
+    where
+        F: FnMut(&str, &'a RawValue),
+    {
+        match self {
+            Self::Props {
+                params: Some(params),
+            } => {
+                let mut deserializer = serde_json::Deserializer::from_str(params.get());
+                Ok(deserializer.deserialize_map(PropsVisitor(f))?)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Convert the response into an owned [Response], copying the values.
+    pub fn to_response(&self) -> Result<Response, ParseResponseError> {
+        match self {
+            Self::Result { id, result } => Ok(Response::Result {
+                id: *id,
+                result: serde_json::from_str(result.get())?,
+            }),
+            Self::Error { id, code, message } => Ok(Response::Error {
+                id: *id,
+                error: LampError {
+                    code: *code,
+                    message: message.to_string(),
+                },
+            }),
+            Self::Props { .. } => {
+                let mut props = BTreeMap::new();
+                let mut err = None;
+                self.for_each_prop(|prop, value| match serde_json::from_str(value.get()) {
+                    Ok(value) => {
+                        let _prev = props.insert(prop.to_string(), value);
+                    }
+                    Err(invalid) => err = Some(invalid.into()),
+                })?;
+                match err {
+                    Some(err) => Err(err),
+                    None => Ok(Response::Props(Notification { props })),
+                }
+            }
+        }
+    }
+
+    /// Check the fields of a deserialized response.
+    fn from_fields(fields: Fields<'a>) -> Result<Self, ParseResponseError> {
+        match fields {
+            Fields {
+                id: Some(id),
+                result: Some(result),
+                ..
+            } => Ok(Self::Result { id, result }),
+            Fields {
+                id: Some(id),
+                error: Some(error),
+                ..
+            } => Ok(Self::Error {
+                id,
+                code: error.code,
+                message: error.message,
+            }),
+            Fields {
+                method: Some(method),
+                params,
+                ..
+            } => match &*method {
+                "props" => Ok(Self::Props { params }),
+                _ => Err(ParseResponseError::UnknownMethod(method.to_string())),
+            },
+            _ => Err(ParseResponseError::MissingPayload),
+        }
+    }
+}
+
+impl FromStr for Response {
+    type Err = ParseResponseError;
+
+    /// Parse a line sent by the lamp (with or without the `\r\n` terminator) in [ParseMode::Lenient].
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s, ParseMode::Lenient)
+    }
+}
+
+impl<'de> Deserialize<'de> for Fields<'de> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_map(FieldsVisitor)
+    }
+}
+
+impl<'de> Visitor<'de> for FieldsVisitor {
+    type Value = Fields<'de>;
+
+    fn expecting(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str("a response object")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+        let mut fields = Fields::default();
+        while let Some(Borrowed(key)) = map.next_key()? {
+            match &*key {
+                "id" => fields.id = map.next_value()?,
+                "result" => fields.result = Some(map.next_value()?),
+                "error" => fields.error = Some(map.next_value()?),
+                "method" => fields.method = Some(map.next_value::<Borrowed<'de>>()?.0),
+                "params" => fields.params = Some(map.next_value()?),
+                _ => {
+                    let _ignored: IgnoredAny = map.next_value()?;
+                    let _first = fields.unknown.get_or_insert(key);
+                }
+            }
+        }
+        Ok(fields)
+    }
+}
+
+impl<'de, F> Visitor<'de> for PropsVisitor<F>
+where
+    F: FnMut(&str, &'de RawValue),
+{
+    type Value = ();
+
+    fn expecting(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str("an object of properties")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(mut self, mut map: A) -> Result<Self::Value, A::Error> {
+        while let Some(Borrowed(prop)) = map.next_key()? {
+            let value = map.next_value()?;
+            self.0(&prop, value);
+        }
+        Ok(())
+    }
+}
+
+impl From<serde_json::Error> for ParseResponseError {
+    fn from(value: serde_json::Error) -> Self {
+        Self::InvalidJson(value.to_string())
+    }
+}
+
+impl core::error::Error for LampError {}
+
+impl core::error::Error for ParseResponseError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::{borrow::ToOwned, vec};
+
+    #[test]
+    fn parse_result() {
+        assert_eq!(
+            "{\"id\":1,\"result\":[\"ok\"]}\r\n".parse(),
+            Ok(Response::Result {
+                id: 1,
+                result: vec![Value::from("ok")]
+            })
+        );
+        let props: Response = r#"{"id":2,"result":["on","",100]}"#.parse().unwrap();
+        assert_eq!(props.id(), Some(2));
+        assert_eq!(
+            props,
+            Response::Result {
+                id: 2,
+                result: vec!["on".into(), "".into(), 100.into()]
+            }
+        );
+    }
+
+    #[test]
+    fn parse_error() {
+        let response: Response = r#"{"id":3,"error":{"code":-1,"message":"unsupported method"}}"#
+            .parse()
+            .unwrap();
+        assert!(!response.is_ok());
+        let error = LampError {
+            code: -1,
+            message: "unsupported method".to_owned(),
+        };
+        assert_eq!(error.to_string(), "unsupported method (code -1)");
+        assert_eq!(response, Response::Error { id: 3, error });
+    }
+
+    #[test]
+    fn parse_props() {
+        let response: Response = r#"{"method":"props","params":{"power":"on","bright":"10"}}"#
+            .parse()
+            .unwrap();
+        assert_eq!(response.id(), None);
+        let props = BTreeMap::from([
+            ("power".to_owned(), Value::from("on")),
+            ("bright".to_owned(), Value::from("10")),
+        ]);
+        assert_eq!(response, Response::Props(Notification { props }));
+    }
+
+    #[test]
+    fn parse_invalid() {
+        assert!(matches!(
+            "not json".parse::<Response>(),
+            Err(ParseResponseError::InvalidJson(_))
+        ));
+        assert_eq!(
+            r#"{"method":"reboot","params":{}}"#.parse::<Response>(),
+            Err(ParseResponseError::UnknownMethod("reboot".to_owned()))
+        );
+        assert_eq!(
+            r#"{"id":4}"#.parse::<Response>(),
+            Err(ParseResponseError::MissingPayload)
+        );
+    }
+
+    #[test]
+    fn parse_modes() {
+        let extra_field = r#"{"id":1,"result":["ok"],"seq":5}"#;
+        assert!(Response::parse(extra_field, ParseMode::Lenient).is_ok());
+        assert_eq!(
+            Response::parse(extra_field, ParseMode::Strict),
+            Err(ParseResponseError::UnknownField("seq".to_owned()))
+        );
+        let known = r#"{"method":"props","params":{"bg_power":"on","nl_br":"10"}}"#;
+        assert!(Response::parse(known, ParseMode::Strict).is_ok());
+        let unknown = r#"{"method":"props","params":{"power":"on","lan_ctrl":"1"}}"#;
+        assert_eq!(
+            Response::parse(unknown, ParseMode::Strict),
+            Err(ParseResponseError::UnknownProperty("lan_ctrl".to_owned()))
+        );
+        let Ok(Response::Props(notification)) = Response::parse(unknown, ParseMode::Lenient) else {
+            panic!("expected a notification");
+        };
+        assert_eq!(notification.get("lan_ctrl"), Some(&Value::from("1")));
+    }
+
+    #[test]
+    fn raw_responses() {
+        let line = r#"{"id":5,"result":["on","",100]}"#;
+        let raw = RawResponse::parse(line, ParseMode::Strict).unwrap();
+        assert_eq!(raw.id(), Some(5));
+        assert!(
+            matches!(raw, RawResponse::Result { result, .. } if result.get() == r#"["on","",100]"#)
+        );
+        assert_eq!(raw.to_response(), line.parse());
+        let line = r#"{"id":6,"error":{"code":-1,"message":"say \"hi\""}}"#;
+        let raw = RawResponse::parse(line, ParseMode::Lenient).unwrap();
+        assert!(
+            matches!(&raw, RawResponse::Error { message: Cow::Owned(message), .. } if message == "say \"hi\"")
+        );
+        assert_eq!(raw.to_response(), line.parse());
+        let line = r#"{"method":"props","params":{"power":"on","ct":4000}}"#;
+        let raw = RawResponse::parse(line, ParseMode::Lenient).unwrap();
+        let mut props = Vec::new();
+        raw.for_each_prop(|prop, value| props.push((prop.to_owned(), value.get())))
+            .unwrap();
+        assert_eq!(
+            props,
+            vec![("power".to_owned(), r#""on""#), ("ct".to_owned(), "4000")]
+        );
+        assert_eq!(raw.to_response(), line.parse());
+        // the properties are checked lazily, unless in strict mode
+        let line = r#"{"method":"props","params":[]}"#;
+        let raw = RawResponse::parse(line, ParseMode::Lenient).unwrap();
+        assert!(matches!(
+            raw.to_response(),
+            Err(ParseResponseError::InvalidJson(_))
+        ));
+        assert!(RawResponse::parse(line, ParseMode::Strict).is_err());
+    }
+
+    #[test]
+    fn error_codes() {
+        let error = |code, message: &str| LampError {
+            code,
+            message: message.to_owned(),
+        };
+        let cases = [
+            (
+                error(-1, "client quota exceeded"),
+                LampErrorCode::QuotaExceeded,
+            ),
+            (
+                error(-1, "method not supported"),
+                LampErrorCode::UnsupportedMethod,
+            ),
+            (
+                error(-1, "Unsupported Method"),
+                LampErrorCode::UnsupportedMethod,
+            ),
+            (error(-5001, "invalid params"), LampErrorCode::InvalidParams),
+            (error(-1, "invalid params"), LampErrorCode::InvalidParams),
+            (error(-5000, "general error"), LampErrorCode::General),
+            (error(-1, "something else"), LampErrorCode::Unknown(-1)),
+            (error(42, ""), LampErrorCode::Unknown(42)),
+        ];
+        for (error, code) in cases {
+            assert_eq!(error.kind(), code, "{error}");
+        }
+        assert_eq!(
+            LampErrorCode::Unknown(7).to_string(),
+            "unknown error (code 7)"
+        );
+    }
+}
@@ -0,0 +1,553 @@
+use alloc::{
+    collections::BTreeMap,
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::fmt::Display;
+use serde_json::Value;
+
+use crate::cmd::{Action, Brightness, Hue, Power, Saturation};
+use crate::colors::Rgb;
+use crate::response::Notification;
+
+/*
+ * Please follow this order:
+ * - structs/enums, ordered s.t. dependencies are above dependents
+ * - impls (e.g. impl LampState)
+ * - impl _ for _ (like Display, From<T>,...)
+ */
+
+/// The mode deciding which color a lamp displays, as reported in the `color_mode` property.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ColorMode {
+    /// The color is given by the `rgb` property.
+    Rgb,
+    /// The color is given by the `ct` property.
+    ColorTemperature,
+    /// The color is given by the `hue` and `sat` properties.
+    Hsv,
+}
+
+/// A property of a lamp that can be queried with `get_prop`, see [Command::get_prop](crate::cmd::Command::get_prop).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Property {
+    /// Whether the lamp is on (`power`).
+    Power,
+    /// The brightness (`bright`).
+    Bright,
+    /// The color temperature (`ct`).
+    Ct,
+    /// The RGB color (`rgb`).
+    Rgb,
+    /// The hue (`hue`).
+    Hue,
+    /// The saturation (`sat`).
+    Sat,
+    /// Which of the colors is displayed (`color_mode`).
+    ColorMode,
+    /// Whether a color flow is running (`flowing`).
+    Flowing,
+    /// The minutes until the lamp turns off (`delayoff`).
+    Delayoff,
+    /// Whether music mode is on (`music_on`).
+    MusicOn,
+    /// The name of the lamp (`name`).
+    Name,
+}
+
+/// The last known state of a lamp.
+///
+/// Every field is None until its value is known,
+/// e.g. because the lamp sent a notification or confirmed a command changing it.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LampState {
+    /// Whether the lamp is on (`power`).
+    pub power: Option<Power>,
+    /// The brightness (`bright`).
+    pub bright: Option<Brightness>,
+    /// The color temperature in kelvins (`ct`).
+    pub ct: Option<u16>,
+    /// The RGB color (`rgb`).
+    pub rgb: Option<Rgb>,
+    /// The hue (`hue`).
+    pub hue: Option<Hue>,
+    /// The saturation (`sat`).
+    pub sat: Option<Saturation>,
+    /// Which of the colors is displayed (`color_mode`).
+    pub color_mode: Option<ColorMode>,
+    /// Whether a color flow is running (`flowing`).
+    pub flowing: Option<bool>,
+    /// The minutes until the lamp turns off, or zero if no timer is set (`delayoff`).
+    pub delayoff: Option<u32>,
+    /// Whether music mode is on (`music_on`).
+    pub music_on: Option<bool>,
+    /// The name of the lamp set with `set_name` (`name`).
+    pub name: Option<String>,
+    /// The properties that aren't modelled by the fields above (such as `bg_power`), with their raw values.
+    ///
+    /// These are kept so that nothing reported by the lamp is dropped, but they aren't part of [LampState::diff].
+    pub unknown: BTreeMap<String, Value>,
+}
+
+/// A change of a property in a [LampState], with its old and new value (None if unknown).
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[allow(missing_docs)] // the fields are always the old and new value
+pub enum StateChange {
+    /// The lamp was turned on or off.
+    PowerChanged {
+        from: Option<Power>,
+        to: Option<Power>,
+    },
+    /// The brightness changed.
+    BrightnessChanged {
+        from: Option<Brightness>,
+        to: Option<Brightness>,
+    },
+    /// The color temperature changed.
+    CtChanged { from: Option<u16>, to: Option<u16> },
+    /// The RGB color changed.
+    RgbChanged { from: Option<Rgb>, to: Option<Rgb> },
+    /// The hue changed.
+    HueChanged { from: Option<Hue>, to: Option<Hue> },
+    /// The saturation changed.
+    SaturationChanged {
+        from: Option<Saturation>,
+        to: Option<Saturation>,
+    },
+    /// The displayed color changed between RGB, color temperature and HSV.
+    ColorModeChanged {
+        from: Option<ColorMode>,
+        to: Option<ColorMode>,
+    },
+    /// A color flow started or stopped.
+    FlowingChanged {
+        from: Option<bool>,
+        to: Option<bool>,
+    },
+    /// The sleep timer changed.
+    DelayoffChanged { from: Option<u32>, to: Option<u32> },
+    /// Music mode was turned on or off.
+    MusicOnChanged {
+        from: Option<bool>,
+        to: Option<bool>,
+    },
+    /// The name changed.
+    NameChanged {
+        from: Option<String>,
+        to: Option<String>,
+    },
+}
+
+impl ColorMode {
+    /// Get the color mode from its number in the protocol.
+    pub const fn from_code(code: i64) -> Option<Self> {
+        match code {
+            1 => Some(Self::Rgb),
+            2 => Some(Self::ColorTemperature),
+            3 => Some(Self::Hsv),
+            _ => None,
+        }
+    }
+
+    /// Get the number of the color mode in the protocol.
+    pub const fn code(self) -> i64 {
+        match self {
+            Self::Rgb => 1,
+            Self::ColorTemperature => 2,
+            Self::Hsv => 3,
+        }
+    }
+}
+
+impl Property {
+    /// All properties modelled by [LampState].
+    pub const ALL: [Self; 11] = [
+        Self::Power,
+        Self::Bright,
+        Self::Ct,
+        Self::Rgb,
+        Self::Hue,
+        Self::Sat,
+        Self::ColorMode,
+        Self::Flowing,
+        Self::Delayoff,
+        Self::MusicOn,
+        Self::Name,
+    ];
+
+    /// The name of the property on the wire, such as `color_mode`.
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Power => "power",
+            Self::Bright => "bright",
+            Self::Ct => "ct",
+            Self::Rgb => "rgb",
+            Self::Hue => "hue",
+            Self::Sat => "sat",
+            Self::ColorMode => "color_mode",
+            Self::Flowing => "flowing",
+            Self::Delayoff => "delayoff",
+            Self::MusicOn => "music_on",
+            Self::Name => "name",
+        }
+    }
+}
+
+impl StateChange {
+    /// The property that changed.
+    pub fn property(&self) -> Property {
+        match self {
+            Self::PowerChanged { .. } => Property::Power,
+            Self::BrightnessChanged { .. } => Property::Bright,
+            Self::CtChanged { .. } => Property::Ct,
+            Self::RgbChanged { .. } => Property::Rgb,
+            Self::HueChanged { .. } => Property::Hue,
+            Self::SaturationChanged { .. } => Property::Sat,
+            Self::ColorModeChanged { .. } => Property::ColorMode,
+            Self::FlowingChanged { .. } => Property::Flowing,
+            Self::DelayoffChanged { .. } => Property::Delayoff,
+            Self::MusicOnChanged { .. } => Property::MusicOn,
+            Self::NameChanged { .. } => Property::Name,
+        }
+    }
+}
+
+impl LampState {
+    /// Create a state from the reply to a `get_prop` request.
+    ///
+    /// The lamp replies with one value per requested property, in the order they were requested.
+    /// Properties the lamp doesn't support are replied as empty strings, so they stay None.
+    /// ```
+    /// # use yeerugina_lib::state::{LampState, Property};
+    /// # use serde_json::Value;
+    /// let values = [Value::from("on"), Value::from("")];
+    /// let state = LampState::from_props(&[Property::Power, Property::Name], &values);
+    /// assert!(state.power.is_some());
+    /// assert_eq!(state.name, None);
+    /// ```
+    pub fn from_props(props: &[Property], values: &[Value]) -> Self {
+        let mut state = Self::default();
+        state.merge_props(props, values);
+        state
+    }
+
+    /// Update the state from the reply to a `get_prop` request, see [LampState::from_props].
+    pub fn merge_props(&mut self, props: &[Property], values: &[Value]) {
+        for (prop, value) in props.iter().zip(values) {
+            let _known = self.apply_prop(prop.name(), value);
+        }
+    }
+
+    /// Update the state from a property reported by the lamp, such as `"bright": "50"`.
+    ///
+    /// Lamps report most values as strings, but numbers are accepted as well.
+    /// Returns false (leaving the fields unchanged) if the property is unknown or its value is invalid;
+    /// lamps report unsupported properties as empty strings, which are ignored in the same way.
+    /// Unknown properties are kept in [LampState::unknown].
+    pub fn apply_prop(&mut self, prop: &str, value: &Value) -> bool {
+        fn set<T>(field: &mut Option<T>, value: Option<T>) -> bool {
+            let known = value.is_some();
+            if known {
+                *field = value;
+            }
+            known
+        }
+        if value.as_str() == Some("") {
+            return false;
+        }
+        let int = int_value(value);
+        match prop {
+            "power" => set(
+                &mut self.power,
+                match value.as_str() {
+                    Some("on") => Some(Power::On),
+                    Some("off") => Some(Power::Off),
+                    _ => None,
+                },
+            ),
+            "bright" => set(
+                &mut self.bright,
+                narrow(int).and_then(|b| Brightness::new(b).ok()),
+            ),
+            "ct" => set(&mut self.ct, narrow(int)),
+            "rgb" => set(&mut self.rgb, narrow(int).map(Rgb::from_int)),
+            "hue" => set(&mut self.hue, narrow(int).and_then(|h| Hue::new(h).ok())),
+            "sat" => set(
+                &mut self.sat,
+                narrow(int).and_then(|s| Saturation::new(s).ok()),
+            ),
+            "color_mode" => set(&mut self.color_mode, int.and_then(ColorMode::from_code)),
+            "flowing" => set(&mut self.flowing, int.map(|flowing| flowing != 0)),
+            "delayoff" => set(&mut self.delayoff, narrow(int)),
+            "music_on" => set(&mut self.music_on, int.map(|music| music != 0)),
+            "name" => set(&mut self.name, value.as_str().map(ToString::to_string)),
+            _ => {
+                let _prev = self.unknown.insert(prop.to_string(), value.clone());
+                false
+            }
+        }
+    }
+
+    /// Get the known properties as the lamp reports them, such as `"bright": "50"`, including the unknown ones.
+    ///
+    /// Applying them with [LampState::apply_prop] restores the state, e.g. after storing it.
+    pub fn to_props(&self) -> BTreeMap<String, Value> {
+        let flag = |on: bool| if on { "1" } else { "0" };
+        let known = [
+            (Property::Power, self.power.map(|power| power.to_string())),
+            (
+                Property::Bright,
+                self.bright.map(|bright| bright.get().to_string()),
+            ),
+            (Property::Ct, self.ct.map(|ct| ct.to_string())),
+            (Property::Rgb, self.rgb.map(|rgb| rgb.to_int().to_string())),
+            (Property::Hue, self.hue.map(|hue| hue.get().to_string())),
+            (Property::Sat, self.sat.map(|sat| sat.get().to_string())),
+            (
+                Property::ColorMode,
+                self.color_mode.map(|mode| mode.code().to_string()),
+            ),
+            (Property::Flowing, self.flowing.map(|on| flag(on).into())),
+            (
+                Property::Delayoff,
+                self.delayoff.map(|mins| mins.to_string()),
+            ),
+            (Property::MusicOn, self.music_on.map(|on| flag(on).into())),
+            (Property::Name, self.name.clone()),
+        ];
+        let mut props = self.unknown.clone();
+        for (prop, value) in known {
+            if let Some(value) = value {
+                let _prev = props.insert(prop.name().into(), value.into());
+            }
+        }
+        props
+    }
+
+    /// Get the properties whose values differ between this state and another one.
+    pub fn changed(&self, other: &Self) -> Vec<Property> {
+        self.diff(other).iter().map(StateChange::property).collect()
+    }
+
+    /// Get the changes from this state to a newer one, in the order of [Property::ALL].
+    pub fn diff(&self, new: &Self) -> Vec<StateChange> {
+        let mut changes = Vec::new();
+        macro_rules! compare {
+            ($($field:ident => $variant:ident),* $(,)?) => {
+                $(
+                    if self.$field != new.$field {
+                        changes.push(StateChange::$variant {
+                            from: self.$field.clone(),
+                            to: new.$field.clone(),
+                        });
+                    }
+                )*
+            };
+        }
+        compare!(
+            power => PowerChanged,
+            bright => BrightnessChanged,
+            ct => CtChanged,
+            rgb => RgbChanged,
+            hue => HueChanged,
+            sat => SaturationChanged,
+            color_mode => ColorModeChanged,
+            flowing => FlowingChanged,
+            delayoff => DelayoffChanged,
+            music_on => MusicOnChanged,
+            name => NameChanged,
+        );
+        changes
+    }
+
+    /// Update the state from all properties in a notification.
+    pub fn apply_notification(&mut self, notification: &Notification) {
+        for (prop, value) in &notification.props {
+            let _known = self.apply_prop(prop, value);
+        }
+    }
+
+    /// Update the state after the lamp confirmed an action.
+    ///
+    /// Relative adjustments make the affected values unknown, and custom actions are ignored.
+    pub fn apply_action(&mut self, action: &Action) {
+        action.apply_to(self);
+    }
+}
+
+impl Display for Property {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Get an integer from a property value, which is either a number or a string containing a number.
+fn int_value(value: &Value) -> Option<i64> {
+    match value {
+        Value::Number(num) => num.as_i64(),
+        Value::String(s) => s.trim().parse().ok(),
+        _ => None,
+    }
+}
+
+/// Convert an integer into a smaller type, if it fits.
+fn narrow<T: TryFrom<i64>>(int: Option<i64>) -> Option<T> {
+    int.and_then(|int| T::try_from(int).ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::borrow::ToOwned;
+
+    #[test]
+    fn apply_props() {
+        let mut state = LampState::default();
+        assert!(state.apply_prop("power", &"on".into()));
+        assert!(state.apply_prop("bright", &"50".into()));
+        assert!(state.apply_prop("ct", &4000.into()));
+        assert!(state.apply_prop("rgb", &"16711680".into()));
+        assert!(state.apply_prop("color_mode", &"2".into()));
+        assert!(state.apply_prop("name", &"desk".into()));
+        assert!(state.apply_prop("flowing", &"0".into()));
+        assert_eq!(
+            state,
+            LampState {
+                power: Some(Power::On),
+                bright: Some(Brightness::new(50).unwrap()),
+                ct: Some(4000),
+                rgb: Some(Rgb::new(255, 0, 0)),
+                color_mode: Some(ColorMode::ColorTemperature),
+                flowing: Some(false),
+                name: Some("desk".to_owned()),
+                ..LampState::default()
+            }
+        );
+    }
+
+    #[test]
+    fn ignores_invalid_props() {
+        let mut state = LampState::default();
+        assert!(!state.apply_prop("bright", &"".into()));
+        assert!(!state.apply_prop("bright", &"0".into()));
+        assert!(!state.apply_prop("hue", &"360".into()));
+        assert!(!state.apply_prop("power", &"maybe".into()));
+        assert_eq!(state, LampState::default());
+        assert!(!state.apply_prop("bg_power", &"on".into()));
+        assert_eq!(state.unknown.get("bg_power"), Some(&Value::from("on")));
+    }
+
+    #[test]
+    fn apply_notification() {
+        let mut state = LampState::default();
+        let props = BTreeMap::from([
+            ("power".to_owned(), Value::from("off")),
+            ("hue".to_owned(), Value::from("270")),
+            ("sat".to_owned(), Value::from("67")),
+            ("bg_power".to_owned(), Value::from("on")),
+        ]);
+        state.apply_notification(&Notification { props });
+        assert_eq!(state.power, Some(Power::Off));
+        assert_eq!(state.hue, Some(Hue::new(270).unwrap()));
+        assert_eq!(state.sat, Some(Saturation::new(67).unwrap()));
+    }
+
+    #[test]
+    fn from_props() {
+        let values = [Value::from("off"), Value::from(""), Value::from("3")];
+        let state = LampState::from_props(
+            &[Property::Power, Property::Ct, Property::ColorMode],
+            &values,
+        );
+        assert_eq!(
+            state,
+            LampState {
+                power: Some(Power::Off),
+                color_mode: Some(ColorMode::Hsv),
+                ..LampState::default()
+            }
+        );
+        for prop in Property::ALL {
+            let value = if prop == Property::Power { "on" } else { "1" };
+            assert!(
+                LampState::default().apply_prop(prop.name(), &value.into()),
+                "{prop}"
+            );
+        }
+        assert!(!LampState::default().apply_prop("name", &"".into()));
+    }
+
+    #[test]
+    fn changed() {
+        let old = LampState {
+            power: Some(Power::On),
+            ct: Some(3000),
+            ..LampState::default()
+        };
+        let new = LampState {
+            power: Some(Power::On),
+            ct: Some(4000),
+            name: Some("desk".to_owned()),
+            ..LampState::default()
+        };
+        assert_eq!(old.changed(&new), std::vec![Property::Ct, Property::Name]);
+        assert_eq!(new.changed(&new), std::vec![]);
+        assert_eq!(
+            old.diff(&new),
+            std::vec![
+                StateChange::CtChanged {
+                    from: Some(3000),
+                    to: Some(4000)
+                },
+                StateChange::NameChanged {
+                    from: None,
+                    to: Some("desk".to_owned())
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn apply_actions() {
+        let mut state = LampState::default();
+        state.apply_action(&Action::new_ct(3000));
+        assert_eq!(state.ct, Some(3000));
+        assert_eq!(state.color_mode, Some(ColorMode::ColorTemperature));
+        let hue = Hue::new(120).unwrap();
+        let sat = Saturation::new(50).unwrap();
+        state.apply_action(&Action::new_hsv(hue, sat));
+        assert_eq!((state.hue, state.sat), (Some(hue), Some(sat)));
+        assert_eq!(state.color_mode, Some(ColorMode::Hsv));
+        state.apply_action(&Action::new_adjust_ct(
+            crate::cmd::Percentage::new(10).unwrap(),
+        ));
+        assert_eq!(state.ct, None);
+        let before = state.clone();
+        state.apply_action(&Action::new_custom("toggle", std::vec![]));
+        assert_eq!(state, before);
+    }
+
+    #[test]
+    fn to_props() {
+        let mut state = LampState {
+            power: Some(Power::On),
+            bright: Some(Brightness::new(50).unwrap()),
+            rgb: Some(Rgb::new(255, 0, 0)),
+            color_mode: Some(ColorMode::Rgb),
+            flowing: Some(false),
+            name: Some("desk".into()),
+            ..LampState::default()
+        };
+        let _prev = state.unknown.insert("bg_power".into(), Value::from("off"));
+        let props = state.to_props();
+        assert_eq!(props.get("bright"), Some(&Value::from("50")));
+        assert_eq!(props.get("color_mode"), Some(&Value::from("1")));
+        assert_eq!(props.get("ct"), None);
+        let mut restored = LampState::default();
+        for (prop, value) in &props {
+            let _known = restored.apply_prop(prop, value);
+        }
+        assert_eq!(restored, state);
+    }
+}
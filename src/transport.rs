@@ -0,0 +1,626 @@
+use log::debug;
+use serde_json::{Value, json};
+
+use std::collections::VecDeque;
+use std::io::{Error, ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::Duration;
+use std::{
+    string::{String, ToString},
+    vec::Vec,
+};
+
+use crate::lamp::LampBuilder;
+use crate::response::LampError;
+
+/*
+ * Please follow this order:
+ * - traits
+ * - structs/enums, ordered s.t. dependencies are above dependents
+ * - impls (e.g. impl MockTransport)
+ * - impl _ for _ (like Transport for TcpStream)
+ */
+
+/// A byte stream connecting a [Lamp](crate::lamp::Lamp) to a lamp, such as a [TcpStream].
+///
+/// The protocol logic of the lamp (ids, replies, notifications, the cached state) works over any transport,
+/// e.g. unix sockets in tests, TLS tunnels or serial bridges.
+/// Everything the lamp sends is read by a background thread from a clone of the transport (see [Transport::try_clone]).
+pub trait Transport: Read + Write + Send + Sized + 'static {
+    /// Create another handle to the same connection, which the background reader thread reads from.
+    fn try_clone(&self) -> std::io::Result<Self>;
+
+    /// Shut down the reading half, the writing half or both halves of the connection.
+    ///
+    /// Shutting down the reading half has to stop the reader thread, i.e. reads from the clones return 0 afterwards.
+    fn shutdown(&self, how: Shutdown) -> std::io::Result<()>;
+
+    /// Get the address of the lamp, if the transport has one (it's reported by the wiretap, and used for reconnecting).
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+
+    /// Get the local address of the connection, if the transport has one (it's used for music mode).
+    fn local_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+
+    /// Switch the connection to nonblocking mode (or back), see [Lamp::set_nonblocking](crate::lamp::Lamp::set_nonblocking).
+    ///
+    /// By default, transports don't support nonblocking mode.
+    fn set_nonblocking(&self, nonblocking: bool) -> std::io::Result<()> {
+        if nonblocking {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                "the transport doesn't support nonblocking mode",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Open a new connection to the lamp at the address with the options of the builder,
+    /// see [Lamp::reconnect](crate::lamp::Lamp::reconnect).
+    ///
+    /// By default, transports can't reconnect.
+    fn reconnect(_peer: Option<SocketAddr>, _builder: &LampBuilder) -> std::io::Result<Self> {
+        Err(Error::new(
+            ErrorKind::Unsupported,
+            "the transport can't reconnect",
+        ))
+    }
+
+    /// Apply the options of this connection (such as its timeouts) to a new connection after reconnecting.
+    fn carry_options_to(&self, _new: &Self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The connection shared by all handles of a [MockTransport].
+#[derive(Debug, Default)]
+struct MockConnection {
+    /// What was written by the lamp, and what it can read.
+    state: Mutex<MockState>,
+    /// Notified when something can be read, or the connection was closed.
+    readable: Condvar,
+}
+
+/// The state of a [MockConnection].
+#[derive(Debug, Default)]
+struct MockState {
+    /// The complete request lines written so far, without their `\r\n`.
+    requests: Vec<String>,
+    /// The part of the last request that was written before its `\r\n`.
+    partial: Vec<u8>,
+    /// The replies to the next requests, in order.
+    replies: VecDeque<Result<Vec<Value>, LampError>>,
+    /// The bytes the lamp can read.
+    incoming: VecDeque<u8>,
+    /// Whether reads return `WouldBlock` instead of waiting.
+    nonblocking: bool,
+    /// Whether reading stopped, because the connection was closed by either side.
+    read_closed: bool,
+    /// Whether the lamp shut down the writing half.
+    write_closed: bool,
+}
+
+/// A [Transport] that doesn't connect anywhere, for testing code using lamps without hardware.
+///
+/// The mock records the requests written to it and replies to them with scripted results,
+/// using the ids of the requests. Requests without a scripted reply aren't replied to.
+/// Clones share the connection, so a clone kept by the test inspects what the lamp did.
+/// ```
+/// # use serde_json::Value;
+/// # use yeerugina_lib::{cmd::{Action, Command, Effect, Power}, lamp::Lamp, transport::MockTransport};
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let mock = MockTransport::new();
+/// mock.push_reply(Ok(vec![Value::from("ok")]));
+/// let mut lamp = Lamp::with_transport(mock.clone())?;
+/// lamp.call(&Command::new(Action::new_power(Power::On), Effect::Sudden))?;
+/// assert_eq!(
+///     mock.requests(),
+///     [r#"{"id":1,"method":"set_power","params":["on","sudden",0]}"#]
+/// );
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct MockTransport {
+    connection: Arc<MockConnection>,
+}
+
+/// The faults a [FaultyTransport] injects, for testing how lamps cope with unreliable connections.
+///
+/// By default, no faults are injected.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Faults {
+    /// The delay before each read and write.
+    pub latency: Duration,
+    /// The most bytes returned by a single read (at least 1), so that responses arrive in pieces.
+    pub max_read: Option<usize>,
+    /// Drop every n-th byte read (counting from when the faults were set), corrupting the responses.
+    pub drop_every: Option<usize>,
+    /// Disconnect after this many bytes were read (counting from when the faults were set), even in the middle of a response.
+    ///
+    /// Reads return 0 afterwards, writes fail with [ErrorKind::ConnectionReset], and the wrapped transport is shut down.
+    pub disconnect_after: Option<usize>,
+}
+
+/// The faults shared by the clones of a [FaultyTransport].
+#[derive(Debug, Default)]
+struct FaultState {
+    /// The faults to inject.
+    faults: Faults,
+    /// The bytes read since the faults were set, including the dropped ones.
+    read: usize,
+    /// Whether the connection was disconnected by [Faults::disconnect_after].
+    disconnected: bool,
+}
+
+/// A [Transport] wrapping another one and injecting [Faults], for testing the framing and reconnecting under adverse conditions.
+///
+/// Clones (e.g. the one read by the reader thread of the lamp) share the faults.
+/// After reconnecting (see [Lamp::reconnect](crate::lamp::Lamp::reconnect)),
+/// the new connection keeps the faults, apart from [Faults::disconnect_after], so that it isn't disconnected again.
+/// ```no_run
+/// # use std::{net::TcpStream, time::Duration};
+/// # use yeerugina_lib::{lamp::Lamp, transport::{FaultyTransport, Faults}};
+/// # fn main() -> std::io::Result<()> {
+/// let faults = Faults {
+///     latency: Duration::from_millis(50),
+///     max_read: Some(3),
+///     ..Faults::default()
+/// };
+/// let lamp = Lamp::with_transport(FaultyTransport::new(TcpStream::connect("192.168.1.20:55443")?, faults))?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct FaultyTransport<T> {
+    /// The wrapped transport.
+    inner: T,
+    /// The faults, shared with the clones.
+    state: Arc<Mutex<FaultState>>,
+}
+
+impl<T> FaultyTransport<T> {
+    /// Wrap a transport, injecting the faults.
+    pub fn new(inner: T, faults: Faults) -> Self {
+        Self {
+            inner,
+            state: Arc::new(Mutex::new(FaultState {
+                faults,
+                ..FaultState::default()
+            })),
+        }
+    }
+
+    /// Get the faults injected currently.
+    pub fn faults(&self) -> Faults {
+        self.lock().faults
+    }
+
+    /// Change the faults for this transport and its clones, restarting the byte counts.
+    ///
+    /// A disconnect injected by [Faults::disconnect_after] is permanent, as the wrapped transport was shut down:
+    /// the transport stays disconnected with the new faults, and only reconnecting gets a working connection.
+    pub fn set_faults(&self, faults: Faults) {
+        let mut state = self.lock();
+        state.faults = faults;
+        state.read = 0;
+    }
+
+    /// Whether the connection was disconnected by [Faults::disconnect_after].
+    pub fn is_disconnected(&self) -> bool {
+        self.lock().disconnected
+    }
+
+    /// Get the wrapped transport.
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    /// Lock the shared faults.
+    fn lock(&self) -> MutexGuard<'_, FaultState> {
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl MockTransport {
+    /// Create a mock without requests or scripted replies.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Script the reply to the next request that isn't replied to yet, as a result or an error of the lamp.
+    pub fn push_reply(&self, reply: Result<Vec<Value>, LampError>) {
+        self.lock().replies.push_back(reply);
+    }
+
+    /// Let the lamp read a line right away, e.g. a notification or a malformed response.
+    ///
+    /// The terminating `\r\n` is added by this method.
+    pub fn push_line(&self, line: &str) {
+        let mut state = self.lock();
+        state.incoming.extend(line.as_bytes());
+        state.incoming.extend(b"\r\n");
+        self.connection.readable.notify_all();
+    }
+
+    /// Get the request lines written so far, without their `\r\n`.
+    pub fn requests(&self) -> Vec<String> {
+        self.lock().requests.clone()
+    }
+
+    /// Close the connection as the lamp would, so that reading ends (and the reader thread of the lamp stops).
+    pub fn close(&self) {
+        self.lock().read_closed = true;
+        self.connection.readable.notify_all();
+    }
+
+    /// Lock the state of the connection.
+    fn lock(&self) -> MutexGuard<'_, MockState> {
+        self.connection
+            .state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl MockState {
+    /// Record the complete requests in the written bytes, and queue the scripted replies to them.
+    fn receive(&mut self, buf: &[u8]) {
+        self.partial.extend_from_slice(buf);
+        while let Some(end) = self.partial.windows(2).position(|sep| sep == b"\r\n") {
+            let line = String::from_utf8_lossy(&self.partial[..end]).into_owned();
+            let _line = self.partial.drain(..end + 2);
+            let id = serde_json::from_str::<Value>(&line)
+                .ok()
+                .and_then(|request| request.get("id").cloned())
+                .unwrap_or(Value::Null);
+            self.requests.push(line);
+            let reply = match self.replies.pop_front() {
+                Some(Ok(result)) => json!({ "id": id, "result": result }),
+                Some(Err(err)) => json!({
+                    "id": id,
+                    "error": { "code": err.code, "message": err.message },
+                }),
+                None => continue,
+            };
+            self.incoming.extend(reply.to_string().as_bytes());
+            self.incoming.extend(b"\r\n");
+        }
+    }
+}
+
+impl FaultState {
+    /// Apply the faults to the bytes just read (the first `len` bytes of the buffer), returning how many bytes are kept.
+    ///
+    /// The kept bytes are moved to the front of the buffer.
+    fn apply(&mut self, buf: &mut [u8], mut len: usize) -> usize {
+        if let Some(after) = self.faults.disconnect_after {
+            let remaining = after.saturating_sub(self.read);
+            if len >= remaining {
+                len = remaining;
+                self.disconnected = true;
+            }
+        }
+        let mut kept = 0;
+        for pos in 0..len {
+            self.read += 1;
+            let dropped = self
+                .faults
+                .drop_every
+                .is_some_and(|every| self.read.is_multiple_of(every));
+            if !dropped {
+                buf[kept] = buf[pos];
+                kept += 1;
+            }
+        }
+        kept
+    }
+}
+
+impl Transport for TcpStream {
+    fn try_clone(&self) -> std::io::Result<Self> {
+        Self::try_clone(self)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> std::io::Result<()> {
+        Self::shutdown(self, how)
+    }
+
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        Self::peer_addr(self).ok()
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        Self::local_addr(self).ok()
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> std::io::Result<()> {
+        Self::set_nonblocking(self, nonblocking)
+    }
+
+    fn reconnect(peer: Option<SocketAddr>, builder: &LampBuilder) -> std::io::Result<Self> {
+        let peer = peer.ok_or_else(|| Error::from(ErrorKind::NotConnected))?;
+        builder.reconnect_addr(peer)
+    }
+
+    fn carry_options_to(&self, new: &Self) -> std::io::Result<()> {
+        new.set_read_timeout(self.read_timeout()?)?;
+        new.set_write_timeout(self.write_timeout()?)?;
+        new.set_nodelay(self.nodelay()?)
+    }
+}
+
+#[cfg(unix)]
+impl Transport for std::os::unix::net::UnixStream {
+    fn try_clone(&self) -> std::io::Result<Self> {
+        Self::try_clone(self)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> std::io::Result<()> {
+        Self::shutdown(self, how)
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> std::io::Result<()> {
+        Self::set_nonblocking(self, nonblocking)
+    }
+
+    fn carry_options_to(&self, new: &Self) -> std::io::Result<()> {
+        new.set_read_timeout(self.read_timeout()?)?;
+        new.set_write_timeout(self.write_timeout()?)
+    }
+}
+
+impl Transport for MockTransport {
+    fn try_clone(&self) -> std::io::Result<Self> {
+        Ok(self.clone())
+    }
+
+    fn shutdown(&self, how: Shutdown) -> std::io::Result<()> {
+        let mut state = self.lock();
+        if matches!(how, Shutdown::Read | Shutdown::Both) {
+            state.read_closed = true;
+        }
+        if matches!(how, Shutdown::Write | Shutdown::Both) {
+            state.write_closed = true;
+        }
+        self.connection.readable.notify_all();
+        Ok(())
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> std::io::Result<()> {
+        self.lock().nonblocking = nonblocking;
+        Ok(())
+    }
+}
+
+impl<T: Transport> Transport for FaultyTransport<T> {
+    fn try_clone(&self) -> std::io::Result<Self> {
+        Ok(Self {
+            inner: self.inner.try_clone()?,
+            state: Arc::clone(&self.state),
+        })
+    }
+
+    fn shutdown(&self, how: Shutdown) -> std::io::Result<()> {
+        self.inner.shutdown(how)
+    }
+
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.inner.peer_addr()
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> std::io::Result<()> {
+        self.inner.set_nonblocking(nonblocking)
+    }
+
+    fn reconnect(peer: Option<SocketAddr>, builder: &LampBuilder) -> std::io::Result<Self> {
+        // the faults are carried over by carry_options_to
+        Ok(Self::new(T::reconnect(peer, builder)?, Faults::default()))
+    }
+
+    fn carry_options_to(&self, new: &Self) -> std::io::Result<()> {
+        self.inner.carry_options_to(&new.inner)?;
+        new.set_faults(Faults {
+            disconnect_after: None,
+            ..self.faults()
+        });
+        Ok(())
+    }
+}
+
+impl<T: Transport> Read for FaultyTransport<T> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let faults = self.faults();
+        if !faults.latency.is_zero() {
+            std::thread::sleep(faults.latency);
+        }
+        let limit = buf.len().min(faults.max_read.unwrap_or(usize::MAX).max(1));
+        loop {
+            if self.is_disconnected() {
+                return Ok(0);
+            }
+            let len = self.inner.read(&mut buf[..limit])?;
+            if len == 0 {
+                return Ok(0);
+            }
+            let kept = self.lock().apply(buf, len);
+            if self.is_disconnected() {
+                debug!("Lamp | Injecting a disconnect");
+                let _res = self.inner.shutdown(Shutdown::Both);
+                return Ok(kept);
+            }
+            // reading 0 bytes would look like a closed connection, so a read of dropped bytes only is repeated
+            if kept > 0 {
+                return Ok(kept);
+            }
+        }
+    }
+}
+
+impl<T: Transport> Write for FaultyTransport<T> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let faults = self.faults();
+        if !faults.latency.is_zero() {
+            std::thread::sleep(faults.latency);
+        }
+        if self.is_disconnected() {
+            return Err(Error::new(
+                ErrorKind::ConnectionReset,
+                "injected disconnect",
+            ));
+        }
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl Read for MockTransport {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut state = self.lock();
+        while state.incoming.is_empty() && !state.read_closed {
+            if state.nonblocking {
+                return Err(Error::from(ErrorKind::WouldBlock));
+            }
+            state = self
+                .connection
+                .readable
+                .wait(state)
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+        }
+        let len = buf.len().min(state.incoming.len());
+        for (dst, src) in buf.iter_mut().zip(state.incoming.drain(..len)) {
+            *dst = src;
+        }
+        Ok(len)
+    }
+}
+
+impl Write for MockTransport {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut state = self.lock();
+        if state.write_closed || state.read_closed {
+            return Err(Error::from(ErrorKind::BrokenPipe));
+        }
+        state.receive(buf);
+        self.connection.readable.notify_all();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cmd::{Action, Command, Effect};
+    use crate::lamp::testing::{reply_to, respond};
+    use crate::lamp::{CallError, Lamp, RetryPolicy};
+    use pretty_assertions::assert_eq;
+    use std::net::TcpListener;
+    use std::{borrow::ToOwned, vec};
+
+    #[test]
+    fn mock_transport() {
+        let mock = MockTransport::new();
+        mock.push_reply(Ok(vec![Value::from("ok")]));
+        mock.push_reply(Err(LampError {
+            code: -5000,
+            message: "general error".to_owned(),
+        }));
+        let mut lamp = Lamp::with_transport(mock.clone()).unwrap();
+        let notifications = lamp.notifications();
+        let cmd = Command::new(Action::new_ct(3200), Effect::Sudden);
+        assert_eq!(lamp.call(&cmd).unwrap(), vec![Value::from("ok")]);
+        assert!(matches!(lamp.call(&cmd), Err(CallError::Lamp(_))));
+        assert_eq!(
+            mock.requests(),
+            [
+                r#"{"id":1,"method":"set_ct_abx","params":[3200,"sudden",0]}"#,
+                r#"{"id":2,"method":"set_ct_abx","params":[3200,"sudden",0]}"#,
+            ]
+        );
+        mock.push_line(r#"{"method":"props","params":{"power":"off"}}"#);
+        let notification = notifications.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(notification.get("power"), Some(&Value::from("off")));
+        // closing the lamp shuts down the mock
+        lamp.close().unwrap();
+        assert!(mock.clone().write(b"{}\r\n").is_err());
+    }
+
+    #[test]
+    fn faulty_transport() {
+        let mock = MockTransport::new();
+        mock.push_line("abcdef");
+        let faults = Faults {
+            max_read: Some(3),
+            drop_every: Some(2),
+            ..Faults::default()
+        };
+        let mut faulty = FaultyTransport::new(mock, faults);
+        let mut received = Vec::new();
+        let mut buf = [0; 16];
+        while received.len() < 4 {
+            let len = faulty.read(&mut buf).unwrap();
+            assert!((1..=2).contains(&len));
+            received.extend_from_slice(&buf[..len]);
+        }
+        assert_eq!(received, b"ace\r");
+
+        let mock = MockTransport::new();
+        mock.push_line("abcdef");
+        let faults = Faults {
+            disconnect_after: Some(2),
+            ..Faults::default()
+        };
+        let mut faulty = FaultyTransport::new(mock, faults);
+        assert_eq!(faulty.read(&mut buf).unwrap(), 2);
+        // the disconnect outlives changing the faults
+        faulty.set_faults(Faults::default());
+        assert!(faulty.is_disconnected());
+        assert_eq!(faulty.read(&mut buf).unwrap(), 0);
+        assert!(faulty.write(b"x").is_err());
+    }
+
+    #[test]
+    fn reconnects_after_injected_disconnect() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let _server = std::thread::spawn(move || {
+            for peer in listener.incoming() {
+                let _responder =
+                    respond(peer.unwrap(), |line| reply_to(line, r#""result":["ok"]"#));
+            }
+        });
+        let faults = Faults {
+            max_read: Some(4),
+            disconnect_after: Some(10),
+            ..Faults::default()
+        };
+        let stream = FaultyTransport::new(TcpStream::connect(addr).unwrap(), faults);
+        let mut lamp = Lamp::with_transport(stream).unwrap();
+        lamp.set_reconnect_policy(Some(RetryPolicy::default()));
+        let events = lamp.connection_events();
+        let cmd = Command::new(Action::new_ct(3200), Effect::Sudden);
+        // the first reply is cut off, so the command is resent on a new connection
+        assert_eq!(lamp.call(&cmd).unwrap(), vec![Value::from("ok")]);
+        assert!(events.try_recv().is_ok());
+        assert!(!lamp.stream().is_disconnected());
+        assert_eq!(lamp.stream().faults().max_read, Some(4));
+        assert_eq!(lamp.stream().faults().disconnect_after, None);
+    }
+}